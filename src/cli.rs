@@ -0,0 +1,525 @@
+//! Command-line arguments. Without a subcommand, TagMaid starts the GUI, which can be
+//! told what to show first so that desktop shortcuts and scripts can drive it.
+use crate::data::archive::ConflictPolicy;
+use crate::data::hydrus::{self, HydrusOptions};
+use crate::data::import::{ImportOptions, ImportProgress, ImportStatus, ImportedFile, Importer};
+use crate::data::link_export::{self, Layout, LinkExport, LinkMode};
+use crate::data::perceptual_hash;
+use crate::data::url_import::{self, UrlImportConfig};
+use crate::database::tagmaid_database::TagMaidDatabase;
+use crate::instance::{self, IpcMessage};
+use crate::server::ServeOptions;
+use anyhow::{bail, Result};
+use clap::{Args, Parser, Subcommand};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(name = "tag-maid", version, about = "A tagging based file explorer")]
+pub struct Cli {
+    /// Library folder to use instead of the default one
+    #[arg(long, global = true, value_name = "FOLDER")]
+    pub library: Option<PathBuf>,
+
+    /// Config file to use instead of `tag-maid.cfg` in the TagMaid data folder
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Starts on the Results page for this search query
+    #[arg(long, value_name = "QUERY", conflicts_with = "files")]
+    pub search: Option<String>,
+
+    /// Files to open in the Add/Edit page, one after the other
+    #[arg(value_name = "FILE")]
+    pub files: Vec<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Starts the local HTTP API instead of the GUI
+    Serve(ServeOptions),
+    /// Imports every file in a folder and its subfolders
+    Import(ImportArgs),
+    /// Imports the files and tags of a Hydrus Network client
+    ImportHydrus(HydrusArgs),
+    /// Downloads files from URLs and imports them, keeping their URL as source
+    ImportUrl(UrlArgs),
+    /// Recomputes the system tags (`system:ext:png`, `system:4k`...) of every file
+    RebuildSystemTags,
+    /// Deletes thumbnails of removed files and of sizes no longer in the config, then makes
+    /// the missing ones of every image
+    RebuildThumbnails,
+    /// Writes the tags of every file with the sync backends enabled in the config
+    /// (XMP sidecars...), creating or refreshing what they store
+    SyncTags,
+    /// Writes TagSpaces sidecars (`.ts/<file>.json`) for the files of a folder that are in
+    /// the library, with their tags
+    ExportTagspaces {
+        /// Folder holding the files
+        folder: PathBuf,
+    },
+    /// Writes the whole library (files, tags, metadata, aliases and implications) to a
+    /// tar archive, to move or share it
+    ExportArchive {
+        /// The archive to create
+        archive: PathBuf,
+    },
+    /// Merges an archive made by `export-archive` into the library
+    ImportArchive {
+        archive: PathBuf,
+
+        /// What to do with files (and aliases) that are both in the library and in the archive
+        #[arg(long, value_enum, default_value_t)]
+        conflicts: ConflictPolicy,
+    },
+    /// Exports the files matching a search to a folder, as symlinks, hardlinks or copies
+    /// under their original names
+    ExportLinks(LinkArgs),
+    /// Writes a static HTML site showing the files matching a search, with their tags
+    ExportGallery {
+        /// The folder to write the site to, empty or holding a gallery exported before
+        folder: PathBuf,
+
+        /// The search query whose results are exported
+        #[arg(long)]
+        query: String,
+
+        /// Shown at the top of the site
+        #[arg(long, default_value = "Gallery")]
+        title: String,
+    },
+    /// Backs up the library (see `backups` in the config), deleting the oldest backups
+    Backup {
+        /// Backs up the stored files too, even if the config doesn't
+        #[arg(long)]
+        files: bool,
+    },
+    /// Lists the backups of the library
+    ListBackups,
+    /// Replaces the library's database with a backup, after checking it. The current
+    /// database is backed up first.
+    Restore {
+        /// A backup folder, or a `sqlite.db` snapshot
+        backup: PathBuf,
+
+        /// Also copies back the stored files of the backup that are missing
+        #[arg(long)]
+        files: bool,
+    },
+    /// Merges another library folder into this one: files it has that this one doesn't are
+    /// copied, and files both have get the tags of both
+    MergeLibrary {
+        /// The other library folder, holding `sqlite.db`
+        other: PathBuf,
+
+        /// Only shows what would be merged
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Lists groups of images that look the same (resized, re-encoded...), computing the
+    /// perceptual hashes that are missing. Use the Duplicates page of the GUI to merge them.
+    FindDuplicates {
+        /// How many bits the perceptual hashes of two images may differ by
+        #[arg(long, default_value_t = perceptual_hash::DEFAULT_DISTANCE)]
+        distance: u32,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct ImportArgs {
+    /// Folder to import
+    pub folder: PathBuf,
+
+    /// Tag to give to every imported file (can be repeated)
+    #[arg(long = "tag", value_name = "TAG")]
+    pub tags: Vec<String>,
+
+    /// Turns the names of the subfolders a file is in into tags
+    #[arg(long)]
+    pub path_tags: bool,
+
+    /// Only imports the files matching this glob, relative to the folder (can be repeated)
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Skips the files matching this glob, relative to the folder (can be repeated)
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Only shows what would be imported, and what each auto-tagging rule would do
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Reads the tags TagSpaces stored in `.ts` sidecars and `[tag1 tag2]` file names
+    #[arg(long)]
+    pub tagspaces: bool,
+}
+
+impl ImportArgs {
+    /// Imports the folder on top of the `options` from the config (auto-tagging rules...)
+    pub fn run(self, db: &TagMaidDatabase, options: ImportOptions) -> Result<()> {
+        let dry_run = self.dry_run;
+        let options = ImportOptions {
+            tags: self.tags.into_iter().collect(),
+            path_tags: self.path_tags,
+            include: self.include,
+            exclude: self.exclude,
+            dry_run,
+            tagspaces: self.tagspaces,
+            ..options
+        };
+        let importer = Importer::new(db.clone(), options)?;
+        let report =
+            importer.import_folder(&self.folder, |progress| print_progress(&progress, dry_run))?;
+        println!("{}", report.summary());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct HydrusArgs {
+    /// The Hydrus `db` folder, holding `client.db`
+    pub folder: PathBuf,
+
+    /// The Hydrus file store, if it isn't `client_files` in the `db` folder
+    #[arg(long, value_name = "FOLDER")]
+    pub files: Option<PathBuf>,
+
+    /// Maps a Hydrus namespace to a TagMaid one, like `--namespace person=character`.
+    /// `--namespace page=` drops the namespace (can be repeated)
+    #[arg(long = "namespace", value_name = "HYDRUS=TAGMAID")]
+    pub namespaces: Vec<String>,
+
+    /// Only shows what would be imported
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+impl HydrusArgs {
+    pub fn run(self, db: &TagMaidDatabase, options: ImportOptions) -> Result<()> {
+        let mut namespaces = HashMap::new();
+        for namespace in self.namespaces.iter() {
+            match namespace.split_once('=') {
+                Some((hydrus, tagmaid)) => namespaces.insert(hydrus.to_owned(), tagmaid.to_owned()),
+                None => bail!("Invalid namespace mapping '{namespace}', expected HYDRUS=TAGMAID"),
+            };
+        }
+        let dry_run = self.dry_run;
+        let options = HydrusOptions {
+            namespaces,
+            files_folder: self.files,
+            import_options: ImportOptions { dry_run, ..options },
+        };
+        let report = hydrus::import(db, &self.folder, options, |progress| {
+            print_progress(&progress, dry_run)
+        })?;
+        for hash in report.missing.iter() {
+            println!("missing from the file store: {hash}");
+        }
+        for path in report.mismatched.iter() {
+            println!("wrong hash, not imported: {}", path.display());
+        }
+        for (tag, count) in report.dropped_tags.iter() {
+            println!("dropped tag: {tag} ({count} files)");
+        }
+        println!("{}", report.summary());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct LinkArgs {
+    /// The folder to export to
+    pub folder: PathBuf,
+
+    /// The search query whose results are exported, like `"client_x -draft"`
+    #[arg(long, required_unless_present = "refresh")]
+    pub query: Option<String>,
+
+    #[arg(long, value_enum, default_value_t)]
+    pub mode: LinkMode,
+
+    /// Puts the files in a sub-folder for each of their tags
+    #[arg(long)]
+    pub by_tag: bool,
+
+    /// Updates a folder exported to before, with the query and options it was exported with
+    #[arg(long, conflicts_with_all = ["query", "mode", "by_tag"])]
+    pub refresh: bool,
+}
+
+impl LinkArgs {
+    pub fn run(self, db: &TagMaidDatabase) -> Result<()> {
+        let report = match self.query {
+            Some(query) if !self.refresh => {
+                let export = LinkExport {
+                    query,
+                    mode: self.mode,
+                    layout: if self.by_tag {
+                        Layout::ByTag
+                    } else {
+                        Layout::Flat
+                    },
+                };
+                link_export::export(db, &self.folder, &export)?
+            }
+            _ => link_export::refresh(db, &self.folder)?,
+        };
+        println!("{}", report.summary());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct UrlArgs {
+    /// Direct links to the files
+    #[arg(required = true)]
+    pub urls: Vec<String>,
+
+    /// Tag to give to every imported file (can be repeated)
+    #[arg(long = "tag", value_name = "TAG")]
+    pub tags: Vec<String>,
+}
+
+impl UrlArgs {
+    /// Imports the URLs one after the other, carrying on when one of them fails
+    pub fn run(
+        self,
+        db: &TagMaidDatabase,
+        options: ImportOptions,
+        config: &UrlImportConfig,
+    ) -> Result<()> {
+        let importer = Importer::new(db.clone(), options)?;
+        let tags: HashSet<String> = self.tags.into_iter().collect();
+        let total = self.urls.len();
+        let mut failed = 0;
+        for (index, url) in self.urls.iter().enumerate() {
+            match url_import::import_url(db, &importer, url, config, &tags) {
+                Ok(file) => print_progress(
+                    &ImportProgress {
+                        done: index + 1,
+                        total,
+                        file: &ImportedFile {
+                            path: PathBuf::from(url),
+                            ..file
+                        },
+                    },
+                    false,
+                ),
+                Err(err) => {
+                    println!("[{}/{total}] {url} (failed: {err:#})", index + 1);
+                    failed += 1;
+                }
+            }
+        }
+        if failed > 0 {
+            bail!("{failed} of {total} URLs couldn't be imported");
+        }
+        Ok(())
+    }
+}
+
+/// Prints a line for each imported file, and what the rules did to it in a dry run
+fn print_progress(progress: &ImportProgress, dry_run: bool) {
+    let file = progress.file;
+    let status = match &file.status {
+        ImportStatus::Imported if dry_run => "would import".to_owned(),
+        ImportStatus::Imported => "imported".to_owned(),
+        ImportStatus::Duplicate => "duplicate".to_owned(),
+        ImportStatus::Skipped(rule_name) if dry_run => format!("would skip: {rule_name}"),
+        ImportStatus::Skipped(rule_name) => format!("skipped: {rule_name}"),
+        ImportStatus::Failed(err) => format!("failed: {err}"),
+    };
+    let tags: Vec<&str> = file.tags.iter().map(|tag| tag.as_str()).collect();
+    println!(
+        "[{}/{}] {} ({status}) [{}]",
+        progress.done,
+        progress.total,
+        file.path.display(),
+        tags.join(" ")
+    );
+    if dry_run {
+        for rule in file.rules.iter() {
+            println!("    rule {rule}");
+        }
+    }
+}
+
+impl Cli {
+    /// What the GUI should do once started. It is the same message that would be
+    /// handed to an already running TagMaid.
+    pub fn startup_message(&self) -> Option<IpcMessage> {
+        if let Some(query) = &self.search {
+            return Some(IpcMessage::Search(query.clone()));
+        }
+        if self.files.is_empty() {
+            return None;
+        }
+        Some(IpcMessage::Open(instance::resolve_file_args(&self.files)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn should_parse_command_line() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from(["tag-maid", "--search", "cat -dog"]).unwrap();
+        assert_eq!(
+            cli.startup_message(),
+            Some(IpcMessage::Search("cat -dog".to_owned()))
+        );
+
+        let cli =
+            Cli::try_parse_from(["tag-maid", "--library", "/tmp/lib", "a.png", "b.png"]).unwrap();
+        assert_eq!(cli.library, Some(PathBuf::from("/tmp/lib")));
+        assert_eq!(
+            cli.files,
+            vec![PathBuf::from("a.png"), PathBuf::from("b.png")]
+        );
+        assert!(cli.command.is_none());
+
+        assert!(Cli::try_parse_from(["tag-maid", "--search", "cat", "a.png"]).is_err());
+    }
+
+    #[test]
+    fn should_parse_serve() {
+        let cli = Cli::try_parse_from([
+            "tag-maid",
+            "--library",
+            "/tmp/lib",
+            "serve",
+            "--port",
+            "8000",
+        ])
+        .unwrap();
+        assert_eq!(cli.library, Some(PathBuf::from("/tmp/lib")));
+        match cli.command {
+            Some(Command::Serve(options)) => assert_eq!(options.port, 8000),
+            other => panic!("Expected serve, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn should_parse_import() {
+        let cli = Cli::try_parse_from([
+            "tag-maid",
+            "import",
+            "Photos",
+            "--path-tags",
+            "--tag",
+            "a",
+            "--tag",
+            "b",
+        ])
+        .unwrap();
+        match cli.command {
+            Some(Command::Import(args)) => {
+                assert_eq!(args.folder, PathBuf::from("Photos"));
+                assert_eq!(args.tags, vec!["a", "b"]);
+                assert!(args.path_tags && !args.dry_run);
+            }
+            other => panic!("Expected import, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn should_parse_import_hydrus() {
+        let cli = Cli::try_parse_from([
+            "tag-maid",
+            "import-hydrus",
+            "db",
+            "--namespace",
+            "person=character",
+        ])
+        .unwrap();
+        match cli.command {
+            Some(Command::ImportHydrus(args)) => {
+                assert_eq!(args.namespaces, vec!["person=character"]);
+                assert!(args.files.is_none());
+            }
+            other => panic!("Expected import-hydrus, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn should_parse_import_archive() {
+        let cli = Cli::try_parse_from([
+            "tag-maid",
+            "import-archive",
+            "library.tar",
+            "--conflicts",
+            "keep",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::ImportArchive {
+                conflicts: ConflictPolicy::Keep,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn should_parse_export_links() {
+        let cli = Cli::try_parse_from([
+            "tag-maid",
+            "export-links",
+            "Out",
+            "--query",
+            "client_x -draft",
+            "--mode",
+            "hardlink",
+            "--by-tag",
+        ])
+        .unwrap();
+        match cli.command {
+            Some(Command::ExportLinks(args)) => {
+                assert_eq!(args.query.as_deref(), Some("client_x -draft"));
+                assert_eq!(args.mode, LinkMode::Hardlink);
+                assert!(args.by_tag && !args.refresh);
+            }
+            other => panic!("Expected export-links, got {other:?}"),
+        }
+        assert!(Cli::try_parse_from(["tag-maid", "export-links", "Out"]).is_err());
+        assert!(Cli::try_parse_from(["tag-maid", "export-links", "Out", "--refresh"]).is_ok());
+    }
+
+    #[test]
+    fn should_parse_merge_library() {
+        let cli =
+            Cli::try_parse_from(["tag-maid", "merge-library", "../shared", "--dry-run"]).unwrap();
+        match cli.command {
+            Some(Command::MergeLibrary { other, dry_run }) => {
+                assert_eq!(other, PathBuf::from("../shared"));
+                assert!(dry_run);
+            }
+            other => panic!("Expected merge-library, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn should_parse_find_duplicates() {
+        let cli = Cli::try_parse_from(["tag-maid", "find-duplicates", "--distance", "3"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::FindDuplicates { distance: 3 })
+        ));
+    }
+
+    #[test]
+    fn should_parse_rebuild_commands() {
+        let cli = Cli::try_parse_from(["tag-maid", "rebuild-system-tags"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::RebuildSystemTags)));
+        let cli = Cli::try_parse_from(["tag-maid", "rebuild-thumbnails"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::RebuildThumbnails)));
+    }
+}
@@ -1,6 +1,27 @@
+pub mod archive;
+pub mod backup;
 pub mod cache;
 pub mod config;
+pub mod download_sidecar;
+pub mod embedded_metadata;
+pub mod html_gallery;
+pub mod hydrus;
+pub mod import;
+pub mod link_export;
+pub mod merge;
+pub mod mime;
+pub mod palette;
+pub mod perceptual_hash;
+pub mod rules;
 pub mod search_command;
+pub mod system_tags;
 pub mod tag_file;
+pub mod tag_sync;
 pub mod tag_util;
+pub mod tagspaces;
+pub mod thumbnail_pool;
+pub mod thumbnails;
 pub mod ui_util;
+pub mod url_import;
+pub mod url_util;
+pub mod watch;
@@ -0,0 +1,402 @@
+//! Portable library archives, to move a library to another computer or share it. An archive
+//! is a tar file holding `manifest.json` (every file with its tags, upload date and source
+//! metadata, plus the aliases and implications of the config) followed by the files
+//! themselves, as `files/<hash>/<original file name>`.
+//!
+//! Importing an archive merges it into a library by file hash. System tags aren't
+//! exported, they are recomputed when importing.
+use crate::data::config::Config;
+use crate::data::{system_tags, tag_file::TagFile, tag_util};
+use crate::database::tagmaid_database::TagMaidDatabase;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST_NAME: &str = "manifest.json";
+pub const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedFile {
+    /// In hexadecimal
+    pub hash: String,
+    pub file_name: String,
+    /// RFC 3339
+    pub upload_date: Option<String>,
+    /// User tags only
+    pub tags: BTreeSet<String>,
+    #[serde(default)]
+    pub source: BTreeMap<String, String>,
+}
+
+impl ArchivedFile {
+    /// Where the file is in the archive
+    pub fn archive_path(&self) -> String {
+        format!("files/{}/{}", self.hash, self.file_name)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub files: Vec<ArchivedFile>,
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+    #[serde(default)]
+    pub implied: HashMap<String, String>,
+}
+
+/// What to do when a file (or an alias) is both in the library and in the archive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConflictPolicy {
+    /// Keeps what the library has
+    Keep,
+    /// Takes what the archive has
+    Replace,
+    /// Keeps the tags of both
+    #[default]
+    Merge,
+}
+
+#[derive(Debug, Default)]
+pub struct ArchiveReport {
+    /// Files that weren't in the library
+    pub added: usize,
+    /// Files already in the library whose tags changed
+    pub updated: usize,
+    /// Files already in the library that were left as they were
+    pub unchanged: usize,
+    /// Files that couldn't be imported, with why
+    pub failed: Vec<String>,
+    /// Aliases and implications added to (or replaced in) the config
+    pub config_entries: usize,
+}
+
+impl ArchiveReport {
+    pub fn summary(&self) -> String {
+        format!(
+            "{} added, {} updated, {} unchanged, {} failed, {} aliases and implications imported",
+            self.added,
+            self.updated,
+            self.unchanged,
+            self.failed.len(),
+            self.config_entries
+        )
+    }
+}
+
+/// Writes every file of the library, with its tags and metadata, to a tar archive at
+/// `destination`. Returns how many files were exported.
+pub fn export(db: &TagMaidDatabase, config: &Config, destination: &Path) -> Result<usize> {
+    let mut manifest = Manifest {
+        version: MANIFEST_VERSION,
+        aliases: config.aliases.clone(),
+        implied: config.implied.clone(),
+        ..Default::default()
+    };
+    let mut stored_paths: Vec<PathBuf> = Vec::new();
+    let mut hashes: Vec<Vec<u8>> = db.get_file_ids()?.into_keys().collect();
+    hashes.sort();
+    for hash in hashes {
+        let tagfile = db.get_tagfile_from_hash(&hash)?;
+        manifest.files.push(ArchivedFile {
+            hash: tag_util::bytes_to_hex(&hash),
+            file_name: tagfile.get_file_name().to_owned(),
+            upload_date: db.get_upload_date(&hash).ok().map(|date| date.to_rfc3339()),
+            tags: tagfile
+                .get_tags()
+                .iter()
+                .filter(|tag| !tag_util::is_system_tag(tag))
+                .cloned()
+                .collect(),
+            source: db.get_source_metadata(&hash)?,
+        });
+        stored_paths.push(tagfile.get_path().clone());
+    }
+
+    let archive_file = File::create(destination)
+        .with_context(|| format!("Couldn't create '{}'", destination.display()))?;
+    let mut builder = tar::Builder::new(archive_file);
+    // The manifest comes first, so importing only needs one pass over the archive
+    let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest_json.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    builder.append_data(&mut header, MANIFEST_NAME, manifest_json.as_slice())?;
+    for (file, stored_path) in manifest.files.iter().zip(stored_paths.iter()) {
+        builder
+            .append_path_with_name(stored_path, file.archive_path())
+            .with_context(|| format!("Couldn't add '{}' to the archive", stored_path.display()))?;
+    }
+    builder.into_inner()?;
+    info!(
+        "archive - export() - Exported {} files to {}",
+        manifest.files.len(),
+        destination.display()
+    );
+    Ok(manifest.files.len())
+}
+
+/// The tags a file already in the library ends up with
+fn resolve_tags(
+    library_tags: &HashSet<String>,
+    archive_tags: &HashSet<String>,
+    policy: ConflictPolicy,
+) -> HashSet<String> {
+    let system_tags = library_tags
+        .iter()
+        .filter(|tag| tag_util::is_system_tag(tag));
+    let user_tags: Box<dyn Iterator<Item = &String>> = match policy {
+        ConflictPolicy::Keep => Box::new(library_tags.iter()),
+        // Without user tags the library's copy of the file would be removed
+        ConflictPolicy::Replace if archive_tags.is_empty() => Box::new(library_tags.iter()),
+        ConflictPolicy::Replace => Box::new(archive_tags.iter()),
+        ConflictPolicy::Merge => Box::new(library_tags.iter().chain(archive_tags.iter())),
+    };
+    user_tags
+        .filter(|tag| !tag_util::is_system_tag(tag))
+        .chain(system_tags)
+        .cloned()
+        .collect()
+}
+
+/// Adds the file extracted at `path` to the library, or merges its tags with the ones of
+/// the same file in the library. Returns whether the file was new, and whether it changed.
+fn import_file(
+    db: &TagMaidDatabase,
+    file: &ArchivedFile,
+    path: &Path,
+    policy: ConflictPolicy,
+) -> Result<(bool, bool)> {
+    let mut tagfile = TagFile::initialise_from_path(path)?;
+    if tag_util::bytes_to_hex(&tagfile.file_hash) != file.hash {
+        bail!("'{}' doesn't match its hash", file.file_name);
+    }
+    // Tags that aren't valid anymore (or never were) would make the whole file fail
+    let archive_tags: HashSet<String> = file
+        .tags
+        .iter()
+        .filter(|tag| tag_util::is_tag_name_valid(tag) && !tag_util::is_system_tag(tag))
+        .cloned()
+        .collect();
+
+    match db.get_tagfile_from_hash(&tagfile.file_hash) {
+        Ok(library_tagfile) => {
+            let tags = resolve_tags(library_tagfile.get_tags(), &archive_tags, policy);
+            let changed = &tags != library_tagfile.get_tags();
+            if changed {
+                db.update_tagfile(&TagFile {
+                    tags,
+                    ..library_tagfile
+                })?;
+            }
+            let mut source = file.source.clone();
+            if policy == ConflictPolicy::Keep {
+                let library_source = db.get_source_metadata(&tagfile.file_hash)?;
+                source.retain(|key, _value| !library_source.contains_key(key));
+            }
+            db.set_source_metadata(&tagfile.file_hash, &source)?;
+            Ok((false, changed))
+        }
+        Err(..) => {
+            tagfile.tags = archive_tags;
+            system_tags::replace(&mut tagfile.tags, path);
+            db.update_tagfile(&tagfile)?;
+            let upload_date = file
+                .upload_date
+                .as_ref()
+                .and_then(|date| DateTime::parse_from_rfc3339(date).ok());
+            if let Some(upload_date) = upload_date {
+                db.set_upload_date(&tagfile.file_hash, &upload_date.with_timezone(&Utc))?;
+            }
+            db.set_source_metadata(&tagfile.file_hash, &file.source)?;
+            Ok((true, true))
+        }
+    }
+}
+
+/// Merges `entries` (aliases or implications) from an archive into the config's
+fn merge_config_entries(
+    config_entries: &mut HashMap<String, String>,
+    entries: &HashMap<String, String>,
+    policy: ConflictPolicy,
+) -> usize {
+    let mut merged = 0;
+    for (key, value) in entries {
+        let take = match config_entries.get(key) {
+            None => true,
+            Some(config_value) => config_value != value && policy == ConflictPolicy::Replace,
+        };
+        if take {
+            config_entries.insert(key.clone(), value.clone());
+            merged += 1;
+        }
+    }
+    merged
+}
+
+/// Merges the archive at `archive_path` into the library, resolving conflicts with `policy`.
+/// Aliases and implications are added to `config`, which is saved if they changed.
+pub fn import(
+    db: &TagMaidDatabase,
+    config: &mut Config,
+    archive_path: &Path,
+    policy: ConflictPolicy,
+) -> Result<ArchiveReport> {
+    let archive_file = File::open(archive_path)
+        .with_context(|| format!("Couldn't open '{}'", archive_path.display()))?;
+    let mut archive = tar::Archive::new(archive_file);
+    let mut entries = archive.entries()?;
+
+    let manifest: Manifest = match entries.next() {
+        Some(entry) => {
+            let entry = entry?;
+            if entry.path()?.as_ref() != Path::new(MANIFEST_NAME) {
+                bail!("'{}' isn't a TagMaid archive", archive_path.display());
+            }
+            serde_json::from_reader(entry).context("Invalid archive manifest")?
+        }
+        None => bail!("'{}' is empty", archive_path.display()),
+    };
+    if manifest.version > MANIFEST_VERSION {
+        bail!(
+            "The archive was made by a newer TagMaid (version {})",
+            manifest.version
+        );
+    }
+    let files: HashMap<String, &ArchivedFile> = manifest
+        .files
+        .iter()
+        .map(|file| (file.archive_path(), file))
+        .collect();
+
+    let mut report = ArchiveReport::default();
+    let mut seen: HashSet<String> = HashSet::new();
+    for entry in entries {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.to_string_lossy().into_owned();
+        let file = match files.get(&entry_path) {
+            Some(file) => *file,
+            None => continue,
+        };
+        seen.insert(entry_path);
+        // Extracted under its original name, which the library keeps. Only the name is
+        // used, so that a crafted manifest can't write outside of the temporary folder.
+        let folder = tempfile::tempdir()?;
+        let file_name = Path::new(&file.file_name)
+            .file_name()
+            .map_or_else(|| "file".into(), |file_name| file_name.to_owned());
+        let path = folder.path().join(file_name);
+        let result = entry
+            .unpack(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| import_file(db, file, &path, policy));
+        match result {
+            Ok((true, _)) => report.added += 1,
+            Ok((false, true)) => report.updated += 1,
+            Ok((false, false)) => report.unchanged += 1,
+            Err(err) => {
+                info!("WARNING: archive::import(): {}: {err:#}", file.file_name);
+                report.failed.push(format!("{}: {err:#}", file.file_name));
+            }
+        }
+    }
+    for file in manifest.files.iter() {
+        if !seen.contains(&file.archive_path()) {
+            report
+                .failed
+                .push(format!("{}: missing from the archive", file.file_name));
+        }
+    }
+
+    report.config_entries = merge_config_entries(&mut config.aliases, &manifest.aliases, policy)
+        + merge_config_entries(&mut config.implied, &manifest.implied, policy);
+    if report.config_entries > 0 {
+        config.save()?;
+    }
+    info!("archive - import() - {}", report.summary());
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::config::ConfigFs;
+    use crate::database::tag_database::test_util::{add_file, create_random_tagmaiddatabase};
+
+    #[test]
+    fn should_export_and_import_archives() {
+        let folder = tempfile::tempdir().unwrap().keep();
+        let source_db = create_random_tagmaiddatabase();
+        let mut source_config = Config::from_fs(ConfigFs::default(), folder.join("a.cfg"));
+        source_config
+            .aliases
+            .insert("kitty".to_owned(), "cat".to_owned());
+        let cat = add_file(&source_db, "cat.png", "cat", &["cat", "cute"]).file_hash;
+        let dog = add_file(&source_db, "dog.png", "dog", &["dog"]).file_hash;
+        let dog_date = DateTime::parse_from_rfc3339("2001-02-03T04:05:06Z").unwrap();
+        source_db
+            .set_upload_date(&dog, &dog_date.with_timezone(&Utc))
+            .unwrap();
+        let source = BTreeMap::from([("url".to_owned(), "https://example.com".to_owned())]);
+        source_db.set_source_metadata(&cat, &source).unwrap();
+
+        let archive_path = folder.join("library.tar");
+        assert_eq!(
+            export(&source_db, &source_config, &archive_path).unwrap(),
+            2
+        );
+
+        // The other library already has the cat, with other tags
+        let db = create_random_tagmaiddatabase();
+        let mut config = Config::from_fs(ConfigFs::default(), folder.join("b.cfg"));
+        add_file(&db, "my cat.png", "cat", &["cat", "sleeping"]);
+        let report = import(&db, &mut config, &archive_path, ConflictPolicy::Merge).unwrap();
+        assert_eq!((report.added, report.updated), (1, 1));
+        assert!(report.failed.is_empty());
+        assert_eq!(config.aliases.get("kitty"), Some(&"cat".to_owned()));
+
+        let tags = db.get_tagfile_from_hash(&cat).unwrap().tags;
+        assert!(["cat", "cute", "sleeping"]
+            .iter()
+            .all(|tag| tags.contains(*tag)));
+        assert_eq!(db.get_source_metadata(&cat).unwrap(), source);
+        assert_eq!(
+            db.get_upload_date(&dog).unwrap().timestamp(),
+            dog_date.timestamp()
+        );
+
+        // Importing again changes nothing
+        let report = import(&db, &mut config, &archive_path, ConflictPolicy::Merge).unwrap();
+        assert_eq!((report.added, report.updated, report.unchanged), (0, 0, 2));
+    }
+
+    #[test]
+    fn should_resolve_tag_conflicts() {
+        let library = HashSet::from(["a".to_owned(), "system:ext:png".to_owned()]);
+        let archive = HashSet::from(["b".to_owned()]);
+        let resolved = |policy| {
+            let mut tags: Vec<String> = resolve_tags(&library, &archive, policy)
+                .into_iter()
+                .collect();
+            tags.sort();
+            tags
+        };
+        assert_eq!(resolved(ConflictPolicy::Keep), vec!["a", "system:ext:png"]);
+        assert_eq!(
+            resolved(ConflictPolicy::Replace),
+            vec!["b", "system:ext:png"]
+        );
+        assert_eq!(
+            resolved(ConflictPolicy::Merge),
+            vec!["a", "b", "system:ext:png"]
+        );
+
+        let untagged = resolve_tags(&library, &HashSet::new(), ConflictPolicy::Replace);
+        assert_eq!(untagged, library);
+    }
+}
@@ -0,0 +1,329 @@
+//! Backups of the library. A backup is a `tagmaid-backup-<date>` folder holding a snapshot
+//! of `sqlite.db`, made with SQLite's online backup API so that it is consistent even while
+//! TagMaid is running, and optionally a copy of `files/`.
+//!
+//! Only the newest `BackupConfig::keep` backups are kept. Stored files never change, so the
+//! ones already in the previous backup are hardlinked from it rather than copied again.
+use crate::database::tagmaid_database::TagMaidDatabase;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use log::*;
+use rusqlite::{Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const BACKUP_PREFIX: &str = "tagmaid-backup-";
+const DATE_FORMAT: &str = "%Y%m%d-%H%M%S-%3f";
+const DATABASE_NAME: &str = "sqlite.db";
+const FILES_FOLDER: &str = "files";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackupConfig {
+    /// Whether backups are made on their own while the GUI or the API server runs
+    pub automatic: bool,
+    /// Hours between two automatic backups
+    pub interval: u64,
+    /// How many backups are kept, the oldest ones are deleted
+    pub keep: usize,
+    /// Whether `files/` is backed up along with the database
+    pub include_files: bool,
+    /// Where backups go, `<library>-backups` next to the library folder by default
+    pub folder: Option<PathBuf>,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        BackupConfig {
+            automatic: false,
+            interval: 24,
+            keep: 7,
+            include_files: false,
+            folder: None,
+        }
+    }
+}
+
+impl BackupConfig {
+    /// The folder holding the backups of the library at `library_path`
+    pub fn folder(&self, library_path: &Path) -> PathBuf {
+        match &self.folder {
+            Some(folder) => folder.clone(),
+            None => {
+                let mut folder = library_path.as_os_str().to_owned();
+                folder.push("-backups");
+                PathBuf::from(folder)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backup {
+    pub path: PathBuf,
+    pub date: DateTime<Utc>,
+}
+
+impl Backup {
+    pub fn has_files(&self) -> bool {
+        self.path.join(FILES_FOLDER).is_dir()
+    }
+}
+
+/// The backups in `folder`, oldest first. Unfinished backups aren't listed.
+pub fn list(folder: &Path) -> Result<Vec<Backup>> {
+    if !folder.exists() {
+        return Ok(Vec::new());
+    }
+    let entries = std::fs::read_dir(folder)
+        .with_context(|| format!("Couldn't read '{}'", folder.display()))?;
+    let mut backups: Vec<Backup> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let date = file_name.strip_prefix(BACKUP_PREFIX)?;
+            let date = NaiveDateTime::parse_from_str(date, DATE_FORMAT).ok()?;
+            Some(Backup {
+                path: entry.path(),
+                date: Utc.from_utc_datetime(&date),
+            })
+        })
+        .collect();
+    backups.sort_by_key(|backup| backup.date);
+    Ok(backups)
+}
+
+/// Copies the stored files of the library to `destination`, hardlinking the ones that are
+/// already in `previous`
+fn copy_files(library_path: &Path, destination: &Path, previous: Option<&Path>) -> Result<()> {
+    std::fs::create_dir_all(destination)?;
+    for entry in std::fs::read_dir(library_path.join(FILES_FOLDER))? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let path = destination.join(entry.file_name());
+        let linked = previous
+            .map(|previous| previous.join(entry.file_name()))
+            .filter(|previous_path| previous_path.is_file())
+            .is_some_and(|previous_path| std::fs::hard_link(previous_path, &path).is_ok());
+        if !linked {
+            std::fs::copy(entry.path(), &path)
+                .with_context(|| format!("Couldn't back up '{}'", entry.path().display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Backs up the library to a new folder in `folder`, without deleting older backups
+fn snapshot(db: &TagMaidDatabase, folder: &Path, include_files: bool) -> Result<Backup> {
+    std::fs::create_dir_all(folder)
+        .with_context(|| format!("Couldn't create '{}'", folder.display()))?;
+    let previous = list(folder)?.into_iter().rev().find(Backup::has_files);
+    let date = Utc::now();
+    let name = format!("{BACKUP_PREFIX}{}", date.format(DATE_FORMAT));
+    // Written under another name first, so that a backup that didn't finish isn't listed
+    let partial_path = folder.join(format!("{name}.partial"));
+    std::fs::create_dir(&partial_path)
+        .with_context(|| format!("Couldn't create '{}'", partial_path.display()))?;
+
+    db.backup_database(&partial_path.join(DATABASE_NAME))?;
+    if include_files {
+        let previous_files = previous.map(|backup| backup.path.join(FILES_FOLDER));
+        copy_files(
+            &db.get_library_path(),
+            &partial_path.join(FILES_FOLDER),
+            previous_files.as_deref(),
+        )?;
+    }
+    let path = folder.join(name);
+    std::fs::rename(&partial_path, &path)?;
+    info!(
+        "backup - snapshot() - Backed up the library to {}",
+        path.display()
+    );
+    Ok(Backup { path, date })
+}
+
+/// Deletes the oldest backups in `folder` so that only `keep` remain (at least one).
+/// Returns how many were deleted.
+pub fn rotate(folder: &Path, keep: usize) -> Result<usize> {
+    let backups = list(folder)?;
+    let extra = backups.len().saturating_sub(keep.max(1));
+    for backup in backups.iter().take(extra) {
+        std::fs::remove_dir_all(&backup.path)
+            .with_context(|| format!("Couldn't delete '{}'", backup.path.display()))?;
+        info!("backup - rotate() - Deleted {}", backup.path.display());
+    }
+    Ok(extra)
+}
+
+/// Backs up the library as set in `config`, then deletes the backups that are too old
+pub fn create(db: &TagMaidDatabase, config: &BackupConfig) -> Result<Backup> {
+    let folder = config.folder(&db.get_library_path());
+    let backup = snapshot(db, &folder, config.include_files)?;
+    rotate(&folder, config.keep)?;
+    Ok(backup)
+}
+
+/// Checks that the database snapshot at `path` is intact and is a library. Returns how
+/// many files it has.
+pub fn check(path: &Path) -> Result<u64> {
+    if !path.is_file() {
+        bail!("'{}' doesn't exist", path.display());
+    }
+    let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Couldn't open '{}'", path.display()))?;
+    let integrity: String = db
+        .query_row("PRAGMA integrity_check", (), |row| row.get(0))
+        .with_context(|| format!("'{}' isn't a SQLite database", path.display()))?;
+    if integrity != "ok" {
+        bail!("'{}' is damaged: {integrity}", path.display());
+    }
+    db.query_row("SELECT COUNT(*) FROM _files", (), |row| row.get(0))
+        .with_context(|| format!("'{}' isn't a TagMaid library", path.display()))
+}
+
+/// Replaces the library's database with the one of the backup at `backup_path` (a backup
+/// folder, or a `sqlite.db` directly), once checked. The current database is backed up
+/// first, in `config`'s folder. With `restore_files`, files of the backup missing from
+/// `files/` are copied back. Returns how many files were copied back.
+pub fn restore(
+    db: &TagMaidDatabase,
+    config: &BackupConfig,
+    backup_path: &Path,
+    restore_files: bool,
+) -> Result<usize> {
+    let (snapshot_path, files_path) = if backup_path.is_dir() {
+        (
+            backup_path.join(DATABASE_NAME),
+            Some(backup_path.join(FILES_FOLDER)),
+        )
+    } else {
+        (backup_path.to_owned(), None)
+    };
+    let file_count = check(&snapshot_path)?;
+    info!("backup - restore() - The backup has {file_count} files");
+
+    let library_path = db.get_library_path();
+    let safety_backup = snapshot(db, &config.folder(&library_path), false)?;
+    info!(
+        "backup - restore() - Backed up the current database to {}",
+        safety_backup.path.display()
+    );
+    db.restore_database(&snapshot_path)?;
+
+    let mut restored = 0;
+    let files_path = match files_path {
+        Some(files_path) if restore_files && files_path.is_dir() => files_path,
+        _ => return Ok(restored),
+    };
+    for entry in std::fs::read_dir(&files_path)? {
+        let entry = entry?;
+        let path = library_path.join(FILES_FOLDER).join(entry.file_name());
+        if !path.exists() {
+            std::fs::copy(entry.path(), &path)
+                .with_context(|| format!("Couldn't restore '{}'", path.display()))?;
+            restored += 1;
+        }
+    }
+    Ok(restored)
+}
+
+/// Makes backups every `BackupConfig::interval` hours in the background, for as long as
+/// it is kept around
+pub struct BackupScheduler {
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for BackupScheduler {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl BackupScheduler {
+    /// Returns `None` if automatic backups are disabled
+    pub fn start(db: TagMaidDatabase, config: &BackupConfig) -> Option<BackupScheduler> {
+        if !config.automatic {
+            return None;
+        }
+        let config = config.clone();
+        let interval = chrono::Duration::hours(config.interval.max(1) as i64);
+        let folder = config.folder(&db.get_library_path());
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        std::thread::spawn(move || {
+            while !thread_stop.load(Ordering::Relaxed) {
+                let last_backup = list(&folder)
+                    .ok()
+                    .and_then(|backups| backups.last().map(|backup| backup.date));
+                let due = match last_backup {
+                    Some(date) => Utc::now() - date >= interval,
+                    None => true,
+                };
+                if due {
+                    if let Err(err) = create(&db, &config) {
+                        info!("WARNING: BackupScheduler: Couldn't back up the library: {err:#}");
+                    }
+                }
+                std::thread::sleep(Duration::from_secs(60));
+            }
+            info!("BackupScheduler - Stopped");
+        });
+        Some(BackupScheduler { stop })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::tag_file::TagFile;
+    use crate::database::tag_database::TagDatabase;
+
+    #[test]
+    fn should_back_up_and_restore() {
+        let folder = tempfile::tempdir().unwrap().keep();
+        let db = TagMaidDatabase::new(TagDatabase::initialise_at(folder.join("library")).unwrap());
+        let config = BackupConfig {
+            keep: 2,
+            include_files: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.folder(&folder.join("library")),
+            folder.join("library-backups")
+        );
+
+        let path = folder.join("cat.png");
+        std::fs::write(&path, "cat").unwrap();
+        let mut tagfile = TagFile::initialise_from_path(&path).unwrap();
+        tagfile.tags.insert("cat".to_owned());
+        db.update_tagfile(&tagfile).unwrap();
+        let backup = create(&db, &config).unwrap();
+        assert_eq!(check(&backup.path.join(DATABASE_NAME)).unwrap(), 1);
+
+        // Losing the tag and the stored file, then getting them back
+        let stored_path = db.get_tagfile_from_hash(&tagfile.file_hash).unwrap().path;
+        tagfile.tags.insert("dog".to_owned());
+        db.update_tagfile(&tagfile).unwrap();
+        std::fs::remove_file(&stored_path).unwrap();
+        assert_eq!(restore(&db, &config, &backup.path, true).unwrap(), 1);
+        assert!(stored_path.is_file());
+        let tags = db.get_tagfile_from_hash(&tagfile.file_hash).unwrap().tags;
+        assert!(tags.contains("cat") && !tags.contains("dog"));
+
+        // The restore backed up the database it replaced, then two more backups are made
+        create(&db, &config).unwrap();
+        create(&db, &config).unwrap();
+        let backups = list(&config.folder(&folder.join("library"))).unwrap();
+        assert_eq!(backups.len(), 2);
+        assert!(backups.iter().all(|other| other.path != backup.path));
+
+        std::fs::write(folder.join("not a database"), "nope").unwrap();
+        assert!(check(&folder.join("not a database")).is_err());
+    }
+}
@@ -1,16 +1,18 @@
 //! Handmade cache
+use crate::data::perceptual_hash::BkTree;
 use crate::data::search_command::Search;
 use crate::ui::TextureLabel;
 use crate::TagFile;
 use anyhow::{bail, Result};
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 
 pub struct TagMaidCache {
     // Caches the hash of a TagFile to the associated TagFile object (if it exists)
     tagfile_cache: RwLock<HashMap<Vec<u8>, TagFile>>,
     results_cache: RwLock<HashMap<Search, Vec<Vec<u8>>>>,
     thumbnails_cache: RwLock<HashMap<TextureLabel, egui::TextureHandle>>,
+    similarity_index: RwLock<Option<Arc<BkTree>>>,
 }
 
 impl TagMaidCache {
@@ -20,6 +22,7 @@ impl TagMaidCache {
             tagfile_cache: RwLock::new(HashMap::new()),
             results_cache: RwLock::new(HashMap::new()),
             thumbnails_cache: RwLock::new(HashMap::new()),
+            similarity_index: RwLock::new(None),
         };
     }
 
@@ -47,6 +50,17 @@ impl TagMaidCache {
         }
     }
 
+    /// Empties the TagFile cache, for when the whole database changed (restoring a backup)
+    pub fn clear_all_tagfiles(&self) -> Result<()> {
+        match self.tagfile_cache.try_write() {
+            Ok(mut cache) => {
+                cache.clear();
+                Ok(())
+            }
+            Err(err) => bail!("Couldn't write to TagFile cache to clear it: {err}"),
+        }
+    }
+
     /// Takes a tagfile hash in the argument. Returns `Some(tagfile)`.
     /// Returns `None` if tagfile wasn't cached.
     pub fn get_tagfile(&self, hash: &Vec<u8>) -> Option<TagFile> {
@@ -70,6 +84,17 @@ impl TagMaidCache {
         }
     }
 
+    /// Clears the cached searches with `color:` terms, the ones a new palette can change
+    pub fn clear_color_searches(&self) -> Result<()> {
+        match self.results_cache.try_write() {
+            Ok(mut cache) => {
+                cache.retain(|search_query, _result_hashes| !search_query.uses_color());
+                Ok(())
+            }
+            Err(err) => bail!("Couldn't write to search cache to clear it: {err}"),
+        }
+    }
+
     /// Caches a search query (of type `crate::data::search_command::Search`) and its results
     /// in a HashMap
     pub fn cache_search(&self, search_query: Search, result_hashes: Vec<Vec<u8>>) -> Result<()> {
@@ -90,6 +115,41 @@ impl TagMaidCache {
         }
     }
 
+    // Similarity index
+
+    /// Caches the index of the perceptual hashes (see `data::perceptual_hash::similarity_index()`)
+    pub fn cache_similarity_index(&self, index: Arc<BkTree>) -> Result<()> {
+        match self.similarity_index.try_write() {
+            Ok(mut cache) => {
+                *cache = Some(index);
+                Ok(())
+            }
+            Err(err) => bail!("Couldn't write to similarity index cache to cache index: {err}"),
+        }
+    }
+
+    pub fn get_similarity_index(&self) -> Option<Arc<BkTree>> {
+        match self.similarity_index.try_read() {
+            Ok(cache) => cache.clone(),
+            Err(_err) => None,
+        }
+    }
+
+    /// Applies `change` to the cached index, if there is one, for when files are added or
+    /// removed. Waits for the lock rather than failing, as a stale index would give wrong
+    /// results.
+    pub fn update_similarity_index(&self, change: impl FnOnce(&mut BkTree)) {
+        if let Some(index) = self.similarity_index.write().unwrap().as_mut() {
+            // Only copied if a search is still using the old one
+            change(Arc::make_mut(index));
+        }
+    }
+
+    /// Drops the index, for when the whole database changes
+    pub fn clear_similarity_index(&self) {
+        *self.similarity_index.write().unwrap() = None;
+    }
+
     // Thumbnails cache
 
     pub fn cache_thumbnail(&self, label: TextureLabel, texture: egui::TextureHandle) -> Result<()> {
@@ -1,5 +1,12 @@
+use crate::data::backup::BackupConfig;
+use crate::data::download_sidecar::DownloadSidecarConfig;
+use crate::data::import::ImportOptions;
+use crate::data::rules::Rule;
+use crate::data::thumbnails::ThumbnailConfig;
+use crate::data::url_import::UrlImportConfig;
 use anyhow::{bail, Result};
-use std::{collections::HashMap, io::Read};
+use log::*;
+use std::{collections::HashMap, io::Read, path::PathBuf};
 
 // TODO Test this stuff or whatever
 #[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
@@ -17,24 +24,91 @@ impl Theme {
     }
 }
 
+/// A folder whose new files get imported automatically (see `data::watch`)
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct WatchFolder {
+    pub path: PathBuf,
+    /// Tags given to every file imported from this folder
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Whether files in subfolders are imported too
+    #[serde(default)]
+    pub recursive: bool,
+}
+
+/// Settings of a tag sync backend (see `data::tag_sync`)
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TagSyncConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Export folder to write the tags to, instead of to the files stored in the library
+    #[serde(default)]
+    pub destination: Option<PathBuf>,
+}
+
 #[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
 pub struct ConfigFs {
     theme: Option<Theme>,
     aliases: Option<HashMap<String, String>>,
     implied: Option<HashMap<String, String>>,
+    watch_folders: Option<Vec<WatchFolder>>,
+    rules: Option<Vec<Rule>>,
+    xmp_sidecars: Option<TagSyncConfig>,
+    xdg_tags: Option<TagSyncConfig>,
+    download_sidecars: Option<DownloadSidecarConfig>,
+    url_import: Option<UrlImportConfig>,
+    backups: Option<BackupConfig>,
+    thumbnails: Option<ThumbnailConfig>,
 }
 
 pub struct Config {
     pub theme: Theme,
+    /// Alias -> the tag it stands for
     pub aliases: HashMap<String, String>,
     pub implied: HashMap<String, String>,
+    pub watch_folders: Vec<WatchFolder>,
+    /// Auto-tagging rules applied to every import (see `data::rules`)
+    pub rules: Vec<Rule>,
+    pub xmp_sidecars: TagSyncConfig,
+    pub xdg_tags: TagSyncConfig,
+    /// gallery-dl / yt-dlp metadata files read on import (see `data::download_sidecar`)
+    pub download_sidecars: DownloadSidecarConfig,
+    /// Limits of downloads from URLs (see `data::url_import`)
+    pub url_import: UrlImportConfig,
+    /// Automatic backups and how many are kept (see `data::backup`)
+    pub backups: BackupConfig,
+    /// Sizes of the thumbnails (see `data::thumbnails`)
+    pub thumbnails: ThumbnailConfig,
+    /// Where the config is saved
+    pub path: PathBuf,
+    /// Why the file at `path` couldn't be read. It is not saved over while set
+    pub load_error: Option<String>,
 }
 impl Config {
-    pub fn from_fs(fs: ConfigFs) -> Self {
+    pub fn from_fs(fs: ConfigFs, path: PathBuf) -> Self {
         Self {
             theme: fs.theme.unwrap_or(Theme::Ika),
             aliases: fs.aliases.unwrap_or_default(),
             implied: fs.implied.unwrap_or_default(),
+            watch_folders: fs.watch_folders.unwrap_or_default(),
+            rules: fs.rules.unwrap_or_default(),
+            xmp_sidecars: fs.xmp_sidecars.unwrap_or_default(),
+            xdg_tags: fs.xdg_tags.unwrap_or_default(),
+            download_sidecars: fs.download_sidecars.unwrap_or_default(),
+            url_import: fs.url_import.unwrap_or_default(),
+            backups: fs.backups.unwrap_or_default(),
+            thumbnails: fs.thumbnails.unwrap_or_default(),
+            path,
+            load_error: None,
+        }
+    }
+    /// Import settings every import path has to use (auto-tagging rules, aliases...)
+    pub fn import_options(&self) -> ImportOptions {
+        ImportOptions {
+            rules: self.rules.clone(),
+            aliases: self.aliases.clone(),
+            download_sidecars: self.download_sidecars.clone(),
+            ..Default::default()
         }
     }
     pub fn as_fs(&self) -> ConfigFs {
@@ -42,30 +116,78 @@ impl Config {
             theme: Some(self.theme),
             aliases: Some(self.aliases.clone()),
             implied: Some(self.implied.clone()),
+            watch_folders: Some(self.watch_folders.clone()),
+            rules: Some(self.rules.clone()),
+            xmp_sidecars: Some(self.xmp_sidecars.clone()),
+            xdg_tags: Some(self.xdg_tags.clone()),
+            download_sidecars: Some(self.download_sidecars.clone()),
+            url_import: Some(self.url_import.clone()),
+            backups: Some(self.backups.clone()),
+            thumbnails: Some(self.thumbnails.clone()),
         }
     }
+    /// `tag-maid.cfg` in the TagMaid data folder
+    pub fn default_path() -> Result<PathBuf> {
+        let mut path = crate::database::tag_database::get_database_path(None)?;
+        path.push("tag-maid.cfg");
+        Ok(path)
+    }
     pub fn load() -> Self {
         // None because there will only be one config
-        let inner: fn() -> Result<ConfigFs> = || {
-            let mut path = crate::database::tag_database::get_database_path(None)?;
-            path.push("tag-maid.cfg");
-            if path.exists() {
-                let mut file = std::fs::File::open(path)?;
-                let mut str = String::new();
-                file.read_to_string(&mut str)?;
-                let de: ConfigFs = serde_json::from_str(&str)?;
-                return Ok(de);
-            } else {
-                bail!("cfg does not exist on disk");
-            }
+        let path = Self::default_path().unwrap_or_else(|_| PathBuf::from("tag-maid.cfg"));
+        Self::load_from(path)
+    }
+    /// Loads the config at `path`, or the default config if there is none.
+    /// A file that exists but can't be read gives the default config with `load_error` set
+    pub fn load_from(path: PathBuf) -> Self {
+        if !path.exists() {
+            return Self::from_fs(ConfigFs::default(), path);
+        }
+        let inner = || -> Result<ConfigFs> {
+            let mut file = std::fs::File::open(&path)?;
+            let mut str = String::new();
+            file.read_to_string(&mut str)?;
+            let de: ConfigFs = serde_json::from_str(&str)?;
+            Ok(de)
         };
-        let res = inner();
-        Self::from_fs(res.unwrap_or_default())
+        match inner() {
+            Ok(fs) => Self::from_fs(fs, path),
+            Err(err) => {
+                let message = format!("{}: {err:#}", path.display());
+                info!("WARNING: Config::load_from(): {message}");
+                let mut config = Self::from_fs(ConfigFs::default(), path);
+                config.load_error = Some(message);
+                config
+            }
+        }
     }
     pub fn save(&self) -> Result<()> {
-        let mut path = crate::database::tag_database::get_database_path(None)?;
-        path.push("tag-maid.cfg");
-        std::fs::write(path, serde_json::to_string(&self.as_fs())?)?;
+        if let Some(err) = &self.load_error {
+            bail!("not saving over the config that could not be read ({err})");
+        }
+        std::fs::write(&self.path, serde_json::to_string(&self.as_fs())?)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_not_save_over_unreadable_config() {
+        let path = tempfile::tempdir().unwrap().keep().join("tag-maid.cfg");
+        let broken = r#"{"aliases": {"cat": "feline"}, "rules": [{"name": "typo"#;
+        std::fs::write(&path, broken).unwrap();
+
+        let config = Config::load_from(path.clone());
+        assert!(config.load_error.is_some());
+        assert!(config.aliases.is_empty());
+        assert!(config.save().is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), broken);
+
+        let config = Config::load_from(path.with_file_name("missing.cfg"));
+        assert!(config.load_error.is_none());
+        config.save().unwrap();
+    }
+}
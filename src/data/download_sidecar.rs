@@ -0,0 +1,235 @@
+//! Metadata files written by downloaders next to what they download: gallery-dl's
+//! `<file>.json` (`--write-metadata`) and yt-dlp's `<name>.info.json` (`--write-info-json`).
+//! When enabled in the config, importing a file reads its sidecar and turns some of its
+//! fields into tags and others into source metadata (the page it came from...), as set
+//! in `DownloadSidecarConfig`.
+//!
+//! Fields are JSON keys, with dots for nested ones (`author.name`). Their value can be a
+//! string, a number or an array of them.
+use crate::data::tag_util;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// What to take from the sidecars
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DownloadSidecarConfig {
+    pub enabled: bool,
+    /// Field -> namespace of the tags made from its values (empty for plain tags)
+    pub tags: BTreeMap<String, String>,
+    /// Source metadata key -> fields it is read from, the first one found is used
+    pub source: BTreeMap<String, Vec<String>>,
+}
+
+impl Default for DownloadSidecarConfig {
+    fn default() -> Self {
+        let tags = [
+            ("tags", ""),
+            ("categories", ""),
+            ("uploader", "artist"),
+            ("category", "site"),
+            ("extractor", "site"),
+        ];
+        let source = [
+            ("url", vec!["webpage_url", "post_url", "url"]),
+            ("uploader", vec!["uploader", "author.name", "user.name"]),
+            ("title", vec!["title"]),
+        ];
+        DownloadSidecarConfig {
+            enabled: false,
+            tags: tags
+                .iter()
+                .map(|(field, namespace)| (field.to_string(), namespace.to_string()))
+                .collect(),
+            source: source
+                .into_iter()
+                .map(|(key, fields)| {
+                    (
+                        key.to_owned(),
+                        fields.into_iter().map(String::from).collect(),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+/// What a sidecar says about a file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DownloadMetadata {
+    /// Valid tag names only
+    pub tags: Vec<String>,
+    pub source: BTreeMap<String, String>,
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("json"))
+}
+
+/// The sidecar of the file at `path`, if there is one
+pub fn sidecar_path(path: &Path) -> Option<PathBuf> {
+    if is_json(path) {
+        return None;
+    }
+    let mut gallery_dl = path.to_owned().into_os_string();
+    gallery_dl.push(".json");
+    let yt_dlp = path.with_extension("info.json");
+    [PathBuf::from(gallery_dl), yt_dlp]
+        .into_iter()
+        .find(|sidecar| sidecar.is_file())
+}
+
+/// Whether the file at `path` is named like a sidecar (`<name>.info.json` or
+/// `<name>.<ext>.json`), even if the file it describes isn't there (yet)
+pub fn is_sidecar_name(path: &Path) -> bool {
+    let stem = match path.file_stem() {
+        Some(stem) if is_json(path) => Path::new(stem),
+        _ => return false,
+    };
+    stem.extension().is_some()
+}
+
+/// The file the sidecar at `path` describes, if `path` is a sidecar and the file exists
+pub fn media_path(path: &Path) -> Option<PathBuf> {
+    let file_name = path.file_name()?.to_string_lossy();
+    if let Some(stem) = file_name.strip_suffix(".info.json") {
+        // yt-dlp drops the extension of the video
+        let entries = std::fs::read_dir(path.parent()?).ok()?;
+        return entries.flatten().map(|entry| entry.path()).find(|media| {
+            !is_json(media)
+                && media
+                    .file_stem()
+                    .is_some_and(|media_stem| media_stem == stem)
+        });
+    }
+    let media = path.with_file_name(file_name.strip_suffix(".json")?);
+    if media.is_file() && !is_json(&media) {
+        Some(media)
+    } else {
+        None
+    }
+}
+
+/// The value at a dotted `field` path
+fn field<'a>(json: &'a Value, field: &str) -> Option<&'a Value> {
+    field.split('.').try_fold(json, |value, key| value.get(key))
+}
+
+/// The text values of a field
+fn values(value: &Value) -> Vec<String> {
+    match value {
+        Value::String(text) if !text.trim().is_empty() => vec![text.trim().to_owned()],
+        Value::Number(number) => vec![number.to_string()],
+        Value::Array(items) => items.iter().flat_map(values).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Reads what `config` asks for in the sidecar of the file at `path`. Tags go through
+/// `aliases`. Returns `None` if there is no sidecar or it can't be parsed.
+pub fn read(
+    path: &Path,
+    config: &DownloadSidecarConfig,
+    aliases: &HashMap<String, String>,
+) -> Option<DownloadMetadata> {
+    let sidecar = std::fs::read_to_string(sidecar_path(path)?).ok()?;
+    let json: Value = serde_json::from_str(&sidecar).ok()?;
+    let mut metadata = DownloadMetadata::default();
+
+    for (field_name, namespace) in config.tags.iter() {
+        let field_values = field(&json, field_name).map(values).unwrap_or_default();
+        for value in field_values {
+            let tag = match tag_util::keyword_to_tag(&value, aliases) {
+                Some(tag) if namespace.is_empty() => tag,
+                Some(tag) => format!("{namespace}:{tag}"),
+                None => continue,
+            };
+            if tag_util::is_tag_name_valid(&tag)
+                && !tag_util::is_system_tag(&tag)
+                && !tag_util::is_reserved_tag(&tag)
+                && !metadata.tags.contains(&tag)
+            {
+                metadata.tags.push(tag);
+            }
+        }
+    }
+
+    for (key, fields) in config.source.iter() {
+        let value = fields
+            .iter()
+            .filter_map(|field_name| field(&json, field_name))
+            .map(values)
+            .find(|values| !values.is_empty());
+        if let Some(value) = value {
+            metadata.source.insert(key.clone(), value.join(", "));
+        }
+    }
+    Some(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_read_downloader_sidecars() {
+        let folder = tempfile::tempdir().unwrap().keep();
+        let image = folder.join("12345_p0.png");
+        std::fs::write(&image, "image").unwrap();
+        std::fs::write(
+            folder.join("12345_p0.png.json"),
+            r#"{"category": "pixiv", "tags": ["Landscape", "Mount Fuji", "???"],
+                "user": {"name": "Some Artist"}, "url": "https://i.pximg.net/12345_p0.png"}"#,
+        )
+        .unwrap();
+        let video = folder.join("Cat video [abc].webm");
+        std::fs::write(&video, "video").unwrap();
+        std::fs::write(
+            folder.join("Cat video [abc].info.json"),
+            r#"{"extractor": "youtube", "uploader": "Cats", "categories": ["Pets & Animals"],
+                "webpage_url": "https://www.youtube.com/watch?v=abc", "title": "Cat video"}"#,
+        )
+        .unwrap();
+
+        let config = DownloadSidecarConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        let aliases = HashMap::from([("landscape".to_owned(), "scenery".to_owned())]);
+        let image_metadata = read(&image, &config, &aliases).unwrap();
+        assert_eq!(
+            image_metadata.tags,
+            vec!["site:pixiv", "scenery", "mount_fuji"]
+        );
+        assert_eq!(image_metadata.source["uploader"], "Some Artist");
+        assert_eq!(
+            image_metadata.source["url"],
+            "https://i.pximg.net/12345_p0.png"
+        );
+
+        let video_metadata = read(&video, &config, &aliases).unwrap();
+        assert_eq!(
+            video_metadata.tags,
+            vec!["pets_animals", "site:youtube", "artist:cats"]
+        );
+        assert_eq!(
+            video_metadata.source["url"],
+            "https://www.youtube.com/watch?v=abc"
+        );
+
+        assert_eq!(
+            media_path(&folder.join("Cat video [abc].info.json")),
+            Some(video)
+        );
+        assert_eq!(media_path(&folder.join("12345_p0.png.json")), Some(image));
+        assert!(media_path(&folder.join("other.json")).is_none());
+
+        assert!(is_sidecar_name(Path::new("Cat video [abc].info.json")));
+        assert!(is_sidecar_name(Path::new("12345_p0.png.json")));
+        assert!(!is_sidecar_name(Path::new("other.json")));
+        assert!(!is_sidecar_name(Path::new("12345_p0.png")));
+    }
+}
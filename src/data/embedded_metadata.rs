@@ -0,0 +1,431 @@
+//! Metadata embedded in images by photo managers (Lightroom, digiKam...): keywords from
+//! XMP (`dc:subject`) and IPTC, and the capture time, camera model and GPS coordinates
+//! from EXIF. Only JPEG, PNG and TIFF files are read.
+use anyhow::Result;
+use regex::Regex;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Extensions of the files metadata is read from
+const SUPPORTED_EXTENSIONS: [&str; 5] = ["jpg", "jpeg", "png", "tif", "tiff"];
+
+/// TIFF tags holding the XMP packet and the IPTC records
+const TIFF_XMP_TAG: u16 = 700;
+const TIFF_IPTC_TAG: u16 = 33723;
+
+/// Photoshop image resource holding the IPTC records in JPEGs
+const PHOTOSHOP_IPTC_RESOURCE: u16 = 0x0404;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct EmbeddedMetadata {
+    /// Keywords as they were written, not turned into tags yet
+    pub keywords: Vec<String>,
+    /// Year, month and day the photo was taken
+    pub taken: Option<(u16, u8, u8)>,
+    pub camera: Option<String>,
+    /// Latitude and longitude in degrees (south and west are negative)
+    pub gps: Option<(f64, f64)>,
+}
+
+impl EmbeddedMetadata {
+    /// Reads the metadata of the file at `path`. Files that aren't supported or don't
+    /// have any metadata give an empty `EmbeddedMetadata`.
+    pub fn read(path: &Path) -> EmbeddedMetadata {
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if !SUPPORTED_EXTENSIONS.contains(&extension.as_str()) {
+            return EmbeddedMetadata::default();
+        }
+        let data = match extension.as_str() {
+            "jpg" | "jpeg" => read_jpeg_segments(path),
+            "png" => read_png_chunks(path),
+            // TIFF directories can point anywhere in the file
+            _ => std::fs::read(path),
+        };
+        match data {
+            Ok(data) => Self::from_bytes(&data),
+            Err(_) => EmbeddedMetadata::default(),
+        }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> EmbeddedMetadata {
+        let mut metadata = EmbeddedMetadata::default();
+        // XMP packets are plain text, whatever the file format is
+        if let Some(xmp) = find_xmp_packet(data) {
+            metadata.keywords.extend(xmp_keywords(&xmp));
+        }
+        if data.starts_with(&[0xFF, 0xD8]) {
+            if let Some(iim) = jpeg_iptc_records(data) {
+                metadata.keywords.extend(iptc_keywords(iim));
+            }
+        }
+        // Not having EXIF data at all is common, so errors are ignored
+        let _ = metadata.read_exif(data);
+
+        let mut seen = std::collections::HashSet::new();
+        metadata
+            .keywords
+            .retain(|keyword| seen.insert(keyword.clone()));
+        metadata
+    }
+
+    fn read_exif(&mut self, data: &[u8]) -> Result<()> {
+        let exif = exif::Reader::new().read_from_container(&mut Cursor::new(data))?;
+        let ascii = |tag: exif::Tag| match exif.get_field(tag, exif::In::PRIMARY) {
+            Some(exif::Field {
+                value: exif::Value::Ascii(values),
+                ..
+            }) => values.first().cloned(),
+            _ => None,
+        };
+
+        let taken = ascii(exif::Tag::DateTimeOriginal).or_else(|| ascii(exif::Tag::DateTime));
+        if let Some(taken) = taken.and_then(|taken| exif::DateTime::from_ascii(&taken).ok()) {
+            self.taken = Some((taken.year, taken.month, taken.day));
+        }
+        self.camera = ascii(exif::Tag::Model)
+            .map(|model| String::from_utf8_lossy(&model).trim().to_owned())
+            .filter(|model| !model.is_empty());
+
+        let coordinate = |tag: exif::Tag, reference_tag: exif::Tag, negative: u8| {
+            let degrees = match exif.get_field(tag, exif::In::PRIMARY) {
+                Some(exif::Field {
+                    value: exif::Value::Rational(values),
+                    ..
+                }) if values.len() == 3 => {
+                    values[0].to_f64() + values[1].to_f64() / 60.0 + values[2].to_f64() / 3600.0
+                }
+                _ => return None,
+            };
+            let reference = ascii(reference_tag)?;
+            Some(if reference.first() == Some(&negative) {
+                -degrees
+            } else {
+                degrees
+            })
+        };
+        let latitude = coordinate(exif::Tag::GPSLatitude, exif::Tag::GPSLatitudeRef, b'S');
+        let longitude = coordinate(exif::Tag::GPSLongitude, exif::Tag::GPSLongitudeRef, b'W');
+        if let (Some(latitude), Some(longitude)) = (latitude, longitude) {
+            self.gps = Some((latitude, longitude));
+        }
+
+        if let Some(field) = exif.get_field(
+            exif::Tag(exif::Context::Tiff, TIFF_IPTC_TAG),
+            exif::In::PRIMARY,
+        ) {
+            let iim: Vec<u8> = match &field.value {
+                exif::Value::Undefined(bytes, _) | exif::Value::Byte(bytes) => bytes.clone(),
+                // Photoshop stores it as LONGs
+                exif::Value::Long(longs) => longs
+                    .iter()
+                    .flat_map(|long| {
+                        if exif.little_endian() {
+                            long.to_le_bytes()
+                        } else {
+                            long.to_be_bytes()
+                        }
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            };
+            self.keywords.extend(iptc_keywords(&iim));
+        }
+        Ok(())
+    }
+}
+
+/// The segments of a JPEG before its image data (where EXIF, XMP and IPTC are), as a
+/// JPEG without the image
+fn read_jpeg_segments(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut data = vec![0; 2];
+    file.read_exact(&mut data)?;
+    let mut header = [0; 4];
+    // Stops at the start of scan, and at markers without a length
+    while file.read_exact(&mut header).is_ok()
+        && header[0] == 0xFF
+        && !matches!(header[1], 0xDA | 0xD0..=0xD9 | 0x01)
+    {
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut segment = vec![0; length.saturating_sub(2)];
+        file.read_exact(&mut segment)?;
+        data.extend(header);
+        data.extend(segment);
+    }
+    data.extend([0xFF, 0xD9]);
+    Ok(data)
+}
+
+/// The chunks of a PNG without the image data ones (`IDAT`)
+fn read_png_chunks(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut data = vec![0; 8];
+    file.read_exact(&mut data)?;
+    let mut header = [0; 8];
+    while file.read_exact(&mut header).is_ok() {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let chunk_type = &header[4..8];
+        // Data and CRC
+        if chunk_type == b"IDAT" {
+            file.seek(SeekFrom::Current(length as i64 + 4))?;
+            continue;
+        }
+        data.extend(header);
+        let read = (&mut file).take(length as u64 + 4).read_to_end(&mut data)?;
+        if read != length + 4 {
+            break;
+        }
+        if chunk_type == b"IEND" {
+            break;
+        }
+    }
+    Ok(data)
+}
+
+/// Returns the XMP packet (`<x:xmpmeta>...</x:xmpmeta>`) found in `data`, if any
+fn find_xmp_packet(data: &[u8]) -> Option<String> {
+    let start = find_bytes(data, b"<x:xmpmeta")?;
+    let end_tag = b"</x:xmpmeta>";
+    let end = start + find_bytes(&data[start..], end_tag)? + end_tag.len();
+    Some(String::from_utf8_lossy(&data[start..end]).into_owned())
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Keywords (`dc:subject`) of an XMP packet or sidecar
+pub fn xmp_keywords(xmp: &str) -> Vec<String> {
+    let subject_regex = Regex::new(r"(?s)<dc:subject>(.*?)</dc:subject>").unwrap();
+    let item_regex = Regex::new(r"(?s)<rdf:li[^>]*>(.*?)</rdf:li>").unwrap();
+    subject_regex
+        .captures_iter(xmp)
+        .flat_map(|subject| {
+            item_regex
+                .captures_iter(subject.get(1).unwrap().as_str())
+                .map(|item| unescape_xml(item[1].trim()))
+                .collect::<Vec<String>>()
+        })
+        .filter(|keyword| !keyword.is_empty())
+        .collect()
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Finds the IPTC records in the Photoshop resources (APP13 segment) of a JPEG
+fn jpeg_iptc_records(data: &[u8]) -> Option<&[u8]> {
+    let mut position = 2;
+    while position + 4 <= data.len() && data[position] == 0xFF {
+        let marker = data[position + 1];
+        // Start of scan: the image data follows, there are no more metadata segments
+        if marker == 0xDA {
+            return None;
+        }
+        let length = u16::from_be_bytes([data[position + 2], data[position + 3]]) as usize;
+        let segment = data.get(position + 4..position + 2 + length)?;
+        if marker == 0xED {
+            if let Some(resources) = segment.strip_prefix(b"Photoshop 3.0\0") {
+                return photoshop_resource(resources, PHOTOSHOP_IPTC_RESOURCE);
+            }
+        }
+        position += 2 + length;
+    }
+    None
+}
+
+/// Finds the data of the Photoshop image resource `id` (`8BIM` blocks)
+fn photoshop_resource(mut resources: &[u8], id: u16) -> Option<&[u8]> {
+    while resources.len() >= 12 && resources.starts_with(b"8BIM") {
+        let resource_id = u16::from_be_bytes([resources[4], resources[5]]);
+        // The name is a Pascal string padded to an even length
+        let name_length = resources[6] as usize;
+        let size_position = 6 + ((name_length + 2) & !1);
+        let size_bytes = resources.get(size_position..size_position + 4)?;
+        let size = u32::from_be_bytes(size_bytes.try_into().ok()?) as usize;
+        let data_position = size_position + 4;
+        let data = resources.get(data_position..data_position + size)?;
+        if resource_id == id {
+            return Some(data);
+        }
+        resources = resources.get(data_position + ((size + 1) & !1)..)?;
+    }
+    None
+}
+
+/// Keywords (record 2, dataset 25) of IPTC-IIM records
+fn iptc_keywords(mut records: &[u8]) -> Vec<String> {
+    let mut keywords = Vec::new();
+    while records.len() >= 5 && records[0] == 0x1C {
+        let (record, dataset) = (records[1], records[2]);
+        let length = u16::from_be_bytes([records[3], records[4]]) as usize;
+        // Extended datasets (length with the high bit set) aren't used for keywords
+        if length & 0x8000 != 0 {
+            break;
+        }
+        let value = match records.get(5..5 + length) {
+            Some(value) => value,
+            None => break,
+        };
+        if record == 2 && dataset == 25 {
+            keywords.push(String::from_utf8_lossy(value).trim().to_owned());
+        }
+        records = &records[5 + length..];
+    }
+    keywords.retain(|keyword| !keyword.is_empty());
+    keywords
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XMP: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF><rdf:Description>
+        <dc:subject><rdf:Bag><rdf:li>Mount Fuji</rdf:li><rdf:li>Rock &amp; Roll</rdf:li></rdf:Bag></dc:subject>
+        </rdf:Description></rdf:RDF></x:xmpmeta>"#;
+
+    fn iptc_keyword(keyword: &str) -> Vec<u8> {
+        let mut record = vec![0x1C, 2, 25];
+        record.extend((keyword.len() as u16).to_be_bytes());
+        record.extend(keyword.as_bytes());
+        record
+    }
+
+    #[test]
+    fn should_read_jpeg_keywords() {
+        let iim = [iptc_keyword("Japan"), iptc_keyword("Mount Fuji")].concat();
+        let mut resources = b"8BIM".to_vec();
+        resources.extend(PHOTOSHOP_IPTC_RESOURCE.to_be_bytes());
+        resources.extend([0, 0]);
+        resources.extend((iim.len() as u32).to_be_bytes());
+        resources.extend(&iim);
+        let app13 = [b"Photoshop 3.0\0".as_slice(), &resources].concat();
+
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xED];
+        jpeg.extend(((app13.len() + 2) as u16).to_be_bytes());
+        jpeg.extend(&app13);
+        jpeg.extend([0xFF, 0xE1]);
+        jpeg.extend(((XMP.len() + 2) as u16).to_be_bytes());
+        jpeg.extend(XMP.as_bytes());
+        jpeg.extend([0xFF, 0xDA, 0, 2, 0xFF, 0xD9]);
+
+        let metadata = EmbeddedMetadata::from_bytes(&jpeg);
+        assert_eq!(
+            metadata.keywords,
+            vec!["Mount Fuji", "Rock & Roll", "Japan"]
+        );
+    }
+
+    #[test]
+    fn should_only_read_metadata_segments() {
+        let folder = tempfile::tempdir().unwrap().keep();
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        jpeg.extend(((XMP.len() + 2) as u16).to_be_bytes());
+        jpeg.extend(XMP.as_bytes());
+        jpeg.extend([0xFF, 0xDA, 0, 2]);
+        jpeg.extend(vec![0x42; 100_000]);
+        jpeg.extend([0xFF, 0xD9]);
+        let jpeg_path = folder.join("photo.jpg");
+        std::fs::write(&jpeg_path, &jpeg).unwrap();
+        assert!(read_jpeg_segments(&jpeg_path).unwrap().len() < 1000);
+        let metadata = EmbeddedMetadata::read(&jpeg_path);
+        assert_eq!(metadata.keywords, vec!["Mount Fuji", "Rock & Roll"]);
+
+        // The XMP chunk comes after the image data
+        let png_path = folder.join("noise.png");
+        let noise = image::RgbImage::from_fn(300, 300, |x, y| {
+            let value = (x.wrapping_mul(2654435761) ^ y.wrapping_mul(40503)) >> 13;
+            image::Rgb([value as u8, (value >> 8) as u8, (value >> 16) as u8])
+        });
+        noise.save(&png_path).unwrap();
+        let mut png = std::fs::read(&png_path).unwrap();
+        let iend = png.split_off(png.len() - 12);
+        let text = [b"XML:com.adobe.xmp\0".as_slice(), XMP.as_bytes()].concat();
+        png.extend((text.len() as u32).to_be_bytes());
+        png.extend(b"tEXt");
+        png.extend(&text);
+        png.extend([0; 4]);
+        png.extend(iend);
+        std::fs::write(&png_path, &png).unwrap();
+        assert!(png.len() > 100_000);
+        assert!(read_png_chunks(&png_path).unwrap().len() < 1000);
+        let metadata = EmbeddedMetadata::read(&png_path);
+        assert_eq!(metadata.keywords, vec!["Mount Fuji", "Rock & Roll"]);
+    }
+
+    #[test]
+    fn should_read_exif() {
+        let rational = |value: u32| exif::Rational::from((value, 1));
+        let fields = [
+            exif::Field {
+                tag: exif::Tag::Model,
+                ifd_num: exif::In::PRIMARY,
+                value: exif::Value::Ascii(vec![b"Canon EOS 5D".to_vec()]),
+            },
+            exif::Field {
+                tag: exif::Tag::DateTimeOriginal,
+                ifd_num: exif::In::PRIMARY,
+                value: exif::Value::Ascii(vec![b"2023:05:14 10:30:00".to_vec()]),
+            },
+            exif::Field {
+                tag: exif::Tag::GPSLatitude,
+                ifd_num: exif::In::PRIMARY,
+                value: exif::Value::Rational(vec![rational(35), rational(21), rational(36)]),
+            },
+            exif::Field {
+                tag: exif::Tag::GPSLatitudeRef,
+                ifd_num: exif::In::PRIMARY,
+                value: exif::Value::Ascii(vec![b"N".to_vec()]),
+            },
+            exif::Field {
+                tag: exif::Tag::GPSLongitude,
+                ifd_num: exif::In::PRIMARY,
+                value: exif::Value::Rational(vec![rational(138), rational(43), rational(48)]),
+            },
+            exif::Field {
+                tag: exif::Tag::GPSLongitudeRef,
+                ifd_num: exif::In::PRIMARY,
+                value: exif::Value::Ascii(vec![b"E".to_vec()]),
+            },
+            exif::Field {
+                tag: exif::Tag(exif::Context::Tiff, TIFF_IPTC_TAG),
+                ifd_num: exif::In::PRIMARY,
+                value: exif::Value::Undefined(iptc_keyword("volcano"), 0),
+            },
+            exif::Field {
+                tag: exif::Tag(exif::Context::Tiff, TIFF_XMP_TAG),
+                ifd_num: exif::In::PRIMARY,
+                value: exif::Value::Byte(XMP.as_bytes().to_vec()),
+            },
+        ];
+        let mut writer = exif::experimental::Writer::new();
+        for field in fields.iter() {
+            writer.push_field(field);
+        }
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+
+        let path = tempfile::tempdir().unwrap().keep().join("fuji.TIF");
+        std::fs::write(&path, tiff.into_inner()).unwrap();
+        let metadata = EmbeddedMetadata::read(&path);
+        assert_eq!(metadata.camera.as_deref(), Some("Canon EOS 5D"));
+        assert_eq!(metadata.taken, Some((2023, 5, 14)));
+        let (latitude, longitude) = metadata.gps.unwrap();
+        assert!((latitude - 35.36).abs() < 0.001 && (longitude - 138.73).abs() < 0.001);
+        assert_eq!(
+            metadata.keywords,
+            vec!["Mount Fuji", "Rock & Roll", "volcano"]
+        );
+    }
+}
@@ -0,0 +1,434 @@
+//! Exports the result of a search as a static HTML site, to share files with people who
+//! don't use TagMaid. Everything is generated offline and the site doesn't load anything
+//! from elsewhere, so it can be zipped, put on a USB drive or on any web server:
+//!
+//! - `index.html`: a grid of every file, with a filter box (`cat -dog`) run in the browser
+//! - `files/<n>.html`: a page for each file, with its tags
+//! - `tags/<n>.html`: a page for each tag, with its files and the tags that go along with it
+//! - `media/` and `thumbs/`: the files themselves and their thumbnails
+//!
+//! Pages are numbered rather than named after files and tags, whose names don't always make
+//! valid URLs (`artist:someone`). System tags are left out.
+use crate::data::search_command::Search;
+use crate::data::{tag_file::TagFile, tag_util};
+use crate::database::tagmaid_database::TagMaidDatabase;
+use anyhow::{bail, Context, Result};
+use image::io::Reader as ImageReader;
+use log::*;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+
+const GENERATOR: &str = r#"<meta name="generator" content="TagMaid">"#;
+const FOLDERS: [&str; 4] = ["files", "tags", "media", "thumbs"];
+
+const STYLE: &str =
+    "body { font-family: sans-serif; margin: 0 auto; max-width: 1200px; padding: 1em; }
+a { color: #3465a4; text-decoration: none; }
+header { display: flex; gap: 1em; align-items: baseline; flex-wrap: wrap; }
+#filter { flex: 1; min-width: 12em; padding: 0.4em; }
+.grid { display: flex; flex-wrap: wrap; gap: 8px; margin: 1em 0; }
+.card { width: 160px; height: 160px; display: flex; align-items: center; justify-content: center;
+  background: #eee; overflow: hidden; }
+.card img { max-width: 100%; max-height: 100%; }
+.card span { color: #666; text-transform: uppercase; }
+.tags { display: flex; flex-wrap: wrap; gap: 0.4em; list-style: none; padding: 0; }
+.tags li a { background: #e6eef7; border-radius: 3px; padding: 0.1em 0.4em; }
+.preview { max-width: 100%; max-height: 80vh; }
+";
+
+/// Shows the cards having every tag of the filter and none of its `-tags`
+const FILTER_SCRIPT: &str = "const filter = document.getElementById('filter');
+const cards = Array.from(document.querySelectorAll('.card'));
+const count = document.getElementById('count');
+function apply() {
+  const terms = filter.value.toLowerCase().split(/\\s+/).filter(term => term.length > 0);
+  let shown = 0;
+  for (const card of cards) {
+    const tags = card.dataset.tags.split(' ');
+    const matches = terms.every(term => term.startsWith('-')
+      ? !tags.includes(term.slice(1))
+      : tags.includes(term));
+    card.style.display = matches ? '' : 'none';
+    if (matches) shown++;
+  }
+  count.textContent = shown + ' files';
+}
+filter.addEventListener('input', apply);
+apply();
+";
+
+#[derive(Debug, Clone)]
+pub struct GalleryOptions {
+    pub title: String,
+    /// Largest side of the thumbnails, in pixels
+    pub thumbnail_size: u32,
+}
+
+impl Default for GalleryOptions {
+    fn default() -> Self {
+        GalleryOptions {
+            title: "Gallery".to_owned(),
+            thumbnail_size: 160,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GalleryReport {
+    pub files: usize,
+    pub tags: usize,
+    /// Files that have no thumbnail (videos, documents, images that couldn't be read)
+    pub without_thumbnail: usize,
+}
+
+struct GalleryFile {
+    tagfile: TagFile,
+    /// User tags, sorted
+    tags: Vec<String>,
+    /// Relative to the site folder
+    media: String,
+    thumbnail: Option<String>,
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// A whole page. `root` leads back to the site folder from the page (`""` or `"../"`).
+fn page(title: &str, root: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">
+{GENERATOR}
+<title>{}</title>
+<link rel=\"stylesheet\" href=\"{root}style.css\">
+</head>
+<body>
+{body}
+</body>
+</html>
+",
+        escape_html(title)
+    )
+}
+
+/// The thumbnail grid of `indices` (into `files`)
+fn grid(files: &[GalleryFile], indices: &[usize], root: &str) -> String {
+    let mut html = String::from("<div class=\"grid\">\n");
+    for &index in indices {
+        let file = &files[index];
+        let content = match &file.thumbnail {
+            Some(thumbnail) => format!(
+                "<img src=\"{root}{thumbnail}\" alt=\"{}\" loading=\"lazy\">",
+                escape_html(file.tagfile.get_file_name())
+            ),
+            None => {
+                let extension = Path::new(file.tagfile.get_file_name())
+                    .extension()
+                    .map(|extension| extension.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| "file".to_owned());
+                format!("<span>{}</span>", escape_html(&extension))
+            }
+        };
+        let _ = writeln!(
+            html,
+            "<a class=\"card\" href=\"{root}files/{index}.html\" title=\"{}\" data-tags=\"{}\">{content}</a>",
+            escape_html(file.tagfile.get_file_name()),
+            escape_html(&file.tags.join(" ").to_lowercase())
+        );
+    }
+    html.push_str("</div>\n");
+    html
+}
+
+/// A list of links to tag pages
+fn tag_list(tags: &[&String], tag_pages: &BTreeMap<String, usize>, root: &str) -> String {
+    let mut html = String::from("<ul class=\"tags\">\n");
+    for tag in tags {
+        let _ = writeln!(
+            html,
+            "<li><a href=\"{root}tags/{}.html\">{}</a></li>",
+            tag_pages[*tag],
+            escape_html(tag)
+        );
+    }
+    html.push_str("</ul>\n");
+    html
+}
+
+/// Writes a thumbnail of the image at `path` to `destination`. Returns `false` if it isn't
+/// an image that can be read.
+fn write_thumbnail(path: &Path, destination: &Path, size: u32) -> bool {
+    let image = match ImageReader::open(path)
+        .ok()
+        .and_then(|reader| reader.with_guessed_format().ok())
+        .and_then(|reader| reader.decode().ok())
+    {
+        Some(image) => image,
+        None => return false,
+    };
+    // JPEG has no transparency, and some formats have more than 8 bits per channel
+    let thumbnail = image::DynamicImage::ImageRgb8(image.thumbnail(size, size).to_rgb8());
+    thumbnail
+        .save_with_format(destination, image::ImageFormat::Jpeg)
+        .is_ok()
+}
+
+/// Empties the site folder, if it is one from an earlier export
+fn prepare_folder(destination: &Path) -> Result<()> {
+    if destination.exists() {
+        let is_gallery = std::fs::read_to_string(destination.join("index.html"))
+            .is_ok_and(|index| index.contains(GENERATOR));
+        let is_empty = std::fs::read_dir(destination)?.next().is_none();
+        if !is_gallery && !is_empty {
+            bail!(
+                "'{}' isn't empty, and isn't a gallery made by TagMaid",
+                destination.display()
+            );
+        }
+        for folder in FOLDERS {
+            let folder = destination.join(folder);
+            if folder.exists() {
+                std::fs::remove_dir_all(&folder)
+                    .with_context(|| format!("Couldn't empty '{}'", folder.display()))?;
+            }
+        }
+    }
+    for folder in FOLDERS {
+        std::fs::create_dir_all(destination.join(folder))
+            .with_context(|| format!("Couldn't create '{}'", destination.display()))?;
+    }
+    Ok(())
+}
+
+/// Writes a site showing the files matching `query` to `destination`, which has to be
+/// empty or a gallery exported before (it is then replaced).
+pub fn export(
+    db: &TagMaidDatabase,
+    query: &str,
+    destination: &Path,
+    options: &GalleryOptions,
+) -> Result<GalleryReport> {
+    let search = Search::from_string(query)?;
+    let mut hashes = db.search(&search)?;
+    prepare_folder(destination)?;
+    // Newest first
+    hashes.sort_by_cached_key(|hash| std::cmp::Reverse(db.get_upload_date(hash).ok()));
+
+    let mut report = GalleryReport::default();
+    let mut files: Vec<GalleryFile> = Vec::new();
+    for (index, hash) in hashes.iter().enumerate() {
+        let tagfile = db.get_tagfile_from_hash(hash)?;
+        let mut tags: Vec<String> = tagfile
+            .get_tags()
+            .iter()
+            .filter(|tag| !tag_util::is_system_tag(tag))
+            .cloned()
+            .collect();
+        tags.sort();
+
+        // The extension ends up in links, so only letters and digits are kept
+        let extension: String = Path::new(tagfile.get_file_name())
+            .extension()
+            .map(|extension| {
+                extension
+                    .to_string_lossy()
+                    .chars()
+                    .filter(char::is_ascii_alphanumeric)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let extension = match extension.is_empty() {
+            true => extension,
+            false => format!(".{extension}"),
+        };
+        let media = format!("media/{index}{extension}");
+        std::fs::copy(tagfile.get_path(), destination.join(&media))
+            .with_context(|| format!("Couldn't copy '{}'", tagfile.get_path().display()))?;
+        let thumbnail_path = format!("thumbs/{index}.jpg");
+        let thumbnail = if write_thumbnail(
+            tagfile.get_path(),
+            &destination.join(&thumbnail_path),
+            options.thumbnail_size,
+        ) {
+            Some(thumbnail_path)
+        } else {
+            report.without_thumbnail += 1;
+            None
+        };
+        files.push(GalleryFile {
+            tagfile,
+            tags,
+            media,
+            thumbnail,
+        });
+    }
+
+    // Tag -> the files having it, in the same order as everywhere else
+    let mut tag_files: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (index, file) in files.iter().enumerate() {
+        for tag in file.tags.iter() {
+            tag_files.entry(tag.clone()).or_default().push(index);
+        }
+    }
+    let tag_pages: BTreeMap<String, usize> = tag_files
+        .keys()
+        .enumerate()
+        .map(|(page, tag)| (tag.clone(), page))
+        .collect();
+    let all_tags: Vec<&String> = tag_files.keys().collect();
+
+    std::fs::write(destination.join("style.css"), STYLE)?;
+    std::fs::write(destination.join("filter.js"), FILTER_SCRIPT)?;
+    let all_files: Vec<usize> = (0..files.len()).collect();
+    let index_body = format!(
+        "<header><h1>{}</h1><input id=\"filter\" type=\"search\" placeholder=\"Filter: cat -dog\"><span id=\"count\">{} files</span></header>\n{}<details><summary>{} tags</summary>\n{}</details>\n<script src=\"filter.js\"></script>",
+        escape_html(&options.title),
+        files.len(),
+        grid(&files, &all_files, ""),
+        all_tags.len(),
+        tag_list(&all_tags, &tag_pages, "")
+    );
+    std::fs::write(
+        destination.join("index.html"),
+        page(&options.title, "", &index_body),
+    )?;
+
+    for (index, file) in files.iter().enumerate() {
+        let name = file.tagfile.get_file_name();
+        let preview = match &file.thumbnail {
+            // Browsers show the original better than the thumbnail
+            Some(..) => format!(
+                "<a href=\"../{0}\"><img class=\"preview\" src=\"../{0}\" alt=\"{1}\"></a>",
+                file.media,
+                escape_html(name)
+            ),
+            None => format!(
+                "<p><a href=\"../{}\">Open {}</a></p>",
+                file.media,
+                escape_html(name)
+            ),
+        };
+        let mut navigation = String::from("<p><a href=\"../index.html\">All files</a>");
+        if index > 0 {
+            let _ = write!(navigation, " · <a href=\"{}.html\">Previous</a>", index - 1);
+        }
+        if index + 1 < files.len() {
+            let _ = write!(navigation, " · <a href=\"{}.html\">Next</a>", index + 1);
+        }
+        navigation.push_str("</p>");
+        let upload_date = db
+            .get_upload_date(&file.tagfile.file_hash)
+            .map(|date| format!("<p>Added on {}</p>\n", date.format("%Y-%m-%d")))
+            .unwrap_or_default();
+        let tags: Vec<&String> = file.tags.iter().collect();
+        let body = format!(
+            "{navigation}\n<h1>{}</h1>\n{preview}\n{upload_date}{}",
+            escape_html(name),
+            tag_list(&tags, &tag_pages, "../")
+        );
+        std::fs::write(
+            destination.join(format!("files/{index}.html")),
+            page(name, "../", &body),
+        )?;
+    }
+
+    for (tag, indices) in tag_files.iter() {
+        // Tags that go along with this one, the most common first
+        let mut related: BTreeMap<&String, usize> = BTreeMap::new();
+        for &index in indices {
+            for other_tag in files[index].tags.iter().filter(|other| *other != tag) {
+                *related.entry(other_tag).or_default() += 1;
+            }
+        }
+        let mut related: Vec<(&String, usize)> = related.into_iter().collect();
+        related.sort_by_key(|(_tag, count)| std::cmp::Reverse(*count));
+        let related: Vec<&String> = related.into_iter().map(|(tag, _count)| tag).collect();
+        let body = format!(
+            "<p><a href=\"../index.html\">All files</a></p>\n<h1>{} ({})</h1>\n{}<h2>Related tags</h2>\n{}",
+            escape_html(tag),
+            indices.len(),
+            grid(&files, indices, "../"),
+            tag_list(&related, &tag_pages, "../")
+        );
+        std::fs::write(
+            destination.join(format!("tags/{}.html", tag_pages[tag])),
+            page(tag, "../", &body),
+        )?;
+    }
+
+    report.files = files.len();
+    report.tags = tag_files.len();
+    info!(
+        "html_gallery - export() - Exported {} files and {} tags to {}",
+        report.files,
+        report.tags,
+        destination.display()
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tag_database::TagDatabase;
+
+    #[test]
+    fn should_export_html_gallery() {
+        let folder = tempfile::tempdir().unwrap().keep();
+        let db = TagMaidDatabase::new(TagDatabase::initialise_at(folder.join("library")).unwrap());
+        let image_path = folder.join("red <square>.png");
+        image::RgbaImage::from_pixel(400, 200, image::Rgba([255, 0, 0, 255]))
+            .save(&image_path)
+            .unwrap();
+        let notes_path = folder.join("notes.txt\"onclick=\"alert(1)");
+        std::fs::write(&notes_path, "notes").unwrap();
+        for (path, tags) in [
+            (&image_path, vec!["red", "artist:ann"]),
+            (&notes_path, vec!["red", "text"]),
+        ] {
+            let mut tagfile = TagFile::initialise_from_path(path).unwrap();
+            tagfile.tags = tags.into_iter().map(String::from).collect();
+            db.update_tagfile(&tagfile).unwrap();
+        }
+
+        let site = folder.join("site");
+        let report = export(&db, "red", &site, &GalleryOptions::default()).unwrap();
+        assert_eq!((report.files, report.tags), (2, 3));
+        assert_eq!(report.without_thumbnail, 1);
+
+        let index = std::fs::read_to_string(site.join("index.html")).unwrap();
+        assert!(index.contains("red &lt;square&gt;.png"));
+        assert!(index.contains("data-tags=\"artist:ann red\""));
+        for page in ["files/0.html", "files/1.html"] {
+            let page = std::fs::read_to_string(site.join(page)).unwrap();
+            assert!(!page.contains("\"onclick"));
+        }
+        let thumbnail = image::open(site.join("thumbs/1.jpg")).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (160, 80));
+        // Tags are numbered alphabetically, `artist:ann` first
+        let tag_page = std::fs::read_to_string(site.join("tags/0.html")).unwrap();
+        assert!(tag_page.contains("artist:ann (1)"));
+        assert!(tag_page.contains("href=\"../tags/1.html\">red</a>"));
+
+        // Exporting again replaces the gallery, but never a folder that isn't one
+        let report = export(&db, "text", &site, &GalleryOptions::default()).unwrap();
+        assert_eq!(report.files, 1);
+        assert!(!site.join("files/1.html").exists());
+        assert!(export(
+            &db,
+            "red",
+            &folder.join("library"),
+            &GalleryOptions::default()
+        )
+        .is_err());
+    }
+}
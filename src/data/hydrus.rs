@@ -0,0 +1,455 @@
+//! Migration from Hydrus Network. A Hydrus client keeps its library in a `db` folder:
+//! `client.db` (services, which files are in which file service and since when),
+//! `client.master.db` (hashes and tag names), `client.mappings.db` (which file has which
+//! tag) and the file store, `client_files/fXX/<sha256>.<ext>`.
+//!
+//! Every file still in a local file service is imported with its current tags from every
+//! tag service, and gets its Hydrus import time as upload date.
+use crate::data::import::{ImportOptions, ImportProgress, ImportReport, ImportStatus, Importer};
+use crate::data::tag_util;
+use crate::database::tagmaid_database::TagMaidDatabase;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use log::*;
+use rusqlite::{Connection, OpenFlags};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+// Hydrus service types (`HC.LOCAL_TAG`...)
+const TAG_REPOSITORY: i64 = 0;
+const LOCAL_FILE_DOMAIN: i64 = 2;
+const LOCAL_TAG: i64 = 5;
+const COMBINED_LOCAL_FILE: i64 = 15;
+const COMBINED_LOCAL_MEDIA: i64 = 21;
+
+/// Hydrus namespaces that have another name here. Namespaces not listed are kept as they are.
+pub const DEFAULT_NAMESPACES: [(&str, &str); 2] = [("creator", "artist"), ("series", "copyright")];
+
+#[derive(Debug, Clone, Default)]
+pub struct HydrusOptions {
+    /// Hydrus namespace -> TagMaid namespace, on top of `DEFAULT_NAMESPACES`.
+    /// An empty TagMaid namespace drops the namespace (`page:3` -> `3`).
+    pub namespaces: HashMap<String, String>,
+    /// The file store, if it isn't `client_files` in the database folder
+    pub files_folder: Option<PathBuf>,
+    /// Options of the import itself (dry run, auto-tagging rules...)
+    pub import_options: ImportOptions,
+}
+
+/// A file from the Hydrus database
+#[derive(Debug, Clone)]
+pub struct HydrusFile {
+    /// SHA-256 of the file
+    pub hash: Vec<u8>,
+    /// Tags as Hydrus has them (`creator:katsushika hokusai`)
+    pub tags: Vec<String>,
+    /// When the file was first imported in Hydrus
+    pub imported_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default)]
+pub struct HydrusReport {
+    pub import: ImportReport,
+    /// Hashes (in hexadecimal) of the files that aren't in the file store
+    pub missing: Vec<String>,
+    /// Files whose content doesn't match their Hydrus hash, left out
+    pub mismatched: Vec<PathBuf>,
+    /// Hydrus tags that couldn't be turned into valid tag names, with how many files had them
+    pub dropped_tags: BTreeMap<String, usize>,
+}
+
+impl HydrusReport {
+    pub fn summary(&self) -> String {
+        format!(
+            "{}, {} missing, {} with a wrong hash, {} tags dropped",
+            self.import.summary(),
+            self.missing.len(),
+            self.mismatched.len(),
+            self.dropped_tags.len()
+        )
+    }
+}
+
+/// Turns a Hydrus tag into a TagMaid one: namespaces are mapped with `namespaces` and spaces
+/// become underscores. The result may still not be a valid tag name.
+pub fn map_tag(hydrus_tag: &str, namespaces: &HashMap<String, String>) -> String {
+    let hydrus_tag = hydrus_tag.trim().to_lowercase();
+    let (namespace, subtag) = match hydrus_tag.split_once(':') {
+        // Hydrus allows a colon at the start of unnamespaced tags (`:)`)
+        Some((namespace, subtag)) if !namespace.is_empty() => (namespace, subtag),
+        _ => ("", hydrus_tag.as_str()),
+    };
+    let namespace = match namespaces.get(namespace) {
+        Some(namespace) => namespace.as_str(),
+        None => DEFAULT_NAMESPACES
+            .iter()
+            .find(|(hydrus_namespace, _)| *hydrus_namespace == namespace)
+            .map_or(namespace, |(_, namespace)| namespace),
+    };
+    let subtag = subtag.split_whitespace().collect::<Vec<_>>().join("_");
+    if namespace.is_empty() {
+        subtag
+    } else {
+        format!("{}:{subtag}", namespace.replace(' ', "_"))
+    }
+}
+
+pub struct HydrusClient {
+    db: Connection,
+}
+
+impl HydrusClient {
+    /// Opens the databases in the Hydrus `db` folder, read-only
+    pub fn open(db_folder: &Path) -> Result<HydrusClient> {
+        let client_path = db_folder.join("client.db");
+        if !client_path.exists() {
+            bail!("No Hydrus database in '{}'", db_folder.display());
+        }
+        let db = Connection::open_with_flags(&client_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("Couldn't open '{}'", client_path.display()))?;
+        for (file_name, schema) in [
+            ("client.master.db", "master"),
+            ("client.mappings.db", "mappings"),
+        ] {
+            let path = db_folder.join(file_name);
+            if !path.exists() {
+                bail!("'{}' is missing", path.display());
+            }
+            db.execute(
+                &format!("ATTACH DATABASE ?1 AS {schema}"),
+                [path.to_string_lossy()],
+            )?;
+        }
+        Ok(HydrusClient { db })
+    }
+
+    fn service_ids(&self, service_types: &[i64]) -> Result<Vec<i64>> {
+        let mut query = self
+            .db
+            .prepare("SELECT service_id, service_type FROM services")?;
+        let rows = query.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let mut ids = Vec::new();
+        for row in rows {
+            let (id, service_type): (i64, i64) = row?;
+            if service_types.contains(&service_type) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    fn has_table(&self, schema: &str, table: &str) -> Result<bool> {
+        let count: i64 = self.db.query_row(
+            &format!(
+                "SELECT COUNT(*) FROM {schema}.sqlite_master WHERE type = 'table' AND name = ?1"
+            ),
+            [table],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    /// Every file in a local file service, with its tags from every tag service
+    pub fn files(&self) -> Result<Vec<HydrusFile>> {
+        // hash_id -> earliest import time in seconds, if any
+        let mut file_times: BTreeMap<i64, Option<i64>> = BTreeMap::new();
+        let file_services =
+            self.service_ids(&[LOCAL_FILE_DOMAIN, COMBINED_LOCAL_FILE, COMBINED_LOCAL_MEDIA])?;
+        for service_id in file_services {
+            let table = format!("current_files_{service_id}");
+            if !self.has_table("main", &table)? {
+                continue;
+            }
+            // Newer clients store milliseconds
+            let time_column = if self.has_column(&table, "timestamp_ms")? {
+                "timestamp_ms / 1000"
+            } else {
+                "timestamp"
+            };
+            let mut query = self
+                .db
+                .prepare(&format!("SELECT hash_id, {time_column} FROM {table}"))?;
+            let rows = query.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?;
+            for row in rows {
+                let (hash_id, time): (i64, Option<i64>) = row?;
+                let earliest = file_times.entry(hash_id).or_insert(time);
+                *earliest = match (*earliest, time) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+            }
+        }
+
+        // Tag repositories map tags to far more files than the local ones, so the mappings
+        // are looked up file by file (Hydrus indexes them by hash_id)
+        let mut tag_queries = Vec::new();
+        for service_id in self.service_ids(&[TAG_REPOSITORY, LOCAL_TAG])? {
+            let table = format!("current_mappings_{service_id}");
+            if !self.has_table("mappings", &table)? {
+                continue;
+            }
+            tag_queries.push(self.db.prepare(&format!(
+                "SELECT n.namespace, s.subtag FROM mappings.{table} m
+                JOIN master.tags t ON t.tag_id = m.tag_id
+                JOIN master.namespaces n ON n.namespace_id = t.namespace_id
+                JOIN master.subtags s ON s.subtag_id = t.subtag_id
+                WHERE m.hash_id = ?1"
+            ))?);
+        }
+
+        let mut hash_query = self
+            .db
+            .prepare("SELECT hash FROM master.hashes WHERE hash_id = ?1")?;
+        let mut files = Vec::new();
+        for (hash_id, time) in file_times {
+            let hash: Vec<u8> = hash_query.query_row([hash_id], |row| row.get(0))?;
+            let mut file_tags: BTreeSet<String> = BTreeSet::new();
+            for query in tag_queries.iter_mut() {
+                let rows = query.query_map([hash_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                for row in rows {
+                    let (namespace, subtag): (String, String) = row?;
+                    file_tags.insert(if namespace.is_empty() {
+                        subtag
+                    } else {
+                        format!("{namespace}:{subtag}")
+                    });
+                }
+            }
+            let file_tags: Vec<String> = file_tags.into_iter().collect();
+            files.push(HydrusFile {
+                hash,
+                tags: file_tags,
+                imported_at: time.and_then(|time| Utc.timestamp_opt(time, 0).single()),
+            });
+        }
+        Ok(files)
+    }
+
+    fn has_column(&self, table: &str, column: &str) -> Result<bool> {
+        let mut query = self.db.prepare(&format!("PRAGMA table_info({table})"))?;
+        let names = query.query_map((), |row| row.get::<_, String>(1))?;
+        for name in names {
+            if name? == column {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// The Hydrus file store, where files are named after their hash in `fXX` (or `fXXX`)
+/// folders. Each folder is listed once, the first time a file is looked for in it.
+struct FileStore {
+    folder: PathBuf,
+    // Folder name -> file stem (the hash) -> path
+    listed: HashMap<String, HashMap<String, PathBuf>>,
+}
+
+impl FileStore {
+    fn new(folder: PathBuf) -> FileStore {
+        FileStore {
+            folder,
+            listed: HashMap::new(),
+        }
+    }
+
+    /// Finds the file with the given hash
+    fn find(&mut self, hash_hex: &str) -> Option<PathBuf> {
+        for prefix_length in [2, 3] {
+            let folder_name = match hash_hex.get(..prefix_length) {
+                Some(prefix) => format!("f{prefix}"),
+                None => continue,
+            };
+            let folder = self.folder.join(&folder_name);
+            let files = self.listed.entry(folder_name).or_insert_with(|| {
+                std::fs::read_dir(folder)
+                    .into_iter()
+                    .flatten()
+                    .flatten()
+                    .filter_map(|entry| {
+                        let path = entry.path();
+                        let stem = path.file_stem()?.to_string_lossy().into_owned();
+                        Some((stem, path))
+                    })
+                    .collect()
+            });
+            if let Some(path) = files.get(hash_hex) {
+                return Some(path.clone());
+            }
+        }
+        None
+    }
+}
+
+fn sha256_matches(path: &Path, hash: &[u8]) -> Result<bool> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().as_slice() == hash)
+}
+
+/// Imports the Hydrus library in `db_folder`, calling `progress` after each file
+pub fn import<F>(
+    db: &TagMaidDatabase,
+    db_folder: &Path,
+    options: HydrusOptions,
+    mut progress: F,
+) -> Result<HydrusReport>
+where
+    F: FnMut(ImportProgress),
+{
+    info!("hydrus - import() - Importing {}", db_folder.display());
+    let files = HydrusClient::open(db_folder)?.files()?;
+    let mut file_store = FileStore::new(
+        options
+            .files_folder
+            .clone()
+            .unwrap_or_else(|| db_folder.join("client_files")),
+    );
+    let dry_run = options.import_options.dry_run;
+    let importer = Importer::new(db.clone(), options.import_options)?;
+    let mut report = HydrusReport::default();
+
+    let total = files.len();
+    for (index, file) in files.iter().enumerate() {
+        let hash_hex = tag_util::bytes_to_hex(&file.hash);
+        let path = match file_store.find(&hash_hex) {
+            Some(path) => path,
+            None => {
+                info!("WARNING: hydrus::import(): {hash_hex} isn't in the file store");
+                report.missing.push(hash_hex);
+                continue;
+            }
+        };
+        if !sha256_matches(&path, &file.hash)? {
+            info!(
+                "WARNING: hydrus::import(): {} doesn't match its hash",
+                path.display()
+            );
+            report.mismatched.push(path);
+            continue;
+        }
+
+        let mut tags = HashSet::new();
+        for hydrus_tag in file.tags.iter() {
+            let tag = map_tag(hydrus_tag, &options.namespaces);
+            if tag_util::is_tag_name_valid(&tag)
+                && !tag_util::is_system_tag(&tag)
+                && !tag_util::is_reserved_tag(&tag)
+            {
+                tags.insert(tag);
+            } else {
+                *report.dropped_tags.entry(hydrus_tag.clone()).or_default() += 1;
+            }
+        }
+        let imported_file = importer.import_file(&path, &tags);
+        if imported_file.status == ImportStatus::Imported && !dry_run {
+            if let Some(imported_at) = file.imported_at {
+                db.set_upload_date(&imported_file.hash, &imported_at)?;
+            }
+        }
+        progress(ImportProgress {
+            done: index + 1,
+            total,
+            file: &imported_file,
+        });
+        report.import.files.push(imported_file);
+    }
+    info!("hydrus - import() - {}", report.summary());
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tag_database::test_util::create_random_tagmaiddatabase;
+
+    #[test]
+    fn should_map_hydrus_tags() {
+        let namespaces = HashMap::from([("page".to_owned(), String::new())]);
+        assert_eq!(
+            map_tag("creator:Katsushika  Hokusai", &namespaces),
+            "artist:katsushika_hokusai"
+        );
+        assert_eq!(map_tag("page:3", &namespaces), "3");
+        assert_eq!(map_tag("great wave", &namespaces), "great_wave");
+        assert_eq!(map_tag(":)", &namespaces), ":)");
+    }
+
+    /// A Hydrus `db` folder with the tables the importer reads, and one file in its store
+    fn create_hydrus_folder() -> (PathBuf, Vec<u8>) {
+        let folder = tempfile::tempdir().unwrap().keep();
+        let content = b"the great wave";
+        let hash = Sha256::digest(content).to_vec();
+        let hash_hex = tag_util::bytes_to_hex(&hash);
+        std::fs::create_dir_all(folder.join(format!("client_files/f{}", &hash_hex[..2]))).unwrap();
+        std::fs::write(
+            folder.join(format!("client_files/f{}/{hash_hex}.jpg", &hash_hex[..2])),
+            content,
+        )
+        .unwrap();
+
+        let client = Connection::open(folder.join("client.db")).unwrap();
+        client
+            .execute_batch(
+                "CREATE TABLE services (service_id INTEGER PRIMARY KEY, service_key BLOB_BYTES, service_type INTEGER, name TEXT, dictionary_string TEXT);
+                INSERT INTO services VALUES (1, x'00', 15, 'all local files', '');
+                INSERT INTO services VALUES (2, x'01', 5, 'my tags', '');
+                CREATE TABLE current_files_1 (hash_id INTEGER PRIMARY KEY, timestamp_ms INTEGER);
+                INSERT INTO current_files_1 VALUES (1, 1500000000000);
+                INSERT INTO current_files_1 VALUES (2, 1500000000000);",
+            )
+            .unwrap();
+        let master = Connection::open(folder.join("client.master.db")).unwrap();
+        master
+            .execute_batch(
+                "CREATE TABLE hashes (hash_id INTEGER PRIMARY KEY, hash BLOB_BYTES UNIQUE);
+                CREATE TABLE namespaces (namespace_id INTEGER PRIMARY KEY, namespace TEXT UNIQUE);
+                CREATE TABLE subtags (subtag_id INTEGER PRIMARY KEY, subtag TEXT UNIQUE);
+                CREATE TABLE tags (tag_id INTEGER PRIMARY KEY, namespace_id INTEGER, subtag_id INTEGER);
+                INSERT INTO hashes VALUES (2, x'00');
+                INSERT INTO namespaces VALUES (1, ''), (2, 'creator'), (3, 'color');
+                INSERT INTO subtags VALUES (1, 'great wave'), (2, 'katsushika hokusai'), (3, 'wave!'), (4, 'blue');
+                INSERT INTO tags VALUES (1, 1, 1), (2, 2, 2), (3, 1, 3), (4, 3, 4);",
+            )
+            .unwrap();
+        master
+            .execute("INSERT INTO hashes VALUES (1, ?1)", [&hash])
+            .unwrap();
+        let mappings = Connection::open(folder.join("client.mappings.db")).unwrap();
+        mappings
+            .execute_batch(
+                "CREATE TABLE current_mappings_2 (tag_id INTEGER, hash_id INTEGER);
+                INSERT INTO current_mappings_2 VALUES (1, 1), (2, 1), (3, 1), (4, 1);",
+            )
+            .unwrap();
+        (folder, hash)
+    }
+
+    #[test]
+    fn should_import_hydrus_library() {
+        let db = create_random_tagmaiddatabase();
+        let (folder, hash) = create_hydrus_folder();
+
+        let files = HydrusClient::open(&folder).unwrap().files().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].hash, hash);
+
+        let report = import(&db, &folder, HydrusOptions::default(), |_progress| {}).unwrap();
+        assert_eq!(report.import.count(&ImportStatus::Imported), 1);
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(
+            report.dropped_tags,
+            BTreeMap::from([("wave!".to_owned(), 1), ("color:blue".to_owned(), 1)])
+        );
+
+        let imported = &report.import.files[0];
+        assert!(imported.tags.contains("great_wave"));
+        assert!(imported.tags.contains("artist:katsushika_hokusai"));
+        assert_eq!(
+            db.get_upload_date(&imported.hash).unwrap().timestamp(),
+            1500000000
+        );
+    }
+}
@@ -0,0 +1,542 @@
+//! Bulk import of files into the library. Every import path (folders, the API, samples...)
+//! goes through `Importer`, so duplicates and tags are handled the same way everywhere.
+use crate::data::download_sidecar::{self, DownloadMetadata, DownloadSidecarConfig};
+use crate::data::embedded_metadata::EmbeddedMetadata;
+use crate::data::rules::{Rule, RuleSet};
+use crate::data::{system_tags, tag_file::TagFile, tag_util, tagspaces};
+use crate::database::tagmaid_database::TagMaidDatabase;
+use anyhow::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use walkdir::WalkDir;
+
+#[derive(Debug, Clone, Default)]
+pub struct ImportOptions {
+    /// Tags given to every imported file
+    pub tags: HashSet<String>,
+    /// Turns the folders between the import root and a file into tags
+    /// (`Photos/2023/Japan/x.jpg` imported from `Photos` gets `2023` and `japan`)
+    pub path_tags: bool,
+    /// Globs matched against paths relative to the import root. When not empty,
+    /// only the files matching one of them are imported.
+    pub include: Vec<String>,
+    /// Files matching one of these globs are skipped
+    pub exclude: Vec<String>,
+    /// Only reports what would be imported, without touching the library
+    pub dry_run: bool,
+    /// Auto-tagging rules, usually the ones from `Config`
+    pub rules: Vec<Rule>,
+    /// Tag aliases from `Config`, applied to the keywords embedded in files
+    pub aliases: HashMap<String, String>,
+    /// Reads the tags TagSpaces left in `.ts` sidecars and `[tag1 tag2]` file name
+    /// suffixes, and leaves the `.ts` folders out
+    pub tagspaces: bool,
+    /// gallery-dl / yt-dlp metadata files to read, from `Config`. Sidecars themselves
+    /// aren't imported when enabled.
+    pub download_sidecars: DownloadSidecarConfig,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportStatus {
+    /// The file is new to the library
+    Imported,
+    /// The file was already in the library (or earlier in this import).
+    /// Its tags are merged with the new ones.
+    Duplicate,
+    /// A rule (named here) said not to import the file
+    Skipped(String),
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportedFile {
+    pub path: PathBuf,
+    /// Empty if the file couldn't be read
+    pub hash: Vec<u8>,
+    /// Tags of the file after the import
+    pub tags: BTreeSet<String>,
+    pub status: ImportStatus,
+    /// What each matching rule did to the file
+    pub rules: Vec<String>,
+    /// Source metadata found in the file's download sidecar
+    pub source: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub files: Vec<ImportedFile>,
+    /// Number of files left out by the include/exclude globs
+    pub filtered_out: usize,
+}
+
+impl ImportReport {
+    pub fn count(&self, status: &ImportStatus) -> usize {
+        self.files
+            .iter()
+            .filter(|file| match (&file.status, status) {
+                (ImportStatus::Failed(_), ImportStatus::Failed(_)) => true,
+                (ImportStatus::Skipped(_), ImportStatus::Skipped(_)) => true,
+                (a, b) => a == b,
+            })
+            .count()
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} imported, {} duplicates, {} skipped by rules, {} failed, {} filtered out",
+            self.count(&ImportStatus::Imported),
+            self.count(&ImportStatus::Duplicate),
+            self.count(&ImportStatus::Skipped(String::new())),
+            self.count(&ImportStatus::Failed(String::new())),
+            self.filtered_out
+        )
+    }
+}
+
+/// Sent to the progress callback after each file
+#[derive(Debug)]
+pub struct ImportProgress<'a> {
+    pub done: usize,
+    pub total: usize,
+    pub file: &'a ImportedFile,
+}
+
+pub struct Importer {
+    db: TagMaidDatabase,
+    options: ImportOptions,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    rules: RuleSet,
+    /// Hashes "imported" so far in a dry run, since they don't end up in the library
+    dry_run_hashes: Mutex<HashSet<Vec<u8>>>,
+}
+
+fn build_globset(globs: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob).with_context(|| format!("Invalid glob '{glob}'"))?);
+    }
+    Ok(builder.build()?)
+}
+
+impl Importer {
+    pub fn new(db: TagMaidDatabase, options: ImportOptions) -> Result<Importer> {
+        let include = if options.include.is_empty() {
+            None
+        } else {
+            Some(build_globset(&options.include)?)
+        };
+        let exclude = build_globset(&options.exclude)?;
+        let rules = RuleSet::new(&options.rules)?;
+        Ok(Importer {
+            db,
+            options,
+            include,
+            exclude,
+            rules,
+            dry_run_hashes: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn get_options(&self) -> &ImportOptions {
+        &self.options
+    }
+
+    /// Tags made from the folders between `root` and the file at `path`
+    pub fn path_tags(root: &Path, path: &Path) -> HashSet<String> {
+        let relative_parent = path
+            .strip_prefix(root)
+            .ok()
+            .and_then(|relative| relative.parent());
+        match relative_parent {
+            Some(parent) => parent
+                .components()
+                .filter_map(|component| {
+                    tag_util::sanitise_tag_name(&component.as_os_str().to_string_lossy())
+                })
+                .collect(),
+            None => HashSet::new(),
+        }
+    }
+
+    /// Whether the include/exclude globs let the file at `path` in
+    pub fn is_included(&self, root: &Path, path: &Path) -> bool {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        let included = match &self.include {
+            Some(include) => include.is_match(relative),
+            None => true,
+        };
+        included && !self.exclude.is_match(relative)
+    }
+
+    /// Imports a single file with `tags` on top of the ones from the options, the rules and,
+    /// if enabled, the TagSpaces or download sidecars. Invalid tags and system tags are
+    /// dropped. If the file is already in the library, the tags are added to the ones it has.
+    /// New files get their system tags, the keywords embedded in them (EXIF/IPTC/XMP) and
+    /// the tags the sync backends of the database find on them (`user.xdg.tags`, XMP
+    /// sidecars...).
+    pub fn import_file(&self, path: &Path, tags: &HashSet<String>) -> ImportedFile {
+        match self.try_import_file(path, tags) {
+            Ok(imported_file) => imported_file,
+            Err(err) => {
+                info!(
+                    "WARNING: import_file(): Couldn't import {}: {err:#}",
+                    path.display()
+                );
+                ImportedFile {
+                    path: path.to_owned(),
+                    hash: Vec::new(),
+                    tags: BTreeSet::new(),
+                    status: ImportStatus::Failed(format!("{err:#}")),
+                    rules: Vec::new(),
+                    source: BTreeMap::new(),
+                }
+            }
+        }
+    }
+
+    fn try_import_file(&self, path: &Path, tags: &HashSet<String>) -> Result<ImportedFile> {
+        let rule_outcome = self.rules.apply(path);
+        if let Some(rule_name) = rule_outcome.skipped_by {
+            debug!(
+                "Importer - import_file() - Rule '{rule_name}' skipped {}",
+                path.display()
+            );
+            return Ok(ImportedFile {
+                path: path.to_owned(),
+                hash: Vec::new(),
+                tags: BTreeSet::new(),
+                status: ImportStatus::Skipped(rule_name),
+                rules: rule_outcome.log,
+                source: BTreeMap::new(),
+            });
+        }
+
+        let new_tagfile = TagFile::initialise_from_path(path)?;
+        let seen_in_dry_run = self.options.dry_run
+            && !self
+                .dry_run_hashes
+                .lock()
+                .unwrap()
+                .insert(new_tagfile.file_hash.clone());
+
+        let (mut tagfile, status) = match self.db.get_tagfile_from_hash(&new_tagfile.file_hash) {
+            Ok(existing_tagfile) => (existing_tagfile, ImportStatus::Duplicate),
+            Err(..) if seen_in_dry_run => (new_tagfile, ImportStatus::Duplicate),
+            Err(..) => (new_tagfile, ImportStatus::Imported),
+        };
+        let old_tags = tagfile.get_tags().clone();
+        let tagspaces_tags: Vec<String> = if self.options.tagspaces {
+            tagspaces::tags(path)
+                .iter()
+                .filter_map(|tag| tag_util::keyword_to_tag(tag, &self.options.aliases))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let download_metadata = if self.options.download_sidecars.enabled {
+            download_sidecar::read(path, &self.options.download_sidecars, &self.options.aliases)
+                .unwrap_or_default()
+        } else {
+            DownloadMetadata::default()
+        };
+        let all_tags = self
+            .options
+            .tags
+            .iter()
+            .chain(tags.iter())
+            .chain(rule_outcome.tags.iter())
+            .chain(tagspaces_tags.iter())
+            .chain(download_metadata.tags.iter());
+        for tag in
+            all_tags.filter(|tag| !tag_util::is_system_tag(tag) && !tag_util::is_reserved_tag(tag))
+        {
+            tagfile.add_tag(tag)?;
+        }
+        if status == ImportStatus::Imported {
+            // Only read for new files, and only once for keywords and system tags
+            let metadata = EmbeddedMetadata::read(path);
+            let keywords = metadata
+                .keywords
+                .iter()
+                .cloned()
+                .chain(self.db.read_synced_tags(path));
+            let keyword_tags = keywords
+                .filter_map(|keyword| tag_util::keyword_to_tag(&keyword, &self.options.aliases));
+            let new_tags: HashSet<String> = keyword_tags
+                .filter(|tag| !tag_util::is_system_tag(tag))
+                .chain(system_tags::compute_with_metadata(path, &metadata))
+                .collect();
+            tagfile.tags.extend(new_tags);
+        }
+
+        // An untagged file already in the library would get removed by `update_tagfile()`
+        let changed = status == ImportStatus::Imported || &old_tags != tagfile.get_tags();
+        if changed && !self.options.dry_run {
+            self.db.update_tagfile(&tagfile)?;
+        }
+        if !download_metadata.source.is_empty() && !self.options.dry_run {
+            self.db
+                .set_source_metadata(&tagfile.file_hash, &download_metadata.source)?;
+        }
+        debug!("Importer - import_file() - {:?} {tagfile}", status);
+
+        Ok(ImportedFile {
+            path: path.to_owned(),
+            hash: tagfile.file_hash.clone(),
+            tags: tagfile.get_tags().iter().cloned().collect(),
+            status,
+            rules: rule_outcome.log,
+            source: download_metadata.source,
+        })
+    }
+
+    /// Imports every file under `root`, calling `progress` after each one.
+    /// Files inside the library folder are left alone.
+    pub fn import_folder<F>(&self, root: &Path, mut progress: F) -> Result<ImportReport>
+    where
+        F: FnMut(ImportProgress),
+    {
+        info!("Importer - import_folder() - Importing {}", root.display());
+        let library_path = self.db.get_library_path();
+        let mut report = ImportReport::default();
+
+        let mut paths: Vec<PathBuf> = Vec::new();
+        for entry in WalkDir::new(root).sort_by_file_name() {
+            let entry = entry.with_context(|| format!("Couldn't read '{}'", root.display()))?;
+            let path = entry.path();
+            if !entry.file_type().is_file() || path.starts_with(&library_path) {
+                continue;
+            }
+            if self.options.tagspaces && tagspaces::is_tagspaces_file(path) {
+                continue;
+            }
+            if self.options.download_sidecars.enabled
+                && download_sidecar::media_path(path).is_some()
+            {
+                continue;
+            }
+            if self.is_included(root, path) {
+                paths.push(path.to_owned());
+            } else {
+                report.filtered_out += 1;
+            }
+        }
+
+        let total = paths.len();
+        for (index, path) in paths.iter().enumerate() {
+            let tags = if self.options.path_tags {
+                Self::path_tags(root, path)
+            } else {
+                HashSet::new()
+            };
+            let imported_file = self.import_file(path, &tags);
+            progress(ImportProgress {
+                done: index + 1,
+                total,
+                file: &imported_file,
+            });
+            report.files.push(imported_file);
+        }
+        info!("Importer - import_folder() - {}", report.summary());
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::search_command::Search;
+    use crate::database::tag_database::test_util::create_random_tagmaiddatabase;
+    use std::fs;
+
+    fn create_photos_folder() -> PathBuf {
+        let root = tempfile::tempdir().unwrap().keep().join("Photos");
+        fs::create_dir_all(root.join("2023/Japan")).unwrap();
+        fs::create_dir_all(root.join("2023/.cache")).unwrap();
+        fs::write(root.join("2023/Japan/tokyo.jpg"), "tokyo").unwrap();
+        fs::write(root.join("2023/Japan/kyoto.jpg"), "kyoto").unwrap();
+        fs::write(root.join("2023/Japan/tokyo copy.jpg"), "tokyo").unwrap();
+        fs::write(root.join("2023/.cache/thumb.jpg"), "thumb").unwrap();
+        fs::write(root.join("2023/notes.txt"), "notes").unwrap();
+        root
+    }
+
+    #[test]
+    fn should_derive_tags_from_path() {
+        let tags = Importer::path_tags(Path::new("Photos"), Path::new("Photos/2023/Japan/x.jpg"));
+        assert_eq!(tags, HashSet::from(["2023".to_owned(), "japan".to_owned()]));
+        assert!(Importer::path_tags(Path::new("Photos"), Path::new("Photos/x.jpg")).is_empty());
+    }
+
+    #[test]
+    fn should_import_folder() {
+        let db = create_random_tagmaiddatabase();
+        let root = create_photos_folder();
+        let options = ImportOptions {
+            tags: HashSet::from(["photo".to_owned()]),
+            path_tags: true,
+            include: vec!["*.jpg".to_owned()],
+            exclude: vec!["**/.cache/**".to_owned()],
+            dry_run: false,
+            ..Default::default()
+        };
+        let importer = Importer::new(db.clone(), options).unwrap();
+        let mut progress_calls = 0;
+        let report = importer
+            .import_folder(&root, |progress| {
+                progress_calls += 1;
+                assert_eq!(progress.total, 3);
+            })
+            .unwrap();
+
+        assert_eq!(progress_calls, 3);
+        assert_eq!(report.filtered_out, 2);
+        assert_eq!(report.count(&ImportStatus::Imported), 2);
+        assert_eq!(report.count(&ImportStatus::Duplicate), 1);
+
+        let results = db
+            .search(&Search::from_string("japan 2023 photo").unwrap())
+            .unwrap();
+        assert_eq!(results.len(), 2);
+
+        // Importing again only finds duplicates
+        let report = importer.import_folder(&root, |_progress| {}).unwrap();
+        assert_eq!(report.count(&ImportStatus::Duplicate), 3);
+    }
+
+    #[test]
+    fn should_not_touch_library_in_dry_run() {
+        let db = create_random_tagmaiddatabase();
+        let root = create_photos_folder();
+        let options = ImportOptions {
+            path_tags: true,
+            dry_run: true,
+            ..Default::default()
+        };
+        let report = Importer::new(db.clone(), options)
+            .unwrap()
+            .import_folder(&root, |_progress| {})
+            .unwrap();
+
+        assert_eq!(report.count(&ImportStatus::Imported), 4);
+        assert_eq!(report.count(&ImportStatus::Duplicate), 1);
+        let tokyo = report
+            .files
+            .iter()
+            .find(|file| file.path.ends_with("tokyo.jpg"))
+            .unwrap();
+        assert_eq!(
+            tokyo.tags,
+            BTreeSet::from(["2023".to_owned(), "japan".to_owned()])
+        );
+        assert!(db.get_all_tags().unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_apply_rules() {
+        let db = create_random_tagmaiddatabase();
+        let root = create_photos_folder();
+        let rules: Vec<Rule> = serde_json::from_str(
+            r#"[
+                { "name": "trips", "conditions": { "source_path": "**/Japan/*" }, "actions": [{ "add_tags": ["trip"] }] },
+                { "name": "cities", "conditions": { "file_name": "^(ky\\w+)\\." }, "actions": ["add_captured_tags"] },
+                { "name": "no text", "conditions": { "extensions": ["txt"] }, "actions": ["skip"] }
+            ]"#,
+        )
+        .unwrap();
+        let options = ImportOptions {
+            exclude: vec!["**/.cache/**".to_owned()],
+            // Would read as a search term, so it is dropped
+            tags: HashSet::from(["color:red".to_owned()]),
+            rules,
+            ..Default::default()
+        };
+        let report = Importer::new(db.clone(), options)
+            .unwrap()
+            .import_folder(&root, |_progress| {})
+            .unwrap();
+
+        assert_eq!(report.count(&ImportStatus::Skipped(String::new())), 1);
+        let kyoto = report
+            .files
+            .iter()
+            .find(|file| file.path.ends_with("kyoto.jpg"))
+            .unwrap();
+        assert_eq!(
+            kyoto.tags,
+            BTreeSet::from([
+                "kyoto".to_owned(),
+                "system:ext:jpg".to_owned(),
+                "system:type:image".to_owned(),
+                "trip".to_owned()
+            ])
+        );
+        assert_eq!(kyoto.rules, vec!["trips: +trip", "cities: +kyoto"]);
+        assert_eq!(db.get_all_tags().unwrap().get("trip"), Some(&2));
+        assert!(!db.get_all_tags().unwrap().contains_key("color:red"));
+    }
+
+    #[test]
+    fn should_import_tagspaces_folder() {
+        let db = create_random_tagmaiddatabase();
+        let root = tempfile::tempdir().unwrap().keep();
+        fs::create_dir(root.join(".ts")).unwrap();
+        fs::write(root.join("beach[Summer sea].jpg"), "beach").unwrap();
+        fs::write(
+            root.join(".ts/beach[Summer sea].jpg.json"),
+            r#"{"tags": [{"title": "Holiday Trip", "type": "sidecar"}]}"#,
+        )
+        .unwrap();
+        let options = ImportOptions {
+            tagspaces: true,
+            ..Default::default()
+        };
+        let report = Importer::new(db.clone(), options)
+            .unwrap()
+            .import_folder(&root, |_progress| {})
+            .unwrap();
+
+        // The sidecar itself isn't imported
+        assert_eq!(report.files.len(), 1);
+        let results = db
+            .search(&Search::from_string("holiday_trip summer sea").unwrap())
+            .unwrap();
+        assert_eq!(results.len(), 1);
+    }
+
+    #[test]
+    fn should_read_download_sidecars() {
+        let db = create_random_tagmaiddatabase();
+        let root = tempfile::tempdir().unwrap().keep();
+        fs::write(root.join("wave.jpg"), "wave").unwrap();
+        fs::write(
+            root.join("wave.jpg.json"),
+            r#"{"category": "danbooru", "tags": ["ukiyo-e"], "post_url": "https://example.com/1"}"#,
+        )
+        .unwrap();
+        let options = ImportOptions {
+            download_sidecars: DownloadSidecarConfig {
+                enabled: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let report = Importer::new(db.clone(), options)
+            .unwrap()
+            .import_folder(&root, |_progress| {})
+            .unwrap();
+
+        // The sidecar itself isn't imported
+        assert_eq!(report.files.len(), 1);
+        let wave = &report.files[0];
+        assert!(wave.tags.contains("site:danbooru") && wave.tags.contains("ukiyo-e"));
+        assert_eq!(
+            db.get_source_metadata(&wave.hash).unwrap()["url"],
+            "https://example.com/1"
+        );
+    }
+}
@@ -0,0 +1,272 @@
+//! Exports the result of a search as a real folder, for programs that don't know about
+//! tags: each file shows up under its original name as a symlink, a hardlink or a copy of
+//! the stored file. Files can also be laid out in one sub-folder per tag, namespaces being
+//! nested folders (`artist:someone` goes to `artist/someone/`).
+//!
+//! The folder remembers its query in `.tagmaid-links.json`, along with what was exported,
+//! so that refreshing it after tags changed only adds and removes what is needed. Files
+//! TagMaid didn't put there are never touched.
+use crate::data::search_command::Search;
+use crate::data::{tag_file::TagFile, tag_util};
+use crate::database::tagmaid_database::TagMaidDatabase;
+use anyhow::{bail, Context, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST_NAME: &str = ".tagmaid-links.json";
+
+/// How the exported files point to the stored ones
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkMode {
+    #[default]
+    Symlink,
+    /// The folder has to be on the same drive as the library
+    Hardlink,
+    Copy,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layout {
+    /// Every file right in the folder
+    #[default]
+    Flat,
+    /// A sub-folder for each tag, with the files having it
+    ByTag,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LinkExport {
+    pub query: String,
+    pub mode: LinkMode,
+    pub layout: Layout,
+}
+
+/// What `.tagmaid-links.json` holds
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    #[serde(flatten)]
+    export: LinkExport,
+    /// Path relative to the folder -> hash of the file, in hexadecimal
+    entries: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default)]
+pub struct LinkReport {
+    pub created: usize,
+    pub removed: usize,
+    pub kept: usize,
+}
+
+impl LinkReport {
+    pub fn summary(&self) -> String {
+        format!(
+            "{} created, {} removed, {} unchanged",
+            self.created, self.removed, self.kept
+        )
+    }
+}
+
+/// The sub-folders `tagfile` goes to
+fn folders(tagfile: &TagFile, layout: Layout) -> Vec<PathBuf> {
+    match layout {
+        Layout::Flat => vec![PathBuf::new()],
+        Layout::ByTag => {
+            let tags: BTreeSet<&String> = tagfile
+                .get_tags()
+                .iter()
+                .filter(|tag| !tag_util::is_system_tag(tag))
+                .collect();
+            tags.iter().map(|tag| tag.split(':').collect()).collect()
+        }
+    }
+}
+
+/// `name`, or `name (2)`, `name (3)`... whichever isn't taken in `folder`
+fn free_name(folder: &Path, name: &str, taken: &HashSet<PathBuf>) -> PathBuf {
+    let name_path = Path::new(name);
+    let stem = name_path.file_stem().map_or_else(
+        || name.to_owned(),
+        |stem| stem.to_string_lossy().into_owned(),
+    );
+    let extension = name_path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|index| match index {
+            1 => folder.join(name),
+            _ => folder.join(format!("{stem} ({index}){extension}")),
+        })
+        .find(|path| !taken.contains(path) && std::fs::symlink_metadata(path).is_err())
+        .unwrap()
+}
+
+fn create_link(stored_path: &Path, path: &Path, mode: LinkMode) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let result = match mode {
+        #[cfg(unix)]
+        LinkMode::Symlink => std::os::unix::fs::symlink(stored_path, path),
+        #[cfg(windows)]
+        LinkMode::Symlink => std::os::windows::fs::symlink_file(stored_path, path),
+        LinkMode::Hardlink => std::fs::hard_link(stored_path, path),
+        LinkMode::Copy => std::fs::copy(stored_path, path).map(|_| ()),
+    };
+    result.with_context(|| format!("Couldn't create '{}'", path.display()))
+}
+
+/// Deletes `folder` and its parents up to `root` as long as they are empty
+fn remove_empty_folders(root: &Path, folder: &Path) {
+    let mut folder = folder.to_owned();
+    while folder != root && folder.starts_with(root) {
+        if std::fs::remove_dir(&folder).is_err() {
+            break;
+        }
+        folder.pop();
+    }
+}
+
+fn read_manifest(folder: &Path) -> Result<Option<Manifest>> {
+    let manifest_path = folder.join(MANIFEST_NAME);
+    if !manifest_path.is_file() {
+        return Ok(None);
+    }
+    let manifest = std::fs::read_to_string(&manifest_path)?;
+    serde_json::from_str(&manifest)
+        .map(Some)
+        .with_context(|| format!("Invalid '{}'", manifest_path.display()))
+}
+
+/// Exports the files matching `export.query` to `folder`. If the folder was already
+/// exported to, what isn't part of the results anymore is removed and the rest is kept.
+pub fn export(db: &TagMaidDatabase, folder: &Path, export: &LinkExport) -> Result<LinkReport> {
+    let search = Search::from_string(&export.query)?;
+    let (old_mode, old_entries) = match read_manifest(folder)? {
+        Some(manifest) => (Some(manifest.export.mode), manifest.entries),
+        None => (None, BTreeMap::new()),
+    };
+
+    // Where each result should be, by sub-folder and hash
+    let mut wanted: BTreeMap<(PathBuf, String), TagFile> = BTreeMap::new();
+    for hash in db.search(&search)? {
+        let tagfile = db.get_tagfile_from_hash(&hash)?;
+        let hash = tag_util::bytes_to_hex(&hash);
+        for sub_folder in folders(&tagfile, export.layout) {
+            wanted.insert((sub_folder, hash.clone()), tagfile.clone());
+        }
+    }
+
+    let mut report = LinkReport::default();
+    let mut entries: BTreeMap<String, String> = BTreeMap::new();
+    // An entry exported before is kept if it is still wanted where it is, if it is still
+    // there and if it was made the same way
+    let same_mode = old_mode == Some(export.mode);
+    for (relative_path, hash) in old_entries {
+        let path = folder.join(&relative_path);
+        let sub_folder = Path::new(&relative_path)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let exists = std::fs::symlink_metadata(&path).is_ok();
+        if exists && same_mode && wanted.remove(&(sub_folder, hash.clone())).is_some() {
+            entries.insert(relative_path, hash);
+            report.kept += 1;
+            continue;
+        }
+        if exists {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("Couldn't remove '{}'", path.display()))?;
+            report.removed += 1;
+        }
+        if let Some(parent) = path.parent() {
+            remove_empty_folders(folder, parent);
+        }
+    }
+
+    let mut taken: HashSet<PathBuf> = HashSet::new();
+    for ((sub_folder, hash), tagfile) in wanted {
+        let path = free_name(&folder.join(&sub_folder), tagfile.get_file_name(), &taken);
+        create_link(tagfile.get_path(), &path, export.mode)?;
+        let relative_path = path.strip_prefix(folder)?.to_string_lossy().into_owned();
+        entries.insert(relative_path, hash);
+        taken.insert(path);
+        report.created += 1;
+    }
+
+    std::fs::create_dir_all(folder)?;
+    let manifest = Manifest {
+        export: export.clone(),
+        entries,
+    };
+    std::fs::write(
+        folder.join(MANIFEST_NAME),
+        serde_json::to_string_pretty(&manifest)?,
+    )?;
+    info!(
+        "link_export - export() - {}: {}",
+        folder.display(),
+        report.summary()
+    );
+    Ok(report)
+}
+
+/// Exports again to `folder`, with the query and settings it was exported with
+pub fn refresh(db: &TagMaidDatabase, folder: &Path) -> Result<LinkReport> {
+    match read_manifest(folder)? {
+        Some(manifest) => export(db, folder, &manifest.export),
+        None => bail!("'{}' wasn't exported to by TagMaid", folder.display()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tag_database::test_util::add_file;
+    use crate::database::tag_database::TagDatabase;
+
+    #[test]
+    fn should_export_search_results_as_links() {
+        let folder = tempfile::tempdir().unwrap().keep();
+        let db = TagMaidDatabase::new(TagDatabase::initialise_at(folder.join("library")).unwrap());
+        add_file(&db, "logo.png", "a", &["client_x", "artist:ann"]);
+        add_file(&db, "logo.png", "b", &["client_x"]);
+        let mut draft = add_file(&db, "draft.png", "c", &["client_x", "draft"]);
+        add_file(&db, "other.png", "d", &["client_y"]);
+
+        let out = folder.join("out");
+        let export = LinkExport {
+            query: "client_x -draft".to_owned(),
+            mode: LinkMode::Copy,
+            layout: Layout::Flat,
+        };
+        let report = super::export(&db, &out, &export).unwrap();
+        assert_eq!(report.created, 2);
+        assert!(out.join("logo.png").is_file() && out.join("logo (2).png").is_file());
+        assert!(!out.join("draft.png").exists());
+
+        // The draft isn't one anymore
+        std::fs::write(out.join("notes.txt"), "mine").unwrap();
+        draft.tags.remove("draft");
+        db.update_tagfile(&draft).unwrap();
+        let report = refresh(&db, &out).unwrap();
+        assert_eq!((report.created, report.removed, report.kept), (1, 0, 2));
+        assert!(out.join("draft.png").is_file());
+
+        let by_tag = LinkExport {
+            layout: Layout::ByTag,
+            ..export
+        };
+        let report = super::export(&db, &out, &by_tag).unwrap();
+        assert_eq!((report.created, report.removed), (4, 3));
+        assert!(out.join("artist/ann/logo.png").is_file());
+        assert!(out.join("client_x/draft.png").is_file());
+        assert!(!out.join("logo.png").exists());
+        assert!(out.join("notes.txt").is_file());
+        assert!(refresh(&db, &folder.join("library")).is_err());
+    }
+}
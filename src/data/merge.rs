@@ -0,0 +1,272 @@
+//! Merges another library folder into this one, matching files by hash: files only the
+//! other library has are copied in (hardlinked when both are on the same drive), and
+//! files both have end up with the tags of both.
+//!
+//! Files removed from one of the libraries are reported rather than brought back or
+//! removed, using the `_removed` table of each library: a file removed here after it was
+//! added to the other library isn't copied again.
+use crate::data::{system_tags, tag_file::TagFile, tag_util};
+use crate::database::tag_database::TagDatabase;
+use crate::database::tagmaid_database::TagMaidDatabase;
+use anyhow::{bail, Result};
+use log::*;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConflictKind {
+    /// Removed from this library, but still in the other one
+    RemovedHere,
+    /// Removed from the other library, but still in this one
+    RemovedThere,
+    /// In the other library's database, but its file isn't in `files/` anymore
+    MissingFile(PathBuf),
+    /// The other library's file doesn't match its hash anymore
+    Modified(PathBuf),
+}
+
+/// A file the merge left alone
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeConflict {
+    /// In hexadecimal
+    pub hash: String,
+    pub file_name: String,
+    pub kind: ConflictKind,
+}
+
+impl fmt::Display for MergeConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match &self.kind {
+            ConflictKind::RemovedHere => "removed from this library, not copied again".to_owned(),
+            ConflictKind::RemovedThere => "removed from the other library, kept here".to_owned(),
+            ConflictKind::MissingFile(path) => format!("'{}' is missing", path.display()),
+            ConflictKind::Modified(path) => {
+                format!("'{}' doesn't match its hash anymore", path.display())
+            }
+        };
+        write!(f, "{} ({}): {reason}", self.file_name, self.hash)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MergeReport {
+    /// Files copied from the other library
+    pub added: usize,
+    /// Files both libraries have, which got new tags
+    pub updated: usize,
+    /// Files both libraries have with the same tags
+    pub unchanged: usize,
+    pub conflicts: Vec<MergeConflict>,
+}
+
+impl MergeReport {
+    pub fn summary(&self) -> String {
+        format!(
+            "{} added, {} updated, {} unchanged, {} conflicts",
+            self.added,
+            self.updated,
+            self.unchanged,
+            self.conflicts.len()
+        )
+    }
+}
+
+/// User tags of `tagfile` that are still valid tag names
+fn user_tags(tagfile: &TagFile) -> HashSet<String> {
+    tagfile
+        .get_tags()
+        .iter()
+        .filter(|tag| tag_util::is_tag_name_valid(tag) && !tag_util::is_system_tag(tag))
+        .cloned()
+        .collect()
+}
+
+/// Copies the file of `other_tagfile` into the library, with its tags, upload date and
+/// source metadata. Returns `false` if the file doesn't match its hash.
+fn copy_file(db: &TagMaidDatabase, other: &TagDatabase, other_tagfile: &TagFile) -> Result<bool> {
+    // Linked under its original name, which the library keeps. The temporary folder is in
+    // the library so that hardlinking works whenever it can.
+    let folder = tempfile::tempdir_in(db.get_library_path())?;
+    let file_name = Path::new(other_tagfile.get_file_name())
+        .file_name()
+        .map_or_else(|| "file".into(), |file_name| file_name.to_owned());
+    let path = folder.path().join(file_name);
+    if std::fs::hard_link(other_tagfile.get_path(), &path).is_err() {
+        std::fs::copy(other_tagfile.get_path(), &path)?;
+    }
+
+    let mut tagfile = TagFile::initialise_from_path(&path)?;
+    if tagfile.file_hash != other_tagfile.file_hash {
+        return Ok(false);
+    }
+    tagfile.tags = user_tags(other_tagfile);
+    system_tags::replace(&mut tagfile.tags, &path);
+    db.update_tagfile(&tagfile)?;
+    if let Ok(upload_date) = other.get_upload_date(&tagfile.file_hash) {
+        db.set_upload_date(&tagfile.file_hash, &upload_date)?;
+    }
+    db.set_source_metadata(
+        &tagfile.file_hash,
+        &other.get_source_metadata(&tagfile.file_hash)?,
+    )?;
+    Ok(true)
+}
+
+/// Merges the library at `other_path` into `db`. Nothing is written with `dry_run`, the
+/// report says what would happen. The other library is only read.
+pub fn merge(db: &TagMaidDatabase, other_path: &Path, dry_run: bool) -> Result<MergeReport> {
+    if !other_path.join("sqlite.db").is_file() {
+        bail!("'{}' isn't a TagMaid library", other_path.display());
+    }
+    let library_path = db.get_library_path();
+    if other_path.canonicalize()? == library_path.canonicalize()? {
+        bail!("Can't merge a library with itself");
+    }
+    let other = TagDatabase::initialise_at(other_path.to_owned())?;
+
+    let file_ids = db.get_file_ids()?;
+    let removed_here = db.get_removed_files()?;
+    let removed_there = other.get_removed_files()?;
+    let mut other_hashes: Vec<Vec<u8>> = other.get_all_file_hashes()?.into_iter().collect();
+    other_hashes.sort();
+
+    let mut report = MergeReport::default();
+    for hash in other_hashes.iter() {
+        let other_tagfile = other.get_tagfile_from_hash(hash)?;
+        let conflict = |kind| MergeConflict {
+            hash: tag_util::bytes_to_hex(hash),
+            file_name: other_tagfile.get_file_name().to_owned(),
+            kind,
+        };
+
+        if file_ids.contains_key(hash) {
+            let tagfile = db.get_tagfile_from_hash(hash)?;
+            let mut tags = tagfile.get_tags().clone();
+            tags.extend(user_tags(&other_tagfile));
+            if &tags == tagfile.get_tags() {
+                report.unchanged += 1;
+                continue;
+            }
+            if !dry_run {
+                db.update_tagfile(&TagFile { tags, ..tagfile })?;
+                // Only fills in what this library doesn't know about the file
+                let mut source = other.get_source_metadata(hash)?;
+                let library_source = db.get_source_metadata(hash)?;
+                source.retain(|key, _value| !library_source.contains_key(key));
+                db.set_source_metadata(hash, &source)?;
+            }
+            report.updated += 1;
+            continue;
+        }
+
+        // A file added to the other library after it was removed from this one is new
+        let removed = match (removed_here.get(hash), other.get_upload_date(hash)) {
+            (Some(removed_date), Ok(upload_date)) => upload_date <= *removed_date,
+            (Some(..), Err(..)) => true,
+            (None, _) => false,
+        };
+        if removed {
+            report.conflicts.push(conflict(ConflictKind::RemovedHere));
+        } else if !other_tagfile.get_path().is_file() {
+            let path = other_tagfile.get_path().clone();
+            report
+                .conflicts
+                .push(conflict(ConflictKind::MissingFile(path)));
+        } else if dry_run || copy_file(db, &other, &other_tagfile)? {
+            report.added += 1;
+        } else {
+            let path = other_tagfile.get_path().clone();
+            report
+                .conflicts
+                .push(conflict(ConflictKind::Modified(path)));
+        }
+    }
+
+    let other_hashes: HashSet<&Vec<u8>> = other_hashes.iter().collect();
+    let mut hashes: Vec<&Vec<u8>> = file_ids.keys().collect();
+    hashes.sort();
+    for hash in hashes {
+        let removed_date = match removed_there.get(hash) {
+            Some(removed_date) if !other_hashes.contains(hash) => removed_date,
+            _ => continue,
+        };
+        if db.get_upload_date(hash)? <= *removed_date {
+            report.conflicts.push(MergeConflict {
+                hash: tag_util::bytes_to_hex(hash),
+                file_name: db.get_tagfile_from_hash(hash)?.get_file_name().to_owned(),
+                kind: ConflictKind::RemovedThere,
+            });
+        }
+    }
+
+    info!(
+        "merge - merge() - {} from {}",
+        report.summary(),
+        other_path.display()
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tag_database::test_util::add_file;
+
+    fn remove_file(db: &TagMaidDatabase, hash: &Vec<u8>) {
+        let tagfile = db.get_tagfile_from_hash(hash).unwrap();
+        db.update_tagfile(&TagFile {
+            tags: HashSet::new(),
+            ..tagfile
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn should_merge_libraries() {
+        let folder = tempfile::tempdir().unwrap().keep();
+        let db = TagMaidDatabase::new(TagDatabase::initialise_at(folder.join("mine")).unwrap());
+        let other_path = folder.join("theirs");
+        let other_db =
+            TagMaidDatabase::new(TagDatabase::initialise_at(other_path.clone()).unwrap());
+
+        let cat = add_file(&db, "cat.png", "cat", &["cat"]).file_hash;
+        add_file(&other_db, "my cat.png", "cat", &["cat", "cute"]);
+        let dog = add_file(&other_db, "dog.png", "dog", &["dog"]).file_hash;
+        let bird = add_file(&db, "bird.png", "bird", &["bird"]).file_hash;
+        add_file(&other_db, "bird.png", "bird", &["bird"]);
+        remove_file(&db, &bird);
+        let fish = add_file(&other_db, "fish.png", "fish", &["fish"]).file_hash;
+        add_file(&db, "fish.png", "fish", &["fish"]);
+        remove_file(&other_db, &fish);
+
+        let report = merge(&db, &other_path, true).unwrap();
+        assert_eq!((report.added, report.updated), (1, 1));
+        assert!(db.get_tagfile_from_hash(&dog).is_err());
+
+        let report = merge(&db, &other_path, false).unwrap();
+        assert_eq!((report.added, report.updated), (1, 1));
+        let kinds: Vec<&ConflictKind> = report
+            .conflicts
+            .iter()
+            .map(|conflict| &conflict.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![&ConflictKind::RemovedHere, &ConflictKind::RemovedThere]
+        );
+        assert!(db
+            .get_tagfile_from_hash(&cat)
+            .unwrap()
+            .tags
+            .contains("cute"));
+        let dog_tagfile = db.get_tagfile_from_hash(&dog).unwrap();
+        assert_eq!(dog_tagfile.get_file_name(), "dog.png");
+        assert!(dog_tagfile.get_path().starts_with(folder.join("mine")));
+        assert!(db.get_tagfile_from_hash(&bird).is_err());
+
+        let report = merge(&db, &other_path, false).unwrap();
+        assert_eq!((report.added, report.updated, report.unchanged), (0, 0, 2));
+        assert!(merge(&db, &folder.join("mine"), false).is_err());
+    }
+}
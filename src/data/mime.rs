@@ -0,0 +1,72 @@
+//! Guessing MIME types from file extensions and the other way around, for the API server,
+//! auto-tagging rules, system tags and URL imports.
+use std::path::Path;
+
+/// Guesses a `Content-Type` from the file extension. Falls back on `application/octet-stream`.
+pub fn content_type_from_path(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "bmp" => "image/bmp",
+        "svg" => "image/svg+xml",
+        "tif" | "tiff" => "image/tiff",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mkv" => "video/x-matroska",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "flac" => "audio/flac",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain; charset=utf-8",
+        "json" => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The extension files of this content type usually have (the opposite of
+/// `content_type_from_path()`)
+pub fn extension_from_content_type(content_type: &str) -> Option<&'static str> {
+    let mime_type = content_type.split(';').next().unwrap_or_default().trim();
+    let extension = match mime_type.to_lowercase().as_str() {
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/bmp" => "bmp",
+        "image/svg+xml" => "svg",
+        "image/tiff" => "tiff",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        "video/x-matroska" => "mkv",
+        "audio/mpeg" => "mp3",
+        "audio/ogg" => "ogg",
+        "audio/flac" => "flac",
+        "application/pdf" => "pdf",
+        "text/plain" => "txt",
+        "application/json" => "json",
+        _ => return None,
+    };
+    Some(extension)
+}
+
+/// Whether `content_type` is `wanted`, which can be a whole type like `image/*`.
+/// Parameters (`; charset=utf-8`) are ignored.
+pub fn content_type_matches(content_type: &str, wanted: &str) -> bool {
+    let mime_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    match wanted.strip_suffix("/*") {
+        Some(wanted_type) => mime_type.split('/').next() == Some(wanted_type),
+        None => mime_type == wanted,
+    }
+}
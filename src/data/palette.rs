@@ -0,0 +1,343 @@
+//! Colour palettes, to search images by colour. Each image gets a few dominant colours
+//! along with how much of the picture they cover, extracted when it is added to the
+//! library. `PaletteFiller` fills in the palettes of files added before.
+//!
+//! Searches use `color:red` for a colour name, or `color:#ff8800` for a precise colour,
+//! optionally with a tolerance (`color:#ff8800~30`). A file matches if a colour covering
+//! at least `MIN_SHARE` percent of it is close enough.
+use crate::data::ui_util;
+use crate::database::tagmaid_database::TagMaidDatabase;
+use anyhow::{bail, Result};
+use image::DynamicImage;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// How many colours a palette has at most
+pub const PALETTE_SIZE: usize = 5;
+/// Colours covering less of the image than this (in percent) don't count for searches
+pub const MIN_SHARE: u8 = 10;
+/// Tolerance of `color:#rrggbb`, as a distance between RGB colours (0 to 441)
+pub const DEFAULT_TOLERANCE: u32 = 40;
+/// Close colours are merged into one while extracting the palette
+const MERGE_DISTANCE: u32 = 48;
+
+/// The names `color:<name>` understands
+pub const COLOR_NAMES: [&str; 12] = [
+    "red", "orange", "yellow", "green", "cyan", "blue", "purple", "pink", "brown", "black",
+    "white", "gray",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PaletteColor {
+    pub rgb: [u8; 3],
+    /// How much of the image has this colour, in percent
+    pub share: u8,
+}
+
+impl PaletteColor {
+    /// `#rrggbb`
+    pub fn hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.rgb[0], self.rgb[1], self.rgb[2])
+    }
+}
+
+/// Most present colour first
+pub type Palette = Vec<PaletteColor>;
+
+fn rgb_distance(a: [u8; 3], b: [u8; 3]) -> u32 {
+    let square = |channel: usize| (a[channel] as i32 - b[channel] as i32).pow(2);
+    ((square(0) + square(1) + square(2)) as f64).sqrt() as u32
+}
+
+/// The closest name of `COLOR_NAMES` for `rgb`
+pub fn color_name(rgb: [u8; 3]) -> &'static str {
+    let [r, g, b] = rgb.map(|channel| channel as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let lightness = (max + min) / 2.0;
+    let chroma = max - min;
+    let saturation = if chroma == 0.0 {
+        0.0
+    } else {
+        chroma / (1.0 - (2.0 * lightness - 1.0).abs())
+    };
+    if lightness < 0.12 {
+        return "black";
+    }
+    if lightness > 0.92 {
+        return "white";
+    }
+    if saturation < 0.15 {
+        return "gray";
+    }
+    let hue = if max == r {
+        60.0 * ((g - b) / chroma).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / chroma + 2.0)
+    } else {
+        60.0 * ((r - g) / chroma + 4.0)
+    };
+    match hue {
+        hue if (15.0..50.0).contains(&hue) && lightness < 0.4 => "brown",
+        hue if !(15.0..345.0).contains(&hue) => "red",
+        hue if hue < 45.0 => "orange",
+        hue if hue < 70.0 => "yellow",
+        hue if hue < 165.0 => "green",
+        hue if hue < 195.0 => "cyan",
+        hue if hue < 255.0 => "blue",
+        hue if hue < 290.0 => "purple",
+        _ => "pink",
+    }
+}
+
+/// The dominant colours of `image`. Transparent pixels are left out.
+pub fn extract(image: &DynamicImage) -> Palette {
+    let small = image.thumbnail(64, 64).to_rgba8();
+    // Pixels are first put in 16x16x16 boxes, then boxes with close average colours are
+    // merged, biggest first
+    let mut boxes: Vec<[u64; 4]> = vec![[0; 4]; 16 * 16 * 16];
+    let mut pixel_count = 0u64;
+    for pixel in small.pixels() {
+        let [r, g, b, a] = pixel.0;
+        if a < 128 {
+            continue;
+        }
+        let index = (r as usize >> 4) * 256 + (g as usize >> 4) * 16 + (b as usize >> 4);
+        let colour_box = &mut boxes[index];
+        colour_box[0] += r as u64;
+        colour_box[1] += g as u64;
+        colour_box[2] += b as u64;
+        colour_box[3] += 1;
+        pixel_count += 1;
+    }
+    if pixel_count == 0 {
+        return Palette::new();
+    }
+    boxes.retain(|colour_box| colour_box[3] > 0);
+    boxes.sort_by_key(|colour_box| std::cmp::Reverse(colour_box[3]));
+
+    // Sums of the channels and pixel count of each colour
+    let mut clusters: Vec<[u64; 4]> = Vec::new();
+    let average = |sums: &[u64; 4]| [0, 1, 2].map(|channel| (sums[channel] / sums[3]) as u8);
+    for colour_box in boxes {
+        let colour = average(&colour_box);
+        let close_cluster = clusters
+            .iter_mut()
+            .find(|cluster| rgb_distance(average(cluster), colour) <= MERGE_DISTANCE);
+        match close_cluster {
+            Some(cluster) => {
+                for (sum, box_sum) in cluster.iter_mut().zip(colour_box) {
+                    *sum += box_sum;
+                }
+            }
+            None => clusters.push(colour_box),
+        }
+    }
+    clusters.sort_by_key(|cluster| std::cmp::Reverse(cluster[3]));
+    clusters
+        .iter()
+        .map(|cluster| PaletteColor {
+            rgb: average(cluster),
+            share: (cluster[3] * 100 / pixel_count) as u8,
+        })
+        // Edges between colours and specks aren't worth keeping
+        .filter(|colour| colour.share >= 2)
+        .take(PALETTE_SIZE)
+        .collect()
+}
+
+/// The palette of the image at `path`, `None` if it isn't an image that can be read
+pub fn from_path(path: &Path) -> Option<Palette> {
+    ui_util::open_image(path).map(|image| extract(&image))
+}
+
+/// What `color:` looks for
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ColorQuery {
+    /// One of `COLOR_NAMES`
+    Named(String),
+    Rgb {
+        rgb: [u8; 3],
+        tolerance: u32,
+    },
+}
+
+impl ColorQuery {
+    /// Parses what follows `color:`: a name, or `#rrggbb` with an optional `~tolerance`
+    pub fn parse(text: &str) -> Result<ColorQuery> {
+        let text = text.to_lowercase();
+        let name = match text.as_str() {
+            "grey" => "gray",
+            name => name,
+        };
+        if COLOR_NAMES.contains(&name) {
+            return Ok(ColorQuery::Named(name.to_owned()));
+        }
+        let (colour, tolerance) = match text.split_once('~') {
+            Some((colour, tolerance)) => match tolerance.parse() {
+                Ok(tolerance) => (colour, tolerance),
+                Err(_) => bail!("\"{tolerance}\" isn't a colour tolerance"),
+            },
+            None => (text.as_str(), DEFAULT_TOLERANCE),
+        };
+        let hex = colour.strip_prefix('#').unwrap_or(colour);
+        match hex::decode(hex) {
+            Ok(bytes) if bytes.len() == 3 => Ok(ColorQuery::Rgb {
+                rgb: [bytes[0], bytes[1], bytes[2]],
+                tolerance,
+            }),
+            _ => bail!(
+                "\"{text}\" isn't a colour, use a name ({}) or #rrggbb",
+                COLOR_NAMES.join(", ")
+            ),
+        }
+    }
+
+    /// Whether one of the colours of `palette` that cover enough of the image matches
+    pub fn matches(&self, palette: &[PaletteColor]) -> bool {
+        palette
+            .iter()
+            .filter(|colour| colour.share >= MIN_SHARE)
+            .any(|colour| match self {
+                ColorQuery::Named(name) => color_name(colour.rgb) == name,
+                ColorQuery::Rgb { rgb, tolerance } => rgb_distance(colour.rgb, *rgb) <= *tolerance,
+            })
+    }
+}
+
+/// Extracts the palettes of the files that don't have one yet. Returns how many were
+/// extracted. Stops early when `stop` is set.
+pub fn fill_missing(db: &TagMaidDatabase, stop: &AtomicBool) -> Result<usize> {
+    let palettes = db.get_palettes()?;
+    let mut filled = 0;
+    for hash in db.get_file_ids()?.into_keys() {
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        if palettes.contains_key(&hash) {
+            continue;
+        }
+        let tagfile = db.get_tagfile_from_hash(&hash)?;
+        db.set_palette(&hash, from_path(tagfile.get_path()).as_ref())?;
+        filled += 1;
+    }
+    Ok(filled)
+}
+
+/// Extracts the palettes of files added before palettes existed, in the background, for
+/// as long as it is kept around
+pub struct PaletteFiller {
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for PaletteFiller {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl PaletteFiller {
+    pub fn start(db: TagMaidDatabase) -> PaletteFiller {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        std::thread::spawn(move || match fill_missing(&db, &thread_stop) {
+            Ok(filled) => info!("PaletteFiller - Extracted {filled} palettes"),
+            Err(err) => info!("WARNING: PaletteFiller: Couldn't extract palettes: {err:#}"),
+        });
+        PaletteFiller { stop }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::search_command::Search;
+    use crate::data::tag_file::TagFile;
+    use crate::database::tag_database::TagDatabase;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn should_name_colours() {
+        assert_eq!(color_name([230, 20, 30]), "red");
+        assert_eq!(color_name([255, 136, 0]), "orange");
+        assert_eq!(color_name([250, 230, 40]), "yellow");
+        assert_eq!(color_name([30, 160, 50]), "green");
+        assert_eq!(color_name([20, 60, 220]), "blue");
+        assert_eq!(color_name([120, 30, 200]), "purple");
+        assert_eq!(color_name([240, 100, 180]), "pink");
+        assert_eq!(color_name([110, 60, 20]), "brown");
+        assert_eq!(color_name([10, 10, 10]), "black");
+        assert_eq!(color_name([250, 250, 250]), "white");
+        assert_eq!(color_name([128, 128, 128]), "gray");
+
+        assert_eq!(
+            ColorQuery::parse("Grey").unwrap(),
+            ColorQuery::Named("gray".to_owned())
+        );
+        assert_eq!(
+            ColorQuery::parse("#ff8800~30").unwrap(),
+            ColorQuery::Rgb {
+                rgb: [255, 136, 0],
+                tolerance: 30
+            }
+        );
+        assert!(ColorQuery::parse("#ff88").is_err());
+        assert!(ColorQuery::parse("#ff8800~far").is_err());
+        assert!(ColorQuery::parse("teal").is_err());
+    }
+
+    #[test]
+    fn should_search_by_colour() {
+        let folder = tempfile::tempdir().unwrap().keep();
+        let db = TagMaidDatabase::new(TagDatabase::initialise_at(folder.join("library")).unwrap());
+
+        // 70% orange, 30% dark blue with a bit of noise
+        let image = RgbImage::from_fn(100, 100, |x, y| match x < 70 {
+            true => Rgb([250, 130 + (y % 8) as u8, 0]),
+            false => Rgb([10, 20, 120]),
+        });
+        let path = folder.join("sunset.png");
+        image.save(&path).unwrap();
+        let mut tagfile = TagFile::initialise_from_path(&path).unwrap();
+        tagfile.tags.insert("sunset".to_owned());
+        db.update_tagfile(&tagfile).unwrap();
+
+        let palette = db.get_palette(&tagfile.file_hash).unwrap().unwrap();
+        assert_eq!(palette.len(), 2);
+        assert_eq!(color_name(palette[0].rgb), "orange");
+        assert!((68..=70).contains(&palette[0].share));
+        assert_eq!(palette[1].hex(), "#0a1478");
+
+        let search = |query: &str| db.search(&Search::from_string(query).unwrap()).unwrap();
+        let found = vec![tagfile.file_hash.clone()];
+        assert_eq!(search("color:orange"), found);
+        assert_eq!(search("sunset color:blue"), found);
+        assert_eq!(search("color:#ff8800"), found);
+        assert!(search("color:#ff8800~2").is_empty());
+        assert!(search("color:green").is_empty());
+        assert_eq!(search("sunset -color:green"), found);
+
+        // A new palette only drops the cached searches by colour
+        assert_eq!(search("sunset"), found);
+        db.set_palette(&tagfile.file_hash, Some(&palette)).unwrap();
+        let cached = |query: &str| {
+            db.get_cache()
+                .get_search(&Search::from_string(query).unwrap())
+                .is_some()
+        };
+        assert!(cached("sunset") && !cached("sunset -color:green"));
+
+        // A library from before palettes
+        let notes = folder.join("notes.txt");
+        std::fs::write(&notes, "notes").unwrap();
+        let mut tagfile = TagFile::initialise_from_path(&notes).unwrap();
+        tagfile.tags.insert("text".to_owned());
+        db.update_tagfile(&tagfile).unwrap();
+        let stop = AtomicBool::new(false);
+        assert_eq!(fill_missing(&db, &stop).unwrap(), 0);
+        assert_eq!(db.get_palette(&tagfile.file_hash).unwrap(), None);
+    }
+}
@@ -0,0 +1,529 @@
+//! Perceptual hashes, to find images that look the same without being byte-identical
+//! (resized, re-encoded, slightly cropped...). The blake3 `TagFile::file_hash` only catches
+//! exact copies.
+//!
+//! The hash is a dHash: the image is shrunk to 9x8 grey pixels, and each bit says whether
+//! a pixel is brighter than its right neighbour. Similar images have hashes that differ by
+//! a few bits, so the Hamming distance between two hashes tells how alike they look.
+//! Hashes are computed when files are added to the library, `HashFiller` fills in the ones
+//! of files added before in the background.
+//!
+//! `similar:<file hash>` searches go through a `BkTree` of the hashes, which only looks at
+//! the part of the library that can be close enough instead of every image.
+use crate::data::{tag_file::TagFile, tag_util, ui_util};
+use crate::database::tagmaid_database::TagMaidDatabase;
+use anyhow::{bail, Result};
+use image::imageops::FilterType;
+use image::DynamicImage;
+use log::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Up to this many different bits, two images are considered the same
+pub const DEFAULT_DISTANCE: u32 = 6;
+
+/// How many bits `similar:` lets hashes differ by. Looser than duplicates: images that
+/// are alike without being the same picture.
+pub const SIMILAR_DISTANCE: u32 = 16;
+
+/// File hashes of images that look the same
+pub type DuplicateGroup = Vec<Vec<u8>>;
+
+pub fn dhash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | brighter as u64;
+        }
+    }
+    hash
+}
+
+/// The hash of the image at `path`, `None` if it isn't an image that can be read
+pub fn from_path(path: &Path) -> Option<u64> {
+    ui_util::open_image(path).map(|image| dhash(&image))
+}
+
+/// How many bits differ between two hashes
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Computes the hashes of the files that don't have one yet. Returns how many were computed.
+/// Stops early when `stop` is set.
+pub fn compute_missing(db: &TagMaidDatabase, stop: &AtomicBool) -> Result<usize> {
+    let phashes = db.get_perceptual_hashes()?;
+    let mut computed = 0;
+    for hash in db.get_file_ids()?.into_keys() {
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        if phashes.contains_key(&hash) {
+            continue;
+        }
+        let tagfile = db.get_tagfile_from_hash(&hash)?;
+        db.set_perceptual_hash(&hash, from_path(tagfile.get_path()))?;
+        computed += 1;
+    }
+    info!("perceptual_hash - compute_missing() - Computed {computed} hashes");
+    Ok(computed)
+}
+
+/// Computes the hashes of files added before perceptual hashes existed, in the background,
+/// for as long as it is kept around
+pub struct HashFiller {
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for HashFiller {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl HashFiller {
+    pub fn start(db: TagMaidDatabase) -> HashFiller {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = compute_missing(&db, &thread_stop) {
+                info!("WARNING: HashFiller: Couldn't compute perceptual hashes: {err:#}");
+            }
+        });
+        HashFiller { stop }
+    }
+}
+
+/// A BK-tree: each node's children are keyed by their distance to it, so that by the
+/// triangle inequality a search within `max_distance` of a hash only has to go down the
+/// children whose key is within `max_distance` of the node's own distance.
+#[derive(Debug, Default, Clone)]
+pub struct BkTree {
+    nodes: Vec<BkNode>,
+    /// File hash -> perceptual hash, for the files in the tree
+    phashes: HashMap<Vec<u8>, u64>,
+}
+
+#[derive(Debug, Clone)]
+struct BkNode {
+    phash: u64,
+    /// Files with exactly this perceptual hash
+    files: Vec<Vec<u8>>,
+    children: BTreeMap<u32, usize>,
+}
+
+impl BkTree {
+    pub fn new(phashes: impl IntoIterator<Item = (Vec<u8>, u64)>) -> BkTree {
+        let mut tree = BkTree::default();
+        for (hash, phash) in phashes {
+            tree.insert(hash, phash);
+        }
+        tree
+    }
+
+    pub fn insert(&mut self, hash: Vec<u8>, phash: u64) {
+        self.phashes.insert(hash.clone(), phash);
+        if self.nodes.is_empty() {
+            self.nodes.push(BkNode {
+                phash,
+                files: vec![hash],
+                children: BTreeMap::new(),
+            });
+            return;
+        }
+        let mut index = 0;
+        loop {
+            let node_distance = distance(self.nodes[index].phash, phash);
+            if node_distance == 0 {
+                self.nodes[index].files.push(hash);
+                return;
+            }
+            match self.nodes[index].children.get(&node_distance) {
+                Some(child) => index = *child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(BkNode {
+                        phash,
+                        files: vec![hash],
+                        children: BTreeMap::new(),
+                    });
+                    self.nodes[index].children.insert(node_distance, child);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Takes the file with hash `hash` out of the tree. Its node stays, without files, to
+    /// keep the paths to its children.
+    pub fn remove(&mut self, hash: &Vec<u8>) {
+        let phash = match self.phashes.remove(hash) {
+            Some(phash) => phash,
+            None => return,
+        };
+        let mut index = 0;
+        loop {
+            let node_distance = distance(self.nodes[index].phash, phash);
+            if node_distance == 0 {
+                self.nodes[index].files.retain(|other| other != hash);
+                return;
+            }
+            match self.nodes[index].children.get(&node_distance) {
+                Some(child) => index = *child,
+                None => return,
+            }
+        }
+    }
+
+    /// The perceptual hash of the file with hash `hash`, if it is in the tree
+    pub fn get(&self, hash: &Vec<u8>) -> Option<u64> {
+        self.phashes.get(hash).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.phashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.phashes.is_empty()
+    }
+
+    /// Files whose hashes are at most `max_distance` away from `phash`, closest first
+    pub fn find(&self, phash: u64, max_distance: u32) -> Vec<(Vec<u8>, u32)> {
+        let mut found = Vec::new();
+        let mut to_visit = match self.nodes.is_empty() {
+            true => vec![],
+            false => vec![0],
+        };
+        while let Some(index) = to_visit.pop() {
+            let node = &self.nodes[index];
+            let node_distance = distance(node.phash, phash);
+            if node_distance <= max_distance {
+                found.extend(node.files.iter().map(|hash| (hash.clone(), node_distance)));
+            }
+            let range = node_distance.saturating_sub(max_distance)..=node_distance + max_distance;
+            to_visit.extend(node.children.range(range).map(|(_distance, child)| *child));
+        }
+        found.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+        found
+    }
+}
+
+/// The `BkTree` of the library, built from the database the first time and then cached
+/// (and kept up to date as files are added or removed). Files whose hash wasn't computed
+/// yet are left out.
+pub fn similarity_index(db: &TagMaidDatabase) -> Result<Arc<BkTree>> {
+    if let Some(index) = db.get_cache().get_similarity_index() {
+        return Ok(index);
+    }
+    let phashes = db
+        .get_perceptual_hashes()?
+        .into_iter()
+        .filter_map(|(hash, phash)| Some((hash, phash?)));
+    let index = Arc::new(BkTree::new(phashes));
+    info!(
+        "perceptual_hash - similarity_index() - Indexed {} images",
+        index.len()
+    );
+    if let Err(err) = db.get_cache().cache_similarity_index(index.clone()) {
+        info!("WARNING: similarity_index(): Couldn't cache the index: {err}");
+    }
+    Ok(index)
+}
+
+/// Images of the library that look like the file with hash `hash`, closest first, along
+/// with how many bits their hashes differ by. The file itself comes first.
+pub fn find_similar(
+    db: &TagMaidDatabase,
+    hash: &Vec<u8>,
+    max_distance: u32,
+) -> Result<Vec<(Vec<u8>, u32)>> {
+    let index = similarity_index(db)?;
+    let phash = match index.get(hash) {
+        Some(phash) => Some(phash),
+        // Only this one file is hashed here if `HashFiller` didn't get to it yet
+        None if !db.get_perceptual_hashes()?.contains_key(hash) => {
+            let phash = from_path(db.get_tagfile_from_hash(hash)?.get_path());
+            db.set_perceptual_hash(hash, phash)?;
+            phash
+        }
+        None => None,
+    };
+    match phash {
+        Some(phash) => Ok(similarity_index(db)?.find(phash, max_distance)),
+        None => bail!(
+            "{} isn't an image of the library",
+            tag_util::bytes_to_hex(hash)
+        ),
+    }
+}
+
+/// Groups the files of `index` whose hashes are at most `max_distance` apart, directly or
+/// through other files of the group. Groups and their files are sorted by file hash.
+pub fn group(index: &BkTree, max_distance: u32) -> Vec<DuplicateGroup> {
+    let phashes: Vec<(&Vec<u8>, u64)> = index
+        .phashes
+        .iter()
+        .map(|(hash, phash)| (hash, *phash))
+        .collect();
+    let positions: HashMap<&Vec<u8>, usize> = phashes
+        .iter()
+        .enumerate()
+        .map(|(position, (hash, _phash))| (*hash, position))
+        .collect();
+    // Union-find over the files
+    let mut parents: Vec<usize> = (0..phashes.len()).collect();
+    fn root(parents: &mut [usize], mut index: usize) -> usize {
+        while parents[index] != index {
+            parents[index] = parents[parents[index]];
+            index = parents[index];
+        }
+        index
+    }
+    for (a, (_hash, phash)) in phashes.iter().enumerate() {
+        for (similar, _distance) in index.find(*phash, max_distance) {
+            let b = positions[&similar];
+            let (root_a, root_b) = (root(&mut parents, a), root(&mut parents, b));
+            parents[root_b] = root_a;
+        }
+    }
+
+    let mut groups: BTreeMap<usize, DuplicateGroup> = BTreeMap::new();
+    for (position, (hash, _phash)) in phashes.iter().enumerate() {
+        let group_root = root(&mut parents, position);
+        groups.entry(group_root).or_default().push((*hash).clone());
+    }
+    let mut groups: Vec<DuplicateGroup> = groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .map(|mut group| {
+            group.sort();
+            group
+        })
+        .collect();
+    groups.sort();
+    groups
+}
+
+/// Groups of images of the library that look the same (see `group()`). Hashes that are
+/// missing are computed first.
+pub fn find_duplicates(db: &TagMaidDatabase, max_distance: u32) -> Result<Vec<DuplicateGroup>> {
+    compute_missing(db, &AtomicBool::new(false))?;
+    let index = similarity_index(db)?;
+    Ok(group(&index, max_distance))
+}
+
+/// Keeps the file with hash `keep` and removes the `others` from the library. The kept
+/// file gets the tags of all of them, and the source metadata it doesn't have yet.
+pub fn merge_duplicates(db: &TagMaidDatabase, keep: &Vec<u8>, others: &[Vec<u8>]) -> Result<()> {
+    if others.contains(keep) {
+        bail!("Can't merge a file with itself");
+    }
+    let kept_tagfile = db.get_tagfile_from_hash(keep)?;
+    let mut tags: HashSet<String> = kept_tagfile.get_tags().clone();
+    let mut source = db.get_source_metadata(keep)?;
+    let mut other_tagfiles: Vec<TagFile> = Vec::new();
+    for other in others {
+        let other_tagfile = db.get_tagfile_from_hash(other)?;
+        tags.extend(
+            other_tagfile
+                .get_tags()
+                .iter()
+                .filter(|tag| !tag_util::is_system_tag(tag))
+                .cloned(),
+        );
+        for (key, value) in db.get_source_metadata(other)? {
+            source.entry(key).or_insert(value);
+        }
+        other_tagfiles.push(other_tagfile);
+    }
+
+    db.update_tagfile(&TagFile {
+        tags,
+        ..kept_tagfile
+    })?;
+    db.set_source_metadata(keep, &source)?;
+    for other_tagfile in other_tagfiles {
+        // Files without tags are removed from the library
+        db.update_tagfile(&TagFile {
+            tags: HashSet::new(),
+            ..other_tagfile
+        })?;
+    }
+    info!(
+        "perceptual_hash - merge_duplicates() - Merged {} files into {}",
+        others.len(),
+        tag_util::bytes_to_hex(keep)
+    );
+    Ok(())
+}
+
+/// Width and height of the images of `hashes`, to help choosing which one to keep
+pub fn dimensions(db: &TagMaidDatabase, hashes: &[Vec<u8>]) -> HashMap<Vec<u8>, (u32, u32)> {
+    hashes
+        .iter()
+        .filter_map(|hash| {
+            let tagfile = db.get_tagfile_from_hash(hash).ok()?;
+            let dimensions = image::image_dimensions(tagfile.get_path()).ok()?;
+            Some((hash.clone(), dimensions))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::search_command::Search;
+    use crate::database::tag_database::TagDatabase;
+    use image::{Rgb, RgbImage};
+
+    /// A gradient with a dark square, which is what the hash picks up. `flipped` makes it
+    /// go the other way, so that it looks nothing alike.
+    fn test_image(width: u32, height: u32, flipped: bool) -> DynamicImage {
+        let mut image = RgbImage::from_fn(width, height, |x, _y| {
+            let value = (x * 255 / width) as u8;
+            Rgb([value, value, value])
+        });
+        for x in (width / 10)..(width * 4 / 10) {
+            for y in (height / 4)..(height / 2) {
+                image.put_pixel(x, y, Rgb([0, 0, 0]));
+            }
+        }
+        let image = DynamicImage::ImageRgb8(image);
+        match flipped {
+            true => image.fliph(),
+            false => image,
+        }
+    }
+
+    #[test]
+    fn should_find_near_duplicates() {
+        let folder = tempfile::tempdir().unwrap().keep();
+        let db = TagMaidDatabase::new(TagDatabase::initialise_at(folder.join("library")).unwrap());
+        let mut hashes = Vec::new();
+        for (name, image, tags) in [
+            ("big.png", test_image(800, 600, false), vec!["cat", "big"]),
+            (
+                "small.jpg",
+                test_image(200, 150, false),
+                vec!["cat", "small"],
+            ),
+            ("other.png", test_image(800, 600, true), vec!["dog"]),
+        ] {
+            let path = folder.join(name);
+            image.save(&path).unwrap();
+            let mut tagfile = TagFile::initialise_from_path(&path).unwrap();
+            tagfile.tags = tags.into_iter().map(String::from).collect();
+            db.update_tagfile(&tagfile).unwrap();
+            hashes.push(tagfile.file_hash);
+        }
+        let notes = folder.join("notes.txt");
+        std::fs::write(&notes, "notes").unwrap();
+        let mut tagfile = TagFile::initialise_from_path(&notes).unwrap();
+        tagfile.tags.insert("text".to_owned());
+        db.update_tagfile(&tagfile).unwrap();
+
+        let phashes = db.get_perceptual_hashes().unwrap();
+        assert_eq!(phashes[&tagfile.file_hash], None);
+        let (big, small) = (phashes[&hashes[0]].unwrap(), phashes[&hashes[1]].unwrap());
+        assert!(distance(big, small) <= DEFAULT_DISTANCE);
+        assert!(distance(big, phashes[&hashes[2]].unwrap()) > DEFAULT_DISTANCE);
+
+        let mut expected = vec![hashes[0].clone(), hashes[1].clone()];
+        expected.sort();
+        assert_eq!(
+            find_duplicates(&db, DEFAULT_DISTANCE).unwrap(),
+            vec![expected]
+        );
+        assert_eq!(dimensions(&db, &hashes[..1])[&hashes[0]], (800, 600));
+
+        // The closest image comes first, tags narrow it down
+        let similar_to_small = format!("similar:{}", tag_util::bytes_to_hex(&hashes[1]));
+        let search = |query: &str| db.search(&Search::from_string(query).unwrap());
+        assert_eq!(
+            search(&similar_to_small).unwrap(),
+            vec![hashes[1].clone(), hashes[0].clone()]
+        );
+        assert_eq!(
+            search(&format!("big {similar_to_small}")).unwrap(),
+            vec![hashes[0].clone()]
+        );
+        let not_an_image = format!("similar:{}", tag_util::bytes_to_hex(&tagfile.file_hash));
+        assert!(search(&not_an_image).is_err());
+
+        merge_duplicates(&db, &hashes[0], &hashes[1..2]).unwrap();
+        let tags = db.get_tagfile_from_hash(&hashes[0]).unwrap().tags;
+        assert!(["cat", "big", "small"]
+            .iter()
+            .all(|tag| tags.contains(*tag)));
+        assert!(db.get_tagfile_from_hash(&hashes[1]).is_err());
+        assert!(find_duplicates(&db, DEFAULT_DISTANCE).unwrap().is_empty());
+
+        // The cached index follows files being removed and added
+        let copy_path = folder.join("copy.png");
+        test_image(400, 300, false).save(&copy_path).unwrap();
+        let mut copy = TagFile::initialise_from_path(&copy_path).unwrap();
+        copy.tags.insert("cat".to_owned());
+        db.update_tagfile(&copy).unwrap();
+        let similar_to_big = format!("similar:{}", tag_util::bytes_to_hex(&hashes[0]));
+        let mut found = search(&similar_to_big).unwrap();
+        found.sort();
+        let mut expected = vec![hashes[0].clone(), copy.file_hash];
+        expected.sort();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn should_find_in_bk_tree_like_brute_force() {
+        // A made up library, with a few files sharing a hash
+        let mut state = 0x2545f4914f6cdd1du64;
+        let phashes: Vec<(Vec<u8>, u64)> = (0..500u32)
+            .map(|index| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let phash = match index % 50 {
+                    0 => 0,
+                    _ => state,
+                };
+                (index.to_be_bytes().to_vec(), phash)
+            })
+            .collect();
+        let mut tree = BkTree::new(phashes.clone());
+        // Removed files don't come up anymore, including the root and ones sharing a hash
+        let (removed, phashes) = phashes.split_at(10);
+        for (hash, _phash) in removed.iter() {
+            tree.remove(hash);
+        }
+        assert_eq!(tree.len(), 490);
+        assert_eq!(tree.get(&removed[0].0), None);
+        for (hash, phash) in phashes.iter().take(20) {
+            assert_eq!(tree.get(hash), Some(*phash));
+            let mut expected: Vec<(Vec<u8>, u32)> = phashes
+                .iter()
+                .map(|(other, other_phash)| (other.clone(), distance(*phash, *other_phash)))
+                .filter(|(_other, distance)| *distance <= SIMILAR_DISTANCE + 10)
+                .collect();
+            expected.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+            assert_eq!(tree.find(*phash, SIMILAR_DISTANCE + 10), expected);
+        }
+        assert!(BkTree::default().find(0, 64).is_empty());
+    }
+
+    #[test]
+    fn should_group_transitively() {
+        let phashes = vec![
+            (vec![1], 0b0000),
+            (vec![2], 0b0011),
+            (vec![3], 0b1111),
+            (vec![4], u64::MAX),
+        ];
+        let index = BkTree::new(phashes);
+        assert_eq!(group(&index, 2), vec![vec![vec![1], vec![2], vec![3]]]);
+        assert!(group(&index, 1).is_empty());
+    }
+}
@@ -0,0 +1,323 @@
+//! Rules that tag (or skip) files automatically when they are imported. They are set in
+//! `Config` and applied by `Importer`, so they run in every import path.
+//!
+//! A rule matches when all of its conditions match, and then runs all of its actions:
+//! ```json
+//! {
+//!     "name": "artist folders",
+//!     "conditions": { "source_path": "**/Art/**", "file_name": "^(\\w+) - ", "extensions": ["png", "jpg"] },
+//!     "actions": [{ "add_tags": ["art"] }, "add_captured_tags"]
+//! }
+//! ```
+use crate::data::mime::{content_type_from_path, content_type_matches};
+use crate::data::tag_util;
+use anyhow::{Context, Result};
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rule {
+    /// Used to tell what each rule did in dry runs
+    pub name: String,
+    #[serde(default)]
+    pub conditions: RuleConditions,
+    pub actions: Vec<RuleAction>,
+}
+
+/// Conditions that are left out always match
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RuleConditions {
+    /// Regex matched against the file name
+    pub file_name: Option<String>,
+    /// File extensions, without the dot (case doesn't matter)
+    pub extensions: Option<Vec<String>>,
+    /// MIME type guessed from the extension, like `image/png` or `image/*`
+    pub mime_type: Option<String>,
+    /// Minimum file size in bytes
+    pub min_size: Option<u64>,
+    /// Maximum file size in bytes
+    pub max_size: Option<u64>,
+    /// Glob matched against the full path the file is imported from
+    pub source_path: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    AddTags(Vec<String>),
+    /// Turns what the groups of the `file_name` regex captured into tags
+    AddCapturedTags,
+    /// Doesn't import the file at all
+    Skip,
+}
+
+/// What the rules decided for a file
+#[derive(Debug, Default, PartialEq)]
+pub struct RuleOutcome {
+    pub tags: HashSet<String>,
+    /// Name of the rule that skipped the file, if one did
+    pub skipped_by: Option<String>,
+    /// What each matching rule did, like `screenshots: +screenshot`
+    pub log: Vec<String>,
+}
+
+struct CompiledRule {
+    rule: Rule,
+    file_name: Option<Regex>,
+    source_path: Option<GlobMatcher>,
+}
+
+/// Rules ready to be applied
+#[derive(Default)]
+pub struct RuleSet {
+    rules: Vec<CompiledRule>,
+}
+
+impl RuleSet {
+    pub fn new(rules: &[Rule]) -> Result<RuleSet> {
+        let mut compiled_rules = Vec::new();
+        for rule in rules {
+            let file_name =
+                match &rule.conditions.file_name {
+                    Some(pattern) => Some(Regex::new(pattern).with_context(|| {
+                        format!("Invalid file name regex in rule '{}'", rule.name)
+                    })?),
+                    None => None,
+                };
+            let source_path = match &rule.conditions.source_path {
+                Some(glob) => Some(
+                    Glob::new(glob)
+                        .with_context(|| {
+                            format!("Invalid source path glob in rule '{}'", rule.name)
+                        })?
+                        .compile_matcher(),
+                ),
+                None => None,
+            };
+            compiled_rules.push(CompiledRule {
+                rule: rule.clone(),
+                file_name,
+                source_path,
+            });
+        }
+        Ok(RuleSet {
+            rules: compiled_rules,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Runs the rules on the file at `path`, the path it is being imported from
+    pub fn apply(&self, path: &Path) -> RuleOutcome {
+        let mut outcome = RuleOutcome::default();
+        if self.rules.is_empty() {
+            return outcome;
+        }
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let size = std::fs::metadata(path).map_or(0, |metadata| metadata.len());
+
+        for compiled_rule in self.rules.iter() {
+            let captures = match compiled_rule.matches(path, &file_name, size) {
+                Some(captures) => captures,
+                None => continue,
+            };
+            let mut rule_tags: Vec<String> = Vec::new();
+            for action in compiled_rule.rule.actions.iter() {
+                match action {
+                    RuleAction::AddTags(tags) => rule_tags.extend(
+                        tags.iter()
+                            .filter(|tag| {
+                                tag_util::is_tag_name_valid(tag) && !tag_util::is_reserved_tag(tag)
+                            })
+                            .cloned(),
+                    ),
+                    RuleAction::AddCapturedTags => rule_tags.extend(
+                        captures
+                            .iter()
+                            .filter_map(|captured| tag_util::sanitise_tag_name(captured)),
+                    ),
+                    RuleAction::Skip => {
+                        if outcome.skipped_by.is_none() {
+                            outcome.skipped_by = Some(compiled_rule.rule.name.clone());
+                        }
+                    }
+                }
+            }
+
+            let mut description: Vec<String> =
+                rule_tags.iter().map(|tag| format!("+{tag}")).collect();
+            if compiled_rule.rule.actions.contains(&RuleAction::Skip) {
+                description.push("skip".to_owned());
+            }
+            outcome.log.push(format!(
+                "{}: {}",
+                compiled_rule.rule.name,
+                description.join(" ")
+            ));
+            outcome.tags.extend(rule_tags);
+        }
+        outcome
+    }
+}
+
+impl CompiledRule {
+    /// Returns what the `file_name` regex captured if every condition matches
+    fn matches(&self, path: &Path, file_name: &str, size: u64) -> Option<Vec<String>> {
+        let conditions = &self.rule.conditions;
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        if let Some(extensions) = &conditions.extensions {
+            if !extensions
+                .iter()
+                .any(|wanted| wanted.trim_start_matches('.').to_lowercase() == extension)
+            {
+                return None;
+            }
+        }
+        if let Some(wanted_mime_type) = &conditions.mime_type {
+            if !content_type_matches(content_type_from_path(path), wanted_mime_type) {
+                return None;
+            }
+        }
+        if conditions.min_size.is_some_and(|min_size| size < min_size)
+            || conditions.max_size.is_some_and(|max_size| size > max_size)
+        {
+            return None;
+        }
+        if let Some(source_path) = &self.source_path {
+            if !source_path.is_match(path) {
+                return None;
+            }
+        }
+        match &self.file_name {
+            Some(file_name_regex) => {
+                let captures = file_name_regex.captures(file_name)?;
+                Some(
+                    captures
+                        .iter()
+                        .skip(1)
+                        .flatten()
+                        .map(|captured| captured.as_str().to_owned())
+                        .collect(),
+                )
+            }
+            None => Some(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn rule(name: &str, conditions: RuleConditions, actions: Vec<RuleAction>) -> Rule {
+        Rule {
+            name: name.to_owned(),
+            conditions,
+            actions,
+        }
+    }
+
+    #[test]
+    fn should_deserialise_rules() {
+        let rules: Vec<Rule> = serde_json::from_str(
+            r#"[{
+                "name": "art",
+                "conditions": { "file_name": "^(\\w+) - ", "extensions": ["png"] },
+                "actions": [{ "add_tags": ["art"] }, "add_captured_tags"]
+            }, { "name": "junk", "actions": ["skip"] }]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            rules[0].actions,
+            vec![
+                RuleAction::AddTags(vec!["art".to_owned()]),
+                RuleAction::AddCapturedTags
+            ]
+        );
+        assert_eq!(rules[1].conditions, RuleConditions::default());
+    }
+
+    #[test]
+    fn should_apply_matching_rules() {
+        let folder = tempfile::tempdir().unwrap().keep().join("Art");
+        fs::create_dir(&folder).unwrap();
+        let image_path = folder.join("Hokusai - The Great Wave.PNG");
+        fs::write(&image_path, "0123456789").unwrap();
+
+        let rules = RuleSet::new(&[
+            rule(
+                "artists",
+                RuleConditions {
+                    file_name: Some(r"^(\w+) - ".to_owned()),
+                    source_path: Some("**/Art/*".to_owned()),
+                    ..Default::default()
+                },
+                vec![
+                    RuleAction::AddTags(vec!["art".to_owned()]),
+                    RuleAction::AddCapturedTags,
+                ],
+            ),
+            rule(
+                "images",
+                RuleConditions {
+                    mime_type: Some("image/*".to_owned()),
+                    extensions: Some(vec![".png".to_owned()]),
+                    ..Default::default()
+                },
+                vec![RuleAction::AddTags(vec!["image".to_owned()])],
+            ),
+            rule(
+                "big files",
+                RuleConditions {
+                    min_size: Some(1000),
+                    ..Default::default()
+                },
+                vec![RuleAction::Skip],
+            ),
+        ])
+        .unwrap();
+
+        let outcome = rules.apply(&image_path);
+        assert_eq!(
+            outcome.tags,
+            HashSet::from(["art".to_owned(), "hokusai".to_owned(), "image".to_owned()])
+        );
+        assert_eq!(outcome.skipped_by, None);
+        assert_eq!(
+            outcome.log,
+            vec!["artists: +art +hokusai", "images: +image"]
+        );
+
+        fs::write(&image_path, vec![0; 2000]).unwrap();
+        assert_eq!(
+            rules.apply(&image_path).skipped_by,
+            Some("big files".to_owned())
+        );
+    }
+
+    #[test]
+    fn should_refuse_invalid_rules() {
+        let invalid = rule(
+            "broken",
+            RuleConditions {
+                file_name: Some("(".to_owned()),
+                ..Default::default()
+            },
+            vec![RuleAction::Skip],
+        );
+        assert!(RuleSet::new(&[invalid]).is_err());
+    }
+}
@@ -1,6 +1,10 @@
-use super::tag_util;
+use super::palette::{ColorQuery, PaletteColor};
+use super::tag_util::{self, COLOR_PREFIX, SIMILAR_PREFIX};
 use anyhow::{bail, Result};
 use std::collections::HashSet;
+
+/// Put before a term to search for the tag as it is (`\color:red` is the tag `color:red`)
+pub const LITERAL_TAG_ESCAPE: char = '\\';
 //Until database is finished
 pub trait Database {
     fn get_tag_count(&self, name: &str) -> u64;
@@ -14,6 +18,10 @@ pub enum SearchOption {
     Not(Box<SearchOption>),
     OrEmpty,
     Or(Box<SearchOption>),
+    /// `similar:<file hash>`, images that look like that file (see `data::perceptual_hash`)
+    Similar(Vec<u8>),
+    /// `color:<name or #rrggbb>`, images with that colour (see `data::palette`)
+    Color(ColorQuery),
 }
 impl SearchOption {
     pub fn add_char(&mut self, c: char) -> Result<()> {
@@ -39,9 +47,56 @@ impl SearchOption {
             Self::Not(t) | Self::Or(t) => {
                 return t.add_char(c);
             }
+            Self::Similar(_) | Self::Color(_) => {
+                bail!("Cannot add char to similar or color");
+            }
         }
         Ok(())
     }
+    /// Whether this is a `color:` term still being parsed
+    fn is_color_term(&self) -> bool {
+        match self {
+            Self::Tag(t) => t.starts_with(COLOR_PREFIX),
+            Self::Not(t) | Self::Or(t) => t.is_color_term(),
+            _ => false,
+        }
+    }
+    /// Turns `similar:<file hash>` and `color:<colour>` tags into `Similar` and `Color`,
+    /// unless they start with `LITERAL_TAG_ESCAPE`
+    fn parse_predicates(self) -> Result<Self> {
+        Ok(match self {
+            Self::Tag(t) => {
+                if let Some(tag) = t.strip_prefix(LITERAL_TAG_ESCAPE) {
+                    Self::Tag(tag.to_owned())
+                } else if let Some(hash) = t.strip_prefix(SIMILAR_PREFIX) {
+                    match hex::decode(hash) {
+                        Ok(hash) if hash.len() == blake3::OUT_LEN => Self::Similar(hash),
+                        _ => bail!("\"{t}\" isn't followed by a file hash"),
+                    }
+                } else if let Some(colour) = t.strip_prefix(COLOR_PREFIX) {
+                    Self::Color(ColorQuery::parse(colour)?)
+                } else {
+                    Self::Tag(t)
+                }
+            }
+            Self::TagSet(v) => Self::TagSet(
+                v.into_iter()
+                    .map(Self::parse_predicates)
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            Self::Not(t) => Self::Not(Box::new(t.parse_predicates()?)),
+            Self::Or(t) => Self::Or(Box::new(t.parse_predicates()?)),
+            other => other,
+        })
+    }
+    fn uses_color(&self) -> bool {
+        match self {
+            Self::Color(_) => true,
+            Self::TagSet(v) => v.iter().any(Self::uses_color),
+            Self::Not(t) | Self::Or(t) => t.uses_color(),
+            _ => false,
+        }
+    }
     pub fn verify(&self) -> Result<()> {
         match self {
             Self::Tag(t) => {
@@ -49,6 +104,9 @@ impl SearchOption {
             }
             Self::TagSet(v) => {
                 for i in v.iter() {
+                    if let Self::Similar(_) = i {
+                        bail!("similar: can't be used in a tag set");
+                    }
                     i.verify()?;
                 }
             }
@@ -59,8 +117,12 @@ impl SearchOption {
                 bail!("Empty or")
             }
             Self::Not(t) | Self::Or(t) => {
+                if let Self::Similar(_) = **t {
+                    bail!("similar: can't be negated or used with ~");
+                }
                 t.verify()?;
             }
+            Self::Similar(_) | Self::Color(_) => {}
         }
         Ok(())
     }
@@ -70,12 +132,16 @@ impl SearchOption {
             _ => false,
         }
     }
-    fn filter_single(&self, tags: &HashSet<String>) -> bool {
+    fn filter_single(&self, tags: &HashSet<String>, palette: Option<&[PaletteColor]>) -> bool {
         match self {
             SearchOption::Tag(s) => tags.contains(s),
-            SearchOption::TagSet(set) => _filter_post(set, tags),
-            SearchOption::Not(t) => !t.filter_single(tags),
-            SearchOption::Or(t) => t.filter_single(tags),
+            SearchOption::TagSet(set) => _filter_post(set, tags, palette),
+            SearchOption::Not(t) => !t.filter_single(tags, palette),
+            SearchOption::Or(t) => t.filter_single(tags, palette),
+            // Tags don't say how a file looks, `TagMaidDatabase::search()` handles it
+            SearchOption::Similar(_) => true,
+            // Files without a palette (not images) have no colour
+            SearchOption::Color(colour) => palette.is_some_and(|palette| colour.matches(palette)),
             _ => {
                 panic!("Invalid search option");
             }
@@ -92,10 +158,20 @@ impl Search {
         Self { v }
     }
     pub fn from_string(s: &str) -> Result<Search> {
-        let search = Self::_search_from_string(&mut s.chars(), 0)?;
+        let search = Self::_search_from_string(&mut s.chars(), 0)?
+            .into_iter()
+            .map(SearchOption::parse_predicates)
+            .collect::<Result<Vec<_>>>()?;
         for i in search.iter() {
             i.verify()?;
         }
+        let similar_count = search
+            .iter()
+            .filter(|i| matches!(i, SearchOption::Similar(_)))
+            .count();
+        if similar_count > 1 {
+            bail!("Only one similar: can be used at a time");
+        }
         Ok(Search::new(search))
     }
     pub fn _search_from_string(
@@ -152,7 +228,14 @@ impl Search {
                     }
                 }
                 '~' => {
-                    fin.push(SearchOption::OrEmpty);
+                    // `color:#ff8800~30` is a colour with a tolerance, not an or
+                    let in_color =
+                        last != ' ' && fin.last().is_some_and(SearchOption::is_color_term);
+                    if in_color {
+                        fin.last_mut().unwrap().add_char(i)?;
+                    } else {
+                        fin.push(SearchOption::OrEmpty);
+                    }
                 }
                 ' ' => {} // Do nothing
                 ch => {
@@ -185,7 +268,15 @@ impl Search {
     }
     /// true if matches query. false if it doesn't
     pub fn filter_post(&self, tags: &HashSet<String>) -> bool {
-        _filter_post(&self.v, tags)
+        _filter_post(&self.v, tags, None)
+    }
+    /// Like `filter_post()`, with the palette of the file for `color:` terms
+    pub fn filter_file(&self, tags: &HashSet<String>, palette: Option<&[PaletteColor]>) -> bool {
+        _filter_post(&self.v, tags, palette)
+    }
+    /// Whether the search has `color:` terms, which need the palettes of the files
+    pub fn uses_color(&self) -> bool {
+        self.v.iter().any(SearchOption::uses_color)
     }
     pub fn initial_tag(&self, d: &dyn Database) -> Option<String> {
         _initial_search_tag(&self.v, d)
@@ -193,13 +284,24 @@ impl Search {
     pub fn first_tag(&self) -> Option<String> {
         _first_tag(&self.v)
     }
+    /// The file hash of `similar:<file hash>`, if the search has one
+    pub fn similar(&self) -> Option<&Vec<u8>> {
+        self.v.iter().find_map(|i| match i {
+            SearchOption::Similar(hash) => Some(hash),
+            _ => None,
+        })
+    }
 }
 
-fn _filter_post(s: &Vec<SearchOption>, tags: &HashSet<String>) -> bool {
+fn _filter_post(
+    s: &Vec<SearchOption>,
+    tags: &HashSet<String>,
+    palette: Option<&[PaletteColor]>,
+) -> bool {
     let ors: Vec<&SearchOption> = s.iter().filter(|&x| x.or()).collect();
     for i in s.iter() {
         if !i.or() {
-            if !i.filter_single(tags) {
+            if !i.filter_single(tags, palette) {
                 return false;
             }
         }
@@ -207,7 +309,7 @@ fn _filter_post(s: &Vec<SearchOption>, tags: &HashSet<String>) -> bool {
     if ors.len() > 0 {
         let mut or = false;
         for i in ors.iter() {
-            or |= i.filter_single(tags);
+            or |= i.filter_single(tags, palette);
         }
         if !or {
             return false;
@@ -270,6 +372,8 @@ fn _initial_search_tag(search: &Vec<SearchOption>, d: &dyn Database) -> Option<S
             },
             SearchOption::Not(..) => {}
             SearchOption::Or(..) => {}
+            SearchOption::Similar(..) => {}
+            SearchOption::Color(..) => {}
             _ => {
                 panic!("Invalid search option")
             }
@@ -354,4 +458,69 @@ mod test {
         assert_eq!(search2.initial_tag(&db), Some(String::from("sitting")));
         assert_eq!(search3.initial_tag(&db), Some(String::from("music_note")))
     }
+    #[test]
+    fn test_parse_similar() {
+        let hash = "00ff".repeat(16);
+        let search = Search::from_string(&format!("cat similar:{hash} -dog")).unwrap();
+        assert_eq!(search.similar(), Some(&[0x00, 0xff].repeat(16)));
+        assert_eq!(search.first_tag(), Some(String::from("cat")));
+        let tags = "cat".split(" ").map(|x| x.to_owned()).collect();
+        assert!(search.filter_post(&tags));
+        assert_eq!(Search::from_string("cat").unwrap().similar(), None);
+
+        assert!(Search::from_string("similar:nothex").is_err());
+        assert!(Search::from_string("similar:00ff").is_err());
+        assert!(Search::from_string(&format!("similar:{hash}00")).is_err());
+        assert!(Search::from_string(&format!("-similar:{hash}")).is_err());
+        assert!(Search::from_string(&format!("[cat similar:{hash}]")).is_err());
+        assert!(Search::from_string(&format!("similar:{hash} similar:{hash}")).is_err());
+    }
+    #[test]
+    fn test_parse_literal_tags() {
+        let search = Search::from_string(r"\color:red -\similar:cat ~\color:blue ~wa").unwrap();
+        assert_eq!(
+            search.v,
+            vec![
+                SearchOption::Tag(String::from("color:red")),
+                SearchOption::Not(Box::new(SearchOption::Tag(String::from("similar:cat")))),
+                SearchOption::Or(Box::new(SearchOption::Tag(String::from("color:blue")))),
+                SearchOption::Or(Box::new(SearchOption::Tag(String::from("wa")))),
+            ]
+        );
+        assert!(!search.uses_color());
+        assert_eq!(search.similar(), None);
+        let tags = HashSet::from([String::from("color:red"), String::from("wa")]);
+        assert!(search.filter_post(&tags));
+        assert!(Search::from_string(r"\").is_err());
+    }
+    #[test]
+    fn test_parse_color() {
+        let search = Search::from_string("logo -color:#ff8800~30 ~wa ~color:red").unwrap();
+        assert_eq!(
+            search.v,
+            vec![
+                SearchOption::Tag(String::from("logo")),
+                SearchOption::Not(Box::new(SearchOption::Color(ColorQuery::Rgb {
+                    rgb: [255, 136, 0],
+                    tolerance: 30
+                }))),
+                SearchOption::Or(Box::new(SearchOption::Tag(String::from("wa")))),
+                SearchOption::Or(Box::new(SearchOption::Color(ColorQuery::Named(
+                    String::from("red")
+                )))),
+            ]
+        );
+        assert!(search.uses_color());
+        assert!(!Search::from_string("logo ~wa").unwrap().uses_color());
+        assert!(Search::from_string("color:teal").is_err());
+
+        let tags = HashSet::from([String::from("logo")]);
+        let red = PaletteColor {
+            rgb: [230, 20, 30],
+            share: 50,
+        };
+        assert!(search.filter_file(&tags, Some(&[red])));
+        assert!(!search.filter_file(&tags, None));
+        assert!(!search.filter_post(&tags));
+    }
 }
@@ -0,0 +1,227 @@
+//! System tags: tags computed from the properties of a file (type, extension, orientation,
+//! resolution...) when it is imported, so nobody has to add them by hand. They all start
+//! with `tag_util::SYSTEM_TAG_PREFIX`, can be searched like any other tag, but can't be
+//! edited by users.
+//!
+//! - `system:ext:<extension>` and `system:type:<image|video|audio|text|application>`
+//! - `system:landscape`, `system:portrait` or `system:square` for images
+//! - `system:8k`, `system:4k`, `system:1080p`, `system:720p` or `system:low_res` for images,
+//!   based on their longest side
+//! - `system:animated` for animated GIF, PNG and WebP images
+//! - From the EXIF metadata of photos: `system:camera:<model>`, `system:taken:<year>`,
+//!   `system:taken:<year-month>` and `system:taken:<year-month-day>`, and `system:geotagged`
+//!   along with `system:gps:<latitude>_<longitude>` rounded down to whole degrees
+//!   (`system:gps:35n_138e`)
+use crate::data::embedded_metadata::EmbeddedMetadata;
+use crate::data::mime::content_type_from_path;
+use crate::data::tag_util::{self, SYSTEM_TAG_PREFIX};
+use crate::database::tagmaid_database::TagMaidDatabase;
+use anyhow::{Context, Result};
+use image::AnimationDecoder;
+use log::*;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// Resolution classes, from the longest side in pixels they need
+const RESOLUTION_CLASSES: [(u32, &str); 4] =
+    [(7680, "8k"), (3840, "4k"), (1920, "1080p"), (1280, "720p")];
+
+fn system_tag(name: &str) -> String {
+    format!("{SYSTEM_TAG_PREFIX}{name}")
+}
+
+/// Computes the system tags of the file at `path`. Properties that can't be read
+/// (an image format that isn't supported...) just don't give any tag.
+pub fn compute(path: &Path) -> HashSet<String> {
+    compute_with_metadata(path, &EmbeddedMetadata::read(path))
+}
+
+/// Same as `compute()`, with the embedded metadata of the file already read
+pub fn compute_with_metadata(path: &Path, metadata: &EmbeddedMetadata) -> HashSet<String> {
+    let mut tags = HashSet::new();
+    let extension = path
+        .extension()
+        .and_then(|extension| tag_util::sanitise_tag_name(&extension.to_string_lossy()));
+    if let Some(extension) = &extension {
+        tags.insert(system_tag(&format!("ext:{extension}")));
+    }
+    let content_type = content_type_from_path(path);
+    let file_type = content_type.split('/').next().unwrap_or_default();
+    if content_type != "application/octet-stream" {
+        tags.insert(system_tag(&format!("type:{file_type}")));
+    }
+
+    if file_type == "image" {
+        if let Ok((width, height)) = image::image_dimensions(path) {
+            let orientation = match width.cmp(&height) {
+                std::cmp::Ordering::Greater => "landscape",
+                std::cmp::Ordering::Less => "portrait",
+                std::cmp::Ordering::Equal => "square",
+            };
+            tags.insert(system_tag(orientation));
+            let longest_side = width.max(height);
+            let resolution = RESOLUTION_CLASSES
+                .iter()
+                .find(|(min_side, _name)| longest_side >= *min_side)
+                .map_or("low_res", |(_min_side, name)| name);
+            tags.insert(system_tag(resolution));
+        }
+        if is_animated(path, extension.as_deref().unwrap_or_default()) {
+            tags.insert(system_tag("animated"));
+        }
+        tags.extend(from_metadata(metadata));
+    }
+    tags
+}
+
+/// System tags for the capture time, camera and location of a photo
+fn from_metadata(metadata: &EmbeddedMetadata) -> HashSet<String> {
+    let mut tags = HashSet::new();
+    if let Some(camera) = metadata
+        .camera
+        .as_deref()
+        .and_then(tag_util::sanitise_tag_name)
+    {
+        tags.insert(system_tag(&format!("camera:{camera}")));
+    }
+    if let Some((year, month, day)) = metadata.taken {
+        tags.insert(system_tag(&format!("taken:{year:04}")));
+        tags.insert(system_tag(&format!("taken:{year:04}-{month:02}")));
+        tags.insert(system_tag(&format!("taken:{year:04}-{month:02}-{day:02}")));
+    }
+    if let Some((latitude, longitude)) = metadata.gps {
+        let latitude_hemisphere = if latitude < 0.0 { 's' } else { 'n' };
+        let longitude_hemisphere = if longitude < 0.0 { 'w' } else { 'e' };
+        tags.insert(system_tag("geotagged"));
+        tags.insert(system_tag(&format!(
+            "gps:{}{latitude_hemisphere}_{}{longitude_hemisphere}",
+            latitude.abs().floor(),
+            longitude.abs().floor()
+        )));
+    }
+    tags
+}
+
+/// Whether the image at `path` has more than one frame
+fn is_animated(path: &Path, extension: &str) -> bool {
+    let inner = || -> Result<bool> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(match extension {
+            "gif" => {
+                let decoder = image::codecs::gif::GifDecoder::new(reader)?;
+                decoder.into_frames().take(2).count() > 1
+            }
+            "png" | "apng" => image::codecs::png::PngDecoder::new(reader)?.is_apng(),
+            "webp" => {
+                // Animated WebPs have the animation flag set in their VP8X header
+                let mut header = [0; 21];
+                reader.take(21).read_exact(&mut header)?;
+                &header[12..16] == b"VP8X" && header[20] & 0x02 != 0
+            }
+            _ => false,
+        })
+    };
+    inner().unwrap_or(false)
+}
+
+/// Replaces the system tags in `tags` by the ones computed for the file at `path`
+pub fn replace(tags: &mut HashSet<String>, path: &Path) {
+    tags.retain(|tag| !tag_util::is_system_tag(tag));
+    tags.extend(compute(path));
+}
+
+/// Recomputes the system tags of every file in the library, for instance after new
+/// system tags were added to TagMaid. Returns how many files got new system tags.
+pub fn rebuild(db: &TagMaidDatabase) -> Result<usize> {
+    let mut updated = 0;
+    for hash in db.get_file_ids()?.into_keys() {
+        let mut tagfile = db
+            .get_tagfile_from_hash(&hash)
+            .context("Couldn't read file while rebuilding system tags")?;
+        let old_tags = tagfile.tags.clone();
+        replace(&mut tagfile.tags, &tagfile.path.clone());
+        if tagfile.tags != old_tags {
+            // Files imported without any tag only have system tags, and have to stay
+            db.update_system_tags(&tagfile)?;
+            updated += 1;
+        }
+    }
+    info!("system_tags - rebuild() - Updated {updated} files");
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_compute_system_tags() {
+        let folder = tempfile::tempdir().unwrap().keep();
+        let image_path = folder.join("wide.PNG");
+        image::RgbImage::new(4000, 10).save(&image_path).unwrap();
+        assert_eq!(
+            compute(&image_path),
+            HashSet::from([
+                "system:ext:png".to_owned(),
+                "system:type:image".to_owned(),
+                "system:landscape".to_owned(),
+                "system:4k".to_owned(),
+            ])
+        );
+
+        let metadata = EmbeddedMetadata {
+            camera: Some("Canon EOS 5D".to_owned()),
+            taken: Some((2023, 5, 14)),
+            gps: Some((35.36, -138.73)),
+            ..Default::default()
+        };
+        assert_eq!(
+            from_metadata(&metadata),
+            HashSet::from([
+                "system:camera:canon_eos_5d".to_owned(),
+                "system:taken:2023".to_owned(),
+                "system:taken:2023-05".to_owned(),
+                "system:taken:2023-05-14".to_owned(),
+                "system:geotagged".to_owned(),
+                "system:gps:35n_138w".to_owned(),
+            ])
+        );
+
+        let text_path = folder.join("notes.txt");
+        std::fs::write(&text_path, "notes").unwrap();
+        let mut tags = HashSet::from(["notes".to_owned(), "system:square".to_owned()]);
+        replace(&mut tags, &text_path);
+        assert_eq!(
+            tags,
+            HashSet::from([
+                "notes".to_owned(),
+                "system:ext:txt".to_owned(),
+                "system:type:text".to_owned(),
+            ])
+        );
+    }
+
+    #[test]
+    fn should_only_remove_files_left_without_user_tags() {
+        use crate::data::tag_file::TagFile;
+        use crate::database::tag_database::TagDatabase;
+
+        let folder = tempfile::tempdir().unwrap().keep();
+        let db = TagMaidDatabase::new(TagDatabase::initialise_at(folder.join("library")).unwrap());
+        let text_path = folder.join("notes.txt");
+        std::fs::write(&text_path, "notes").unwrap();
+        // Imported without any tag, with system tags from an older version
+        let mut tagfile = TagFile::initialise_from_path(&text_path).unwrap();
+        tagfile.tags.insert("system:square".to_owned());
+        db.update_tagfile(&tagfile).unwrap();
+
+        assert_eq!(rebuild(&db).unwrap(), 1);
+        let tagfile = db.get_tagfile_from_hash(&tagfile.file_hash).unwrap();
+        assert_eq!(tagfile.tags, compute(&text_path));
+
+        db.update_tagfile(&tagfile).unwrap();
+        assert!(db.get_tagfile_from_hash(&tagfile.file_hash).is_err());
+    }
+}
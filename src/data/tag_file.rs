@@ -1,5 +1,4 @@
 //! File for managing the TagFile structure
-use crate::data::ui_util;
 use anyhow::{bail, Context, Result};
 use std::collections::HashSet;
 use std::fs::{File, Metadata};
@@ -170,13 +169,6 @@ impl TagFile {
         Ok(hash_bytes)
     }
 
-    pub fn get_thumbnail_path(&self) -> PathBuf {
-        let file_path = *(&self.get_path());
-        // Thumbnail dimensions are hardcoded to 100x100 (maximum, aspect ratio is preserved)
-        let thumbnail_path = ui_util::create_image_thumbnail(file_path, 100, 100);
-        return thumbnail_path;
-    }
-
     pub fn display(&self) -> String {
         return format!(
             "TagFile{{file_name: {}, path: {}, tags: {:?}, file_hash: {:?}}}",
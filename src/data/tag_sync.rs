@@ -0,0 +1,73 @@
+//! Tag sync backends mirror the tags of the library outside of `sqlite.db`, so that other
+//! tools can read them. `TagMaidDatabase` calls them whenever the tags of a file change;
+//! errors are only logged, since the library itself is up to date either way.
+//!
+//! Only user tags are written: system tags can be recomputed by anyone.
+pub mod xdg_tags;
+pub mod xmp_sidecar;
+
+use crate::data::{config::Config, tag_file::TagFile, tag_util};
+use crate::database::tagmaid_database::TagMaidDatabase;
+use anyhow::Result;
+use log::*;
+use std::path::Path;
+
+pub trait TagSync: Send + Sync {
+    /// Used in logs
+    fn name(&self) -> &'static str;
+
+    /// Writes the tags of `tagfile`, whose path is the one of the file stored in the library
+    fn write(&self, tagfile: &TagFile) -> Result<()>;
+
+    /// Called when `tagfile` is removed from the library
+    fn remove(&self, _tagfile: &TagFile) -> Result<()> {
+        Ok(())
+    }
+
+    /// Tags this backend finds on the file at `path`, as they were written (they may not
+    /// be valid tag names). Used to keep them when importing the file.
+    fn read(&self, _path: &Path) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Tags that get written by the backends, sorted
+pub fn synced_tags(tagfile: &TagFile) -> Vec<String> {
+    let mut tags: Vec<String> = tagfile
+        .get_tags()
+        .iter()
+        .filter(|tag| !tag_util::is_system_tag(tag))
+        .cloned()
+        .collect();
+    tags.sort();
+    tags
+}
+
+/// The backends enabled in the config
+pub fn from_config(config: &Config) -> Vec<Box<dyn TagSync>> {
+    let mut backends: Vec<Box<dyn TagSync>> = Vec::new();
+    if config.xmp_sidecars.enabled {
+        backends.push(Box::new(xmp_sidecar::XmpSidecar::new(
+            config.xmp_sidecars.destination.clone(),
+        )));
+    }
+    if config.xdg_tags.enabled {
+        backends.push(Box::new(xdg_tags::XdgTags::new(
+            config.xdg_tags.destination.clone(),
+        )));
+    }
+    backends
+}
+
+/// Writes the tags of every file in the library with the backends of `db`, to create or
+/// refresh everything they store. Returns how many files were written.
+pub fn sync_all(db: &TagMaidDatabase) -> Result<usize> {
+    let mut synced = 0;
+    for hash in db.get_file_ids()?.into_keys() {
+        let tagfile = db.get_tagfile_from_hash(&hash)?;
+        db.sync_tags(&tagfile);
+        synced += 1;
+    }
+    info!("tag_sync - sync_all() - Synced {synced} files");
+    Ok(synced)
+}
@@ -0,0 +1,129 @@
+//! Tags stored in the `user.xdg.tags` extended attribute, as a comma-separated list, which
+//! Dolphin and other file managers show and search. Files in the library are usually
+//! hardlinks of the imported ones, so the original files get the tags too.
+//!
+//! Filesystems without extended attributes (FAT, some network shares...) are skipped.
+use super::{synced_tags, TagSync};
+use crate::data::tag_file::TagFile;
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+pub const XDG_TAGS_ATTRIBUTE: &str = "user.xdg.tags";
+
+pub struct XdgTags {
+    /// Export folder holding copies of the stored files under their original names.
+    /// `None` tags the files stored in the library.
+    destination: Option<PathBuf>,
+}
+
+impl XdgTags {
+    pub fn new(destination: Option<PathBuf>) -> XdgTags {
+        XdgTags { destination }
+    }
+
+    /// The file getting the attribute for `tagfile`
+    fn target_path(&self, tagfile: &TagFile) -> PathBuf {
+        match &self.destination {
+            Some(destination) => destination.join(tagfile.get_file_name()),
+            None => tagfile.get_path().clone(),
+        }
+    }
+}
+
+/// Whether the error only means that the filesystem (or OS) has no extended attributes
+#[cfg(unix)]
+fn is_unsupported(err: &std::io::Error) -> bool {
+    err.kind() == std::io::ErrorKind::Unsupported
+}
+
+#[cfg(unix)]
+impl TagSync for XdgTags {
+    fn name(&self) -> &'static str {
+        "user.xdg.tags"
+    }
+
+    fn write(&self, tagfile: &TagFile) -> Result<()> {
+        let path = self.target_path(tagfile);
+        // Files missing from the export folder haven't been exported, there is nothing to tag
+        if !path.exists() {
+            return Ok(());
+        }
+        let tags = synced_tags(tagfile).join(",");
+        let result = if tags.is_empty() {
+            match xattr::remove(&path, XDG_TAGS_ATTRIBUTE) {
+                // Removing an attribute that isn't there fails too
+                Err(_) if matches!(xattr::get(&path, XDG_TAGS_ATTRIBUTE), Ok(None)) => Ok(()),
+                result => result,
+            }
+        } else if xattr::get(&path, XDG_TAGS_ATTRIBUTE)
+            .is_ok_and(|old_tags| old_tags.as_deref() == Some(tags.as_bytes()))
+        {
+            Ok(())
+        } else {
+            xattr::set(&path, XDG_TAGS_ATTRIBUTE, tags.as_bytes())
+        };
+        match result {
+            Err(err) if is_unsupported(&err) => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<String>> {
+        match xattr::get(path, XDG_TAGS_ATTRIBUTE) {
+            Ok(Some(tags)) => Ok(String::from_utf8_lossy(&tags)
+                .split(',')
+                .map(|tag| tag.trim().to_owned())
+                .filter(|tag| !tag.is_empty())
+                .collect()),
+            Ok(None) => Ok(Vec::new()),
+            Err(err) if is_unsupported(&err) => Ok(Vec::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+impl TagSync for XdgTags {
+    fn name(&self) -> &'static str {
+        "user.xdg.tags"
+    }
+
+    fn write(&self, _tagfile: &TagFile) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_mirror_tags_to_xattr() {
+        let folder = tempfile::tempdir().unwrap().keep();
+        let mut tagfile = TagFile::new();
+        tagfile.path = folder.join("cat.png");
+        tagfile.file_name = "cat.png".to_owned();
+        tagfile.tags = ["cat", "sleeping", "system:ext:png"]
+            .map(|tag| tag.to_owned())
+            .into();
+        std::fs::write(&tagfile.path, "not really a png").unwrap();
+
+        let xdg_tags = XdgTags::new(None);
+        xdg_tags.write(&tagfile).unwrap();
+        let supported = matches!(xattr::get(&tagfile.path, XDG_TAGS_ATTRIBUTE), Ok(Some(_)));
+        if supported {
+            assert_eq!(
+                xdg_tags.read(&tagfile.path).unwrap(),
+                vec!["cat", "sleeping"]
+            );
+        }
+
+        tagfile.tags.clear();
+        xdg_tags.write(&tagfile).unwrap();
+        assert!(xdg_tags.read(&tagfile.path).unwrap().is_empty());
+
+        // Files that weren't exported are left alone
+        let export = XdgTags::new(Some(folder.join("export")));
+        export.write(&tagfile).unwrap();
+    }
+}
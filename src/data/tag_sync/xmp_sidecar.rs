@@ -0,0 +1,201 @@
+//! XMP sidecars: `<file>.xmp` files next to the stored files (or in an export folder)
+//! listing their tags as `dc:subject` keywords, which Lightroom, digiKam, darktable...
+//! understand. Existing sidecars are refreshed: only their keywords are replaced.
+use super::{synced_tags, TagSync};
+use crate::data::embedded_metadata::xmp_keywords;
+use crate::data::tag_file::TagFile;
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::path::{Path, PathBuf};
+
+const DC_NAMESPACE: &str = r#"xmlns:dc="http://purl.org/dc/elements/1.1/""#;
+
+pub struct XmpSidecar {
+    /// Folder the sidecars are written to, named after the original file names.
+    /// `None` writes them next to the files stored in the library.
+    destination: Option<PathBuf>,
+}
+
+impl XmpSidecar {
+    pub fn new(destination: Option<PathBuf>) -> XmpSidecar {
+        XmpSidecar { destination }
+    }
+
+    /// Where the sidecar of `tagfile` goes
+    pub fn sidecar_path(&self, tagfile: &TagFile) -> PathBuf {
+        match &self.destination {
+            Some(destination) => destination.join(format!("{}.xmp", tagfile.get_file_name())),
+            None => {
+                let mut path = tagfile.get_path().clone().into_os_string();
+                path.push(".xmp");
+                PathBuf::from(path)
+            }
+        }
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&apos;")
+        .replace('"', "&quot;")
+}
+
+fn subject_element(tags: &[String]) -> String {
+    if tags.is_empty() {
+        return String::new();
+    }
+    let items: String = tags
+        .iter()
+        .map(|tag| format!("     <rdf:li>{}</rdf:li>\n", escape_xml(tag)))
+        .collect();
+    format!("<dc:subject>\n    <rdf:Bag>\n{items}    </rdf:Bag>\n   </dc:subject>")
+}
+
+/// A new sidecar with `tags` as keywords
+fn new_sidecar(tags: &[String]) -> String {
+    format!(
+        r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/" x:xmptk="TagMaid">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    {DC_NAMESPACE}>
+   {}
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>
+"#,
+        subject_element(tags)
+    )
+}
+
+/// Replaces the keywords of an existing sidecar, keeping everything else. Returns `None`
+/// if it doesn't look like XMP.
+fn refresh_sidecar(sidecar: &str, tags: &[String]) -> Option<String> {
+    let subject = subject_element(tags);
+    let subject_regex = Regex::new(r"(?s)<dc:subject\s*/>|<dc:subject>.*?</dc:subject>").unwrap();
+    if subject_regex.is_match(sidecar) {
+        return Some(
+            subject_regex
+                .replace(sidecar, regex::NoExpand(&subject))
+                .into_owned(),
+        );
+    }
+    if tags.is_empty() {
+        return Some(sidecar.to_owned());
+    }
+    // No keywords yet: they go in the first description, which may need the namespace
+    let description_regex = Regex::new(r"(?s)<rdf:Description\b([^>]*?)(/?)>").unwrap();
+    let description = description_regex.captures(sidecar)?;
+    let attributes = &description[1];
+    let namespace = if sidecar.contains("xmlns:dc=") {
+        String::new()
+    } else {
+        format!(" {DC_NAMESPACE}")
+    };
+    let replacement = if &description[2] == "/" {
+        format!("<rdf:Description{attributes}{namespace}>\n   {subject}\n  </rdf:Description>")
+    } else {
+        format!("<rdf:Description{attributes}{namespace}>\n   {subject}")
+    };
+    let range = description.get(0).unwrap().range();
+    Some(format!(
+        "{}{replacement}{}",
+        &sidecar[..range.start],
+        &sidecar[range.end..]
+    ))
+}
+
+impl TagSync for XmpSidecar {
+    fn name(&self) -> &'static str {
+        "XMP sidecar"
+    }
+
+    fn write(&self, tagfile: &TagFile) -> Result<()> {
+        let path = self.sidecar_path(tagfile);
+        let tags = synced_tags(tagfile);
+        let sidecar = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|sidecar| refresh_sidecar(&sidecar, &tags))
+            .unwrap_or_else(|| new_sidecar(&tags));
+        write_if_changed(&path, &sidecar)
+    }
+
+    /// Keywords of the sidecar of a file about to be imported
+    fn read(&self, path: &Path) -> Result<Vec<String>> {
+        let mut sidecar_path = path.to_owned().into_os_string();
+        sidecar_path.push(".xmp");
+        match std::fs::read_to_string(&sidecar_path) {
+            Ok(sidecar) => Ok(xmp_keywords(&sidecar)),
+            Err(_) => Ok(Vec::new()),
+        }
+    }
+
+    fn remove(&self, tagfile: &TagFile) -> Result<()> {
+        let path = self.sidecar_path(tagfile);
+        if path.exists() {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("Couldn't remove '{}'", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+fn write_if_changed(path: &Path, content: &str) -> Result<()> {
+    if std::fs::read_to_string(path).is_ok_and(|old_content| old_content == content) {
+        return Ok(());
+    }
+    std::fs::write(path, content).with_context(|| format!("Couldn't write '{}'", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_write_and_refresh_sidecars() {
+        let folder = tempfile::tempdir().unwrap().keep();
+        let mut tagfile = TagFile::new();
+        tagfile.path = folder.join("1700000000-0123456789abcdef-fuji.jpg");
+        tagfile.file_name = "fuji.jpg".to_owned();
+        tagfile.tags = ["japan", "mount_fuji", "system:ext:jpg"]
+            .map(|tag| tag.to_owned())
+            .into();
+
+        let sidecar = XmpSidecar::new(None);
+        sidecar.write(&tagfile).unwrap();
+        let sidecar_path = folder.join("1700000000-0123456789abcdef-fuji.jpg.xmp");
+        let xmp = std::fs::read_to_string(&sidecar_path).unwrap();
+        assert_eq!(xmp_keywords(&xmp), vec!["japan", "mount_fuji"]);
+
+        // Other tools' metadata is kept when refreshing
+        let xmp = xmp.replace(
+            "<rdf:Description rdf:about=\"\"",
+            "<rdf:Description rdf:about=\"\" xmp:Rating=\"5\"",
+        );
+        std::fs::write(&sidecar_path, xmp).unwrap();
+        tagfile.tags.remove("japan");
+        sidecar.write(&tagfile).unwrap();
+        let xmp = std::fs::read_to_string(&sidecar_path).unwrap();
+        assert_eq!(xmp_keywords(&xmp), vec!["mount_fuji"]);
+        assert!(xmp.contains("xmp:Rating=\"5\""));
+
+        assert_eq!(sidecar.read(&tagfile.path).unwrap(), vec!["mount_fuji"]);
+        sidecar.remove(&tagfile).unwrap();
+        assert!(!sidecar_path.exists());
+
+        let export = XmpSidecar::new(Some(folder.clone()));
+        assert_eq!(export.sidecar_path(&tagfile), folder.join("fuji.jpg.xmp"));
+    }
+
+    #[test]
+    fn should_add_keywords_to_foreign_sidecars() {
+        let xmp = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF><rdf:Description rdf:about="" xmp:Rating="3"/></rdf:RDF></x:xmpmeta>"#;
+        let refreshed = refresh_sidecar(xmp, &["cat".to_owned()]).unwrap();
+        assert_eq!(xmp_keywords(&refreshed), vec!["cat"]);
+        assert!(refreshed.contains(DC_NAMESPACE) && refreshed.contains("xmp:Rating=\"3\""));
+        assert!(refresh_sidecar("not xmp", &["cat".to_owned()]).is_none());
+    }
+}
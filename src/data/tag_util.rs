@@ -1,16 +1,40 @@
 use anyhow::{bail, Context, Result};
 use hex;
 use regex::Regex;
+use std::collections::HashMap;
+
+/// Prefix of the tags computed from file properties (see `data::system_tags`)
+pub const SYSTEM_TAG_PREFIX: &str = "system:";
+/// Prefixes of the search terms that aren't tags (see `data::search_command`)
+pub const SIMILAR_PREFIX: &str = "similar:";
+pub const COLOR_PREFIX: &str = "color:";
 
 /// Returns true if the tag string is valid and can be used in the database.
 /// Returns false otherwise.
-/// Conditions: Alphanumeric and paranthesis. Underscores, hypthens and aposthrophes
-/// allowed but not at the beginning or the end of the string.
+/// Conditions: Alphanumeric (in any script) and paranthesis. Underscores, hypthens and
+/// aposthrophes allowed but not at the beginning or the end of the string.
+/// Namespaced tags (`system:ext:png`) are made of valid segments separated by colons.
 pub fn is_tag_name_valid(tag_name: &str) -> bool {
-    let re = Regex::new(r"^[()a-zA-Z0-9]([()a-zA-Z0-9-_']*[()a-zA-Z0-9])?$").unwrap();
+    let re = Regex::new(
+        r"^[()\p{Alphabetic}\p{N}]([()\p{Alphabetic}\p{N}\-_']*[()\p{Alphabetic}\p{N}])?(:[()\p{Alphabetic}\p{N}]([()\p{Alphabetic}\p{N}\-_']*[()\p{Alphabetic}\p{N}])?)*$",
+    )
+    .unwrap();
     re.is_match(tag_name)
 }
 
+/// System tags are computed by TagMaid, users can't add or remove them
+pub fn is_system_tag(tag_name: &str) -> bool {
+    tag_name.starts_with(SYSTEM_TAG_PREFIX)
+}
+
+/// Tags starting like a search term (`color:red`) aren't made from imported or typed tags.
+/// Ones already in a library can still be searched for with a backslash (`\color:red`).
+pub fn is_reserved_tag(tag_name: &str) -> bool {
+    [SIMILAR_PREFIX, COLOR_PREFIX]
+        .iter()
+        .any(|prefix| tag_name.starts_with(prefix))
+}
+
 /// Returns Ok() is tag name is valid (according to `is_tag_name_valid()`)
 /// Returns Err() otherwise
 pub fn validate_tag_name(tag_name: &str) -> Result<()> {
@@ -21,6 +45,47 @@ pub fn validate_tag_name(tag_name: &str) -> Result<()> {
     }
 }
 
+/// Turns free text (a folder name, a keyword...) into a valid tag name if possible:
+/// lowercases it, replaces spaces and dots by underscores and drops other characters
+/// that aren't allowed. Returns `None` if nothing usable is left.
+pub fn sanitise_tag_name(text: &str) -> Option<String> {
+    let mut tag = String::new();
+    for c in text.trim().to_lowercase().chars() {
+        let c = match c {
+            ' ' | '.' | '_' => '_',
+            c if c.is_alphanumeric() || "()-'".contains(c) => c,
+            _ => continue,
+        };
+        // Collapses runs of underscores left by replaced characters
+        if c == '_' && tag.ends_with('_') {
+            continue;
+        }
+        tag.push(c);
+    }
+    let tag = tag.trim_matches(|c| "_-'".contains(c)).to_owned();
+    if is_tag_name_valid(&tag) {
+        Some(tag)
+    } else {
+        None
+    }
+}
+
+/// Turns a keyword embedded in a file into a tag. Keywords (or the tags they turn into)
+/// that are aliases are replaced by the tag they stand for.
+pub fn keyword_to_tag(keyword: &str, aliases: &HashMap<String, String>) -> Option<String> {
+    let tag = sanitise_tag_name(keyword)?;
+    let alias_target = aliases
+        .get(keyword)
+        .or_else(|| aliases.get(&keyword.to_lowercase()))
+        .or_else(|| aliases.get(&tag));
+    match alias_target {
+        Some(alias_target) if is_tag_name_valid(alias_target) && !is_reserved_tag(alias_target) => {
+            Some(alias_target.clone())
+        }
+        _ => Some(tag),
+    }
+}
+
 /// Returns the first 16 characters of the file hash in a hexadecimal string.
 /// Used for file names in the database.
 pub fn trimmed_hash_hex(hash: &Vec<u8>) -> Result<String> {
@@ -57,6 +122,60 @@ mod tests {
         assert!(is_tag_name_valid("B4W"));
         assert!(is_tag_name_valid("B4Wk11"));
         assert!(is_tag_name_valid("11B4Wk"));
+        assert!(is_tag_name_valid("system:ext:png"));
+        assert!(is_tag_name_valid("富士山"));
+        assert!(is_tag_name_valid("character:ёлка-2"));
+    }
+
+    #[test]
+    fn should_sanitise_tags() {
+        assert_eq!(sanitise_tag_name("Japan"), Some("japan".to_owned()));
+        assert_eq!(sanitise_tag_name("2023"), Some("2023".to_owned()));
+        assert_eq!(
+            sanitise_tag_name("  Summer Holiday. 2023 "),
+            Some("summer_holiday_2023".to_owned())
+        );
+        assert_eq!(sanitise_tag_name("_café-"), Some("café".to_owned()));
+        assert_eq!(
+            sanitise_tag_name("東京タワー"),
+            Some("東京タワー".to_owned())
+        );
+        assert_eq!(
+            sanitise_tag_name("Москва Сити"),
+            Some("москва_сити".to_owned())
+        );
+        assert_eq!(
+            sanitise_tag_name("Rock ★ Roll"),
+            Some("rock_roll".to_owned())
+        );
+        assert_eq!(sanitise_tag_name("..."), None);
+        assert_eq!(sanitise_tag_name(""), None);
+    }
+
+    #[test]
+    fn should_turn_keywords_into_tags() {
+        let aliases = HashMap::from([("fuji".to_owned(), "mount_fuji".to_owned())]);
+        assert_eq!(
+            keyword_to_tag("Fuji", &aliases),
+            Some("mount_fuji".to_owned())
+        );
+        assert_eq!(
+            keyword_to_tag("Rock & Roll", &aliases),
+            Some("rock_roll".to_owned())
+        );
+        assert_eq!(keyword_to_tag("???", &aliases), None);
+
+        let aliases = HashMap::from([("sky".to_owned(), "color:blue".to_owned())]);
+        assert_eq!(keyword_to_tag("Sky", &aliases), Some("sky".to_owned()));
+    }
+
+    #[test]
+    fn should_reserve_search_prefixes() {
+        assert!(is_reserved_tag("color:red"));
+        assert!(is_reserved_tag("similar:cat"));
+        assert!(!is_reserved_tag("colorful"));
+        assert!(!is_reserved_tag("hair_color:red"));
+        assert_eq!(sanitise_tag_name("color:red"), Some("colorred".to_owned()));
     }
 
     #[test]
@@ -80,5 +199,8 @@ mod tests {
         assert!(!is_tag_name_valid("---"));
         assert!(!is_tag_name_valid("__"));
         assert!(!is_tag_name_valid("___"));
+        assert!(!is_tag_name_valid("system:"));
+        assert!(!is_tag_name_valid(":BAWK"));
+        assert!(!is_tag_name_valid("system::BAWK"));
     }
 }
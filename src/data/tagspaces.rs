@@ -0,0 +1,181 @@
+//! TagSpaces compatibility. TagSpaces stores the tags of a file in a `.ts/<file>.json`
+//! sidecar in the file's folder, or in the file name itself (`photo[cat sleeping].jpg`).
+//! Both are read when importing with `ImportOptions::tagspaces`, and `export_folder()`
+//! writes the sidecars back out.
+use crate::data::{tag_file::TagFile, tag_util};
+use crate::database::tagmaid_database::TagMaidDatabase;
+use anyhow::{Context, Result};
+use log::*;
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Folder holding the sidecars, in every tagged folder
+pub const TAGSPACES_FOLDER: &str = ".ts";
+
+/// `<folder>/.ts/<file name>.json` for the file at `path`
+pub fn sidecar_path(path: &Path) -> Option<PathBuf> {
+    let file_name = path.file_name()?.to_string_lossy();
+    let folder = path.parent()?.join(TAGSPACES_FOLDER);
+    Some(folder.join(format!("{file_name}.json")))
+}
+
+/// Whether `path` is in a `.ts` folder, where only TagSpaces' own files are
+pub fn is_tagspaces_file(path: &Path) -> bool {
+    path.components()
+        .any(|component| component.as_os_str() == TAGSPACES_FOLDER)
+}
+
+/// Tags in the file name, between brackets right before the extension
+pub fn file_name_tags(path: &Path) -> Vec<String> {
+    let stem = match path.file_stem() {
+        Some(stem) => stem.to_string_lossy(),
+        None => return Vec::new(),
+    };
+    let suffix_regex = Regex::new(r"\[([^\[\]]*)\]$").unwrap();
+    match suffix_regex.captures(&stem) {
+        Some(captures) => captures[1].split_whitespace().map(String::from).collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Tags (their `title`) in the sidecar of the file at `path`
+pub fn sidecar_tags(path: &Path) -> Vec<String> {
+    let sidecar = sidecar_path(path)
+        .and_then(|sidecar_path| std::fs::read_to_string(sidecar_path).ok())
+        .and_then(|sidecar| serde_json::from_str::<Value>(&sidecar).ok());
+    match sidecar
+        .as_ref()
+        .and_then(|sidecar| sidecar["tags"].as_array())
+    {
+        Some(tags) => tags
+            .iter()
+            .filter_map(|tag| tag["title"].as_str())
+            .map(String::from)
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Tags from both the sidecar and the file name, as TagSpaces wrote them (they may not
+/// be valid tag names)
+pub fn tags(path: &Path) -> Vec<String> {
+    let mut tags = sidecar_tags(path);
+    tags.extend(file_name_tags(path));
+    tags
+}
+
+/// Writes `tags` in the sidecar of the file at `path`. The rest of an existing sidecar
+/// (description, tag colours...) is kept.
+pub fn write_sidecar(path: &Path, tags: &[String]) -> Result<()> {
+    let sidecar_path = sidecar_path(path).context("Invalid file path")?;
+    let mut sidecar: Map<String, Value> = std::fs::read_to_string(&sidecar_path)
+        .ok()
+        .and_then(|sidecar| serde_json::from_str(&sidecar).ok())
+        .unwrap_or_else(|| {
+            let mut sidecar = Map::new();
+            sidecar.insert("appName".to_owned(), json!("TagMaid"));
+            sidecar
+        });
+
+    let old_tags: Vec<Value> = match sidecar.get("tags") {
+        Some(Value::Array(old_tags)) => old_tags.clone(),
+        _ => Vec::new(),
+    };
+    let new_tags: Vec<Value> = tags
+        .iter()
+        .map(|tag| {
+            // Tags imported from TagSpaces keep their title, like `Holiday Trip`
+            old_tags
+                .iter()
+                .find(|old_tag| {
+                    old_tag["title"]
+                        .as_str()
+                        .and_then(tag_util::sanitise_tag_name)
+                        == Some(tag.to_owned())
+                })
+                .cloned()
+                .unwrap_or_else(|| json!({ "title": tag, "type": "sidecar" }))
+        })
+        .collect();
+    sidecar.insert("tags".to_owned(), Value::Array(new_tags));
+    sidecar.insert(
+        "lastUpdated".to_owned(),
+        json!(chrono::Utc::now().timestamp_millis()),
+    );
+
+    std::fs::create_dir_all(sidecar_path.parent().unwrap())?;
+    std::fs::write(&sidecar_path, serde_json::to_string_pretty(&sidecar)?)
+        .with_context(|| format!("Couldn't write '{}'", sidecar_path.display()))
+}
+
+/// Writes TagSpaces sidecars for every file under `root` that is in the library, with
+/// its tags (system tags and the ones already in its file name aside). Returns how many
+/// sidecars were written.
+pub fn export_folder(db: &TagMaidDatabase, root: &Path) -> Result<usize> {
+    let mut exported = 0;
+    for entry in WalkDir::new(root).sort_by_file_name() {
+        let entry = entry.with_context(|| format!("Couldn't read '{}'", root.display()))?;
+        let path = entry.path();
+        if !entry.file_type().is_file() || is_tagspaces_file(path) {
+            continue;
+        }
+        let hash = TagFile::initialise_from_path(path)?.file_hash;
+        let tagfile = match db.get_tagfile_from_hash(&hash) {
+            Ok(tagfile) => tagfile,
+            Err(..) => continue,
+        };
+        let name_tags: Vec<String> = file_name_tags(path)
+            .iter()
+            .filter_map(|tag| tag_util::sanitise_tag_name(tag))
+            .collect();
+        let mut tags: Vec<String> = tagfile
+            .get_tags()
+            .iter()
+            .filter(|tag| !tag_util::is_system_tag(tag) && !name_tags.contains(tag))
+            .cloned()
+            .collect();
+        if tags.is_empty() && !sidecar_path(path).is_some_and(|sidecar| sidecar.exists()) {
+            continue;
+        }
+        tags.sort();
+        write_sidecar(path, &tags)?;
+        exported += 1;
+    }
+    info!("tagspaces - export_folder() - Wrote {exported} sidecars");
+    Ok(exported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_read_tagspaces_tags() {
+        let folder = tempfile::tempdir().unwrap().keep();
+        let path = folder.join("holiday[Beach summer-2023].jpg");
+        std::fs::write(&path, "beach").unwrap();
+        std::fs::create_dir(folder.join(".ts")).unwrap();
+        std::fs::write(
+            folder.join(".ts/holiday[Beach summer-2023].jpg.json"),
+            r##"{"appName": "TagSpaces", "description": "Nice",
+                "tags": [{"title": "Sea Side", "color": "#008000", "type": "sidecar"}]}"##,
+        )
+        .unwrap();
+
+        assert_eq!(tags(&path), vec!["Sea Side", "Beach", "summer-2023"]);
+        assert!(file_name_tags(Path::new("holiday.jpg")).is_empty());
+        assert!(is_tagspaces_file(&folder.join(".ts/x.json")));
+
+        write_sidecar(&path, &["new_tag".to_owned(), "sea_side".to_owned()]).unwrap();
+        let sidecar: Value =
+            serde_json::from_str(&std::fs::read_to_string(sidecar_path(&path).unwrap()).unwrap())
+                .unwrap();
+        assert_eq!(sidecar["description"], "Nice");
+        assert_eq!(sidecar["tags"][0]["title"], "new_tag");
+        // Tags that were already there are kept as they were
+        assert_eq!(sidecar["tags"][1]["title"], "Sea Side");
+        assert_eq!(sidecar["tags"][1]["color"], "#008000");
+    }
+}
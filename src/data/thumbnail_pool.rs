@@ -0,0 +1,244 @@
+//! A fixed number of threads making the grid thumbnails of search results.
+//!
+//! Each search hands its results to `ThumbnailPool::queue()`, which drops whatever the
+//! previous search still had waiting. Thumbnails are made in the order of the results,
+//! except for the ones `prioritise()` was called with (the rows on screen), which go first.
+use crate::data::thumbnails::{ThumbnailSize, ThumbnailStore};
+use crate::database::tagmaid_database::TagMaidDatabase;
+use log::*;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+
+/// Called by the workers every time a thumbnail is done, to redraw the UI for instance
+pub type Notify = Arc<dyn Fn() + Send + Sync>;
+
+// Lowest first: (0 if prioritised, position, file hash)
+type QueuedJob = Reverse<(u8, u64, Vec<u8>)>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ThumbnailProgress {
+    pub done: usize,
+    pub total: usize,
+}
+
+impl ThumbnailProgress {
+    pub fn is_done(&self) -> bool {
+        self.done >= self.total
+    }
+
+    pub fn fraction(&self) -> f32 {
+        match self.total {
+            0 => 1.0,
+            total => self.done as f32 / total as f32,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    // May hold the same hash twice once it is prioritised, `waiting` tells which one counts
+    queue: BinaryHeap<QueuedJob>,
+    waiting: HashSet<Vec<u8>>,
+    prioritised: HashSet<Vec<u8>>,
+    // Every thumbnail made so far, an empty path for files that aren't images
+    paths: HashMap<Vec<u8>, Arc<PathBuf>>,
+    // Bumped by every `queue()`, so that jobs of older searches don't count as progress
+    batch: u64,
+    next_position: u64,
+    progress: ThumbnailProgress,
+    stop: bool,
+}
+
+impl State {
+    fn push(&mut self, priority: u8, hash: Vec<u8>) {
+        self.queue
+            .push(Reverse((priority, self.next_position, hash)));
+        self.next_position += 1;
+    }
+
+    fn queue(&mut self, hashes: &[Vec<u8>]) {
+        self.queue.clear();
+        self.waiting.clear();
+        self.prioritised.clear();
+        self.batch += 1;
+        self.progress = ThumbnailProgress {
+            done: 0,
+            total: hashes.len(),
+        };
+        for hash in hashes {
+            if self.paths.contains_key(hash) {
+                self.progress.done += 1;
+            } else if self.waiting.insert(hash.clone()) {
+                self.push(1, hash.clone());
+            } else {
+                // The same file twice in the results
+                self.progress.total -= 1;
+            }
+        }
+    }
+
+    fn prioritise(&mut self, hashes: &[Vec<u8>]) -> bool {
+        let mut moved = false;
+        for hash in hashes {
+            if self.waiting.contains(hash) && self.prioritised.insert(hash.clone()) {
+                self.push(0, hash.clone());
+                moved = true;
+            }
+        }
+        moved
+    }
+
+    /// The next file to make a thumbnail of, and the batch it belongs to
+    fn take(&mut self) -> Option<(Vec<u8>, u64)> {
+        while let Some(Reverse((_priority, _position, hash))) = self.queue.pop() {
+            if self.waiting.remove(&hash) {
+                return Some((hash, self.batch));
+            }
+        }
+        None
+    }
+
+    fn finish(&mut self, hash: Vec<u8>, batch: u64, path: PathBuf) {
+        self.paths.insert(hash, Arc::new(path));
+        if batch == self.batch {
+            self.progress.done += 1;
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    wake_up: Condvar,
+}
+
+pub struct ThumbnailPool {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ThumbnailPool {
+    /// Starts `workers` threads (at least one) making `size` thumbnails into `store`
+    pub fn new(
+        db: TagMaidDatabase,
+        store: ThumbnailStore,
+        size: ThumbnailSize,
+        workers: usize,
+        notify: Notify,
+    ) -> ThumbnailPool {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            wake_up: Condvar::new(),
+        });
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let shared = shared.clone();
+                let db = db.clone();
+                let store = store.clone();
+                let notify = notify.clone();
+                std::thread::spawn(move || work(&shared, &db, &store, size, &notify))
+            })
+            .collect();
+        ThumbnailPool { shared, workers }
+    }
+
+    /// Replaces the files waiting for a thumbnail with `hashes`, in that order
+    pub fn queue(&self, hashes: &[Vec<u8>]) {
+        self.shared.state.lock().unwrap().queue(hashes);
+        self.shared.wake_up.notify_all();
+    }
+
+    /// Moves the files of `hashes` that are still waiting ahead of the others
+    pub fn prioritise(&self, hashes: &[Vec<u8>]) {
+        let moved = self.shared.state.lock().unwrap().prioritise(hashes);
+        if moved {
+            self.shared.wake_up.notify_all();
+        }
+    }
+
+    /// The thumbnail of the file with hash `hash`, if it was made already. The path is
+    /// empty if the file isn't an image.
+    pub fn get(&self, hash: &Vec<u8>) -> Option<Arc<PathBuf>> {
+        self.shared.state.lock().unwrap().paths.get(hash).cloned()
+    }
+
+    /// How far along the thumbnails of the last `queue()` are
+    pub fn progress(&self) -> ThumbnailProgress {
+        self.shared.state.lock().unwrap().progress
+    }
+}
+
+impl Drop for ThumbnailPool {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().stop = true;
+        self.shared.wake_up.notify_all();
+        for worker in self.workers.drain(..) {
+            worker.join().ok();
+        }
+    }
+}
+
+fn work(
+    shared: &Shared,
+    db: &TagMaidDatabase,
+    store: &ThumbnailStore,
+    size: ThumbnailSize,
+    notify: &Notify,
+) {
+    loop {
+        let (hash, batch) = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                if state.stop {
+                    return;
+                }
+                if let Some(job) = state.take() {
+                    break job;
+                }
+                state = shared.wake_up.wait(state).unwrap();
+            }
+        };
+        let path = match db.get_tagfile_from_hash(&hash) {
+            Ok(tagfile) => store.get(&tagfile, size).unwrap_or_default(),
+            Err(err) => {
+                info!("WARNING: thumbnail_pool::work(): Couldn't find file: {err:#}");
+                PathBuf::new()
+            }
+        };
+        shared.state.lock().unwrap().finish(hash, batch, path);
+        notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_queue_prioritise_and_cancel() {
+        let hashes: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i]).collect();
+        let mut state = State::default();
+        state.queue(&hashes);
+        state.prioritise(&[vec![3], vec![4]]);
+        let (first, batch) = state.take().unwrap();
+        assert_eq!(first, vec![3]);
+        state.finish(first, batch, PathBuf::from("3.webp"));
+        assert_eq!(state.take().unwrap().0, vec![4]);
+        // The prioritised entries don't come back a second time
+        assert_eq!(state.take().unwrap().0, vec![0]);
+        assert_eq!(state.progress, ThumbnailProgress { done: 1, total: 5 });
+
+        // A new search drops what was waiting, and an old job finishing doesn't count
+        state.queue(&[vec![3], vec![7]]);
+        assert_eq!(state.progress, ThumbnailProgress { done: 1, total: 2 });
+        state.finish(vec![0], batch, PathBuf::new());
+        assert_eq!(state.progress.done, 1);
+        let (last, batch) = state.take().unwrap();
+        assert_eq!(last, vec![7]);
+        assert_eq!(state.take(), None);
+        state.finish(last, batch, PathBuf::from("7.webp"));
+        assert!(state.progress.is_done());
+    }
+}
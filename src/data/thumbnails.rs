@@ -0,0 +1,341 @@
+//! Thumbnails of the library's images, kept apart from the stored files in
+//! `thumbs/<file hash>-<size>.webp`, size being the largest side in pixels.
+//!
+//! There is one size for the results grid, twice that for HiDPI screens, and a bigger one
+//! for the View page (see `ThumbnailConfig`). Thumbnails are made the first time they are
+//! asked for. Since the size is part of the name, changing the sizes in the config makes
+//! new ones; `collect_garbage()` deletes the ones of other sizes and of removed files.
+use crate::data::{tag_file::TagFile, tag_util, ui_util};
+use crate::database::tagmaid_database::TagMaidDatabase;
+use anyhow::{Context, Result};
+use image::{DynamicImage, ImageOutputFormat};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+
+pub const THUMBNAILS_FOLDER: &str = "thumbs";
+const EXTENSION: &str = "webp";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThumbnailConfig {
+    /// Largest side of the thumbnails of the results grid, in pixels
+    pub grid: u32,
+    /// Largest side of the images of the View page, in pixels
+    pub preview: u32,
+    /// How many threads make the thumbnails of search results
+    pub workers: usize,
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> Self {
+        ThumbnailConfig {
+            grid: 100,
+            preview: 650,
+            workers: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbnailSize {
+    Grid,
+    /// Twice the grid size, for screens with more than one pixel per point
+    GridHiDpi,
+    Preview,
+}
+
+impl ThumbnailSize {
+    /// Parses `grid`, `hidpi` or `preview`
+    pub fn from_name(name: &str) -> Option<ThumbnailSize> {
+        match name {
+            "grid" => Some(ThumbnailSize::Grid),
+            "hidpi" => Some(ThumbnailSize::GridHiDpi),
+            "preview" => Some(ThumbnailSize::Preview),
+            _ => None,
+        }
+    }
+
+    /// The grid size fitting a screen with `pixels_per_point`
+    pub fn grid_for(pixels_per_point: f32) -> ThumbnailSize {
+        match pixels_per_point > 1.0 {
+            true => ThumbnailSize::GridHiDpi,
+            false => ThumbnailSize::Grid,
+        }
+    }
+}
+
+impl ThumbnailConfig {
+    pub fn pixels(&self, size: ThumbnailSize) -> u32 {
+        match size {
+            ThumbnailSize::Grid => self.grid,
+            ThumbnailSize::GridHiDpi => self.grid * 2,
+            ThumbnailSize::Preview => self.preview,
+        }
+    }
+
+    /// Every size in use, in pixels
+    pub fn all_pixels(&self) -> BTreeSet<u32> {
+        [
+            ThumbnailSize::Grid,
+            ThumbnailSize::GridHiDpi,
+            ThumbnailSize::Preview,
+        ]
+        .into_iter()
+        .map(|size| self.pixels(size))
+        .collect()
+    }
+}
+
+fn thumbnail_name(hash: &Vec<u8>, pixels: u32) -> String {
+    format!("{}-{pixels}.{EXTENSION}", tag_util::bytes_to_hex(hash))
+}
+
+/// The file hash (in hexadecimal) and size of a thumbnail, from its file name
+fn parse_thumbnail_name(file_name: &str) -> Option<(&str, u32)> {
+    let stem = file_name.strip_suffix(&format!(".{EXTENSION}"))?;
+    let (hash, pixels) = stem.rsplit_once('-')?;
+    if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some((hash, pixels.parse().ok()?))
+}
+
+/// Deletes every thumbnail of the file with hash `hash` from the library at
+/// `library_path`, whatever its size. Returns how many were deleted.
+pub fn remove(library_path: &Path, hash: &Vec<u8>) -> Result<usize> {
+    let folder = library_path.join(THUMBNAILS_FOLDER);
+    if !folder.is_dir() {
+        return Ok(0);
+    }
+    let hash = tag_util::bytes_to_hex(hash);
+    let mut removed = 0;
+    for entry in std::fs::read_dir(&folder)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if parse_thumbnail_name(&file_name).is_some_and(|(other, _pixels)| other == hash) {
+            std::fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Deletes a thumbnail found by `ThumbnailStore::collect_garbage()`, returns whether it
+/// was deleted
+fn remove_garbage(path: &Path) -> bool {
+    match std::fs::remove_file(path) {
+        Ok(()) => true,
+        Err(err) => {
+            info!(
+                "WARNING: collect_garbage(): Couldn't delete '{}': {err}",
+                path.display()
+            );
+            false
+        }
+    }
+}
+
+/// The thumbnails of a library. Cheap to clone, to hand it to threads.
+#[derive(Debug, Clone)]
+pub struct ThumbnailStore {
+    folder: PathBuf,
+    config: ThumbnailConfig,
+}
+
+impl ThumbnailStore {
+    pub fn new(library_path: &Path, config: &ThumbnailConfig) -> ThumbnailStore {
+        ThumbnailStore {
+            folder: library_path.join(THUMBNAILS_FOLDER),
+            config: config.clone(),
+        }
+    }
+
+    pub fn config(&self) -> &ThumbnailConfig {
+        &self.config
+    }
+
+    /// Where the thumbnail of the file with hash `hash` is, whether it was made yet or not
+    pub fn path(&self, hash: &Vec<u8>, size: ThumbnailSize) -> PathBuf {
+        self.folder
+            .join(thumbnail_name(hash, self.config.pixels(size)))
+    }
+
+    /// The thumbnail of `tagfile`, made if it doesn't exist yet. `None` if the file isn't
+    /// an image that can be read.
+    pub fn get(&self, tagfile: &TagFile, size: ThumbnailSize) -> Option<PathBuf> {
+        let path = self.path(&tagfile.file_hash, size);
+        if path.is_file() {
+            return Some(path);
+        }
+        let image = ui_util::open_image(tagfile.get_path())?;
+        match self.write(&image, &path, self.config.pixels(size)) {
+            Ok(()) => Some(path),
+            Err(err) => {
+                info!("WARNING: ThumbnailStore::get(): Couldn't make a thumbnail of {tagfile}: {err:#}");
+                None
+            }
+        }
+    }
+
+    fn write(&self, image: &DynamicImage, path: &Path, pixels: u32) -> Result<()> {
+        std::fs::create_dir_all(&self.folder)?;
+        let thumbnail = image.thumbnail(pixels, pixels);
+        let thumbnail = match thumbnail.color().has_alpha() {
+            true => DynamicImage::ImageRgba8(thumbnail.to_rgba8()),
+            false => DynamicImage::ImageRgb8(thumbnail.to_rgb8()),
+        };
+        // Written under another name first, so that a thumbnail being made by another
+        // thread is never read half written
+        let mut file = tempfile::NamedTempFile::new_in(&self.folder)?;
+        thumbnail.write_to(file.as_file_mut(), ImageOutputFormat::WebP)?;
+        file.persist(path)
+            .with_context(|| format!("Couldn't write '{}'", path.display()))?;
+        Ok(())
+    }
+
+    /// Makes the thumbnails of every size of every image in the library that doesn't have
+    /// them yet. Returns how many files were looked at.
+    pub fn generate_all(&self, db: &TagMaidDatabase) -> Result<usize> {
+        let hashes = db.get_file_ids()?;
+        for hash in hashes.keys() {
+            let tagfile = db.get_tagfile_from_hash(hash)?;
+            for size in [
+                ThumbnailSize::Grid,
+                ThumbnailSize::GridHiDpi,
+                ThumbnailSize::Preview,
+            ] {
+                // Files that aren't images are skipped as soon as they can't be read
+                if self.get(&tagfile, size).is_none() {
+                    break;
+                }
+            }
+        }
+        Ok(hashes.len())
+    }
+
+    /// Deletes the thumbnails of files that aren't in the library anymore and of sizes not
+    /// in use, as well as the `thumb_<name>.jpg` files older versions put in `files/`.
+    /// Other files (thumbnails still being written) are left alone, and files that can't
+    /// be deleted are skipped. Returns how many were deleted.
+    pub fn collect_garbage(&self, db: &TagMaidDatabase) -> Result<usize> {
+        let hashes: HashSet<String> = db
+            .get_file_ids()?
+            .into_keys()
+            .map(|hash| tag_util::bytes_to_hex(&hash))
+            .collect();
+        let all_pixels = self.config.all_pixels();
+        let mut removed = 0;
+        if self.folder.is_dir() {
+            for entry in std::fs::read_dir(&self.folder)?.flatten() {
+                let file_name = entry.file_name().to_string_lossy().into_owned();
+                let (hash, pixels) = match parse_thumbnail_name(&file_name) {
+                    Some(parsed) => parsed,
+                    None => continue,
+                };
+                // Files added since the list of hashes was read are in use too
+                let in_use = all_pixels.contains(&pixels)
+                    && (hashes.contains(hash)
+                        || hex::decode(hash)
+                            .is_ok_and(|hash| db.get_tagfile_from_hash(&hash).is_ok()));
+                if !in_use && remove_garbage(&entry.path()) {
+                    removed += 1;
+                }
+            }
+        }
+
+        // Old thumbnails, which are never stored files themselves
+        let library_path = db.get_library_path();
+        let stored_paths: HashSet<PathBuf> = db
+            .get_file_ids()?
+            .into_keys()
+            .filter_map(|hash| db.get_tagfile_from_hash(&hash).ok())
+            .map(|tagfile| tagfile.path)
+            .collect();
+        let files_folder = library_path.join("files");
+        if files_folder.is_dir() {
+            for entry in std::fs::read_dir(&files_folder)?.flatten() {
+                let path = entry.path();
+                let file_name = path
+                    .file_name()
+                    .map(|file_name| file_name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let old_thumbnail = file_name.starts_with("thumb_") && file_name.ends_with(".jpg");
+                if old_thumbnail && !stored_paths.contains(&path) && remove_garbage(&path) {
+                    removed += 1;
+                }
+            }
+        }
+        info!("thumbnails - collect_garbage() - Deleted {removed} thumbnails");
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tag_database::TagDatabase;
+
+    #[test]
+    fn should_store_thumbnails_by_hash_and_size() {
+        let folder = tempfile::tempdir().unwrap().keep();
+        let library_path = folder.join("library");
+        let db = TagMaidDatabase::new(TagDatabase::initialise_at(library_path.clone()).unwrap());
+        let add_file = |path: &Path| {
+            let mut tagfile = TagFile::initialise_from_path(path).unwrap();
+            tagfile.tags.insert("test".to_owned());
+            db.update_tagfile(&tagfile).unwrap();
+            db.get_tagfile_from_hash(&tagfile.file_hash).unwrap()
+        };
+        let image_path = folder.join("wide.png");
+        image::RgbImage::new(1000, 500).save(&image_path).unwrap();
+        let image = add_file(&image_path);
+        let notes_path = folder.join("notes.txt");
+        std::fs::write(&notes_path, "notes").unwrap();
+        let notes = add_file(&notes_path);
+
+        let store = ThumbnailStore::new(&library_path, &ThumbnailConfig::default());
+        let grid = store.get(&image, ThumbnailSize::Grid).unwrap();
+        assert_eq!(grid, store.path(&image.file_hash, ThumbnailSize::Grid));
+        assert!(grid.starts_with(library_path.join(THUMBNAILS_FOLDER)));
+        assert!(grid.to_string_lossy().ends_with("-100.webp"));
+        assert_eq!(image::image_dimensions(&grid).unwrap(), (100, 50));
+        let hidpi = store.get(&image, ThumbnailSize::GridHiDpi).unwrap();
+        assert_eq!(image::image_dimensions(&hidpi).unwrap(), (200, 100));
+        assert_eq!(store.get(&notes, ThumbnailSize::Grid), None);
+        // Nothing is added next to the stored files
+        let stored_files = std::fs::read_dir(library_path.join("files")).unwrap();
+        assert_eq!(stored_files.count(), 2);
+
+        // The grid size changed: the old ones go, the HiDPI one of the old size is the new
+        // grid size and stays
+        let legacy_thumbnail = library_path.join("files").join("thumb_wide.jpg");
+        std::fs::write(&legacy_thumbnail, "old").unwrap();
+        // A thumbnail another thread is still writing
+        let being_written = library_path.join(THUMBNAILS_FOLDER).join(".tmpAbC123");
+        std::fs::write(&being_written, "half").unwrap();
+        let config = ThumbnailConfig {
+            grid: 200,
+            ..Default::default()
+        };
+        let store = ThumbnailStore::new(&library_path, &config);
+        assert_eq!(store.collect_garbage(&db).unwrap(), 2);
+        assert!(!grid.exists() && hidpi.exists() && !legacy_thumbnail.exists());
+        assert!(being_written.exists());
+        std::fs::remove_file(&being_written).unwrap();
+        assert_eq!(store.generate_all(&db).unwrap(), 2);
+        assert!(store
+            .path(&image.file_hash, ThumbnailSize::Preview)
+            .is_file());
+
+        // Removing the file from the library removes its thumbnails
+        db.update_tagfile(&TagFile {
+            tags: HashSet::new(),
+            ..image.clone()
+        })
+        .unwrap();
+        let thumbnails = std::fs::read_dir(library_path.join(THUMBNAILS_FOLDER)).unwrap();
+        assert_eq!(thumbnails.count(), 0);
+    }
+}
@@ -0,0 +1,238 @@
+//! Imports files from URLs (direct links to images, videos...): the file is downloaded to a
+//! temporary folder, imported like any other file and its URL is kept as the `url` source
+//! metadata of the file.
+//!
+//! Downloads are limited in size and only some content types are accepted (see
+//! `UrlImportConfig`), so that pasting the URL of a web page doesn't import the page.
+use crate::data::import::{ImportStatus, ImportedFile, Importer};
+use crate::data::mime::{content_type_matches, extension_from_content_type};
+use crate::data::url_util::percent_decode;
+use crate::database::tagmaid_database::TagMaidDatabase;
+use anyhow::{bail, Context, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Source metadata key holding the URL a file was downloaded from
+pub const SOURCE_URL_KEY: &str = "url";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UrlImportConfig {
+    /// In bytes
+    pub max_size: u64,
+    /// Accepted content types, whole types like `image/*` included
+    pub content_types: Vec<String>,
+    /// In seconds, for the whole download
+    pub timeout: u64,
+}
+
+impl Default for UrlImportConfig {
+    fn default() -> Self {
+        UrlImportConfig {
+            max_size: 200 * 1024 * 1024,
+            content_types: vec![
+                "image/*".to_owned(),
+                "video/*".to_owned(),
+                "audio/*".to_owned(),
+            ],
+            timeout: 60,
+        }
+    }
+}
+
+/// A downloaded file, deleted along with its temporary folder when dropped
+#[derive(Debug)]
+pub struct Download {
+    pub path: PathBuf,
+    pub content_type: String,
+    _folder: TempDir,
+}
+
+/// The name the file at `url` is saved as: the last segment of its path, with an extension
+/// matching `content_type` if it has none
+fn file_name_from_url(url: &str, content_type: &str) -> String {
+    let path = url
+        .split(['?', '#'])
+        .next()
+        .unwrap_or_default()
+        .split_once("://")
+        .map_or("", |(_scheme, rest)| rest);
+    let last_segment = match path.split_once('/') {
+        Some((_host, path)) => path.rsplit('/').next().unwrap_or_default(),
+        None => "",
+    };
+    // Only keeps what is safe in a file name on every OS
+    let name: String = percent_decode(last_segment)
+        .chars()
+        .filter(|c| !"/\\:*?\"<>|".contains(*c) && !c.is_control())
+        .collect();
+    let name = name.trim_matches(|c: char| c == '.' || c.is_whitespace());
+    let name = if name.is_empty() { "download" } else { name };
+    match extension_from_content_type(content_type) {
+        Some(extension) if Path::new(name).extension().is_none() => format!("{name}.{extension}"),
+        _ => name.to_owned(),
+    }
+}
+
+/// Downloads the file at `url` to a temporary folder
+pub fn download(url: &str, config: &UrlImportConfig) -> Result<Download> {
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        bail!("Only http:// and https:// URLs can be imported");
+    }
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(config.timeout))
+        .build();
+    let response = match agent.get(url).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(code, _response)) => bail!("The server answered {code}"),
+        Err(err) => return Err(err).with_context(|| format!("Couldn't download '{url}'")),
+    };
+
+    let content_type = response.content_type().to_owned();
+    let accepted = config
+        .content_types
+        .iter()
+        .any(|wanted| content_type_matches(&content_type, wanted));
+    if !accepted {
+        bail!("'{url}' is {content_type}, which isn't accepted");
+    }
+    let too_big = response
+        .header("Content-Length")
+        .and_then(|length| length.parse::<u64>().ok())
+        .is_some_and(|length| length > config.max_size);
+    if too_big {
+        bail!("'{url}' is bigger than {} bytes", config.max_size);
+    }
+
+    let file_name = file_name_from_url(response.get_url(), &content_type);
+    let mut content = Vec::new();
+    // The length can be missing or wrong, so the limit is also checked while reading
+    response
+        .into_reader()
+        .take(config.max_size + 1)
+        .read_to_end(&mut content)
+        .with_context(|| format!("Couldn't download '{url}'"))?;
+    if content.len() as u64 > config.max_size {
+        bail!("'{url}' is bigger than {} bytes", config.max_size);
+    }
+
+    let folder = tempfile::tempdir()?;
+    let path = folder.path().join(file_name);
+    std::fs::write(&path, content)?;
+    debug!(
+        "url_import - download() - Downloaded {url} to {}",
+        path.display()
+    );
+    Ok(Download {
+        path,
+        content_type,
+        _folder: folder,
+    })
+}
+
+/// Downloads and imports the file at `url` with `tags`, and records where it came from.
+/// Fails if the download or the import does (files skipped by rules aren't an error).
+pub fn import_url(
+    db: &TagMaidDatabase,
+    importer: &Importer,
+    url: &str,
+    config: &UrlImportConfig,
+    tags: &HashSet<String>,
+) -> Result<ImportedFile> {
+    let download = download(url, config)?;
+    let imported_file = importer.import_file(&download.path, tags);
+    match &imported_file.status {
+        ImportStatus::Failed(err) => bail!("Couldn't import '{url}': {err}"),
+        ImportStatus::Imported | ImportStatus::Duplicate if !importer.get_options().dry_run => {
+            let source = BTreeMap::from([(SOURCE_URL_KEY.to_owned(), url.to_owned())]);
+            db.set_source_metadata(&imported_file.hash, &source)?;
+        }
+        _ => {}
+    }
+    info!(
+        "url_import - import_url() - {url}: {:?}",
+        imported_file.status
+    );
+    Ok(imported_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::import::ImportOptions;
+    use crate::database::tag_database::test_util::create_random_tagmaiddatabase;
+    use tiny_http::{Header, Response, Server};
+
+    /// Serves a tiny PNG at `/cat`, an HTML page at `/page` and a big PNG at `/big`
+    fn start_server() -> String {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", server.server_addr().to_ip().unwrap());
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let (content_type, body) = match request.url() {
+                    "/cat" => ("image/png", b"not really a png".to_vec()),
+                    "/page" => ("text/html; charset=utf-8", b"<html></html>".to_vec()),
+                    "/big" => ("image/png", vec![0; 2048]),
+                    _ => {
+                        let _ = request.respond(Response::empty(404));
+                        continue;
+                    }
+                };
+                let header = Header::from_bytes("Content-Type", content_type).unwrap();
+                let _ = request.respond(Response::from_data(body).with_header(header));
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn should_name_downloads() {
+        assert_eq!(
+            file_name_from_url("https://example.com/a/Mount%20Fuji.jpg?w=100", "image/jpeg"),
+            "Mount Fuji.jpg"
+        );
+        assert_eq!(
+            file_name_from_url("https://example.com/media/123", "image/png"),
+            "123.png"
+        );
+        assert_eq!(
+            file_name_from_url("https://example.com", "image/png"),
+            "download.png"
+        );
+    }
+
+    #[test]
+    fn should_import_urls() {
+        let db = create_random_tagmaiddatabase();
+        let importer = Importer::new(db.clone(), ImportOptions::default()).unwrap();
+        let address = start_server();
+        let config = UrlImportConfig {
+            max_size: 1024,
+            ..Default::default()
+        };
+        let tags = HashSet::from(["cat".to_owned()]);
+
+        let url = format!("{address}/cat");
+        let imported_file = import_url(&db, &importer, &url, &config, &tags).unwrap();
+        assert_eq!(imported_file.status, ImportStatus::Imported);
+        assert!(imported_file.tags.contains("cat"));
+        let tagfile = db.get_tagfile_from_hash(&imported_file.hash).unwrap();
+        assert_eq!(tagfile.get_file_name(), "cat.png");
+        assert_eq!(
+            db.get_source_metadata(&imported_file.hash).unwrap()[SOURCE_URL_KEY],
+            url
+        );
+
+        for refused in ["/page", "/big", "/missing"] {
+            let url = format!("{address}{refused}");
+            assert!(import_url(&db, &importer, &url, &config, &tags).is_err());
+        }
+        assert!(import_url(&db, &importer, "file:///etc/passwd", &config, &tags).is_err());
+        assert_eq!(db.get_all_tags().unwrap().get("cat"), Some(&1));
+    }
+}
@@ -0,0 +1,370 @@
+//! Watch folders: files that show up or change in the folders listed in `Config` are
+//! imported automatically with the default tags of their folder.
+//!
+//! Folders are monitored with `notify` (inotify on Linux). Events are debounced: a file
+//! is only imported once it stopped changing for `DEBOUNCE_DELAY`, so files that are
+//! still being written (downloads, screenshots being saved...) aren't picked up halfway.
+use crate::data::config::WatchFolder;
+use crate::data::download_sidecar;
+use crate::data::import::{ImportOptions, ImportStatus, Importer};
+use crate::database::tagmaid_database::TagMaidDatabase;
+use anyhow::{Context, Result};
+use log::*;
+use notify::event::{AccessKind, AccessMode};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+pub const DEBOUNCE_DELAY: Duration = Duration::from_secs(2);
+
+/// Extensions of files that browsers and other tools write to before renaming them
+const TEMPORARY_EXTENSIONS: [&str; 5] = ["part", "crdownload", "tmp", "download", "partial"];
+
+/// Keeps track of when files last changed, and hands them over once they have been
+/// left alone for long enough.
+#[derive(Debug)]
+pub struct Debouncer {
+    delay: Duration,
+    pending: HashMap<PathBuf, Instant>,
+}
+
+impl Debouncer {
+    pub fn new(delay: Duration) -> Debouncer {
+        Debouncer {
+            delay,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Records that the file at `path` changed at `now`
+    pub fn record(&mut self, path: PathBuf, now: Instant) {
+        self.pending.insert(path, now);
+    }
+
+    /// Removes and returns the files that haven't changed since `now - delay`
+    pub fn take_ready(&mut self, now: Instant) -> Vec<PathBuf> {
+        let delay = self.delay;
+        let mut ready: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_path, changed)| now.duration_since(**changed) >= delay)
+            .map(|(path, _changed)| path.clone())
+            .collect();
+        for path in ready.iter() {
+            self.pending.remove(path);
+        }
+        ready.sort();
+        ready
+    }
+}
+
+/// Hidden and temporary files are never imported
+fn is_ignored(path: &Path) -> bool {
+    let file_name = match path.file_name() {
+        Some(file_name) => file_name.to_string_lossy(),
+        None => return true,
+    };
+    let temporary = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .is_some_and(|extension| TEMPORARY_EXTENSIONS.contains(&extension.as_str()));
+    file_name.starts_with('.') || file_name.ends_with('~') || temporary
+}
+
+/// A watch folder along with the importer that gives its files the folder's tags
+struct WatchedFolder {
+    folder: WatchFolder,
+    importer: Importer,
+}
+
+/// Watches the folders for as long as it is kept around.
+pub struct FolderWatcher {
+    _watcher: RecommendedWatcher,
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for FolderWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl FolderWatcher {
+    /// Starts watching `folders`. Returns `None` if there is nothing to watch.
+    /// Folders that can't be watched (they don't exist...) are skipped with a warning.
+    /// Files are imported with `options` (from the config), plus the tags of their folder.
+    pub fn start(
+        db: TagMaidDatabase,
+        folders: &[WatchFolder],
+        options: &ImportOptions,
+    ) -> Result<Option<FolderWatcher>> {
+        if folders.is_empty() {
+            return Ok(None);
+        }
+        Self::start_with_delay(db, folders, options, DEBOUNCE_DELAY).map(Some)
+    }
+
+    pub fn start_with_delay(
+        db: TagMaidDatabase,
+        folders: &[WatchFolder],
+        options: &ImportOptions,
+        delay: Duration,
+    ) -> Result<FolderWatcher> {
+        let (sender, receiver) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender)
+            .context("Couldn't initialise file system watcher")?;
+
+        let library_path = db.get_library_path();
+        let mut watched: Vec<WatchedFolder> = Vec::new();
+        for folder in folders {
+            let mode = if folder.recursive {
+                RecursiveMode::Recursive
+            } else {
+                RecursiveMode::NonRecursive
+            };
+            // Events come with canonical paths, so the folders have to be too
+            let path = match std::fs::canonicalize(&folder.path) {
+                Ok(path) => path,
+                Err(err) => {
+                    info!(
+                        "WARNING: FolderWatcher::start(): Can't watch '{}': {err}",
+                        folder.path.display()
+                    );
+                    continue;
+                }
+            };
+            if let Err(err) = watcher.watch(&path, mode) {
+                info!(
+                    "WARNING: FolderWatcher::start(): Can't watch '{}': {err}",
+                    path.display()
+                );
+                continue;
+            }
+            info!("FolderWatcher - start() - Watching {}", path.display());
+
+            let folder_options = ImportOptions {
+                tags: folder.tags.iter().cloned().collect(),
+                ..options.clone()
+            };
+            watched.push(WatchedFolder {
+                folder: WatchFolder {
+                    path,
+                    ..folder.clone()
+                },
+                importer: Importer::new(db.clone(), folder_options)?,
+            });
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        std::thread::spawn(move || {
+            let mut debouncer = Debouncer::new(delay);
+            let tick = (delay / 4).max(Duration::from_millis(50));
+            while !thread_stop.load(Ordering::Relaxed) {
+                match receiver.recv_timeout(tick) {
+                    Ok(Ok(event)) => {
+                        // Reading files to hash them triggers access events too, so only
+                        // the ones telling that a file was written to count
+                        let relevant = matches!(
+                            event.kind,
+                            EventKind::Create(_)
+                                | EventKind::Modify(_)
+                                | EventKind::Access(AccessKind::Close(AccessMode::Write))
+                        );
+                        for path in event.paths {
+                            if relevant && !is_ignored(&path) && !path.starts_with(&library_path) {
+                                debouncer.record(path, Instant::now());
+                            }
+                        }
+                    }
+                    Ok(Err(err)) => info!("WARNING: FolderWatcher: {err}"),
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+                for path in debouncer.take_ready(Instant::now()) {
+                    Self::import(&watched, &path);
+                }
+            }
+            info!("FolderWatcher - Stopped");
+        });
+
+        Ok(FolderWatcher {
+            _watcher: watcher,
+            stop,
+        })
+    }
+
+    /// Imports the file at `path` with the tags of the most specific watch folder it is in
+    fn import(watched: &[WatchedFolder], path: &Path) {
+        // The file may be gone already, or be a folder created in a recursive watch folder
+        if !path.is_file() {
+            return;
+        }
+        let watched_folder = watched
+            .iter()
+            .filter(|watched_folder| {
+                let folder = &watched_folder.folder;
+                match path.parent() {
+                    Some(parent) if folder.recursive => parent.starts_with(&folder.path),
+                    Some(parent) => parent == folder.path,
+                    None => false,
+                }
+            })
+            .max_by_key(|watched_folder| watched_folder.folder.path.components().count());
+        if let Some(watched_folder) = watched_folder {
+            let sidecars_enabled = watched_folder
+                .importer
+                .get_options()
+                .download_sidecars
+                .enabled;
+            // Download sidecars aren't files of their own: one written after its file updates
+            // the file, and one written first (yt-dlp, while the video is still a `.part`) is
+            // read when the file itself shows up
+            let path = if sidecars_enabled && download_sidecar::is_sidecar_name(path) {
+                match download_sidecar::media_path(path) {
+                    Some(media_path) => media_path,
+                    None => return,
+                }
+            } else {
+                path.to_owned()
+            };
+            let imported_file = watched_folder
+                .importer
+                .import_file(&path, &Default::default());
+            if imported_file.status == ImportStatus::Imported {
+                info!("FolderWatcher - Imported {}", path.display());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tag_database::TagDatabase;
+    use rand::distributions::{Alphanumeric, DistString};
+    use std::fs;
+
+    #[test]
+    fn should_wait_for_files_to_settle() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(Duration::from_secs(2));
+        debouncer.record(PathBuf::from("a.png"), start);
+        debouncer.record(PathBuf::from("b.png"), start);
+        // b.png is still being written
+        debouncer.record(PathBuf::from("b.png"), start + Duration::from_secs(1));
+
+        assert!(debouncer
+            .take_ready(start + Duration::from_secs(1))
+            .is_empty());
+        assert_eq!(
+            debouncer.take_ready(start + Duration::from_secs(2)),
+            vec![PathBuf::from("a.png")]
+        );
+        assert_eq!(
+            debouncer.take_ready(start + Duration::from_secs(3)),
+            vec![PathBuf::from("b.png")]
+        );
+        assert!(debouncer
+            .take_ready(start + Duration::from_secs(10))
+            .is_empty());
+    }
+
+    #[test]
+    fn should_ignore_temporary_files() {
+        assert!(is_ignored(Path::new("/downloads/video.mp4.part")));
+        assert!(is_ignored(Path::new("/downloads/image.png.crdownload")));
+        assert!(is_ignored(Path::new("/screenshots/.screenshot.png")));
+        assert!(!is_ignored(Path::new("/screenshots/screenshot.png")));
+    }
+
+    #[test]
+    fn should_import_new_files() {
+        let tmp_path = tempfile::tempdir().unwrap().keep();
+        let random_string = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+        let db = TagMaidDatabase::new(
+            TagDatabase::initialise(random_string, Some(tmp_path.clone())).unwrap(),
+        );
+        let watched_path = tmp_path.join("Screenshots");
+        fs::create_dir(&watched_path).unwrap();
+        let folders = vec![WatchFolder {
+            path: watched_path.clone(),
+            tags: vec!["screenshot".to_owned()],
+            recursive: false,
+        }];
+        let _watcher = FolderWatcher::start_with_delay(
+            db.clone(),
+            &folders,
+            &Default::default(),
+            Duration::from_millis(200),
+        )
+        .unwrap();
+
+        fs::write(watched_path.join("screenshot.png"), "not really a png").unwrap();
+        fs::write(watched_path.join("download.part"), "half a file").unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while db.get_all_tags().unwrap().is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        // Give the temporary file a chance to be (wrongly) imported too
+        std::thread::sleep(Duration::from_millis(500));
+        assert_eq!(db.get_all_tags().unwrap().get("screenshot"), Some(&1));
+    }
+
+    #[test]
+    fn should_wait_for_the_file_of_download_sidecars() {
+        let tmp_path = tempfile::tempdir().unwrap().keep();
+        let random_string = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+        let db = TagMaidDatabase::new(
+            TagDatabase::initialise(random_string, Some(tmp_path.clone())).unwrap(),
+        );
+        let watched_path = tmp_path.join("Videos");
+        fs::create_dir(&watched_path).unwrap();
+        let folders = vec![WatchFolder {
+            path: watched_path.clone(),
+            tags: vec!["video".to_owned()],
+            recursive: false,
+        }];
+        let options = ImportOptions {
+            download_sidecars: download_sidecar::DownloadSidecarConfig {
+                enabled: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let _watcher = FolderWatcher::start_with_delay(
+            db.clone(),
+            &folders,
+            &options,
+            Duration::from_millis(200),
+        )
+        .unwrap();
+
+        // yt-dlp writes the sidecar first, and the video once it is downloaded
+        fs::write(
+            watched_path.join("clip.info.json"),
+            r#"{"uploader": "Cats"}"#,
+        )
+        .unwrap();
+        fs::write(watched_path.join("clip.mp4.part"), "half a video").unwrap();
+        std::thread::sleep(Duration::from_millis(600));
+        assert!(db.get_all_tags().unwrap().is_empty());
+        fs::rename(
+            watched_path.join("clip.mp4.part"),
+            watched_path.join("clip.mp4"),
+        )
+        .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while db.get_all_tags().unwrap().is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        let tags = db.get_all_tags().unwrap();
+        assert_eq!(tags.get("video"), Some(&1));
+        assert_eq!(tags.get("artist:cats"), Some(&1));
+    }
+}
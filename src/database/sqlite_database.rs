@@ -1,12 +1,14 @@
 //! SqliteDatabase is the internal component that handles everything SQL related
 //! to the `sqlite.db` database.
+use crate::data::palette::Palette;
 use crate::data::tag_file::TagFile;
 use crate::database::tag_database::get_database_path;
 use anyhow::{bail, Context, Result};
 use chrono::{DateTime, Utc};
 use log::*;
-use rusqlite::Connection;
-use std::collections::HashSet;
+use rusqlite::backup::Progress;
+use rusqlite::{Connection, DatabaseName};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{self, File, ReadDir};
 use std::path::Path;
 use std::path::PathBuf;
@@ -52,6 +54,9 @@ It has 2 types of tables:
     `file_path`, `file_hash`, `upload_date`, and `tags`.
 2) One table for each tag. Each row in these tables contain the hashes of
     the files which are linked to this tag. These tables are used for searching.
+
+Tag table names are quoted in queries, since tags can start with a digit or
+contain characters like `-` and `'`.
 */
 pub struct SqliteDatabase {
     db: Connection,
@@ -66,7 +71,12 @@ impl SqliteDatabase {
         let mut path: PathBuf =
             get_database_path(custom_path).context("Couldn't find database path")?;
         path.push(name);
-        path.push("sqlite.db");
+        Self::initialise_at(&path)
+    }
+
+    /// Opens the connection to `sqlite.db` inside the library folder `library_path`.
+    pub fn initialise_at(library_path: &Path) -> Result<SqliteDatabase> {
+        let path = library_path.join("sqlite.db");
         debug!(
             "SqliteDatabase - initialise_default() - Opening connection to database at path {}",
             &path.display()
@@ -90,6 +100,48 @@ impl SqliteDatabase {
         )
         .context("Couldn't create '_files' table for database")?;
 
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS _sources (
+                file_hash   BLOB NOT NULL,
+                key         TEXT NOT NULL,
+                value       TEXT NOT NULL,
+                PRIMARY KEY (file_hash, key)
+            )",
+            (),
+        )
+        .context("Couldn't create '_sources' table for database")?;
+
+        // Files removed from the library, so that merging with another library can tell
+        // them apart from files that were never there
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS _removed (
+                file_hash    BLOB PRIMARY KEY,
+                removed_date TIMESTAMP NOT NULL
+            )",
+            (),
+        )
+        .context("Couldn't create '_removed' table for database")?;
+
+        // `phash` is NULL for files that aren't images (see `data::perceptual_hash`)
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS _perceptual_hashes (
+                file_hash   BLOB PRIMARY KEY,
+                phash       INTEGER
+            )",
+            (),
+        )
+        .context("Couldn't create '_perceptual_hashes' table for database")?;
+
+        // `palette` is NULL for files that aren't images (see `data::palette`)
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS _palettes (
+                file_hash   BLOB PRIMARY KEY,
+                palette     TEXT
+            )",
+            (),
+        )
+        .context("Couldn't create '_palettes' table for database")?;
+
         Ok(SqliteDatabase { db: db })
     }
 
@@ -127,6 +179,7 @@ impl SqliteDatabase {
             "INSERT INTO _files (file_name, file_hash, file_path, upload_date, tags) VALUES (?1, ?2, ?3, ?4, ?5)",
             (&file_name, &file_hash, &file_path_str, &now, &file_tags_serialised),
         )?;
+        db.execute("DELETE FROM _removed WHERE file_hash IS (?)", [&file_hash])?;
         Ok(())
     }
 
@@ -164,6 +217,23 @@ impl SqliteDatabase {
                         &file.file_hash
                     )
                 })?;
+                db.execute(
+                    "DELETE FROM _sources WHERE file_hash IS (?)",
+                    [&file.file_hash],
+                )?;
+                db.execute(
+                    "DELETE FROM _perceptual_hashes WHERE file_hash IS (?)",
+                    [&file.file_hash],
+                )?;
+                db.execute(
+                    "DELETE FROM _palettes WHERE file_hash IS (?)",
+                    [&file.file_hash],
+                )?;
+                let now: DateTime<Utc> = SystemTime::now().into();
+                db.execute(
+                    "INSERT OR REPLACE INTO _removed (file_hash, removed_date) VALUES (?1, ?2)",
+                    (&file.file_hash, now.to_rfc3339()),
+                )?;
             }
             None => {}
         }
@@ -171,7 +241,7 @@ impl SqliteDatabase {
         for tag in tags_from_hash_result {
             if (&self.get_hashes_from_tag(&tag)?).contains(&file.file_hash) {
                 // Remove 2)
-                let query = format!("DELETE FROM {tag} WHERE file_hash IS (?)");
+                let query = format!("DELETE FROM \"{tag}\" WHERE file_hash IS (?)");
                 db.execute(query.as_str(), [&file.file_hash])
                     .with_context(|| {
                         format!(
@@ -237,10 +307,224 @@ impl SqliteDatabase {
         }
     }
 
+    /// Returns the date at which the file with the given hash was added to `_files`.
+    pub fn get_upload_date(&self, hash: &Vec<u8>) -> Result<DateTime<Utc>> {
+        Ok(self.get_file_from_hash(hash)?.upload_date)
+    }
+
+    /// Overrides the upload date of the file with the given hash (used when migrating from
+    /// other tools, which know when the file was first added).
+    pub fn set_upload_date(&self, hash: &Vec<u8>, upload_date: &DateTime<Utc>) -> Result<()> {
+        let db: &Connection = &self.db;
+        let updated = db.execute(
+            "UPDATE _files SET upload_date = ?1 WHERE file_hash IS ?2",
+            (upload_date.to_rfc3339(), hash),
+        )?;
+        if updated == 0 {
+            bail!("No file with hash '{:?}'", hash);
+        }
+        Ok(())
+    }
+
+    /// Sets source metadata (where the file was downloaded from...) of the file with the
+    /// given hash. Keys that are already set are overwritten, the others are kept.
+    pub fn set_source_metadata(
+        &self,
+        hash: &Vec<u8>,
+        source: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        let db: &Connection = &self.db;
+        for (key, value) in source.iter() {
+            db.execute(
+                "INSERT OR REPLACE INTO _sources (file_hash, key, value) VALUES (?1, ?2, ?3)",
+                (hash, key, value),
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn get_source_metadata(&self, hash: &Vec<u8>) -> Result<BTreeMap<String, String>> {
+        let db: &Connection = &self.db;
+        let mut quer = db.prepare("SELECT key, value FROM _sources WHERE file_hash IS ?1")?;
+        let rows = quer.query_map([hash], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let mut source = BTreeMap::new();
+        for row in rows {
+            let (key, value): (String, String) = row?;
+            source.insert(key, value);
+        }
+        Ok(source)
+    }
+
+    /// Sets the perceptual hash of the file with the given hash, `None` meaning that it
+    /// isn't an image.
+    pub fn set_perceptual_hash(&self, hash: &Vec<u8>, phash: Option<u64>) -> Result<()> {
+        let db: &Connection = &self.db;
+        // SQLite integers are signed, the bits are what matters
+        db.execute(
+            "INSERT OR REPLACE INTO _perceptual_hashes (file_hash, phash) VALUES (?1, ?2)",
+            (hash, phash.map(|phash| phash as i64)),
+        )?;
+        Ok(())
+    }
+
+    /// Returns the perceptual hash of every file that has been looked at, keyed by file hash.
+    pub fn get_perceptual_hashes(&self) -> Result<HashMap<Vec<u8>, Option<u64>>> {
+        let db: &Connection = &self.db;
+        let mut quer = db.prepare("SELECT file_hash, phash FROM _perceptual_hashes")?;
+        let rows = quer.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let mut phashes = HashMap::new();
+        for row in rows {
+            let (hash, phash): (Vec<u8>, Option<i64>) = row?;
+            phashes.insert(hash, phash.map(|phash| phash as u64));
+        }
+        Ok(phashes)
+    }
+
+    /// Sets the colour palette of the file with the given hash, `None` meaning that it
+    /// isn't an image.
+    pub fn set_palette(&self, hash: &Vec<u8>, palette: Option<&Palette>) -> Result<()> {
+        let db: &Connection = &self.db;
+        let palette = match palette {
+            Some(palette) => Some(serde_json::to_string(palette)?),
+            None => None,
+        };
+        db.execute(
+            "INSERT OR REPLACE INTO _palettes (file_hash, palette) VALUES (?1, ?2)",
+            (hash, palette),
+        )?;
+        Ok(())
+    }
+
+    /// Returns the palette of the file with the given hash. `None` if it isn't an image or
+    /// if its palette wasn't extracted yet.
+    pub fn get_palette(&self, hash: &Vec<u8>) -> Result<Option<Palette>> {
+        let db: &Connection = &self.db;
+        let mut quer = db.prepare("SELECT palette FROM _palettes WHERE file_hash = (?)")?;
+        let mut rows = quer.query([hash])?;
+        let palette: Option<String> = match rows.next()? {
+            Some(row) => row.get(0)?,
+            None => None,
+        };
+        match palette {
+            Some(palette) => Ok(Some(serde_json::from_str(&palette)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the palette of every file that has been looked at, keyed by file hash.
+    pub fn get_palettes(&self) -> Result<HashMap<Vec<u8>, Option<Palette>>> {
+        let db: &Connection = &self.db;
+        let mut quer = db.prepare("SELECT file_hash, palette FROM _palettes")?;
+        let rows = quer.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let mut palettes = HashMap::new();
+        for row in rows {
+            let (hash, palette): (Vec<u8>, Option<String>) = row?;
+            let palette = match palette {
+                Some(palette) => Some(serde_json::from_str(&palette)?),
+                None => None,
+            };
+            palettes.insert(hash, palette);
+        }
+        Ok(palettes)
+    }
+
+    /// Returns when each file that was removed from the library (and not added back since)
+    /// was removed, keyed by file hash.
+    pub fn get_removed_files(&self) -> Result<HashMap<Vec<u8>, DateTime<Utc>>> {
+        let db: &Connection = &self.db;
+        let mut quer = db.prepare("SELECT file_hash, removed_date FROM _removed")?;
+        let rows = quer.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let mut removed_files = HashMap::new();
+        for row in rows {
+            let (hash, removed_date): (Vec<u8>, String) = row?;
+            let removed_date = DateTime::parse_from_rfc3339(&removed_date)
+                .with_context(|| format!("Invalid removal date '{removed_date}'"))?;
+            removed_files.insert(hash, removed_date.with_timezone(&Utc));
+        }
+        Ok(removed_files)
+    }
+
+    /// Copies the database to a new database at `path` with SQLite's online backup API,
+    /// which gives a consistent snapshot even while TagMaid is using it.
+    pub fn backup_to(&self, path: &Path) -> Result<()> {
+        self.db
+            .backup(DatabaseName::Main, path, None)
+            .with_context(|| format!("Couldn't back up the database to '{}'", path.display()))
+    }
+
+    /// Replaces the whole content of the database with the one of the database at `path`.
+    pub fn restore_from(&mut self, path: &Path) -> Result<()> {
+        self.db
+            .restore(DatabaseName::Main, path, None::<fn(Progress)>)
+            .with_context(|| format!("Couldn't restore the database from '{}'", path.display()))
+    }
+
+    /// Returns the `id` of every file in `_files`, keyed by file hash.
+    pub fn get_file_ids(&self) -> Result<HashMap<Vec<u8>, u64>> {
+        let db: &Connection = &self.db;
+        let mut quer = db.prepare("SELECT id, file_hash FROM _files")?;
+        let rows = quer.query_map((), |row| Ok((row.get(1)?, row.get(0)?)))?;
+        let mut ids: HashMap<Vec<u8>, u64> = HashMap::new();
+        for row in rows {
+            let (hash, id) = row?;
+            ids.insert(hash, id);
+        }
+        Ok(ids)
+    }
+
+    /// Retrieves the hash of the file with the given `id` in `_files`.
+    pub fn get_hash_from_id(&self, id: u64) -> Result<Vec<u8>> {
+        let db: &Connection = &self.db;
+        db.query_row("SELECT file_hash FROM _files WHERE id = ?", [id], |row| {
+            row.get(0)
+        })
+        .with_context(|| format!("No file found in database with id {id}"))
+    }
+
+    /// Returns true if a table exists for the given tag, which means at least one
+    /// file was tagged with it at some point.
+    pub fn has_tag(&self, tag: &str) -> Result<bool> {
+        let db: &Connection = &self.db;
+        let mut quer =
+            db.prepare("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?")?;
+        let count: u64 = quer.query_row([tag], |row| row.get(0))?;
+        Ok(count > 0)
+    }
+
+    /// Retrieves every tag along with the number of files it is attached to.
+    /// Tag tables are told apart from the internal ones (such as `_files`) by their name,
+    /// since valid tag names can't start with an underscore.
+    pub fn get_all_tags(&self) -> Result<HashMap<String, u64>> {
+        let db: &Connection = &self.db;
+        let mut quer = db.prepare(
+            "SELECT name FROM sqlite_master WHERE type = 'table'
+                AND name NOT LIKE '\\_%' ESCAPE '\\' AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\'",
+        )?;
+        let names = quer
+            .query_map((), |row| row.get::<_, String>(0))
+            .context("Tag table query map failed")?;
+
+        let mut tags: HashMap<String, u64> = HashMap::new();
+        for name in names {
+            let tag = name?;
+            let count: u64 = db
+                .query_row(
+                    format!("SELECT COUNT(*) FROM \"{tag}\"").as_str(),
+                    (),
+                    |row| row.get(0),
+                )
+                .with_context(|| format!("Couldn't count files in tag table {tag}"))?;
+            if count > 0 {
+                tags.insert(tag, count);
+            }
+        }
+        Ok(tags)
+    }
+
     /// Retrieves every hash contained in a given tag's table. Used for search.
     pub fn get_hashes_from_tag(&self, tag: &str) -> Result<HashSet<Vec<u8>>> {
         let db: &Connection = &self.db;
-        let mut quer = db.prepare(format!("SELECT file_hash FROM {tag}").as_str())
+        let mut quer = db.prepare(format!("SELECT file_hash FROM \"{tag}\"").as_str())
             .with_context(|| format!("SQL search for '{tag}' table failed. The '{tag}' table most likely does not exist"))?;
         let hashes = quer
             .query_map((), |row| Ok(row.get(0)?))
@@ -264,21 +548,37 @@ impl SqliteDatabase {
         Ok(hashes_hashset)
     }
 
-    /// This function does two things:
+    /// This function does three things:
     /// 1) It iterates the file's tags and for each of them adds the file hash
     /// in the corresponding tag table.
-    /// 2) It *updates* (does not add) the `_files` entry which also has an entry
+    /// 2) It removes the file hash from the tables of the tags the file no longer has.
+    /// 3) It *updates* (does not add) the `_files` entry which also has an entry
     /// for tags in each individual file.
     pub fn update_tags_to_file(&self, file: &TagFile) -> Result<()> {
         let db: &Connection = &self.db;
 
+        // Has to be read before the `_files` entry gets overwritten in 3)
+        let old_tags: HashSet<String> = match self.get_tagfile_from_hash(&file.file_hash) {
+            Ok(tagfile) => tagfile.tags,
+            Err(_err) => HashSet::new(),
+        };
+        for tag in old_tags.difference(&file.tags) {
+            info!(
+                "SqliteDatabse - update_tags_to_file() - Removing hash value {:?} from tag table {}",
+                &file.file_hash, &tag
+            );
+            let query = format!("DELETE FROM \"{tag}\" WHERE file_hash IS (?)");
+            db.execute(query.as_str(), [&file.file_hash])
+                .with_context(|| format!("SQLite: Couldn't remove tag from '{tag}' table"))?;
+        }
+
         for tag in &file.tags {
             info!(
                 "SqliteDatabse - update_tags_to_file() - Creating tag table for {} if not exists",
                 &tag
             );
             let query = format!(
-                "CREATE TABLE IF NOT EXISTS {tag} (
+                "CREATE TABLE IF NOT EXISTS \"{tag}\" (
                 id          INTEGER PRIMARY KEY,
                 file_hash   BLOB NOT NULL UNIQUE
             )"
@@ -287,7 +587,7 @@ impl SqliteDatabase {
                 .with_context(|| format!("SQLite: Couldn't create {tag} table for database"))?;
 
             info!("SqliteDatabse - update_tags_to_file() - Inserting hash value {:?} into tag table {}", &file.file_hash, &tag);
-            let query = format!("INSERT OR IGNORE INTO {tag} (file_hash) VALUES (?)");
+            let query = format!("INSERT OR IGNORE INTO \"{tag}\" (file_hash) VALUES (?)");
             db.execute(query.as_str(), [&file.file_hash])
                 .with_context(|| format!("SQLite: Couldn't insert tag into '{tag}' table"))?;
         }
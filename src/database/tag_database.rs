@@ -1,12 +1,13 @@
 //! TagDatabase is the old database interface. It is in the process of being repurposed as the
 //! "filesystem" interface, used for hardlinking files to the database path etc.
+use crate::data::palette::Palette;
 use crate::data::tag_file::TagFile;
 use crate::database::sqlite_database::{SqliteDatabase, TagFileSqlite};
 use anyhow::{bail, Context, Result};
 use chrono::{DateTime, Utc};
 use log::*;
 use rusqlite::Connection;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{self, File, ReadDir};
 use std::path::Path;
 use std::path::PathBuf;
@@ -74,11 +75,15 @@ impl TagDatabase {
 
         // IKA TODO: Handle edge cases for "name" variable
         path.push(&name);
+        Self::initialise_at(path)
+    }
+
+    /// Opens the library folder at `path`, creating it if needed. Its name is the
+    /// name of the folder.
+    pub fn initialise_at(path: PathBuf) -> Result<TagDatabase> {
         if !Path::new(&path).exists() {
-            fs::create_dir(&path).context(format!(
-                "Can't create '{}' folder because it already exists",
-                &path.display()
-            ))?;
+            fs::create_dir_all(&path)
+                .context(format!("Can't create '{}' folder", &path.display()))?;
         }
 
         let mut files_path = path.clone();
@@ -90,8 +95,12 @@ impl TagDatabase {
             ))?;
         }
 
-        let sqlite_databases = SqliteDatabase::initialise(&name, custom_path)?;
+        let sqlite_databases = SqliteDatabase::initialise_at(&path)?;
 
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
         let db_folder = fs::read_dir(&path)?;
         Ok(TagDatabase {
             name: name,
@@ -187,6 +196,142 @@ impl TagDatabase {
         Ok(hashes)
     }
 
+    pub fn set_source_metadata(
+        &self,
+        hash: &Vec<u8>,
+        source: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        let db: &SqliteDatabase = &self.sqlite_database;
+        db.set_source_metadata(hash, source).with_context(|| {
+            format!(
+                "Database: Couldn't set source metadata of file hash {:?}",
+                &hash
+            )
+        })
+    }
+
+    pub fn get_source_metadata(&self, hash: &Vec<u8>) -> Result<BTreeMap<String, String>> {
+        let db: &SqliteDatabase = &self.sqlite_database;
+        db.get_source_metadata(hash).with_context(|| {
+            format!(
+                "Database: Couldn't get source metadata of file hash {:?}",
+                &hash
+            )
+        })
+    }
+
+    pub fn set_perceptual_hash(&self, hash: &Vec<u8>, phash: Option<u64>) -> Result<()> {
+        debug!("TagDatabase - set_perceptual_hash() - hash: {:?}", &hash);
+        let db: &SqliteDatabase = &self.sqlite_database;
+        db.set_perceptual_hash(hash, phash).with_context(|| {
+            format!(
+                "Database: Couldn't set perceptual hash of file hash {:?}",
+                &hash
+            )
+        })
+    }
+
+    pub fn get_perceptual_hashes(&self) -> Result<HashMap<Vec<u8>, Option<u64>>> {
+        info!("TagDatabase - get_perceptual_hashes()");
+        let db: &SqliteDatabase = &self.sqlite_database;
+        db.get_perceptual_hashes()
+            .context("Database: Couldn't get perceptual hashes")
+    }
+
+    pub fn set_palette(&self, hash: &Vec<u8>, palette: Option<&Palette>) -> Result<()> {
+        debug!("TagDatabase - set_palette() - hash: {:?}", &hash);
+        let db: &SqliteDatabase = &self.sqlite_database;
+        db.set_palette(hash, palette)
+            .with_context(|| format!("Database: Couldn't set palette of file hash {:?}", &hash))
+    }
+
+    pub fn get_palette(&self, hash: &Vec<u8>) -> Result<Option<Palette>> {
+        debug!("TagDatabase - get_palette() - hash: {:?}", &hash);
+        let db: &SqliteDatabase = &self.sqlite_database;
+        db.get_palette(hash)
+            .with_context(|| format!("Database: Couldn't get palette of file hash {:?}", &hash))
+    }
+
+    pub fn get_palettes(&self) -> Result<HashMap<Vec<u8>, Option<Palette>>> {
+        info!("TagDatabase - get_palettes()");
+        let db: &SqliteDatabase = &self.sqlite_database;
+        db.get_palettes().context("Database: Couldn't get palettes")
+    }
+
+    pub fn get_removed_files(&self) -> Result<HashMap<Vec<u8>, DateTime<Utc>>> {
+        info!("TagDatabase - get_removed_files()");
+        let db: &SqliteDatabase = &self.sqlite_database;
+        db.get_removed_files()
+            .context("Database: Couldn't get removed files")
+    }
+
+    pub fn backup_database(&self, path: &Path) -> Result<()> {
+        info!("TagDatabase - backup_database() - path: {}", path.display());
+        self.sqlite_database.backup_to(path)
+    }
+
+    pub fn restore_database(&mut self, path: &Path) -> Result<()> {
+        info!(
+            "TagDatabase - restore_database() - path: {}",
+            path.display()
+        );
+        self.sqlite_database.restore_from(path)
+    }
+
+    pub fn get_file_ids(&self) -> Result<HashMap<Vec<u8>, u64>> {
+        info!("TagDatabase - get_file_ids()");
+        let db: &SqliteDatabase = &self.sqlite_database;
+        let ids = db
+            .get_file_ids()
+            .context("Database: Couldn't get file ids")?;
+        Ok(ids)
+    }
+
+    pub fn get_hash_from_id(&self, id: u64) -> Result<Vec<u8>> {
+        debug!("TagDatabase - get_hash_from_id() - id: {}", &id);
+        let db: &SqliteDatabase = &self.sqlite_database;
+        db.get_hash_from_id(id)
+            .with_context(|| format!("Database: Couldn't get hash from file id {}", &id))
+    }
+
+    pub fn has_tag(&self, tag: &str) -> Result<bool> {
+        debug!("TagDatabase - has_tag() - tag: {}", &tag);
+        let db: &SqliteDatabase = &self.sqlite_database;
+        db.has_tag(tag)
+            .with_context(|| format!("Database: Couldn't check if tag {} exists", &tag))
+    }
+
+    pub fn get_all_tags(&self) -> Result<HashMap<String, u64>> {
+        info!("TagDatabase - get_all_tags()");
+        let db: &SqliteDatabase = &self.sqlite_database;
+        let tags = db
+            .get_all_tags()
+            .context("Database: Couldn't get all tags")?;
+        Ok(tags)
+    }
+
+    pub fn get_upload_date(&self, hash: &Vec<u8>) -> Result<DateTime<Utc>> {
+        debug!("TagDatabase - get_upload_date() - hash: {:?}", &hash);
+        let db: &SqliteDatabase = &self.sqlite_database;
+        db.get_upload_date(hash).with_context(|| {
+            format!(
+                "Database: Couldn't get upload date of file hash {:?}",
+                &hash
+            )
+        })
+    }
+
+    pub fn set_upload_date(&self, hash: &Vec<u8>, upload_date: &DateTime<Utc>) -> Result<()> {
+        debug!("TagDatabase - set_upload_date() - hash: {:?}", &hash);
+        let db: &SqliteDatabase = &self.sqlite_database;
+        db.set_upload_date(hash, upload_date).with_context(|| {
+            format!(
+                "Database: Couldn't set upload date of file hash {:?}",
+                &hash
+            )
+        })
+    }
+
     // pub fn cleanup(&self) -> Result<()> {
     //     // Stage 1: Mark for cleanup
     //     let file_hashes = &self.get_all_file_hashes()?;
@@ -217,6 +362,31 @@ impl TagDatabase {
     // }
 }
 
+/// Helpers shared by the tests of the modules working on a library
+#[cfg(test)]
+pub mod test_util {
+    use super::TagDatabase;
+    use crate::data::tag_file::TagFile;
+    use crate::database::tagmaid_database::TagMaidDatabase;
+    use rand::distributions::{Alphanumeric, DistString};
+
+    pub fn create_random_tagmaiddatabase() -> TagMaidDatabase {
+        let tmp_path = tempfile::tempdir().unwrap().keep();
+        let random_string = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+        TagMaidDatabase::new(TagDatabase::initialise(random_string, Some(tmp_path)).unwrap())
+    }
+
+    /// Adds a file named `name` holding `content` to the library, with `tags`
+    pub fn add_file(db: &TagMaidDatabase, name: &str, content: &str, tags: &[&str]) -> TagFile {
+        let path = tempfile::tempdir().unwrap().keep().join(name);
+        std::fs::write(&path, content).unwrap();
+        let mut tagfile = TagFile::initialise_from_path(&path).unwrap();
+        tagfile.tags = tags.iter().map(|tag| tag.to_string()).collect();
+        db.update_tagfile(&tagfile).unwrap();
+        tagfile
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -2,17 +2,25 @@
 //! You probably want to use this if you deal with the files one way or another.
 //! It is built on top of Arc<> and therefore can be cloned cheaply.
 //! It is initialised once in main(), so a full restart would be required to change it.
-use crate::data::{cache::TagMaidCache, tag_file::TagFile};
-use crate::database::tag_database::TagDatabase;
+use crate::data::palette::{self, Palette};
+use crate::data::perceptual_hash;
+use crate::data::tag_sync::TagSync;
+use crate::data::{cache::TagMaidCache, search_command::Search, tag_file::TagFile};
+use crate::data::{tag_util, thumbnails};
+use crate::database::tag_database::{self, TagDatabase};
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
 use log::*;
 use std::cell::RefCell;
-use std::collections::HashSet;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 
 pub struct TagMaidDatabase {
     pub filesystem_db: Arc<Mutex<TagDatabase>>,
     cache: Arc<TagMaidCache>,
+    /// Called whenever the tags of a file change (see `data::tag_sync`)
+    tag_syncs: Arc<RwLock<Vec<Box<dyn TagSync>>>>,
 }
 
 impl Clone for TagMaidDatabase {
@@ -20,23 +28,81 @@ impl Clone for TagMaidDatabase {
         return TagMaidDatabase {
             filesystem_db: Arc::clone(&self.filesystem_db),
             cache: Arc::clone(&self.cache),
+            tag_syncs: Arc::clone(&self.tag_syncs),
         };
     }
 }
 
+// TODO: Put db_name in Config
+const DEFAULT_DB_NAME: &str = "frank";
+
 /// Initialises the database.
 pub fn init() -> TagMaidDatabase {
-    // TODO: Put db_name in Config
-    let db_name = "frank";
+    let db_name = DEFAULT_DB_NAME;
     let filesystem_db: TagDatabase = TagDatabase::initialise(db_name.to_owned(), None).unwrap();
     info!("Initialising TagMaidDatabse of name {db_name}");
-    return TagMaidDatabase {
-        filesystem_db: Arc::new(Mutex::new(filesystem_db)),
-        cache: Arc::new(TagMaidCache::init()),
-    };
+    return TagMaidDatabase::new(filesystem_db);
+}
+
+/// The library folder `init()` opens, for what must happen before the database is opened
+pub fn default_library_path() -> Result<PathBuf> {
+    Ok(tag_database::get_database_path(None)?.join(DEFAULT_DB_NAME))
+}
+
+/// Initialises the database from a library folder chosen by the user.
+pub fn init_at(library_path: PathBuf) -> Result<TagMaidDatabase> {
+    info!(
+        "Initialising TagMaidDatabase at path {}",
+        library_path.display()
+    );
+    let filesystem_db = TagDatabase::initialise_at(library_path)?;
+    Ok(TagMaidDatabase::new(filesystem_db))
 }
 
 impl TagMaidDatabase {
+    /// Wraps an already initialised `TagDatabase`, with an empty cache.
+    pub fn new(filesystem_db: TagDatabase) -> TagMaidDatabase {
+        TagMaidDatabase {
+            filesystem_db: Arc::new(Mutex::new(filesystem_db)),
+            cache: Arc::new(TagMaidCache::init()),
+            tag_syncs: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+
+    /// Sets the backends the tags are mirrored to, usually the ones enabled in `Config`
+    pub fn set_tag_syncs(&self, tag_syncs: Vec<Box<dyn TagSync>>) {
+        *self.tag_syncs.write().unwrap() = tag_syncs;
+    }
+
+    /// Tags that the backends find on the file at `path` (a file about to be imported)
+    pub fn read_synced_tags(&self, path: &Path) -> Vec<String> {
+        let mut tags = Vec::new();
+        for tag_sync in self.tag_syncs.read().unwrap().iter() {
+            match tag_sync.read(path) {
+                Ok(synced_tags) => tags.extend(synced_tags),
+                Err(err) => info!(
+                    "WARNING: read_synced_tags(): {} failed for {}: {err:#}",
+                    tag_sync.name(),
+                    path.display()
+                ),
+            }
+        }
+        tags
+    }
+
+    /// Writes the tags of `tagfile` (a file stored in the library) with every backend.
+    /// Failures are logged rather than returned: the library is up to date anyway.
+    pub fn sync_tags(&self, tagfile: &TagFile) {
+        for tag_sync in self.tag_syncs.read().unwrap().iter() {
+            if let Err(err) = tag_sync.write(tagfile) {
+                info!(
+                    "WARNING: sync_tags(): {} failed for {tagfile}: {err:#}",
+                    tag_sync.name()
+                );
+            }
+        }
+    }
+
     pub fn get_fs_db(&self) -> Arc<Mutex<TagDatabase>> {
         return self.filesystem_db.clone();
     }
@@ -45,7 +111,24 @@ impl TagMaidDatabase {
         return self.cache.clone();
     }
 
+    /// Returns the path of the library folder, which contains `sqlite.db` and `files/`.
+    pub fn get_library_path(&self) -> PathBuf {
+        self.get_fs_db().lock().unwrap().path.clone()
+    }
+
+    /// Adds the file to the library, or saves its tags if it is already in it. A file left
+    /// without any user tag (system tags don't count) is removed from the library.
     pub fn update_tagfile(&self, tf: &TagFile) -> Result<()> {
+        self.write_tagfile(tf, true)
+    }
+
+    /// Same as `update_tagfile()`, but never removes the file, for when only its system tags
+    /// changed (see `data::system_tags::rebuild()`)
+    pub fn update_system_tags(&self, tf: &TagFile) -> Result<()> {
+        self.write_tagfile(tf, false)
+    }
+
+    fn write_tagfile(&self, tf: &TagFile, remove_untagged: bool) -> Result<()> {
         info!("Updating {tf}");
 
         // Clearing search cache; since a file has been
@@ -61,39 +144,94 @@ impl TagMaidDatabase {
             Err(_err) => {}
         }
 
+        // Decoding a large image takes a while, so it is done before locking the database
+        // for everyone else. Computed once and for all, to find near-duplicates and search
+        // by colour later.
+        let is_new = self
+            .get_fs_db()
+            .lock()
+            .unwrap()
+            .sqlite_database
+            .get_tagfile_from_hash(&tf.file_hash)
+            .is_err();
+        let image = match is_new {
+            true => crate::data::ui_util::open_image(&tf.path),
+            false => None,
+        };
+        let phash = image.as_ref().map(perceptual_hash::dhash);
+        let palette = image.as_ref().map(palette::extract);
+        drop(image);
+
         let fs_db_mutex = &self.get_fs_db();
         let fs_db = fs_db_mutex.lock().unwrap();
         let sql_db = &fs_db.sqlite_database;
 
+        // What ends up in the cache: it has to point to the stored file rather
+        // than to the file the user gave us, which may be moved or deleted later
+        let mut cached_tf = tf.clone();
+
         // Sqlite
-        if sql_db.get_tagfile_from_hash(&tf.file_hash).is_err() {
-            // File isn't in db
-            info!("Updating {tf}: File not present in SQL database, uploading it");
-
-            // Filesystem
-            // Uploads file if it doesn't exist
-            let uploaded_file = fs_db.upload_file(tf)?;
-
-            sql_db.add_file(&uploaded_file)?;
-        } else {
-            if (&tf.tags).is_empty() {
-                // File is already in database AND has no tags; we delete
-                info!("Updating {tf}: File has no tags, removing it");
-                sql_db.remove_file(&tf)?;
-
-                // Removing the TagFile from the cache
-                match self.get_cache().clear_tagfile_cache(tf.clone()) {
-                    Ok(_ok) => {
-                        info!("Clearing TagFile cache for {tf}.");
-                    }
-                    Err(_err) => {}
+        match sql_db.get_tagfile_from_hash(&tf.file_hash) {
+            Err(..) => {
+                // File isn't in db
+                info!("Updating {tf}: File not present in SQL database, uploading it");
+
+                // Filesystem
+                // Uploads file if it doesn't exist
+                let uploaded_file = fs_db.upload_file(tf)?;
+
+                sql_db.add_file(&uploaded_file)?;
+                // Not decoded if the file was removed by another thread since the check
+                // above, they are filled in later in that case
+                if is_new {
+                    sql_db.set_perceptual_hash(&uploaded_file.file_hash, phash)?;
+                    sql_db.set_palette(&uploaded_file.file_hash, palette.as_ref())?;
+                }
+                if let Some(phash) = phash {
+                    let hash = uploaded_file.file_hash.clone();
+                    self.get_cache()
+                        .update_similarity_index(|index| index.insert(hash, phash));
                 }
+                cached_tf.path = uploaded_file.path;
+                cached_tf.file_name = uploaded_file.file_name;
+            }
+            Ok(stored_tf) => {
+                let has_user_tags = tf.tags.iter().any(|tag| !tag_util::is_system_tag(tag));
+                if remove_untagged && !has_user_tags {
+                    // File is already in database AND has no tags; we delete
+                    info!("Updating {tf}: File has no tags, removing it");
+                    sql_db.remove_file(&tf)?;
+                    self.get_cache()
+                        .update_similarity_index(|index| index.remove(&tf.file_hash));
+                    if let Err(err) = thumbnails::remove(&fs_db.path, &tf.file_hash) {
+                        info!("WARNING: update_tagfile(): Couldn't remove the thumbnails of {tf}: {err:#}");
+                    }
+
+                    // Removing the TagFile from the cache
+                    match self.get_cache().clear_tagfile_cache(tf.clone()) {
+                        Ok(_ok) => {
+                            info!("Clearing TagFile cache for {tf}.");
+                        }
+                        Err(_err) => {}
+                    }
 
-                return Ok(());
+                    for tag_sync in self.tag_syncs.read().unwrap().iter() {
+                        if let Err(err) = tag_sync.remove(&stored_tf) {
+                            info!(
+                                "WARNING: update_tagfile(): {} failed to remove {tf}: {err:#}",
+                                tag_sync.name()
+                            );
+                        }
+                    }
+
+                    return Ok(());
+                }
+                cached_tf.path = stored_tf.path;
+                cached_tf.file_name = stored_tf.file_name;
             }
         }
 
-        match self.get_cache().cache_tagfile(tf.clone()) {
+        match self.get_cache().cache_tagfile(cached_tf.clone()) {
             Ok(_ok) => {
                 info!("Updating TagFile cache for {tf}.");
             }
@@ -102,6 +240,7 @@ impl TagMaidDatabase {
 
         info!("Updating {tf}: Updating tags to SQL");
         sql_db.update_tags_to_file(tf)?;
+        self.sync_tags(&cached_tf);
 
         Ok(())
     }
@@ -147,6 +286,192 @@ impl TagMaidDatabase {
         let tags = &self.get_tagfile_from_hash(&hash)?.tags;
         return Ok(tags.to_owned());
     }
+
+    pub fn get_upload_date(&self, hash: &Vec<u8>) -> Result<DateTime<Utc>> {
+        let fs_db_mutex = &self.get_fs_db();
+        let fs_db = fs_db_mutex.lock().unwrap();
+        fs_db.get_upload_date(hash)
+    }
+
+    pub fn set_upload_date(&self, hash: &Vec<u8>, upload_date: &DateTime<Utc>) -> Result<()> {
+        let fs_db_mutex = &self.get_fs_db();
+        let fs_db = fs_db_mutex.lock().unwrap();
+        fs_db.set_upload_date(hash, upload_date)
+    }
+
+    /// Where the file was downloaded from, its uploader... (see `data::download_sidecar`)
+    pub fn set_source_metadata(
+        &self,
+        hash: &Vec<u8>,
+        source: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        let fs_db_mutex = &self.get_fs_db();
+        let fs_db = fs_db_mutex.lock().unwrap();
+        fs_db.set_source_metadata(hash, source)
+    }
+
+    pub fn get_source_metadata(&self, hash: &Vec<u8>) -> Result<BTreeMap<String, String>> {
+        let fs_db_mutex = &self.get_fs_db();
+        let fs_db = fs_db_mutex.lock().unwrap();
+        fs_db.get_source_metadata(hash)
+    }
+
+    /// `None` for files that aren't images (see `data::perceptual_hash`)
+    pub fn set_perceptual_hash(&self, hash: &Vec<u8>, phash: Option<u64>) -> Result<()> {
+        let fs_db_mutex = &self.get_fs_db();
+        let fs_db = fs_db_mutex.lock().unwrap();
+        fs_db.set_perceptual_hash(hash, phash)?;
+        self.get_cache().update_similarity_index(|index| {
+            index.remove(hash);
+            if let Some(phash) = phash {
+                index.insert(hash.clone(), phash);
+            }
+        });
+        Ok(())
+    }
+
+    pub fn get_perceptual_hashes(&self) -> Result<HashMap<Vec<u8>, Option<u64>>> {
+        let fs_db_mutex = &self.get_fs_db();
+        let fs_db = fs_db_mutex.lock().unwrap();
+        fs_db.get_perceptual_hashes()
+    }
+
+    /// `None` for files that aren't images (see `data::palette`)
+    pub fn set_palette(&self, hash: &Vec<u8>, palette: Option<&Palette>) -> Result<()> {
+        let fs_db_mutex = &self.get_fs_db();
+        let fs_db = fs_db_mutex.lock().unwrap();
+        fs_db.set_palette(hash, palette)?;
+        // Searches by colour may give other results now
+        if let Err(err) = self.get_cache().clear_color_searches() {
+            info!("WARNING: set_palette(): Couldn't clear the search cache: {err}");
+        }
+        Ok(())
+    }
+
+    pub fn get_palette(&self, hash: &Vec<u8>) -> Result<Option<Palette>> {
+        let fs_db_mutex = &self.get_fs_db();
+        let fs_db = fs_db_mutex.lock().unwrap();
+        fs_db.get_palette(hash)
+    }
+
+    pub fn get_palettes(&self) -> Result<HashMap<Vec<u8>, Option<Palette>>> {
+        let fs_db_mutex = &self.get_fs_db();
+        let fs_db = fs_db_mutex.lock().unwrap();
+        fs_db.get_palettes()
+    }
+
+    /// Files removed from the library, with when they were (see `data::merge`)
+    pub fn get_removed_files(&self) -> Result<HashMap<Vec<u8>, DateTime<Utc>>> {
+        let fs_db_mutex = &self.get_fs_db();
+        let fs_db = fs_db_mutex.lock().unwrap();
+        fs_db.get_removed_files()
+    }
+
+    /// Writes a consistent snapshot of `sqlite.db` to `path` (see `data::backup`)
+    pub fn backup_database(&self, path: &Path) -> Result<()> {
+        let fs_db_mutex = &self.get_fs_db();
+        let fs_db = fs_db_mutex.lock().unwrap();
+        fs_db.backup_database(path)
+    }
+
+    /// Replaces the database with the snapshot at `path`, and forgets everything cached
+    pub fn restore_database(&self, path: &Path) -> Result<()> {
+        let fs_db_mutex = &self.get_fs_db();
+        let mut fs_db = fs_db_mutex.lock().unwrap();
+        fs_db.restore_database(path)?;
+        self.get_cache().clear_results_cache()?;
+        self.get_cache().clear_similarity_index();
+        self.get_cache().clear_all_tagfiles()
+    }
+
+    /// Returns the `_files` row id of every file, keyed by file hash.
+    pub fn get_file_ids(&self) -> Result<HashMap<Vec<u8>, u64>> {
+        let fs_db_mutex = &self.get_fs_db();
+        let fs_db = fs_db_mutex.lock().unwrap();
+        fs_db.get_file_ids()
+    }
+
+    pub fn get_hash_from_id(&self, id: u64) -> Result<Vec<u8>> {
+        let fs_db_mutex = &self.get_fs_db();
+        let fs_db = fs_db_mutex.lock().unwrap();
+        fs_db.get_hash_from_id(id)
+    }
+
+    /// Returns every tag in use along with the number of files tagged with it.
+    pub fn get_all_tags(&self) -> Result<HashMap<String, u64>> {
+        let fs_db_mutex = &self.get_fs_db();
+        let fs_db = fs_db_mutex.lock().unwrap();
+        fs_db.get_all_tags()
+    }
+
+    /// Returns the hashes of the files matching a search query, sorted by hash so that
+    /// the order stays the same between calls. Results are cached until the next edit.
+    pub fn search(&self, search: &Search) -> Result<Vec<Vec<u8>>> {
+        if let Some(cached_results) = self.get_cache().get_search(search) {
+            debug!("Retrieved search cache");
+            return Ok(cached_results);
+        }
+
+        // Images that look like the one of `similar:`, with how far they are from it
+        let similar: Option<HashMap<Vec<u8>, u32>> = match search.similar() {
+            Some(hash) => Some(
+                perceptual_hash::find_similar(self, hash, perceptual_hash::SIMILAR_DISTANCE)?
+                    .into_iter()
+                    .collect(),
+            ),
+            None => None,
+        };
+
+        let fs_db_mutex = &self.get_fs_db();
+        let fs_db = fs_db_mutex.lock().unwrap();
+        // Files are narrowed down using the table of the first tag (or the similar images),
+        // then every candidate is checked against the whole query
+        let mut candidates = match (search.first_tag(), &similar) {
+            (Some(tag), _) => {
+                if fs_db.has_tag(&tag)? {
+                    fs_db.get_hashes_from_tag(&tag)?
+                } else {
+                    HashSet::new()
+                }
+            }
+            (None, Some(similar)) => similar.keys().cloned().collect(),
+            (None, None) => fs_db.get_all_file_hashes()?,
+        };
+        drop(fs_db);
+
+        // Only read when needed, `color:` terms look at them
+        let palettes = match search.uses_color() {
+            true => self.get_palettes()?,
+            false => HashMap::new(),
+        };
+        candidates.retain(|hash| match self.get_tags_from_hash(hash) {
+            Ok(tags) => {
+                let palette = palettes.get(hash).and_then(|palette| palette.as_deref());
+                search.filter_file(&tags, palette)
+            }
+            Err(..) => false,
+        });
+        let mut results: Vec<Vec<u8>> = candidates.into_iter().collect();
+        match &similar {
+            // Most alike first
+            Some(similar) => {
+                results.retain(|hash| similar.contains_key(hash));
+                results.sort_by_key(|hash| (similar[hash], hash.clone()));
+            }
+            None => results.sort(),
+        }
+
+        match self
+            .get_cache()
+            .cache_search(search.clone(), results.clone())
+        {
+            Ok(()) => {}
+            Err(err) => {
+                info!("WARNING: search(): Couldn't open cache as mutable because it was already being borrowed: {err}");
+            }
+        }
+        Ok(results)
+    }
 }
 
 #[cfg(test)]
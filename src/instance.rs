@@ -0,0 +1,255 @@
+//! Makes sure only one TagMaid runs per library, since two processes would both write to
+//! the same SQLite database and files folder.
+//!
+//! The first instance holds an exclusive lock on `tag-maid.lock` in the library folder
+//! and listens on `tag-maid.sock` next to it. When another instance is launched with files
+//! (for example from a file manager's "Open with") or with a search, it forwards them
+//! through the socket and exits, and the running instance shows them.
+use anyhow::{bail, Context, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+const LOCK_FILE_NAME: &str = "tag-maid.lock";
+const SOCKET_FILE_NAME: &str = "tag-maid.sock";
+
+/// The running instance may hold the lock but not be listening yet, so we retry a bit
+const CONNECT_ATTEMPTS: u32 = 20;
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// A connection that doesn't send its message by then is dropped, so it can't hold up the others
+const READ_TIMEOUT: Duration = Duration::from_secs(2);
+/// Longest message we read, paths of a few thousand files
+const MAX_MESSAGE_LEN: u64 = 1024 * 1024;
+
+/// What goes through the socket, one JSON message per line and per connection
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpcMessage {
+    /// Files to open in the Add/Edit flow, as absolute paths
+    Open(Vec<PathBuf>),
+    /// Search query to show the results of
+    Search(String),
+}
+
+/// Held by the running instance for as long as it lives. The lock is released by the OS
+/// when the process exits, even if it crashes, so a stale lock file is never a problem.
+pub struct InstanceLock {
+    _lock_file: File,
+    socket_path: PathBuf,
+}
+
+pub enum Instance {
+    /// We are the only instance for this library
+    Primary(InstanceLock),
+    /// Another instance already has this library open
+    AlreadyRunning,
+}
+
+/// Tries to become the running instance for the library at `library_path`, creating the
+/// folder if needed since this happens before the database is opened.
+pub fn acquire(library_path: &Path) -> Result<Instance> {
+    std::fs::create_dir_all(library_path)
+        .with_context(|| format!("Couldn't create '{}' folder", library_path.display()))?;
+    let lock_path = library_path.join(LOCK_FILE_NAME);
+    let lock_file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("Couldn't open lock file '{}'", lock_path.display()))?;
+    match lock_file.try_lock() {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) => return Ok(Instance::AlreadyRunning),
+        Err(std::fs::TryLockError::Error(err)) => {
+            return Err(err).with_context(|| format!("Couldn't lock '{}'", lock_path.display()));
+        }
+    }
+
+    // Leaving our PID in there makes it easier to find out who holds the lock
+    lock_file.set_len(0)?;
+    write!(&lock_file, "{}", std::process::id())?;
+
+    info!(
+        "Instance - acquire() - Acquired lock {}",
+        lock_path.display()
+    );
+    Ok(Instance::Primary(InstanceLock {
+        _lock_file: lock_file,
+        socket_path: library_path.join(SOCKET_FILE_NAME),
+    }))
+}
+
+/// Makes absolute paths out of file arguments, skipping the ones that aren't files.
+pub fn resolve_file_args(args: &[PathBuf]) -> Vec<PathBuf> {
+    args.iter()
+        .filter_map(|arg| match std::fs::canonicalize(arg) {
+            Ok(path) if path.is_file() => Some(path),
+            _ => {
+                info!(
+                    "WARNING: resolve_file_args(): Ignoring '{}': not a file",
+                    arg.display()
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+impl InstanceLock {
+    /// Listens for other instances in a background thread, calling `on_message` with
+    /// what they send us.
+    pub fn listen<F>(&self, on_message: F) -> Result<()>
+    where
+        F: Fn(IpcMessage) + Send + 'static,
+    {
+        // We hold the lock, so a socket file left there can only be from a crashed instance
+        if self.socket_path.exists() {
+            std::fs::remove_file(&self.socket_path)?;
+        }
+        let listener = UnixListener::bind(&self.socket_path)
+            .with_context(|| format!("Couldn't listen on '{}'", self.socket_path.display()))?;
+        info!(
+            "Instance - listen() - Listening on {}",
+            self.socket_path.display()
+        );
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        info!("WARNING: listen(): Couldn't accept connection: {err}");
+                        continue;
+                    }
+                };
+                if let Err(err) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+                    info!("WARNING: listen(): Couldn't set read timeout: {err}");
+                    continue;
+                }
+                let mut line = String::new();
+                let mut reader = BufReader::new(stream.take(MAX_MESSAGE_LEN));
+                if let Err(err) = reader.read_line(&mut line) {
+                    info!("WARNING: listen(): Couldn't read message: {err}");
+                    continue;
+                }
+                match serde_json::from_str::<IpcMessage>(&line) {
+                    Ok(message) => {
+                        info!("Instance - listen() - Received {message:?}");
+                        on_message(message);
+                    }
+                    Err(err) => {
+                        info!("WARNING: listen(): Ignoring invalid message '{line}': {err}");
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.socket_path).ok();
+    }
+}
+
+/// Hands `message` to the instance running for the library at `library_path`.
+#[cfg(unix)]
+pub fn send(library_path: &Path, message: &IpcMessage) -> Result<()> {
+    let socket_path = library_path.join(SOCKET_FILE_NAME);
+    let mut attempts = 0;
+    let mut stream = loop {
+        match UnixStream::connect(&socket_path) {
+            Ok(stream) => break stream,
+            Err(err) => {
+                attempts += 1;
+                if attempts >= CONNECT_ATTEMPTS {
+                    return Err(err).with_context(|| {
+                        format!(
+                            "Couldn't reach the running TagMaid on '{}'",
+                            socket_path.display()
+                        )
+                    });
+                }
+                std::thread::sleep(CONNECT_RETRY_DELAY);
+            }
+        }
+    };
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+impl InstanceLock {
+    pub fn listen<F>(&self, _on_message: F) -> Result<()>
+    where
+        F: Fn(IpcMessage) + Send + 'static,
+    {
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+pub fn send(_library_path: &Path, _message: &IpcMessage) -> Result<()> {
+    bail!("Handing files to a running TagMaid is only supported on Unix")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn should_allow_only_one_instance() {
+        let library_path = tempfile::tempdir().unwrap().keep();
+        let first = acquire(&library_path).unwrap();
+        assert!(matches!(first, Instance::Primary(_)));
+        assert!(matches!(
+            acquire(&library_path).unwrap(),
+            Instance::AlreadyRunning
+        ));
+
+        drop(first);
+        assert!(matches!(
+            acquire(&library_path).unwrap(),
+            Instance::Primary(_)
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn should_forward_files_to_running_instance() {
+        let library_path = tempfile::tempdir().unwrap().keep();
+        let lock = match acquire(&library_path).unwrap() {
+            Instance::Primary(lock) => lock,
+            Instance::AlreadyRunning => panic!("No instance should be running"),
+        };
+        let (sender, receiver) = mpsc::channel();
+        lock.listen(move |message| sender.send(message).unwrap())
+            .unwrap();
+
+        // A connection that never sends anything doesn't keep the others waiting
+        let _idle = UnixStream::connect(library_path.join(SOCKET_FILE_NAME)).unwrap();
+        let files = vec![PathBuf::from("/tmp/a.png"), PathBuf::from("/tmp/b c.png")];
+        send(&library_path, &IpcMessage::Open(files.clone())).unwrap();
+        send(&library_path, &IpcMessage::Search("cat".to_owned())).unwrap();
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            IpcMessage::Open(files)
+        );
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            IpcMessage::Search("cat".to_owned())
+        );
+    }
+}
@@ -1,20 +1,216 @@
 #![allow(dead_code, unused_imports)]
+pub mod cli;
 pub mod data;
 pub mod database;
+pub mod instance;
+pub mod server;
 pub mod ui;
+use crate::cli::{Cli, Command};
 use crate::data::{config::Config, tag_file::TagFile};
 use crate::database::{tag_database::TagDatabase, tagmaid_database::TagMaidDatabase};
 use anyhow::{bail, Context, Result};
+use clap::Parser;
 use image::EncodableLayout;
 #[macro_use]
 extern crate log;
 
 /// main() initialises the database (TagMaidDatabase) and configuration (Config) objects.
+/// See `cli::Cli` for the command-line arguments. If TagMaid is already running for the
+/// library, what the arguments ask for is handed to it and this process exits.
 fn main() -> Result<()> {
     env_logger::init();
     info!("Starting up TagMaid. Hello!");
 
-    let db: TagMaidDatabase = database::tagmaid_database::init();
+    let cli = Cli::parse();
+
+    // File paths are stored from the library path, so they must not depend on the cwd
+    let library_path = match &cli.library {
+        Some(library_path) => std::path::absolute(library_path)
+            .with_context(|| format!("Invalid library path '{}'", library_path.display()))?,
+        None => database::tagmaid_database::default_library_path()?,
+    };
+
+    // Locked before the database is opened, so a second instance never touches it
+    let instance_lock = match instance::acquire(&library_path)? {
+        instance::Instance::Primary(lock) => lock,
+        instance::Instance::AlreadyRunning => {
+            if cli.command.is_some() {
+                bail!("TagMaid is already running for this library, close it first");
+            }
+            return match cli.startup_message() {
+                Some(message) => {
+                    println!("TagMaid is already running, handing {message:?} over to it");
+                    instance::send(&library_path, &message)
+                }
+                None => {
+                    println!("TagMaid is already running");
+                    Ok(())
+                }
+            };
+        }
+    };
+
+    let db: TagMaidDatabase = database::tagmaid_database::init_at(library_path.clone())?;
+
+    let mut cfg = match &cli.config {
+        Some(config_path) => Config::load_from(config_path.clone()),
+        None => Config::load(),
+    };
+    if let Some(err) = &cfg.load_error {
+        println!("Couldn't read the config, using the default one without saving over it: {err}");
+    }
+
+    // Every tag change is mirrored to the backends enabled in the config
+    db.set_tag_syncs(data::tag_sync::from_config(&cfg));
+
+    let startup_message = cli.startup_message();
+    // Commands run once and exit, except `serve` which needs the background work below
+    let serve_options = match cli.command {
+        Some(Command::Import(import_args)) => return import_args.run(&db, cfg.import_options()),
+        Some(Command::ImportHydrus(hydrus_args)) => {
+            return hydrus_args.run(&db, cfg.import_options())
+        }
+        Some(Command::ImportUrl(url_args)) => {
+            return url_args.run(&db, cfg.import_options(), &cfg.url_import)
+        }
+        Some(Command::SyncTags) => {
+            let synced = data::tag_sync::sync_all(&db)?;
+            println!("Synced the tags of {synced} files");
+            return Ok(());
+        }
+        Some(Command::RebuildThumbnails) => {
+            let store = data::thumbnails::ThumbnailStore::new(&library_path, &cfg.thumbnails);
+            let removed = store.collect_garbage(&db)?;
+            let files = store.generate_all(&db)?;
+            println!("Deleted {removed} old thumbnails, made the missing ones of {files} files");
+            return Ok(());
+        }
+        Some(Command::RebuildSystemTags) => {
+            let updated = data::system_tags::rebuild(&db)?;
+            println!("Updated the system tags of {updated} files");
+            return Ok(());
+        }
+        Some(Command::ExportTagspaces { folder }) => {
+            let exported = data::tagspaces::export_folder(&db, &folder)?;
+            println!("Wrote {exported} TagSpaces sidecars");
+            return Ok(());
+        }
+        Some(Command::ExportArchive { archive }) => {
+            let exported = data::archive::export(&db, &cfg, &archive)?;
+            println!("Exported {exported} files to {}", archive.display());
+            return Ok(());
+        }
+        Some(Command::ImportArchive { archive, conflicts }) => {
+            let report = data::archive::import(&db, &mut cfg, &archive, conflicts)?;
+            for failure in report.failed.iter() {
+                println!("failed: {failure}");
+            }
+            println!("{}", report.summary());
+            return Ok(());
+        }
+        Some(Command::MergeLibrary { other, dry_run }) => {
+            let report = data::merge::merge(&db, &other, dry_run)?;
+            for conflict in report.conflicts.iter() {
+                println!("conflict: {conflict}");
+            }
+            println!("{}", report.summary());
+            return Ok(());
+        }
+        Some(Command::FindDuplicates { distance }) => {
+            let groups = data::perceptual_hash::find_duplicates(&db, distance)?;
+            for group in groups.iter() {
+                println!("---");
+                let dimensions = data::perceptual_hash::dimensions(&db, group);
+                for hash in group {
+                    let tagfile = db.get_tagfile_from_hash(hash)?;
+                    let (width, height) = dimensions.get(hash).copied().unwrap_or_default();
+                    println!(
+                        "{} {width}x{height} {}",
+                        data::tag_util::bytes_to_hex(hash),
+                        tagfile.get_file_name()
+                    );
+                }
+            }
+            println!("{} groups of near-duplicates", groups.len());
+            return Ok(());
+        }
+        Some(Command::ExportLinks(link_args)) => return link_args.run(&db),
+        Some(Command::ExportGallery {
+            folder,
+            query,
+            title,
+        }) => {
+            let options = data::html_gallery::GalleryOptions {
+                title,
+                ..Default::default()
+            };
+            let report = data::html_gallery::export(&db, &query, &folder, &options)?;
+            println!(
+                "Exported {} files and {} tags to {}",
+                report.files,
+                report.tags,
+                folder.join("index.html").display()
+            );
+            return Ok(());
+        }
+        Some(Command::Backup { files }) => {
+            let config = data::backup::BackupConfig {
+                include_files: files || cfg.backups.include_files,
+                ..cfg.backups.clone()
+            };
+            let backup = data::backup::create(&db, &config)?;
+            println!("Backed up the library to {}", backup.path.display());
+            return Ok(());
+        }
+        Some(Command::ListBackups) => {
+            let folder = cfg.backups.folder(&library_path);
+            for backup in data::backup::list(&folder)? {
+                let files = if backup.has_files() {
+                    " (with files)"
+                } else {
+                    ""
+                };
+                println!(
+                    "{} {}{files}",
+                    backup.date.to_rfc3339(),
+                    backup.path.display()
+                );
+            }
+            return Ok(());
+        }
+        Some(Command::Restore { backup, files }) => {
+            let restored = data::backup::restore(&db, &cfg.backups, &backup, files)?;
+            println!("Restored the database from {}", backup.display());
+            if files {
+                println!("Copied back {restored} missing files");
+            }
+            return Ok(());
+        }
+        Some(Command::Serve(options)) => Some(options),
+        None => None,
+    };
+
+    // Watch folders are imported from for as long as the GUI or the API server runs
+    let _folder_watcher =
+        data::watch::FolderWatcher::start(db.clone(), &cfg.watch_folders, &cfg.import_options())?;
+    let _backup_scheduler = data::backup::BackupScheduler::start(db.clone(), &cfg.backups);
+    // Libraries from before palettes and perceptual hashes existed get them in the background
+    let _palette_filler = data::palette::PaletteFiller::start(db.clone());
+    let _hash_filler = data::perceptual_hash::HashFiller::start(db.clone());
+    // Thumbnails of removed files or of sizes no longer in the config
+    let thumbnail_store = data::thumbnails::ThumbnailStore::new(&library_path, &cfg.thumbnails);
+    let gc_db = db.clone();
+    std::thread::spawn(move || {
+        if let Err(err) = thumbnail_store.collect_garbage(&gc_db) {
+            info!("WARNING: main(): Couldn't clean up thumbnails: {err:#}");
+        }
+    });
+
+    if let Some(mut options) = serve_options {
+        options.import_options = cfg.import_options();
+        options.thumbnails = cfg.thumbnails.clone();
+        return server::serve(db, options);
+    }
     #[cfg(feature = "import_samples")]
     import_samples(&db)?;
 
@@ -23,15 +219,20 @@ fn main() -> Result<()> {
     #[cfg(feature = "manual")]
     manual_db(&db)?;
 
-    let cfg = Config::load();
-
-    app_main(db.clone(), cfg)?;
+    app_main(db.clone(), cfg, instance_lock, startup_message)?;
 
     Ok(())
 }
 
 /// egui initialisation function
-fn app_main(db: TagMaidDatabase, config: data::config::Config) -> Result<()> {
+/// `startup_message` tells the GUI what to show first, like other instances do
+/// through `instance_lock`
+fn app_main(
+    db: TagMaidDatabase,
+    config: data::config::Config,
+    instance_lock: instance::InstanceLock,
+    startup_message: Option<instance::IpcMessage>,
+) -> Result<()> {
     let mut frame_options = eframe::NativeOptions::default();
     frame_options.drag_and_drop_support = true;
     frame_options.resizable = false;
@@ -51,7 +252,15 @@ fn app_main(db: TagMaidDatabase, config: data::config::Config) -> Result<()> {
     eframe::run_native(
         "Tag Maid",
         frame_options,
-        Box::new(|cc| Box::new(ui::TagMaid::new(cc, db, config))),
+        Box::new(|cc| {
+            Box::new(ui::TagMaid::new(
+                cc,
+                db,
+                config,
+                instance_lock,
+                startup_message,
+            ))
+        }),
     )
     .unwrap();
     Ok(())
@@ -59,18 +268,18 @@ fn app_main(db: TagMaidDatabase, config: data::config::Config) -> Result<()> {
 
 #[cfg(feature = "import_samples")]
 fn import_samples(db: &TagMaidDatabase) -> Result<()> {
-    let paths = std::fs::read_dir("src/sample").unwrap();
+    use crate::data::import::{ImportOptions, Importer};
 
-    for path in paths {
-        let path_path = path.as_ref().unwrap().path().clone();
-        if (&path.unwrap().metadata().unwrap().is_file()).to_owned() {
-            println!("Adding file {} to db", &path_path.display());
-            let mut file = TagFile::initialise_from_path(&path_path)?;
-            // Hardcoded don't care + ratio + stream Frank Ocean
-            file.add_tag("frank_ocean")?;
-            db.update_tagfile(&file)?;
-        }
-    }
+    // Hardcoded don't care + ratio + stream Frank Ocean
+    let options = ImportOptions {
+        tags: std::collections::HashSet::from(["frank_ocean".to_owned()]),
+        ..Default::default()
+    };
+    let importer = Importer::new(db.clone(), options)?;
+    let report = importer.import_folder(std::path::Path::new("src/sample"), |progress| {
+        println!("Adding file {} to db", progress.file.path.display());
+    })?;
+    println!("{}", report.summary());
     Ok(())
 }
 
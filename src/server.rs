@@ -0,0 +1,519 @@
+//! Local HTTP server exposing the library to scripts and browsers (`tag-maid serve`).
+//! It only ever listens on localhost, and every request goes through `TagMaidDatabase`
+//! so the cache stays consistent with the database.
+//!
+//! Since any web page can send requests to localhost, requests whose `Host` isn't the
+//! address we listen on (DNS rebinding) or that come from another site's `Origin` are
+//! refused, token or not.
+//!
+//! Requests are handled one at a time: the database sits behind a single `Mutex` anyway,
+//! and it keeps cache invalidation simple.
+pub mod api;
+pub mod booru;
+
+use crate::data::import::ImportOptions;
+use crate::data::mime::content_type_from_path;
+use crate::data::thumbnails::ThumbnailConfig;
+use crate::data::url_util::percent_decode;
+use crate::database::tagmaid_database::TagMaidDatabase;
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use tiny_http::{Header, Method, Request, ResponseBox, Server};
+
+pub const DEFAULT_PORT: u16 = 7878;
+
+/// Environment variable that can hold the access token, so it doesn't show up in `ps`
+pub const TOKEN_ENV_VAR: &str = "TAGMAID_API_TOKEN";
+
+pub const DEFAULT_MAX_UPLOAD_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Options for `tag-maid serve`
+#[derive(Debug, Clone, clap::Args)]
+pub struct ServeOptions {
+    /// Port to listen on
+    #[arg(long, default_value_t = DEFAULT_PORT)]
+    pub port: u16,
+    /// Access token that every request then has to carry, in an `Authorization: Bearer`
+    /// header or in a `token` query parameter (handy for `<img>` tags)
+    #[arg(long, env = TOKEN_ENV_VAR, hide_env_values = true)]
+    pub token: Option<String>,
+    /// Maximum size of an uploaded file, in bytes
+    #[arg(long, default_value_t = DEFAULT_MAX_UPLOAD_SIZE)]
+    pub max_upload_size: u64,
+    /// How uploads are imported (auto-tagging rules...), taken from the config
+    #[arg(skip)]
+    pub import_options: ImportOptions,
+    /// Sizes of the thumbnails, taken from the config
+    #[arg(skip)]
+    pub thumbnails: ThumbnailConfig,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            token: None,
+            max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
+            import_options: ImportOptions::default(),
+            thumbnails: ThumbnailConfig::default(),
+        }
+    }
+}
+
+/// Error returned by the request handlers, turned into a JSON `{"error": ...}` response.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: u16, message: impl Into<String>) -> ApiError {
+        ApiError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> ApiError {
+        Self::new(400, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> ApiError {
+        Self::new(404, message)
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> ApiError {
+        ApiError::new(500, format!("{err:#}"))
+    }
+}
+
+pub type ApiResult = std::result::Result<ResponseBox, ApiError>;
+
+/// A request URL split into its path segments and its (decoded) query parameters
+#[derive(Debug, PartialEq)]
+pub struct RequestUrl {
+    pub segments: Vec<String>,
+    pub query: HashMap<String, String>,
+}
+
+impl RequestUrl {
+    pub fn parse(url: &str) -> RequestUrl {
+        let (path, query_string) = match url.split_once('?') {
+            Some((path, query_string)) => (path, query_string),
+            None => (url, ""),
+        };
+        let segments = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(percent_decode)
+            .collect();
+        let query = query_string
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.split_once('=') {
+                Some((key, value)) => (percent_decode(key), percent_decode(value)),
+                None => (percent_decode(pair), String::new()),
+            })
+            .collect();
+        RequestUrl { segments, query }
+    }
+
+    pub fn segments(&self) -> Vec<&str> {
+        self.segments.iter().map(|s| s.as_str()).collect()
+    }
+
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.query.get(key).map(|s| s.as_str())
+    }
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+}
+
+pub fn json_response<T: Serialize>(status: u16, value: &T) -> ApiResult {
+    let json = serde_json::to_vec(value).context("Couldn't serialise response to JSON")?;
+    let length = json.len();
+    Ok(tiny_http::Response::new(
+        status.into(),
+        vec![header("Content-Type", "application/json")],
+        Box::new(Cursor::new(json)) as Box<dyn Read + Send>,
+        Some(length),
+        None,
+    ))
+}
+
+pub fn error_response(err: &ApiError) -> ResponseBox {
+    let mut body = HashMap::new();
+    body.insert("error", err.message.as_str());
+    json_response(err.status, &body)
+        .unwrap_or_else(|_err| tiny_http::Response::empty(err.status).boxed())
+}
+
+/// Streams a file from the disk, so that large files are not loaded in memory
+pub fn file_response(path: &Path) -> ApiResult {
+    let file = File::open(path)
+        .map_err(|_err| ApiError::not_found(format!("File '{}' is missing", path.display())))?;
+    let length = file.metadata().ok().map(|metadata| metadata.len() as usize);
+    Ok(tiny_http::Response::new(
+        200.into(),
+        vec![header("Content-Type", content_type_from_path(path))],
+        Box::new(file) as Box<dyn Read + Send>,
+        length,
+        None,
+    ))
+}
+
+/// Reads the request body, refusing it if it is bigger than `max_size`
+pub fn read_body(request: &mut Request, max_size: u64) -> std::result::Result<Vec<u8>, ApiError> {
+    if request
+        .body_length()
+        .is_some_and(|length| length as u64 > max_size)
+    {
+        return Err(ApiError::new(413, "Request body is too large"));
+    }
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(max_size + 1)
+        .read_to_end(&mut body)
+        .map_err(|err| ApiError::bad_request(format!("Couldn't read request body: {err}")))?;
+    if body.len() as u64 > max_size {
+        return Err(ApiError::new(413, "Request body is too large"));
+    }
+    Ok(body)
+}
+
+fn header_value<'a>(request: &'a Request, field: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(field))
+        .map(|h| h.value.as_str())
+}
+
+/// Compares in a time that doesn't depend on where the first difference is, so the
+/// token can't be guessed byte by byte
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn is_authorised(request: &Request, url: &RequestUrl, token: &Option<String>) -> bool {
+    let token = match token {
+        Some(token) => token,
+        None => return true,
+    };
+    let header_token = header_value(request, "Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|t| t.trim());
+    // Booru clients send it as `api_key`
+    [header_token, url.param("token"), url.param("api_key")]
+        .into_iter()
+        .flatten()
+        .any(|candidate| constant_time_eq(candidate, token))
+}
+
+/// Hosts the server can be reached at, with their port
+fn allowed_hosts(port: u16) -> [String; 2] {
+    [format!("127.0.0.1:{port}"), format!("localhost:{port}")]
+}
+
+/// Whether the `Host` is us and, for requests made by browsers, whether the page comes
+/// from us too. Clients that don't send these headers (scripts...) are let through.
+fn is_same_origin(request: &Request, port: u16) -> bool {
+    let allowed_hosts = allowed_hosts(port);
+    let host_allowed = match header_value(request, "Host") {
+        Some(host) => allowed_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host)),
+        None => true,
+    };
+    let origin_allowed = match header_value(request, "Origin") {
+        Some(origin) => allowed_hosts
+            .iter()
+            .any(|allowed| origin.eq_ignore_ascii_case(&format!("http://{allowed}"))),
+        None => true,
+    };
+    host_allowed && origin_allowed
+}
+
+/// Base URL that clients used to reach us, taken from the `Host` header
+fn base_url(request: &Request, options: &ServeOptions) -> String {
+    let host = header_value(request, "Host")
+        .map(|host| host.to_owned())
+        .unwrap_or_else(|| format!("127.0.0.1:{}", options.port));
+    format!("http://{host}")
+}
+
+/// Routes a request to its handler and builds the response. Kept apart from `serve()`
+/// so it can be called without a socket.
+pub fn handle_request(
+    db: &TagMaidDatabase,
+    options: &ServeOptions,
+    request: &mut Request,
+) -> ResponseBox {
+    let url = RequestUrl::parse(request.url());
+    if !is_same_origin(request, options.port) {
+        return error_response(&ApiError::new(403, "Requests from other sites are refused"));
+    }
+    if !is_authorised(request, &url, &options.token) {
+        return error_response(&ApiError::new(401, "Missing or invalid access token"));
+    }
+
+    let method = request.method().clone();
+    let links = booru::Links {
+        base_url: base_url(request, options),
+        token: options.token.clone(),
+    };
+    let result = match (&method, url.segments().as_slice()) {
+        (Method::Get, ["api", "search"]) => api::search(db, &url),
+        (Method::Get, ["api", "tags"]) => api::list_tags(db),
+        (Method::Post, ["api", "files"]) => api::upload_file(db, options, &url, request),
+        (Method::Get, ["api", "files", hash]) => api::get_file(db, hash),
+        (Method::Get, ["api", "files", hash, "content"]) => api::get_file_content(db, hash),
+        (Method::Get, ["api", "files", hash, "thumbnail"]) => {
+            api::get_file_thumbnail(db, options, hash, &url)
+        }
+        (Method::Post, ["api", "files", hash, "tags"]) => api::edit_tags(db, hash, request),
+        (Method::Get, ["posts.json"]) => booru::list_posts(db, &url, &links),
+        (Method::Get, ["posts", post]) => booru::get_post(db, post, &links),
+        (Method::Get, ["counts", "posts.json"]) => booru::count_posts(db, &url),
+        (Method::Get, ["tags.json"]) => booru::list_tags(db, &url),
+        _ => Err(ApiError::not_found("No such endpoint")),
+    };
+
+    match result {
+        Ok(response) => response,
+        Err(err) => {
+            info!(
+                "Server - {} {} - {}: {}",
+                method,
+                request.url(),
+                err.status,
+                err.message
+            );
+            error_response(&err)
+        }
+    }
+}
+
+/// Starts the server on `127.0.0.1` and handles requests until the process is stopped.
+pub fn serve(db: TagMaidDatabase, options: ServeOptions) -> Result<()> {
+    let server = Server::http(("127.0.0.1", options.port))
+        .map_err(|err| anyhow!("Couldn't start server on port {}: {err}", options.port))?;
+    println!(
+        "TagMaid API listening on http://127.0.0.1:{}{}",
+        options.port,
+        if options.token.is_some() {
+            " (access token required)"
+        } else {
+            ""
+        }
+    );
+
+    for mut request in server.incoming_requests() {
+        debug!("Server - {} {}", request.method(), request.url());
+        let response = handle_request(&db, &options, &mut request);
+        if let Err(err) = request.respond(response) {
+            info!("WARNING: serve(): Couldn't send response: {err}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tag_database::test_util::create_random_tagmaiddatabase;
+    use tiny_http::TestRequest;
+
+    pub(super) fn send(
+        db: &TagMaidDatabase,
+        options: &ServeOptions,
+        test_request: TestRequest,
+    ) -> (u16, String) {
+        let mut request: Request = test_request.into();
+        let response = handle_request(db, options, &mut request);
+        let status = response.status_code().0;
+        let mut body = String::new();
+        response.into_reader().read_to_string(&mut body).unwrap();
+        (status, body)
+    }
+
+    #[test]
+    fn should_parse_request_url() {
+        let url = RequestUrl::parse("/api/search?q=cat+-dog%20~bird&empty&token=a%2Fb");
+        assert_eq!(url.segments(), vec!["api", "search"]);
+        assert_eq!(url.param("q"), Some("cat -dog ~bird"));
+        assert_eq!(url.param("empty"), Some(""));
+        assert_eq!(url.param("token"), Some("a/b"));
+    }
+
+    #[test]
+    fn should_require_token() {
+        let db = create_random_tagmaiddatabase();
+        let options = ServeOptions {
+            token: Some("secret".to_string()),
+            ..Default::default()
+        };
+
+        let (status, _) = send(&db, &options, TestRequest::new().with_path("/api/tags"));
+        assert_eq!(status, 401);
+
+        let (status, _) = send(
+            &db,
+            &options,
+            TestRequest::new().with_path("/api/tags?token=secret"),
+        );
+        assert_eq!(status, 200);
+
+        let (status, _) = send(
+            &db,
+            &options,
+            TestRequest::new()
+                .with_path("/api/tags")
+                .with_header(header("Authorization", "Bearer secret")),
+        );
+        assert_eq!(status, 200);
+    }
+
+    #[test]
+    fn should_refuse_other_hosts_and_origins() {
+        let db = create_random_tagmaiddatabase();
+        let options = ServeOptions::default();
+        let request = |headers: &[(&str, &str)]| {
+            let mut request = TestRequest::new().with_path("/api/tags");
+            for (field, value) in headers {
+                request = request.with_header(header(field, value));
+            }
+            send(&db, &options, request).0
+        };
+
+        assert_eq!(request(&[("Host", "localhost:7878")]), 200);
+        assert_eq!(
+            request(&[
+                ("Host", "127.0.0.1:7878"),
+                ("Origin", "http://127.0.0.1:7878")
+            ]),
+            200
+        );
+        // DNS rebinding
+        assert_eq!(request(&[("Host", "evil.example:7878")]), 403);
+        assert_eq!(request(&[("Host", "127.0.0.1:8080")]), 403);
+        // A page of another site posting to us
+        assert_eq!(
+            request(&[
+                ("Host", "127.0.0.1:7878"),
+                ("Origin", "https://evil.example")
+            ]),
+            403
+        );
+        assert_eq!(request(&[("Origin", "null")]), 403);
+
+        assert!(constant_time_eq("secret", "secret"));
+        assert!(!constant_time_eq("secret", "secreT"));
+        assert!(!constant_time_eq("secret", "secret2"));
+    }
+
+    #[test]
+    fn should_upload_search_and_edit_tags() {
+        let db = create_random_tagmaiddatabase();
+        let options = ServeOptions::default();
+
+        let (status, body) = send(
+            &db,
+            &options,
+            TestRequest::new()
+                .with_method(Method::Post)
+                .with_path("/api/files?name=notes.txt&tags=cat,black_body")
+                .with_body("meow"),
+        );
+        assert_eq!(status, 201, "{body}");
+        let uploaded: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let hash = uploaded["hash"].as_str().unwrap().to_owned();
+        assert_eq!(uploaded["file_name"], "notes.txt");
+        assert_eq!(
+            uploaded["tags"],
+            serde_json::json!(["black_body", "cat", "system:ext:txt", "system:type:text"])
+        );
+
+        let (status, body) = send(
+            &db,
+            &options,
+            TestRequest::new().with_path("/api/search?q=cat"),
+        );
+        assert_eq!(status, 200);
+        let results: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(results["results"][0]["hash"], hash.as_str());
+
+        let (status, body) = send(
+            &db,
+            &options,
+            TestRequest::new()
+                .with_method(Method::Post)
+                .with_path(&format!("/api/files/{hash}/tags"))
+                .with_body(r#"{"add": ["sleeping"], "remove": ["cat"]}"#),
+        );
+        assert_eq!(status, 200, "{body}");
+
+        let (status, _) = send(
+            &db,
+            &options,
+            TestRequest::new()
+                .with_method(Method::Post)
+                .with_path(&format!("/api/files/{hash}/tags"))
+                .with_body(r#"{"remove": ["system:ext:txt"]}"#),
+        );
+        assert_eq!(status, 400);
+
+        let (status, _) = send(
+            &db,
+            &options,
+            TestRequest::new()
+                .with_method(Method::Post)
+                .with_path(&format!("/api/files/{hash}/tags"))
+                .with_body(r#"{"remove": ["sleeping", "black_body"]}"#),
+        );
+        assert_eq!(status, 409);
+
+        let (_, body) = send(
+            &db,
+            &options,
+            TestRequest::new().with_path("/api/search?q=cat"),
+        );
+        let results: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(results["results"], serde_json::json!([]));
+
+        let (_, body) = send(&db, &options, TestRequest::new().with_path("/api/tags"));
+        let tags: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            tags,
+            serde_json::json!([
+                {"name": "black_body", "count": 1},
+                {"name": "sleeping", "count": 1},
+                {"name": "system:ext:txt", "count": 1},
+                {"name": "system:type:text", "count": 1}
+            ])
+        );
+
+        let (status, body) = send(
+            &db,
+            &options,
+            TestRequest::new().with_path(&format!("/api/files/{hash}/content")),
+        );
+        assert_eq!(status, 200);
+        assert_eq!(body, "meow");
+    }
+}
@@ -0,0 +1,246 @@
+//! JSON endpoints under `/api`:
+//! - `GET /api/search?q=<query>` searches using the `search_command` syntax
+//! - `GET /api/tags` lists every tag with its file count
+//! - `POST /api/files?name=<file name>&tags=<tag1,tag2>` uploads the request body as a file
+//! - `GET /api/files/<hash>` returns the metadata of a file
+//! - `GET /api/files/<hash>/content` and `GET /api/files/<hash>/thumbnail` download them,
+//!   `?size=grid|hidpi|preview` choosing the size of the thumbnail
+//! - `POST /api/files/<hash>/tags` with `{"add": [...], "remove": [...]}` edits tags
+use super::{
+    file_response, json_response, read_body, ApiError, ApiResult, RequestUrl, ServeOptions,
+};
+use crate::data::import::{ImportOptions, ImportStatus, Importer};
+use crate::data::thumbnails::{ThumbnailSize, ThumbnailStore};
+use crate::data::{search_command::Search, tag_file::TagFile, tag_util};
+use crate::database::tagmaid_database::TagMaidDatabase;
+use anyhow::Context;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use tiny_http::Request;
+
+/// What the API returns for a file
+#[derive(Debug, Serialize)]
+pub struct FileInfo {
+    pub hash: String,
+    pub file_name: String,
+    pub path: String,
+    pub upload_date: Option<String>,
+    /// Sorted alphabetically
+    pub tags: Vec<String>,
+    /// Where the file came from (`url`, `uploader`...), see `data::download_sidecar`
+    pub source: BTreeMap<String, String>,
+}
+
+impl FileInfo {
+    pub fn from_tagfile(db: &TagMaidDatabase, tagfile: &TagFile) -> FileInfo {
+        let mut tags: Vec<String> = tagfile.get_tags().iter().cloned().collect();
+        tags.sort();
+        FileInfo {
+            hash: tag_util::bytes_to_hex(&tagfile.file_hash),
+            file_name: tagfile.get_file_name().to_owned(),
+            path: tagfile.get_path().to_string_lossy().into_owned(),
+            upload_date: db
+                .get_upload_date(&tagfile.file_hash)
+                .ok()
+                .map(|date| date.to_rfc3339()),
+            tags,
+            source: db
+                .get_source_metadata(&tagfile.file_hash)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResults {
+    query: String,
+    results: Vec<FileInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct TagCount {
+    name: String,
+    count: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct TagEdit {
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+/// Decodes a file hash given in hexadecimal in the URL
+pub fn parse_hash(hash_hex: &str) -> std::result::Result<Vec<u8>, ApiError> {
+    match hex::decode(hash_hex) {
+        Ok(hash) if hash.len() == blake3::OUT_LEN => Ok(hash),
+        _ => Err(ApiError::bad_request(format!(
+            "Invalid file hash '{hash_hex}'"
+        ))),
+    }
+}
+
+pub fn get_tagfile(db: &TagMaidDatabase, hash_hex: &str) -> std::result::Result<TagFile, ApiError> {
+    let hash = parse_hash(hash_hex)?;
+    db.get_tagfile_from_hash(&hash)
+        .map_err(|_err| ApiError::not_found(format!("No file with hash '{hash_hex}'")))
+}
+
+fn validate_tags(tags: &[String]) -> std::result::Result<(), ApiError> {
+    for tag in tags {
+        tag_util::validate_tag_name(tag).map_err(|err| ApiError::bad_request(err.to_string()))?;
+        if tag_util::is_system_tag(tag) {
+            return Err(ApiError::bad_request(format!(
+                "System tag '{tag}' can't be edited"
+            )));
+        }
+        if tag_util::is_reserved_tag(tag) {
+            return Err(ApiError::bad_request(format!(
+                "Tag '{tag}' starts like a search term"
+            )));
+        }
+    }
+    Ok(())
+}
+
+pub fn search(db: &TagMaidDatabase, url: &RequestUrl) -> ApiResult {
+    let query = url.param("q").unwrap_or_default().to_owned();
+    let search = Search::from_string(&query)
+        .map_err(|err| ApiError::bad_request(format!("Invalid search '{query}': {err}")))?;
+    let results: Vec<FileInfo> = db
+        .search(&search)?
+        .iter()
+        .filter_map(|hash| db.get_tagfile_from_hash(hash).ok())
+        .map(|tagfile| FileInfo::from_tagfile(db, &tagfile))
+        .collect();
+    json_response(200, &SearchResults { query, results })
+}
+
+pub fn list_tags(db: &TagMaidDatabase) -> ApiResult {
+    let mut tags: Vec<TagCount> = db
+        .get_all_tags()?
+        .into_iter()
+        .map(|(name, count)| TagCount { name, count })
+        .collect();
+    tags.sort_by(|a, b| a.name.cmp(&b.name));
+    json_response(200, &tags)
+}
+
+pub fn get_file(db: &TagMaidDatabase, hash_hex: &str) -> ApiResult {
+    let tagfile = get_tagfile(db, hash_hex)?;
+    json_response(200, &FileInfo::from_tagfile(db, &tagfile))
+}
+
+pub fn get_file_content(db: &TagMaidDatabase, hash_hex: &str) -> ApiResult {
+    let tagfile = get_tagfile(db, hash_hex)?;
+    file_response(tagfile.get_path())
+}
+
+pub fn get_file_thumbnail(
+    db: &TagMaidDatabase,
+    options: &ServeOptions,
+    hash_hex: &str,
+    url: &RequestUrl,
+) -> ApiResult {
+    let tagfile = get_tagfile(db, hash_hex)?;
+    let size = match url.param("size") {
+        Some(name) => ThumbnailSize::from_name(name)
+            .ok_or_else(|| ApiError::bad_request("size has to be grid, hidpi or preview"))?,
+        None => ThumbnailSize::Grid,
+    };
+    let store = ThumbnailStore::new(&db.get_library_path(), &options.thumbnails);
+    match store.get(&tagfile, size) {
+        Some(thumbnail_path) => file_response(&thumbnail_path),
+        None => Err(ApiError::not_found("This file has no thumbnail")),
+    }
+}
+
+/// Stores the request body as a new file. If the file is already in the library,
+/// the given tags are added to the ones it already has.
+pub fn upload_file(
+    db: &TagMaidDatabase,
+    options: &ServeOptions,
+    url: &RequestUrl,
+    request: &mut Request,
+) -> ApiResult {
+    // Only keep the last component, so the name can't be used to write elsewhere
+    let file_name = url
+        .param("name")
+        .and_then(|name| Path::new(name).file_name())
+        .and_then(|name| name.to_str())
+        .unwrap_or("upload")
+        .to_owned();
+    let tags: Vec<String> = url
+        .param("tags")
+        .unwrap_or_default()
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| tag.to_owned())
+        .collect();
+    validate_tags(&tags)?;
+
+    let body = read_body(request, options.max_upload_size)?;
+    if body.is_empty() {
+        return Err(ApiError::bad_request("Uploaded file is empty"));
+    }
+
+    // The temporary folder is created inside the library so the upload can be hardlinked
+    let tmp_dir = tempfile::tempdir_in(db.get_library_path())
+        .context("Couldn't create temporary folder for upload")?;
+    let tmp_path = tmp_dir.path().join(&file_name);
+    std::fs::write(&tmp_path, &body).context("Couldn't write uploaded file")?;
+
+    let importer = Importer::new(db.clone(), options.import_options.clone())?;
+    let tags: HashSet<String> = tags.into_iter().collect();
+    let imported_file = importer.import_file(&tmp_path, &tags);
+    let status = match imported_file.status {
+        ImportStatus::Imported => 201,
+        ImportStatus::Duplicate => 200,
+        ImportStatus::Skipped(rule_name) => {
+            return Err(ApiError::new(
+                422,
+                format!("File skipped by rule '{rule_name}'"),
+            ))
+        }
+        ImportStatus::Failed(err) => return Err(ApiError::new(500, err)),
+    };
+
+    let tagfile = db.get_tagfile_from_hash(&imported_file.hash)?;
+    info!("Server - upload_file() - Uploaded {tagfile}");
+    json_response(status, &FileInfo::from_tagfile(db, &tagfile))
+}
+
+pub fn edit_tags(db: &TagMaidDatabase, hash_hex: &str, request: &mut Request) -> ApiResult {
+    let mut tagfile = get_tagfile(db, hash_hex)?;
+    let body = read_body(request, 1024 * 1024)?;
+    let edit: TagEdit = serde_json::from_slice(&body)
+        .map_err(|err| ApiError::bad_request(format!("Invalid tag edit: {err}")))?;
+    validate_tags(&edit.add)?;
+    if let Some(tag) = edit.remove.iter().find(|tag| tag_util::is_system_tag(tag)) {
+        return Err(ApiError::bad_request(format!(
+            "System tag '{tag}' can't be edited"
+        )));
+    }
+
+    for tag in edit.remove.iter() {
+        tagfile.remove_tag(tag)?;
+    }
+    for tag in edit.add.iter() {
+        tagfile.add_tag(tag)?;
+    }
+    // Same rule as the Edit page: removing every tag would remove the file itself
+    if !tagfile
+        .get_tags()
+        .iter()
+        .any(|tag| !tag_util::is_system_tag(tag))
+    {
+        return Err(ApiError::new(409, "A file has to keep at least one tag"));
+    }
+    db.update_tagfile(&tagfile)?;
+
+    let tagfile = db.get_tagfile_from_hash(&tagfile.file_hash)?;
+    json_response(200, &FileInfo::from_tagfile(db, &tagfile))
+}
@@ -0,0 +1,431 @@
+//! Read-only endpoints modelled on Danbooru's API, so existing booru clients can browse the library:
+//! - `GET /posts.json?tags=<query>&limit=<n>&page=<n>`
+//! - `GET /posts/<id>.json`
+//! - `GET /counts/posts.json?tags=<query>`
+//! - `GET /tags.json?search[name_matches]=<pattern>&search[order]=<count|name>&limit=<n>&page=<n>`
+//!
+//! Posts are the `_files` rows, and their id is the row id. Danbooru's tag query syntax
+//! (`tag -tag ~tag`) is the same as ours, so queries go to `Search` as they are once the
+//! metatags we don't support (`rating:`, `order:`...) are taken out.
+use super::{json_response, ApiError, ApiResult, RequestUrl};
+use crate::data::url_util::percent_encode;
+use crate::data::{search_command::Search, tag_file::TagFile, tag_util};
+use crate::database::tagmaid_database::TagMaidDatabase;
+use regex::Regex;
+use serde::Serialize;
+use std::path::Path;
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 200;
+
+/// Danbooru metatags that are accepted but ignored (except for `order:`)
+const METATAGS: [&str; 10] = [
+    "order:",
+    "rating:",
+    "status:",
+    "limit:",
+    "score:",
+    "fav:",
+    "ordfav:",
+    "user:",
+    "source:",
+    "filetype:",
+];
+
+/// Used to build the absolute URLs of files and thumbnails in posts
+pub struct Links {
+    /// e.g. `http://127.0.0.1:7878`
+    pub base_url: String,
+    pub token: Option<String>,
+}
+
+impl Links {
+    fn file_url(&self, hash_hex: &str, endpoint: &str) -> String {
+        let mut url = format!("{}/api/files/{hash_hex}/{endpoint}", self.base_url);
+        if let Some(token) = &self.token {
+            url.push_str("?token=");
+            url.push_str(&percent_encode(token));
+        }
+        url
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Post {
+    pub id: u64,
+    pub created_at: Option<String>,
+    /// Danbooru clients use it as a unique key. We only have Blake3 hashes, so this is
+    /// the first 32 hexadecimal characters of it, NOT an actual MD5.
+    pub md5: String,
+    pub file_ext: String,
+    pub file_size: u64,
+    pub image_width: u32,
+    pub image_height: u32,
+    pub rating: String,
+    pub score: i64,
+    pub source: String,
+    pub tag_string: String,
+    pub tag_string_general: String,
+    pub tag_string_artist: String,
+    pub tag_string_character: String,
+    pub tag_string_copyright: String,
+    pub tag_string_meta: String,
+    pub tag_count: usize,
+    pub tag_count_general: usize,
+    pub file_url: String,
+    pub large_file_url: String,
+    pub preview_file_url: String,
+    pub has_large: bool,
+    pub is_deleted: bool,
+}
+
+impl Post {
+    pub fn from_tagfile(db: &TagMaidDatabase, id: u64, tagfile: &TagFile, links: &Links) -> Post {
+        let hash_hex = tag_util::bytes_to_hex(&tagfile.file_hash);
+        let path: &Path = tagfile.get_path();
+        let mut tags: Vec<&String> = tagfile.get_tags().iter().collect();
+        tags.sort();
+        let tag_string = tags
+            .iter()
+            .map(|tag| tag.as_str())
+            .collect::<Vec<&str>>()
+            .join(" ");
+        let (image_width, image_height) = image::image_dimensions(path).unwrap_or((0, 0));
+        let file_url = links.file_url(&hash_hex, "content");
+
+        Post {
+            id,
+            created_at: db
+                .get_upload_date(&tagfile.file_hash)
+                .ok()
+                .map(|date| date.to_rfc3339()),
+            md5: hash_hex[..32].to_owned(),
+            file_ext: Path::new(tagfile.get_file_name())
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_lowercase())
+                .unwrap_or_default(),
+            file_size: std::fs::metadata(path).map_or(0, |metadata| metadata.len()),
+            image_width,
+            image_height,
+            rating: "g".to_owned(),
+            score: 0,
+            source: db
+                .get_source_metadata(&tagfile.file_hash)
+                .ok()
+                .and_then(|mut source| source.remove("url"))
+                .unwrap_or_default(),
+            tag_string_general: tag_string.clone(),
+            tag_string,
+            tag_string_artist: String::new(),
+            tag_string_character: String::new(),
+            tag_string_copyright: String::new(),
+            tag_string_meta: String::new(),
+            tag_count: tags.len(),
+            tag_count_general: tags.len(),
+            large_file_url: file_url.clone(),
+            file_url,
+            preview_file_url: links.file_url(&hash_hex, "thumbnail"),
+            has_large: false,
+            is_deleted: false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BooruTag {
+    /// Tags have no id in TagMaid: this is their position in alphabetical order
+    pub id: usize,
+    pub name: String,
+    pub post_count: u64,
+    pub category: u8,
+    pub is_deprecated: bool,
+    pub words: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Page {
+    Number(usize),
+    BeforeId(u64),
+    AfterId(u64),
+}
+
+fn parse_page(url: &RequestUrl) -> Result<Page, ApiError> {
+    let page = url.param("page").unwrap_or("1");
+    let parsed = if let Some(id) = page.strip_prefix('b') {
+        id.parse().map(Page::BeforeId).ok()
+    } else if let Some(id) = page.strip_prefix('a') {
+        id.parse().map(Page::AfterId).ok()
+    } else {
+        page.parse().ok().filter(|n| *n > 0).map(Page::Number)
+    };
+    parsed.ok_or_else(|| ApiError::bad_request(format!("Invalid page '{page}'")))
+}
+
+fn parse_limit(url: &RequestUrl) -> Result<usize, ApiError> {
+    match url.param("limit") {
+        Some(limit) => limit
+            .parse::<usize>()
+            .map(|limit| limit.clamp(1, MAX_LIMIT))
+            .map_err(|_err| ApiError::bad_request(format!("Invalid limit '{limit}'"))),
+        None => Ok(DEFAULT_LIMIT),
+    }
+}
+
+/// Splits a Danbooru tag query into a query `Search` understands and whether posts
+/// should be sorted by ascending id (`order:id_asc`). Other metatags are dropped.
+fn translate_query(tags: &str) -> (String, bool) {
+    let mut ascending = false;
+    let mut kept: Vec<&str> = Vec::new();
+    for token in tags.split_whitespace() {
+        let lowercase = token.trim_start_matches(['-', '~']).to_lowercase();
+        if METATAGS
+            .iter()
+            .any(|metatag| lowercase.starts_with(metatag))
+        {
+            if lowercase == "order:id_asc" || lowercase == "order:id" {
+                ascending = true;
+            }
+            continue;
+        }
+        kept.push(token);
+    }
+    (kept.join(" "), ascending)
+}
+
+/// How many entries come before page `n`. Pages that far away would overflow, there is
+/// nothing there anyway.
+fn page_offset(n: usize, limit: usize) -> usize {
+    n.checked_sub(1)
+        .and_then(|previous_pages| previous_pages.checked_mul(limit))
+        .unwrap_or(usize::MAX)
+}
+
+/// The (id, hash) of files
+type PostIds = Vec<(u64, Vec<u8>)>;
+
+/// Returns the (id, hash) of every file matching a Danbooru tag query, newest first
+/// unless `order:id_asc` is given, and whether they are sorted by ascending id.
+fn search_posts(db: &TagMaidDatabase, tags: &str) -> Result<(PostIds, bool), ApiError> {
+    let (query, ascending) = translate_query(tags);
+    let search = Search::from_string(&query)
+        .map_err(|err| ApiError::bad_request(format!("Invalid tags '{tags}': {err}")))?;
+    let ids = db.get_file_ids()?;
+    let mut posts: Vec<(u64, Vec<u8>)> = db
+        .search(&search)?
+        .into_iter()
+        .filter_map(|hash| ids.get(&hash).map(|id| (*id, hash)))
+        .collect();
+    posts.sort_by_key(|(id, _hash)| *id);
+    if !ascending {
+        posts.reverse();
+    }
+    Ok((posts, ascending))
+}
+
+pub fn list_posts(db: &TagMaidDatabase, url: &RequestUrl, links: &Links) -> ApiResult {
+    let limit = parse_limit(url)?;
+    let page = parse_page(url)?;
+    let (posts, ascending) = search_posts(db, url.param("tags").unwrap_or_default())?;
+
+    let page_posts: Vec<(u64, Vec<u8>)> = match page {
+        Page::Number(n) => posts
+            .into_iter()
+            .skip(page_offset(n, limit))
+            .take(limit)
+            .collect(),
+        Page::BeforeId(before) => posts
+            .into_iter()
+            .filter(|(id, _hash)| *id < before)
+            .take(limit)
+            .collect(),
+        Page::AfterId(after) => {
+            // The posts right after `after`, in the order of the others
+            let mut after_posts: Vec<(u64, Vec<u8>)> = posts
+                .into_iter()
+                .filter(|(id, _hash)| *id > after)
+                .collect();
+            after_posts.sort_by_key(|(id, _hash)| *id);
+            after_posts.truncate(limit);
+            if !ascending {
+                after_posts.reverse();
+            }
+            after_posts
+        }
+    };
+    let response: Vec<Post> = page_posts
+        .iter()
+        .filter_map(|(id, hash)| {
+            let tagfile = db.get_tagfile_from_hash(hash).ok()?;
+            Some(Post::from_tagfile(db, *id, &tagfile, links))
+        })
+        .collect();
+    json_response(200, &response)
+}
+
+/// `post` is the last segment of `/posts/<id>.json`
+pub fn get_post(db: &TagMaidDatabase, post: &str, links: &Links) -> ApiResult {
+    let id: u64 = post
+        .strip_suffix(".json")
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| ApiError::not_found(format!("No post '{post}'")))?;
+    let hash = db
+        .get_hash_from_id(id)
+        .map_err(|_err| ApiError::not_found(format!("No post with id {id}")))?;
+    let tagfile = db.get_tagfile_from_hash(&hash)?;
+    json_response(200, &Post::from_tagfile(db, id, &tagfile, links))
+}
+
+pub fn count_posts(db: &TagMaidDatabase, url: &RequestUrl) -> ApiResult {
+    let (posts, _ascending) = search_posts(db, url.param("tags").unwrap_or_default())?;
+    json_response(
+        200,
+        &serde_json::json!({ "counts": { "posts": posts.len() } }),
+    )
+}
+
+/// Matches a Danbooru `name_matches` pattern, where `*` stands for any characters
+fn pattern_to_regex(pattern: &str) -> Regex {
+    let escaped: Vec<String> = pattern.split('*').map(regex::escape).collect();
+    Regex::new(&format!("(?i)^{}$", escaped.join(".*"))).unwrap()
+}
+
+pub fn list_tags(db: &TagMaidDatabase, url: &RequestUrl) -> ApiResult {
+    let limit = parse_limit(url)?;
+    let page = match parse_page(url)? {
+        Page::Number(n) => n,
+        _ => return Err(ApiError::bad_request("Tags can only be paged by number")),
+    };
+    let pattern = pattern_to_regex(url.param("search[name_matches]").unwrap_or("*"));
+
+    let mut all_tags: Vec<(String, u64)> = db.get_all_tags()?.into_iter().collect();
+    all_tags.sort();
+    let mut tags: Vec<BooruTag> = all_tags
+        .into_iter()
+        .enumerate()
+        .filter(|(_index, (name, _count))| pattern.is_match(name))
+        .map(|(index, (name, post_count))| BooruTag {
+            id: index + 1,
+            words: name.split('_').map(|word| word.to_owned()).collect(),
+            name,
+            post_count,
+            category: 0,
+            is_deprecated: false,
+        })
+        .collect();
+    match url.param("search[order]").unwrap_or("count") {
+        "name" => {}
+        "count" => tags.sort_by(|a, b| b.post_count.cmp(&a.post_count).then(a.id.cmp(&b.id))),
+        order => {
+            return Err(ApiError::bad_request(format!(
+                "Unsupported order '{order}'"
+            )))
+        }
+    }
+
+    let response: Vec<BooruTag> = tags
+        .into_iter()
+        .skip(page_offset(page, limit))
+        .take(limit)
+        .collect();
+    json_response(200, &response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tag_database::test_util::{add_file, create_random_tagmaiddatabase};
+    use crate::server::tests::send;
+    use crate::server::ServeOptions;
+    use tiny_http::TestRequest;
+
+    #[test]
+    fn should_translate_danbooru_query() {
+        assert_eq!(
+            translate_query("cat -dog rating:g order:id_asc ~bird"),
+            ("cat -dog ~bird".to_owned(), true)
+        );
+        assert_eq!(translate_query("-status:deleted"), (String::new(), false));
+        assert!(pattern_to_regex("cat*").is_match("Cat_ears"));
+        assert!(!pattern_to_regex("cat*").is_match("black_cat"));
+    }
+
+    #[test]
+    fn should_page_posts_newest_first() {
+        let db = create_random_tagmaiddatabase();
+        let options = ServeOptions::default();
+        add_file(&db, "first.txt", "first", &["cat"]);
+        add_file(&db, "second.txt", "second", &["cat", "sleeping"]);
+        add_file(&db, "third.txt", "third", &["dog"]);
+
+        let (status, body) = send(
+            &db,
+            &options,
+            TestRequest::new().with_path("/posts.json?tags=cat+rating:g&limit=1&page=2"),
+        );
+        assert_eq!(status, 200, "{body}");
+        let posts: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(posts.as_array().unwrap().len(), 1);
+        assert_eq!(posts[0]["id"], 1);
+        assert_eq!(posts[0]["tag_string"], "cat");
+
+        let huge_page = format!("/posts.json?limit=100&page={}", usize::MAX);
+        let (status, body) = send(&db, &options, TestRequest::new().with_path(&huge_page));
+        assert_eq!((status, body.as_str()), (200, "[]"));
+        let huge_page = format!("/tags.json?limit=100&page={}", usize::MAX);
+        let (status, body) = send(&db, &options, TestRequest::new().with_path(&huge_page));
+        assert_eq!((status, body.as_str()), (200, "[]"));
+
+        // The post right after the first one, not the newest
+        let ids = |path: &str| -> Vec<u64> {
+            let (_, body) = send(&db, &options, TestRequest::new().with_path(path));
+            let posts: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+            posts
+                .iter()
+                .map(|post| post["id"].as_u64().unwrap())
+                .collect()
+        };
+        assert_eq!(ids("/posts.json?limit=1&page=a1"), vec![2]);
+        assert_eq!(ids("/posts.json?limit=2&page=a1"), vec![3, 2]);
+        assert_eq!(
+            ids("/posts.json?tags=order:id_asc&limit=2&page=a1"),
+            vec![2, 3]
+        );
+        assert_eq!(ids("/posts.json?limit=1&page=b3"), vec![2]);
+
+        let (_, body) = send(&db, &options, TestRequest::new().with_path("/posts/2.json"));
+        let post: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(post["tag_string"], "cat sleeping");
+        assert!(post["file_url"].as_str().unwrap().ends_with("/content"));
+        let links = Links {
+            base_url: "http://127.0.0.1:7878".to_owned(),
+            token: Some("a&b#c".to_owned()),
+        };
+        assert_eq!(
+            links.file_url("ab12", "content"),
+            "http://127.0.0.1:7878/api/files/ab12/content?token=a%26b%23c"
+        );
+
+        let (_, body) = send(
+            &db,
+            &options,
+            TestRequest::new().with_path("/counts/posts.json?tags=cat"),
+        );
+        assert_eq!(body, r#"{"counts":{"posts":2}}"#);
+
+        let (_, body) = send(&db, &options, TestRequest::new().with_path("/tags.json"));
+        let tags: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(tags[0]["name"], "cat");
+        assert_eq!(tags[0]["post_count"], 2);
+        assert_eq!(tags[1]["name"], "dog");
+
+        let (_, body) = send(
+            &db,
+            &options,
+            TestRequest::new().with_path("/tags.json?search%5Bname_matches%5D=s*"),
+        );
+        let tags: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(tags.as_array().unwrap().len(), 1);
+        assert_eq!(tags[0]["name"], "sleeping");
+        assert_eq!(tags[0]["id"], 3);
+    }
+}
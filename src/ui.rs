@@ -8,7 +8,7 @@ use image::EncodableLayout;
 
 use std::{
     cell::RefCell,
-    collections::{BTreeSet, HashMap},
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
     path::PathBuf,
     rc::Rc,
     sync::{Arc, Mutex, MutexGuard, RwLock},
@@ -17,10 +17,17 @@ use std::{
 use crate::data::{
     self,
     config::{Config, Theme},
+    import::{ImportOptions, ImportStatus, ImportedFile, Importer},
+    perceptual_hash::DuplicateGroup,
     search_command::Search,
     tag_file::TagFile,
+    thumbnail_pool::ThumbnailPool,
+    thumbnails::{ThumbnailSize, ThumbnailStore},
+    url_import,
 };
 
+use crate::instance::{InstanceLock, IpcMessage};
+
 use crate::database::{
     sqlite_database::SqliteDatabase, tag_database::TagDatabase, tagmaid_database::TagMaidDatabase,
 };
@@ -60,6 +67,7 @@ enum ViewPage {
     View,
     Edit,
     RemoveFile,
+    Duplicates,
 }
 impl ViewPage {
     fn add(&self) -> bool {
@@ -95,7 +103,9 @@ impl ViewPage {
 
 pub struct TagMaid {
     mode: ViewPage,
-    thumbnail_paths: Arc<RwLock<HashMap<Vec<u8>, Arc<PathBuf>>>>,
+    thumbnails: ThumbnailStore,
+    // Makes the grid thumbnails of the results in the background
+    thumbnail_pool: ThumbnailPool,
     db: TagMaidDatabase,
     conf: Config,
     // Search
@@ -108,6 +118,8 @@ pub struct TagMaid {
     // Add form
     add_path: Option<PathBuf>,
     path_future: Option<std::thread::JoinHandle<Option<PathBuf>>>,
+    add_url: String,
+    url_future: Option<std::thread::JoinHandle<Result<ImportedFile>>>,
     // View
     viewmode_tagfile_hash: Option<Vec<u8>>,
     // Edit
@@ -116,26 +128,131 @@ pub struct TagMaid {
     edit_add_tags: String,
     // Remove
     remove_tagfile: Option<TagFile>,
+    // Duplicates
+    duplicate_groups: Vec<DuplicateGroup>,
+    duplicate_index: usize,
+    // Index in the group of the file that is kept when merging
+    duplicate_keep: usize,
+    duplicates_future: Option<std::thread::JoinHandle<Result<Vec<DuplicateGroup>>>>,
+    duplicates_status: Option<String>,
+    // Folder import
+    folder_future: Option<std::thread::JoinHandle<Option<PathBuf>>>,
+    import_handle: Option<std::thread::JoinHandle<()>>,
+    import_status: Arc<Mutex<Option<String>>>,
+    // Files waiting to go through the Add/Edit flow, from the command line or other instances
+    open_queue: VecDeque<PathBuf>,
+    // Messages from other instances, handled on the next frame
+    inbox: Arc<Mutex<VecDeque<IpcMessage>>>,
+    instance_lock: InstanceLock,
 }
 impl TagMaid {
-    pub fn new(_cc: &eframe::CreationContext<'_>, db: TagMaidDatabase, conf: Config) -> Self {
-        Self {
+    pub fn new(
+        cc: &eframe::CreationContext<'_>,
+        db: TagMaidDatabase,
+        conf: Config,
+        instance_lock: InstanceLock,
+        startup_message: Option<IpcMessage>,
+    ) -> Self {
+        let inbox = Arc::new(Mutex::new(VecDeque::new()));
+        let listener_inbox = inbox.clone();
+        let ctx = cc.egui_ctx.clone();
+        let listening = instance_lock.listen(move |message| {
+            listener_inbox.lock().unwrap().push_back(message);
+            ctx.request_repaint();
+        });
+        if let Err(err) = listening {
+            info!("WARNING: TagMaid::new(): Other instances won't be able to hand us files: {err}");
+        }
+
+        let thumbnails = ThumbnailStore::new(&db.get_library_path(), &conf.thumbnails);
+        let pixels_per_point = cc.integration_info.native_pixels_per_point.unwrap_or(1.0);
+        let ctx = cc.egui_ctx.clone();
+        let thumbnail_pool = ThumbnailPool::new(
+            db.clone(),
+            thumbnails.clone(),
+            ThumbnailSize::grid_for(pixels_per_point),
+            conf.thumbnails.workers,
+            Arc::new(move || ctx.request_repaint()),
+        );
+
+        let mut app = Self {
             mode: ViewPage::Search,
+            thumbnails,
+            thumbnail_pool,
             db: db,
             search: String::new(),
             results: Arc::new(Mutex::new(Vec::new())),
             update_search: Arc::new(Mutex::new(false)),
             search_err: None,
             search_options: None,
-            thumbnail_paths: Arc::new(RwLock::new(HashMap::new())),
             add_path: None,
             path_future: None,
+            add_url: String::new(),
+            url_future: None,
             conf: conf,
             viewmode_tagfile_hash: None,
             edit_hash: None,
             edit_tags: BTreeSet::new(),
             edit_add_tags: String::new(),
             remove_tagfile: None,
+            duplicate_groups: Vec::new(),
+            duplicate_index: 0,
+            duplicate_keep: 0,
+            duplicates_future: None,
+            duplicates_status: None,
+            folder_future: None,
+            import_handle: None,
+            import_status: Arc::new(Mutex::new(None)),
+            open_queue: VecDeque::new(),
+            inbox,
+            instance_lock,
+        };
+        if let Some(message) = startup_message {
+            app.handle_message(message);
+        }
+        app
+    }
+
+    /// Handles what the command line or another instance asked for
+    fn handle_message(&mut self, message: IpcMessage) {
+        match message {
+            IpcMessage::Open(paths) => {
+                self.open_queue.extend(paths);
+                // Queued files wait for the user to be done with the one being edited
+                let editing = matches!(self.mode, ViewPage::Edit)
+                    || (self.mode.add() && self.add_path.is_some());
+                if !editing {
+                    self.open_next_queued_file();
+                }
+            }
+            IpcMessage::Search(query) => {
+                self.search = query;
+                self.start_search();
+            }
+        }
+    }
+
+    /// Skips to the next queued file, shown in the header of the Add and Edit tabs
+    fn ui_next_file_button(&mut self, ui: &mut egui::Ui) {
+        let queued_files = self.open_queue.len();
+        if queued_files > 0
+            && ui
+                .button(format!("Next file ({queued_files} left)"))
+                .clicked()
+        {
+            self.open_next_queued_file();
+        }
+    }
+
+    /// Sends the next queued file to the Add/Edit flow, if there is one
+    fn open_next_queued_file(&mut self) {
+        let next_path = self.open_queue.pop_front();
+        if let Some(path) = next_path {
+            info!("Opening queued file {}", path.display());
+            self.add_path = Some(path);
+            self.edit_hash = None;
+            self.edit_tags = BTreeSet::new();
+            self.mode = ViewPage::Add;
         }
     }
 
@@ -192,66 +309,28 @@ impl TagMaid {
         return handle;
     }
 
-    fn get_thumbnail_path(
-        thumbnail_paths: Arc<RwLock<HashMap<Vec<u8>, Arc<PathBuf>>>>,
-        tagfile: Arc<TagFile>,
-    ) -> Arc<PathBuf> {
-        let hash = &tagfile.file_hash;
-        if thumbnail_paths.read().unwrap().get(hash).is_none() {
-            let path = tagfile.get_thumbnail_path();
-            thumbnail_paths
-                .write()
-                .unwrap()
-                .insert(hash.clone(), path.into());
-        }
-        thumbnail_paths.read().unwrap()[hash].clone()
+    /// The View sized version of `tagfile`, or the file itself if it can't be made
+    fn preview_path(&self, tagfile: &TagFile) -> PathBuf {
+        self.thumbnails
+            .get(tagfile, ThumbnailSize::Preview)
+            .unwrap_or_else(|| tagfile.get_path().to_owned())
     }
 
     /// Obtain results from a given search query `se`. Designed to work in a thread.
     /// Saves results to `res`. `searching` is `true` when the search is being done, `false`
-    /// after it is over. `thumbnail_paths` is handled immediately here for optimisation purposes  
+    /// after it is over.
     fn get_results(
         se: Search,
         res: Arc<Mutex<Vec<Vec<u8>>>>,
         searching: Arc<Mutex<bool>>,
         db: TagMaidDatabase,
-        thumbnail_paths: Arc<RwLock<HashMap<Vec<u8>, Arc<PathBuf>>>>,
     ) -> Result<()> {
         info!("Grabbing results");
-        // No cached results
-        let fs_db_mutex = db.get_fs_db();
-        let fs_db: MutexGuard<TagDatabase> = fs_db_mutex.lock().unwrap();
-        let mut cands = match se.first_tag() {
-            Some(s) => fs_db.get_hashes_from_tag(&s),
-            None => fs_db.get_all_file_hashes(),
-        };
-        drop(fs_db);
+        let cands = db.search(&se);
         if cands.is_err() {
             *searching.lock().unwrap() = false;
             return Err(cands.unwrap_err());
         } else {
-            cands.as_mut().unwrap().retain(|hash| {
-                let tags = &db.get_tags_from_hash(hash);
-                match tags {
-                    Ok(tags) => se.filter_post(&tags),
-                    Err(..) => false,
-                }
-            });
-            let mut pool = Vec::new();
-            for i in cands.as_ref().unwrap().iter() {
-                if let Ok(tf) = db.get_tagfile_from_hash(i) {
-                    let thumbnail_paths = thumbnail_paths.clone();
-                    pool.push(std::thread::spawn(move || {
-                        Self::get_thumbnail_path(thumbnail_paths, tf.into());
-                    }));
-                }
-                while pool.len() > 5 {
-                    pool.retain(|i| !i.is_finished());
-                }
-            }
-            for i in pool.into_iter() {
-                i.join().ok();
-            }
             let results_vec: Vec<Vec<u8>> = cands.unwrap().into_iter().collect();
             *res.lock().unwrap() = results_vec;
             *searching.lock().unwrap() = false;
@@ -288,40 +367,41 @@ impl TagMaid {
             self.ui_logo(ctx, ui);
             ui.label(egui::RichText::new("Add").font(egui::FontId::monospace(40.0)));
             ui.add_space(15.0);
+            self.ui_next_file_button(ui);
         });
         ui.add(egui::Separator::default().horizontal());
         ui.add_space(5.0);
         match &self.add_path {
             Some(dragged_file_path) => {
-                let maybe_tagfile: Option<TagFile> =
-                    TagFile::initialise_from_path(dragged_file_path).ok();
-                match maybe_tagfile {
-                    Some(tagfile) => {
-                        // Load file hash and tags (if file in database), then send to edit mode
-
-                        // Caching file hash and tags in a BTreeSet to have it look sorted
-                        self.edit_tags = BTreeSet::new();
-                        let maybe_tags = &self.db.get_tags_from_hash(&tagfile.file_hash).ok();
-                        self.edit_hash = Some((&tagfile.file_hash).clone());
-                        match maybe_tags {
-                            Some(tags) => {
-                                for tag in tags {
-                                    self.edit_tags.insert(tag.clone());
-                                }
-                            }
-                            None => {
-                                // File not in db so add it
-                                self.db.update_tagfile(&tagfile).ok();
-                            }
+                // Goes through the importer so the auto-tagging rules apply
+                let imported_file = Importer::new(self.db.clone(), self.conf.import_options())
+                    .map(|importer| importer.import_file(dragged_file_path, &HashSet::new()));
+                match imported_file {
+                    Ok(imported_file) => match imported_file.status {
+                        ImportStatus::Imported | ImportStatus::Duplicate => {
+                            // Caching file hash and tags in a BTreeSet to have it look sorted
+                            self.edit_hash = Some(imported_file.hash);
+                            self.edit_tags = imported_file.tags;
+                            self.mode = ViewPage::Edit;
                         }
-                        self.mode = ViewPage::Edit;
-                    }
-                    None => {
-                        self.ui_error(
-                            ctx,
-                            ui,
-                            "Sorry, we couldn't initialise this file you just dragged.",
-                        );
+                        ImportStatus::Skipped(rule_name) => {
+                            *self.import_status.lock().unwrap() = Some(format!(
+                                "{} was skipped by rule '{rule_name}'",
+                                dragged_file_path.display()
+                            ));
+                            self.add_path = None;
+                            self.open_next_queued_file();
+                        }
+                        ImportStatus::Failed(_) => {
+                            self.ui_error(
+                                ctx,
+                                ui,
+                                "Sorry, we couldn't initialise this file you just dragged.",
+                            );
+                        }
+                    },
+                    Err(err) => {
+                        self.ui_error(ctx, ui, &format!("Invalid auto-tagging rules: {err:#}"));
                     }
                 }
             }
@@ -349,6 +429,7 @@ impl TagMaid {
             self.ui_logo(ctx, ui);
             ui.label(egui::RichText::new("Edit").font(egui::FontId::monospace(40.0)));
             ui.add_space(15.0);
+            self.ui_next_file_button(ui);
         });
         ui.add(egui::Separator::default().horizontal());
         ui.add_space(5.0);
@@ -357,10 +438,9 @@ impl TagMaid {
         match &self.edit_hash {
             Some(hash) => {
                 let mut tagfile = self.db.get_tagfile_from_hash(hash).unwrap();
-                let image_texture = &self.get_texture(
-                    ctx,
-                    &TextureLabel::FileThumbnail(Arc::new(PathBuf::from(tagfile.get_path()))),
-                );
+                let image_path = self.preview_path(&tagfile);
+                let image_texture =
+                    &self.get_texture(ctx, &TextureLabel::FileThumbnail(Arc::new(image_path)));
                 let file_tags = &self.edit_tags.clone();
                 ui.vertical_centered(|ui| {
                     ui.horizontal(|ui| {
@@ -375,12 +455,19 @@ impl TagMaid {
                                             .font(egui::FontId::monospace(17.0)),
                                     );
                                     ui.add_space(5.0);
-                                    for tag in file_tags.clone().into_iter() {
+                                    // System tags are computed by TagMaid so they can't be removed
+                                    let (system_tags, user_tags): (Vec<String>, Vec<String>) =
+                                        file_tags
+                                            .iter()
+                                            .cloned()
+                                            .partition(|tag| data::tag_util::is_system_tag(tag));
+                                    let user_tag_count = user_tags.len();
+                                    for tag in user_tags.into_iter() {
                                         ui.horizontal(|ui| {
                                             if ui.button("-").clicked() {
                                                 // TODO: If all tags are removed, safely delete file
                                                 // (right now we prevent the user from doing that because it panics)
-                                                if file_tags.len() > 1 {
+                                                if user_tag_count > 1 {
                                                     // Update DB
                                                     tagfile.remove_tag(&tag).ok();
                                                     self.db.update_tagfile(&tagfile).ok();
@@ -395,6 +482,20 @@ impl TagMaid {
                                             );
                                         });
                                     }
+                                    if !system_tags.is_empty() {
+                                        ui.add_space(10.0);
+                                        ui.label(
+                                            egui::RichText::new("System tags")
+                                                .font(egui::FontId::monospace(17.0)),
+                                        );
+                                        for tag in system_tags.iter() {
+                                            ui.label(
+                                                egui::RichText::new(tag)
+                                                    .font(egui::FontId::monospace(14.0))
+                                                    .weak(),
+                                            );
+                                        }
+                                    }
                                 });
                             });
                         });
@@ -443,7 +544,10 @@ impl TagMaid {
                             {
                                 let split_tags = self.edit_add_tags.split_whitespace();
                                 for tag in split_tags {
-                                    if data::tag_util::is_tag_name_valid(&tag) {
+                                    if data::tag_util::is_tag_name_valid(tag)
+                                        && !data::tag_util::is_system_tag(tag)
+                                        && !data::tag_util::is_reserved_tag(tag)
+                                    {
                                         // TODO: Adding tags one by one sucks
                                         tagfile.add_tag(&tag).ok();
                                         self.db.update_tagfile(&tagfile).ok();
@@ -462,6 +566,131 @@ impl TagMaid {
         }
     }
 
+    /// Lets the user pick a folder to import in the background, with the names of its
+    /// subfolders as tags, and shows how the import is going.
+    fn ui_import_folder(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
+        let importing = self
+            .import_handle
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished());
+        let folder_label = ui.label(
+            egui::RichText::new("...or click here to import a whole folder")
+                .font(egui::FontId::monospace(20.0)),
+        );
+        if folder_label.interact(egui::Sense::click()).clicked()
+            && !importing
+            && self.folder_future.is_none()
+        {
+            self.folder_future = Some(std::thread::spawn(|| rfd::FileDialog::new().pick_folder()));
+        }
+        if let Some(status) = self.import_status.lock().unwrap().as_ref() {
+            ui.label(egui::RichText::new(status).font(egui::FontId::monospace(14.0)));
+        }
+
+        let picked = self
+            .folder_future
+            .as_ref()
+            .is_some_and(|future| future.is_finished());
+        if picked {
+            // the first unwrap is checked the second one carries the panic from the thread
+            if let Some(folder) = self.folder_future.take().unwrap().join().unwrap() {
+                let db = self.db.clone();
+                let options = self.conf.import_options();
+                let status = self.import_status.clone();
+                let ctx = ctx.clone();
+                self.import_handle = Some(std::thread::spawn(move || {
+                    Self::import_folder(db, folder, options, status, ctx);
+                }));
+            }
+        }
+    }
+
+    /// Lets the user paste a link to a file to download and import it in the background,
+    /// then edit its tags.
+    fn ui_import_url(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
+        ui.add_space(20.0);
+        ui.horizontal(|ui| {
+            ui.label(
+                egui::RichText::new("...or paste a link:").font(egui::FontId::monospace(20.0)),
+            );
+            ui.text_edit_singleline(&mut self.add_url);
+            let downloading = self.url_future.is_some();
+            let button = ui.add_enabled(!downloading, egui::Button::new("Import URL"));
+            if button.clicked() && !self.add_url.trim().is_empty() {
+                let db = self.db.clone();
+                let options = self.conf.import_options();
+                let config = self.conf.url_import.clone();
+                let url = self.add_url.trim().to_owned();
+                let ctx = ctx.clone();
+                *self.import_status.lock().unwrap() = Some(format!("Downloading {url}..."));
+                self.url_future = Some(std::thread::spawn(move || {
+                    let imported_file = Importer::new(db.clone(), options).and_then(|importer| {
+                        url_import::import_url(&db, &importer, &url, &config, &HashSet::new())
+                    });
+                    ctx.request_repaint();
+                    imported_file
+                }));
+            }
+        });
+
+        let finished = self
+            .url_future
+            .as_ref()
+            .is_some_and(|future| future.is_finished());
+        if finished {
+            // the first unwrap is checked the second one carries the panic from the thread
+            let result = self.url_future.take().unwrap().join().unwrap();
+            let status = match result {
+                Ok(imported_file) => match imported_file.status {
+                    ImportStatus::Skipped(rule_name) => Some(format!(
+                        "{} was skipped by rule '{rule_name}'",
+                        self.add_url
+                    )),
+                    _ => {
+                        self.edit_hash = Some(imported_file.hash);
+                        self.edit_tags = imported_file.tags;
+                        self.mode = ViewPage::Edit;
+                        self.add_url.clear();
+                        None
+                    }
+                },
+                Err(err) => Some(format!("Couldn't import the link: {err:#}")),
+            };
+            *self.import_status.lock().unwrap() = status;
+        }
+    }
+
+    /// Imports `folder` with `Importer` on top of the `options` from the config, writing
+    /// its progress to `status`. Designed to work in a thread.
+    fn import_folder(
+        db: TagMaidDatabase,
+        folder: PathBuf,
+        options: ImportOptions,
+        status: Arc<Mutex<Option<String>>>,
+        ctx: egui::Context,
+    ) {
+        let options = ImportOptions {
+            path_tags: true,
+            ..options
+        };
+        let result = Importer::new(db, options).and_then(|importer| {
+            importer.import_folder(&folder, |progress| {
+                *status.lock().unwrap() = Some(format!(
+                    "Importing {}/{}: {}",
+                    progress.done,
+                    progress.total,
+                    progress.file.path.display()
+                ));
+                ctx.request_repaint();
+            })
+        });
+        *status.lock().unwrap() = Some(match result {
+            Ok(report) => report.summary(),
+            Err(err) => format!("Import failed: {err:#}"),
+        });
+        ctx.request_repaint();
+    }
+
     /// The "Add" tab when no file is being edited.
     /// It tells the user to drag a file or choose one with the file dialog.
     fn ui_add_drag(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
@@ -475,44 +704,16 @@ impl TagMaid {
                 if click_label_response.clicked() && self.path_future.is_none() {
                     self.path_future = Some(std::thread::spawn(|| rfd::FileDialog::new().pick_file()));
                 }
+                self.ui_import_folder(ctx, ui);
+                self.ui_import_url(ctx, ui);
             });
             if let Some(s) = &self.path_future {
                 if s.is_finished() {
                     // the first unwrap is checked the second one carries the panic from the thread
                     let r = self.path_future.take().unwrap().join().unwrap();
                     if let Some(p) = r {
-                        self.add_path = Some(p.clone());
-                        self.edit_tags = BTreeSet::new();
-                        let empty_tagfile = TagFile::initialise_from_path(&p).ok();
-                        match empty_tagfile {
-                            Some(loaded_tagfile) => {
-                                let maybe_db_tagfile = self.db.get_tagfile_from_hash(&loaded_tagfile.file_hash).ok();
-                                match maybe_db_tagfile {
-                                    Some(tagfile) => {
-                                        // File in db
-                                        for tag in tagfile.get_tags().clone() {
-                                            self.edit_tags.insert(tag);
-                                        }
-                                        self.edit_hash = Some(tagfile.file_hash.clone());
-                                    }
-                                    None => {
-                                        // File not in db yet; add it
-                                        self.db.update_tagfile(&loaded_tagfile).ok();
-                                        self.edit_hash = Some(loaded_tagfile.file_hash.clone());
-                                    }
-                                }
-                                // TODO: is this reaaaally necessary?
-                                if self.db.get_tagfile_from_hash(&self.edit_hash.clone().unwrap()).is_ok() {
-                                    // File is in db now, send user to edit mode
-                                    self.mode = ViewPage::Edit;
-                                } else {
-                                    self.ui_error(ctx, ui, "Sorry, we couldn't initialise this file you just tried to add in the database.");
-                                }
-                            }
-                            None => {
-                                self.ui_error(ctx, ui, "Sorry, we couldn't initialise this file you just tried to add.");
-                            }
-                        }
+                        // ui_add() takes it from there
+                        self.add_path = Some(p);
                     }
                 }
             }
@@ -528,10 +729,12 @@ impl TagMaid {
         });
         ui.add(egui::Separator::default().horizontal());
         ui.add_space(5.0);
+        // Buttons that start a new search, done once the file isn't borrowed anymore
+        let mut new_search: Option<String> = None;
         match &self.viewmode_tagfile_hash {
             Some(hash) => {
                 let tagfile = self.db.get_tagfile_from_hash(&hash).unwrap();
-                let image_path = tagfile.get_path().to_owned();
+                let image_path = self.preview_path(&tagfile);
                 let image_texture =
                     &self.get_texture(ctx, &TextureLabel::FileThumbnail(Arc::new(image_path)));
 
@@ -548,6 +751,16 @@ impl TagMaid {
                         }
                         self.mode = ViewPage::Edit;
                     }
+                    let find_similar = ui
+                        .button("Find similar")
+                        .on_hover_text("Searches for images that look like this one. Add tags to the search to narrow it down");
+                    if find_similar.clicked() {
+                        new_search = Some(format!(
+                            "{}{}",
+                            data::tag_util::SIMILAR_PREFIX,
+                            data::tag_util::bytes_to_hex(hash)
+                        ));
+                    }
                     if ui.button("Copy path").clicked() {
                         ctx.output_mut(|out| {
                             out.copied_text = tagfile.get_path().to_string_lossy().to_string();
@@ -572,6 +785,35 @@ impl TagMaid {
                     }
                 });
 
+                // Colour swatches, each one as wide as the part of the image it covers
+                if let Ok(Some(palette)) = self.db.get_palette(hash) {
+                    ui.add_space(5.0);
+                    ui.horizontal(|ui| {
+                        ui.spacing_mut().item_spacing.x = 0.0;
+                        for colour in palette.iter() {
+                            let size = egui::vec2(8.0 + colour.share as f32 * 3.0, 20.0);
+                            let (rect, response) =
+                                ui.allocate_exact_size(size, egui::Sense::click());
+                            let [r, g, b] = colour.rgb;
+                            ui.painter()
+                                .rect_filled(rect, 0.0, egui::Color32::from_rgb(r, g, b));
+                            let hover_text = format!(
+                                "{} ({}, {}%)\nClick to search for this colour",
+                                colour.hex(),
+                                data::palette::color_name(colour.rgb),
+                                colour.share
+                            );
+                            if response.on_hover_text(hover_text).clicked() {
+                                new_search = Some(format!(
+                                    "{}{}",
+                                    data::tag_util::COLOR_PREFIX,
+                                    colour.hex()
+                                ));
+                            }
+                        }
+                    });
+                }
+
                 ui.add_space(15.0);
 
                 ui.group(|ui| {
@@ -594,6 +836,10 @@ impl TagMaid {
                 ui.label("No file selected");
             }
         }
+        if let Some(query) = new_search {
+            self.search = query;
+            self.start_search();
+        }
     }
 
     fn ui_remove_file(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
@@ -629,10 +875,9 @@ impl TagMaid {
                 });
                 ui.add_space(15.0);
                 ui.vertical_centered(|ui| {
-                    let image_texture = &self.get_texture(
-                        ctx,
-                        &TextureLabel::FileThumbnail(Arc::new(PathBuf::from(tagfile.get_path()))),
-                    );
+                    let image_path = self.preview_path(tagfile);
+                    let image_texture =
+                        &self.get_texture(ctx, &TextureLabel::FileThumbnail(Arc::new(image_path)));
 
                     let height_limit = 340.0;
                     let width_limit = 650.0;
@@ -656,6 +901,244 @@ impl TagMaid {
         }
     }
 
+    /// The "Duplicates" tab. Finds images that look the same and shows each group side by
+    /// side, so that the user can pick the one to keep. Merging keeps the tags of all of them.
+    fn ui_duplicates(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            self.ui_logo(ctx, ui);
+            ui.label(egui::RichText::new("Duplicates").font(egui::FontId::monospace(40.0)));
+            ui.add_space(15.0);
+        });
+        ui.add(egui::Separator::default().horizontal());
+        ui.add_space(5.0);
+
+        let finished = self
+            .duplicates_future
+            .as_ref()
+            .is_some_and(|future| future.is_finished());
+        if finished {
+            // the first unwrap is checked the second one carries the panic from the thread
+            match self.duplicates_future.take().unwrap().join().unwrap() {
+                Ok(groups) => {
+                    self.duplicates_status =
+                        Some(format!("{} groups of near-duplicates", groups.len()));
+                    self.duplicate_groups = groups;
+                }
+                Err(err) => {
+                    self.duplicates_status = Some(format!("Couldn't look for duplicates: {err:#}"));
+                }
+            }
+            self.duplicate_index = 0;
+            self.duplicate_keep = 0;
+        }
+
+        ui.horizontal(|ui| {
+            let searching = self.duplicates_future.is_some();
+            if ui
+                .add_enabled(!searching, egui::Button::new("Find duplicates"))
+                .clicked()
+            {
+                let db = self.db.clone();
+                let ctx = ctx.clone();
+                self.duplicates_status = Some("Looking for duplicates...".to_owned());
+                self.duplicates_future = Some(std::thread::spawn(move || {
+                    let groups = data::perceptual_hash::find_duplicates(
+                        &db,
+                        data::perceptual_hash::DEFAULT_DISTANCE,
+                    );
+                    ctx.request_repaint();
+                    groups
+                }));
+            }
+            if let Some(status) = &self.duplicates_status {
+                ui.label(status);
+            }
+        });
+        ui.add_space(5.0);
+
+        let group = match self.duplicate_groups.get(self.duplicate_index) {
+            Some(group) => group.clone(),
+            None => return,
+        };
+        let mut merge = false;
+        let mut skip = false;
+        ui.horizontal(|ui| {
+            ui.label(format!(
+                "Group {} of {}",
+                self.duplicate_index + 1,
+                self.duplicate_groups.len()
+            ));
+            if ui
+                .add_enabled(self.duplicate_index > 0, egui::Button::new("Previous"))
+                .clicked()
+            {
+                self.duplicate_index -= 1;
+                self.duplicate_keep = 0;
+            }
+            let has_next = self.duplicate_index + 1 < self.duplicate_groups.len();
+            if ui
+                .add_enabled(has_next, egui::Button::new("Next"))
+                .clicked()
+            {
+                self.duplicate_index += 1;
+                self.duplicate_keep = 0;
+            }
+            merge = ui
+                .button("Merge")
+                .on_hover_text(
+                    "Keeps the selected file with the tags of all of them, removes the others",
+                )
+                .clicked();
+            skip = ui
+                .button("Skip")
+                .on_hover_text("Not duplicates, forget about this group")
+                .clicked();
+        });
+        ui.add_space(10.0);
+
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            ui.columns(group.len(), |columns| {
+                for (index, hash) in group.iter().enumerate() {
+                    let ui = &mut columns[index];
+                    let tagfile = match self.db.get_tagfile_from_hash(hash) {
+                        Ok(tagfile) => tagfile,
+                        Err(_) => {
+                            ui.label("This file isn't in the library anymore");
+                            continue;
+                        }
+                    };
+                    let image_path = self.preview_path(&tagfile);
+                    let image_texture =
+                        &self.get_texture(ctx, &TextureLabel::FileThumbnail(Arc::new(image_path)));
+                    let img_size: Vec2 = image_texture.size_vec2();
+                    let width_limit = ui.available_width();
+                    let height_limit = 300.0;
+                    let scale = (width_limit / img_size.x)
+                        .min(height_limit / img_size.y)
+                        .min(1.0);
+                    ui.image(image_texture.id(), img_size * scale);
+                    ui.radio_value(&mut self.duplicate_keep, index, "Keep this one");
+                    ui.label(egui::RichText::new(tagfile.get_file_name()).strong());
+                    ui.label(format!("{} x {}", img_size.x, img_size.y));
+                    let mut tags: Vec<&String> = tagfile.get_tags().iter().collect();
+                    tags.sort();
+                    for tag in tags {
+                        ui.label(tag);
+                    }
+                }
+            });
+        });
+
+        if merge {
+            let keep = &group[self.duplicate_keep.min(group.len() - 1)];
+            let others: Vec<Vec<u8>> = group.iter().filter(|hash| hash != &keep).cloned().collect();
+            match data::perceptual_hash::merge_duplicates(&self.db, keep, &others) {
+                Ok(()) => {
+                    // The removed files shouldn't show up in the results anymore
+                    self.results
+                        .lock()
+                        .unwrap()
+                        .retain(|hash| !others.contains(hash));
+                    self.duplicates_status = Some(format!("Merged {} files", group.len()));
+                    skip = true;
+                }
+                Err(err) => {
+                    self.duplicates_status = Some(format!("Couldn't merge: {err:#}"));
+                }
+            }
+        }
+        if skip {
+            self.duplicate_groups.remove(self.duplicate_index);
+            self.duplicate_index = self
+                .duplicate_index
+                .min(self.duplicate_groups.len().saturating_sub(1));
+            self.duplicate_keep = 0;
+        }
+    }
+
+    /// Parses `self.search`, runs it and sends the user to the Results page,
+    /// or shows the parsing error under the search bar.
+    fn start_search(&mut self) {
+        match Search::from_string(&self.search) {
+            Ok(v) => {
+                *self.update_search.lock().unwrap() = true;
+                self.search_err = None;
+                let nbool = Arc::clone(&self.update_search);
+                let nres = Arc::clone(&self.results);
+                let db = self.db.clone();
+                let search = v.clone();
+                let mut is_cached = false;
+                self.search_options = Some(v.clone());
+
+                // Try finding a cached search
+
+                match self.db.get_cache().get_search(&search) {
+                    Some(search_results) => {
+                        is_cached = true;
+                        *nres.lock().unwrap() = search_results.clone();
+                        *nbool.clone().lock().unwrap() = false;
+                    }
+                    None => {}
+                }
+
+                // Search wasn't cached
+                if !is_cached {
+                    let handle = std::thread::spawn(move || {
+                        match Self::get_results(search, nres.clone(), nbool, db) {
+                            Ok(..) => {}
+                            Err(..) => {
+                                nres.clone().lock().unwrap().clear();
+                            }
+                        }
+                    });
+                    /*
+                    So there was basically a data race or whatever its called because
+                    the thread takes time and the caching functions would accidentally
+                    access the previous result because the thread wasn't updating it yet
+
+                    So... I used join(), it works perfectly as intended, but this makes
+                    me question whether it defeats the whole purpose of the thread. Not that it
+                    panics or whatever, but idk.
+
+                    I couldn't implement the "put in cache" apart in the get_results() function
+                    because the cache is a RefCell and even though it compiles and works fine,
+                    the "real" RefCell never gets updated so the stuff never got cached.
+
+                    Idk how to fix this other than join(). Imo given that we can only do one search
+                    at once (as a user) I'd say it's fine right now. But something feels wrong.
+                    */
+                    if handle.join().is_err() {
+                        // Search failed, stop hanging it
+                        *self.update_search.lock().unwrap() = false;
+                    };
+
+                    // Attempts to cache the search results
+                    match self
+                        .db
+                        .get_cache()
+                        .cache_search(v.clone(), self.results.clone().lock().unwrap().to_vec())
+                    {
+                        Ok(()) => {}
+                        Err(err) => {
+                            // Fails silently because not being able to cache sometimes isn't
+                            // that big of a deal
+                            info!("WARNING: start_search(): Couldn't open cache as mutable because it was already being borrowed: {err}");
+                        }
+                    }
+                }
+
+                // Thumbnails of the previous search that weren't made yet are dropped
+                self.thumbnail_pool.queue(&self.results.lock().unwrap());
+
+                // Search is done, send user to results page
+                self.mode = ViewPage::Results;
+            }
+            Err(s) => {
+                self.search_err = Some(s.to_string());
+            }
+        }
+    }
+
     /// The "Search" tab
     fn ui_search(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
         ui.horizontal(|ui| {
@@ -675,86 +1158,7 @@ impl TagMaid {
             if (search_input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)))
                 || ui.button("Search").clicked()
             {
-                // Handle search
-
-                match Search::from_string(&self.search) {
-                    Ok(v) => {
-                        *self.update_search.lock().unwrap() = true;
-                        self.search_err = None;
-                        let nbool = Arc::clone(&self.update_search);
-                        let nres = Arc::clone(&self.results);
-                        let db = self.db.clone();
-                        let search = v.clone();
-                        let thumbnail_paths = self.thumbnail_paths.clone();
-                        let mut is_cached = false;
-                        self.search_options = Some(v.clone());
-
-                        // Try finding a cached search
-
-                        match self.db.get_cache().get_search(&search) {
-                            Some(search_results) => {
-                                is_cached = true;
-                                *nres.lock().unwrap() = search_results.clone();
-                                *nbool.clone().lock().unwrap() = false;
-                            },
-                            None => {}
-                        }
-
-                        // Search wasn't cached
-                        if !is_cached {
-                            let handle = std::thread::spawn(move || {
-                                match Self::get_results(
-                                    search,
-                                    nres.clone(),
-                                    nbool,
-                                    db,
-                                    thumbnail_paths,
-                                ) {
-                                    Ok(..) => {}
-                                    Err(..) => {
-                                        nres.clone().lock().unwrap().clear();
-                                    }
-                                }
-                            });
-                            /*
-                            So there was basically a data race or whatever its called because
-                            the thread takes time and the caching functions would accidentally
-                            access the previous result because the thread wasn't updating it yet
-
-                            So... I used join(), it works perfectly as intended, but this makes
-                            me question whether it defeats the whole purpose of the thread. Not that it
-                            panics or whatever, but idk.
-
-                            I couldn't implement the "put in cache" apart in the get_results() function 
-                            because the cache is a RefCell and even though it compiles and works fine,
-                            the "real" RefCell never gets updated so the stuff never got cached.
-                            
-                            Idk how to fix this other than join(). Imo given that we can only do one search
-                            at once (as a user) I'd say it's fine right now. But something feels wrong. 
-                            */
-                            if handle.join().is_err() {
-                                // Search failed, stop hanging it
-                                *self.update_search.lock().unwrap() = false;
-                            };
-
-                            // Attempts to cache the search results
-                            match self.db.get_cache().cache_search(v.clone(), self.results.clone().lock().unwrap().to_vec()) {
-                                Ok(()) => {},
-                                Err(err) => {
-                                    // Fails silently because not being able to cache sometimes isn't
-                                    // that big of a deal
-                                    info!("WARNING: ui_search(): Couldn't open cache as mutable because it was already being borrowed: {err}");
-                                }
-                            }
-                        }
-
-                        // Search is done, send user to results page
-                        self.mode = ViewPage::Results;
-                    }
-                    Err(s) => {
-                        self.search_err = Some(s.to_string());
-                    }
-                }
+                self.start_search();
             }
             if let Some(err) = &self.search_err {
                 ui.colored_label(egui::Color32::from_rgb(255, 0, 0), err);
@@ -777,8 +1181,12 @@ impl TagMaid {
             },
         );
         let tagfile_name = &tagfile.get_file_name().to_owned();
+        // HiDPI thumbnails have more pixels but take as much room
+        let grid = self.conf.thumbnails.grid as f32;
+        let img_size = image_texture.size_vec2();
+        let img_size = img_size * (grid / img_size.x.max(img_size.y)).min(1.0);
         ui.centered_and_justified(|ui| {
-            let image = ui.image(image_texture.id(), image_texture.size_vec2());
+            let image = ui.image(image_texture.id(), img_size);
             let response = &image.interact(egui::Sense::click());
             if response.clicked() {
                 // User clicked on an image to see the file details:
@@ -833,8 +1241,18 @@ impl TagMaid {
                     }
                     // Items Per Row (might be a config option later)
                     const IPR: usize = 6;
+                    let progress = self.thumbnail_pool.progress();
+                    if !progress.is_done() {
+                        ui.add(
+                            egui::ProgressBar::new(progress.fraction())
+                                .text(format!("Thumbnails {}/{}", progress.done, progress.total)),
+                        );
+                    }
                     let chunks: Vec<_> = res.chunks(IPR).collect();
                     egui::ScrollArea::vertical().show_rows(ui, 120.0, chunks.len(), |ui, range| {
+                        // Rows on screen get their thumbnails first
+                        self.thumbnail_pool
+                            .prioritise(&chunks[range.clone()].concat());
                         ui.vertical_centered(|ui| {
                             for row in &chunks[range] {
                                 // ROW GUI
@@ -856,10 +1274,7 @@ impl TagMaid {
                                                         ctx,
                                                         ui,
                                                         &tagfile,
-                                                        Some(Self::get_thumbnail_path(
-                                                            self.thumbnail_paths.clone(),
-                                                            tagfile.clone(),
-                                                        )),
+                                                        self.thumbnail_pool.get(r),
                                                     );
                                                 });
                                             });
@@ -877,7 +1292,9 @@ impl TagMaid {
 }
 impl eframe::App for TagMaid {
     fn on_close_event(&mut self) -> bool {
-        self.conf.save().expect("failed to save");
+        if let Err(err) = self.conf.save() {
+            info!("WARNING: on_close_event(): {err:#}");
+        }
         true
     }
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
@@ -894,6 +1311,10 @@ impl eframe::App for TagMaid {
                 }
             }
         });
+        let messages: Vec<IpcMessage> = self.inbox.lock().unwrap().drain(..).collect();
+        for message in messages {
+            self.handle_message(message);
+        }
         egui::TopBottomPanel::top("pan").show(ctx, |ui| {
             egui::menu::bar(ui, |ui| {
                 if ui.button("Search").clicked() {
@@ -909,6 +1330,9 @@ impl eframe::App for TagMaid {
                     self.edit_tags = BTreeSet::new();
                     self.mode = ViewPage::Add;
                 }
+                if ui.button("Duplicates").clicked() {
+                    self.mode = ViewPage::Duplicates;
+                }
                 #[cfg(feature = "ui_debug")]
                 if ui.button("Debug").clicked() {
                     self.mode = ViewPage::Debug;
@@ -931,6 +1355,9 @@ impl eframe::App for TagMaid {
             ViewPage::RemoveFile => {
                 self.ui_remove_file(ctx, ui);
             }
+            ViewPage::Duplicates => {
+                self.ui_duplicates(ctx, ui);
+            }
             #[cfg(feature = "ui_debug")]
             ViewPage::Debug => {
                 egui::ScrollArea::vertical().show(ui, |ui| {